@@ -0,0 +1,56 @@
+use actix_web::{dev::ServiceRequest, http::header};
+
+/// Compare tokens in constant time,
+/// so the token can't be guessed by timing.
+fn tokens_equal(expected: &[u8], actual: &[u8]) -> bool {
+    expected.len() == actual.len()
+        && expected
+            .iter()
+            .zip(actual)
+            .fold(0, |diff, (left, right)| diff | (left ^ right))
+            == 0
+}
+
+/// Check the admin token of a request.
+///
+/// The `Authorization` header is removed after the check,
+/// so the token doesn't get into hook messages
+/// that are sent by admin handlers.
+pub fn authorize(request: &mut ServiceRequest, token: &str) -> bool {
+    let authorized = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|actual| tokens_equal(token.as_bytes(), actual.trim().as_bytes()));
+    request.headers_mut().remove(header::AUTHORIZATION);
+    authorized
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::{authorize, tokens_equal};
+
+    #[test]
+    fn compare_tokens() {
+        assert!(tokens_equal(b"secret", b"secret"));
+        assert!(!tokens_equal(b"secret", b"secreT"));
+        assert!(!tokens_equal(b"secret", b"secret2"));
+        assert!(!tokens_equal(b"secret", b""));
+    }
+
+    #[test]
+    fn header_is_removed() {
+        let mut request = TestRequest::get()
+            .insert_header(("Authorization", "Bearer secret"))
+            .to_srv_request();
+        assert!(authorize(&mut request, "secret"));
+        assert!(!request.headers().contains_key("Authorization"));
+        let mut request = TestRequest::get()
+            .insert_header(("Authorization", "secret"))
+            .to_srv_request();
+        assert!(!authorize(&mut request, "secret"));
+    }
+}
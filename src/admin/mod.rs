@@ -0,0 +1,156 @@
+use actix_web::{
+    dev::{Server, Service, ServiceResponse},
+    middleware, web, App, HttpServer,
+};
+
+use crate::{
+    errors::{RustusError, RustusResult},
+    reload::Reloader,
+    routes::not_found,
+    State,
+};
+
+mod auth;
+mod routes;
+
+/// Register routes for managing uploads.
+fn uploads(web_app: &mut web::ServiceConfig) {
+    web_app
+        .route("/uploads", web::get().to(routes::list_uploads))
+        .route("/uploads/{file_id}", web::get().to(routes::get_upload))
+        .route(
+            "/uploads/{file_id}",
+            web::delete().to(routes::terminate_upload),
+        )
+        .route(
+            "/uploads/{file_id}/finish",
+            web::post().to(routes::finish_upload),
+        )
+        .route(
+            "/uploads/{file_id}/concat",
+            web::post().to(routes::concat_upload),
+        )
+        .route(
+            "/uploads/{file_id}/hooks/{hook}",
+            web::post().to(routes::send_hook),
+        );
+}
+
+/// Configure admin API.
+///
+/// Admin API is added only if the admin token is set.
+/// All its handlers require this token.
+///
+/// Uploads of tenants are managed under `/tenants/{name}`.
+pub fn setup(
+    state: State,
+    tenants: Vec<(String, State)>,
+    reloader: Reloader,
+) -> impl Fn(&mut web::ServiceConfig) {
+    move |web_app| {
+        let Some(token) = state.config.admin_opts.token.clone() else {
+            return;
+        };
+        let pending = state.pending.clone();
+        let mut scope = web::scope(state.config.admin_url().as_str())
+            .app_data(web::Data::new(state.clone()))
+            .app_data(web::Data::new(reloader.clone()))
+            .wrap_fn(move |mut req, srv| {
+                let fut = if auth::authorize(&mut req, &token) {
+                    Ok(srv.call(req))
+                } else {
+                    Err(req.error_response(RustusError::Unauthorized))
+                };
+                async move {
+                    match fut {
+                        Ok(fut) => fut.await.map(ServiceResponse::map_into_left_body),
+                        Err(response) => Ok(response.map_into_right_body()),
+                    }
+                }
+            })
+            // Shutdown waits for admin requests in progress too.
+            .wrap_fn(move |req, srv| {
+                let request = pending.start_request();
+                let fut = srv.call(req);
+                async move {
+                    let response = fut.await;
+                    drop(request);
+                    response
+                }
+            });
+        for (name, tenant) in &tenants {
+            scope = scope.service(
+                web::scope(format!("/tenants/{name}").as_str())
+                    .app_data(web::Data::new(tenant.clone()))
+                    .configure(uploads),
+            );
+        }
+        web_app.service(
+            scope
+                .configure(uploads)
+                .route("/reload", web::post().to(routes::reload)),
+        );
+    }
+}
+
+/// Create a separate server for admin API.
+///
+/// The server is created only if admin API
+/// is enabled and the admin port is set.
+/// Otherwise admin API is served by the main server.
+pub fn create_server(
+    state: State,
+    tenants: Vec<(String, State)>,
+    reloader: Reloader,
+) -> RustusResult<Option<Server>> {
+    let Some(port) = state.config.admin_opts.port else {
+        return Ok(None);
+    };
+    if state.config.admin_opts.token.is_none() {
+        return Ok(None);
+    }
+    let host = state.config.host.clone();
+    let server = HttpServer::new(move || {
+        App::new()
+            .configure(setup(state.clone(), tenants.clone(), reloader.clone()))
+            .wrap(middleware::Logger::new("\"%r\" \"-\" \"%s\" \"%a\" \"%D\""))
+            .default_service(web::route().to(not_found))
+    })
+    .workers(1)
+    // Signals are handled by the main server.
+    .disable_signals()
+    .bind((host, port))?;
+    Ok(Some(server.run()))
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::setup;
+    use crate::{reload::Reloader, state::State};
+    use actix_web::{dev::ServiceResponse, test::init_service, App};
+
+    pub const TEST_TOKEN: &str = "test-token";
+
+    pub async fn get_admin_service(
+        state: State,
+    ) -> impl actix_web::dev::Service<
+        actix_http::Request,
+        Response = ServiceResponse,
+        Error = actix_web::Error,
+    > {
+        get_tenants_admin_service(state, Vec::new()).await
+    }
+
+    pub async fn get_tenants_admin_service(
+        mut state: State,
+        tenants: Vec<(String, State)>,
+    ) -> impl actix_web::dev::Service<
+        actix_http::Request,
+        Response = ServiceResponse,
+        Error = actix_web::Error,
+    > {
+        state.config.admin_opts.token = Some(String::from(TEST_TOKEN));
+        let reloader = Reloader::new(&state, &tenants);
+        init_service(App::new().configure(setup(state, tenants, reloader))).await
+    }
+}
@@ -0,0 +1,619 @@
+use std::str::FromStr;
+
+use actix_web::{web, HttpRequest, HttpResponse};
+use futures::StreamExt;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{
+    data_storage::base::DataStorage,
+    errors::{RustusError, RustusResult},
+    file_info::FileInfo,
+    info_storage::{base::InfoStorage, query::UploadFilter},
+    notifiers::Hook,
+    reload::Reloader,
+    utils::hashes::{upload_digest_matches, RunningDigest},
+    State,
+};
+
+/// Maximum number of uploads on a single page.
+const MAX_PAGE_SIZE: usize = 1000;
+
+const fn default_limit() -> usize {
+    100
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PageParams {
+    cursor: Option<String>,
+    #[serde(default = "default_limit")]
+    limit: usize,
+}
+
+/// List uploads.
+///
+/// Uploads are filtered by query parameters
+/// and returned page by page. To get the next page,
+/// the client passes `next_cursor` from the response
+/// as `cursor` parameter.
+pub async fn list_uploads(
+    state: web::Data<State>,
+    filter: web::Query<UploadFilter>,
+    page: web::Query<PageParams>,
+) -> RustusResult<HttpResponse> {
+    if !(1..=MAX_PAGE_SIZE).contains(&page.limit) {
+        return Err(RustusError::WrongPageLimit(MAX_PAGE_SIZE));
+    }
+    let uploads = state
+        .info_storage
+        .list(&filter, page.cursor.as_deref(), page.limit)
+        .await?;
+    Ok(HttpResponse::Ok().json(uploads))
+}
+
+/// Get raw information about an upload.
+pub async fn get_upload(
+    state: web::Data<State>,
+    file_id: web::Path<String>,
+) -> RustusResult<HttpResponse> {
+    let file_info = state.info_storage.get_info(&file_id).await?;
+    Ok(HttpResponse::Ok().json(file_info))
+}
+
+/// Remove an upload.
+///
+/// Unlike termination extension, it ignores
+/// pre-terminate hook. Info is removed even if
+/// the data is already missing.
+pub async fn terminate_upload(
+    request: HttpRequest,
+    state: web::Data<State>,
+    file_id: web::Path<String>,
+) -> RustusResult<HttpResponse> {
+    state
+        .locker
+        .with_lock(&file_id, terminate_locked(&request, &state, &file_id))
+        .await
+}
+
+async fn terminate_locked(
+    request: &HttpRequest,
+    state: &web::Data<State>,
+    file_id: &str,
+) -> RustusResult<HttpResponse> {
+    let file_info = state.info_storage.get_info(file_id).await?;
+    state.info_storage.remove_info(&file_info.id).await?;
+    if let Some(data_storage) = state.data_storages.get(&file_info.storage) {
+        match data_storage.remove_file(&file_info).await {
+            Ok(()) | Err(RustusError::FileNotFound) => {}
+            Err(err) => return Err(err),
+        }
+    } else {
+        log::warn!(
+            "Data of upload {} is kept, since it's in unknown storage {}",
+            file_info.id,
+            file_info.storage
+        );
+    }
+    spawn_hook(request, state, Hook::PostTerminate, file_info);
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Mark an upload as finished.
+///
+/// Length of the upload becomes equal to its offset,
+/// and the storage completes the upload as if
+/// the last bytes were written.
+pub async fn finish_upload(
+    request: HttpRequest,
+    state: web::Data<State>,
+    file_id: web::Path<String>,
+) -> RustusResult<HttpResponse> {
+    state
+        .locker
+        .with_lock(&file_id, finish_locked(&request, &state, &file_id))
+        .await
+}
+
+async fn finish_locked(
+    request: &HttpRequest,
+    state: &web::Data<State>,
+    file_id: &str,
+) -> RustusResult<HttpResponse> {
+    let mut file_info = state.info_storage.get_info(file_id).await?;
+    if file_info.is_final {
+        return Err(RustusError::FinishingFinalUpload);
+    }
+    if Some(file_info.offset) == file_info.length {
+        return Err(RustusError::UploadFinished);
+    }
+    file_info.length = Some(file_info.offset);
+    file_info.deferred_size = false;
+    // No bytes are written, so the digest is finalized
+    // and checked before the upload is completed.
+    let offset = file_info.offset;
+    RunningDigest::restore(&file_info, state.config.upload_digest).save(&mut file_info, offset);
+    if !upload_digest_matches(&file_info) {
+        return Err(RustusError::WrongChecksum);
+    }
+    state
+        .data_storages
+        .for_upload(&file_info)?
+        .add_bytes(&mut file_info, futures::stream::empty().boxed_local())
+        .await?;
+    state.info_storage.update_info(&mut file_info).await?;
+    spawn_hook(request, state, Hook::PostFinish, file_info.clone());
+    Ok(HttpResponse::Ok().json(file_info))
+}
+
+/// Concatenate parts of a final upload again.
+///
+/// It's used if concatenation has failed
+/// when the final upload was created.
+pub async fn concat_upload(
+    request: HttpRequest,
+    state: web::Data<State>,
+    file_id: web::Path<String>,
+) -> RustusResult<HttpResponse> {
+    state
+        .locker
+        .with_lock(&file_id, concat_locked(&request, &state, &file_id))
+        .await
+}
+
+async fn concat_locked(
+    request: &HttpRequest,
+    state: &web::Data<State>,
+    file_id: &str,
+) -> RustusResult<HttpResponse> {
+    let mut file_info = state.info_storage.get_info(file_id).await?;
+    if !file_info.is_final {
+        return Err(RustusError::NotFinalUpload);
+    }
+    if Some(file_info.offset) == file_info.length {
+        return Err(RustusError::UploadFinished);
+    }
+    let data_storage = state.data_storages.for_upload(&file_info)?;
+    let mut parts_info = Vec::new();
+    for part_id in file_info.parts.clone().unwrap_or_default() {
+        let part = state.info_storage.get_info(&part_id).await?;
+        if part.length != Some(part.offset) {
+            return Err(RustusError::PartNotComplete(part.id));
+        }
+        if part.storage != file_info.storage {
+            return Err(RustusError::PartInAnotherStorage(part.id));
+        }
+        parts_info.push(part);
+    }
+    // Previous attempt might have written some data.
+    file_info.offset = 0;
+    data_storage.truncate(&mut file_info).await?;
+    let final_size = parts_info.iter().map(|part| part.offset).sum();
+    if let Err(err) = data_storage
+        .concat_files(&mut file_info, parts_info.clone())
+        .await
+    {
+        // Storage might have dropped its state of the failed attempt,
+        // so the next retry mustn't reuse the saved one.
+        state.info_storage.update_info(&mut file_info).await?;
+        return Err(err);
+    }
+    file_info.offset = final_size;
+    file_info.length = Some(final_size);
+    state.info_storage.update_info(&mut file_info).await?;
+    if state.config.remove_parts {
+        for part in parts_info {
+            data_storage.remove_file(&part).await?;
+            state.info_storage.remove_info(part.id.as_str()).await?;
+        }
+    }
+    spawn_hook(request, state, Hook::PostFinish, file_info.clone());
+    Ok(HttpResponse::Ok().json(file_info))
+}
+
+/// Send a hook for an upload again.
+///
+/// The hook is sent before the response,
+/// so errors of notifiers are returned to the client.
+pub async fn send_hook(
+    request: HttpRequest,
+    state: web::Data<State>,
+    path: web::Path<(String, String)>,
+) -> RustusResult<HttpResponse> {
+    let (file_id, hook_name) = path.into_inner();
+    let hook = Hook::from_str(&hook_name).map_err(|_| RustusError::UnknownHook(hook_name))?;
+    let options = state.reloadable.current();
+    if !options.hook_is_active(hook) {
+        return Err(RustusError::HookDisabled(hook.to_string()));
+    }
+    let file_info = state.info_storage.get_info(&file_id).await?;
+    let message = state.config.notification_opts.hooks_format.format(
+        &request,
+        &file_info,
+        state.config.notification_opts.behind_proxy,
+    );
+    options
+        .notification_manager
+        .send_message(message, hook, &file_info, request.headers())
+        .await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Reload hooks, notifiers, limits and CORS origins.
+///
+/// Options are read from the same sources as on startup.
+/// Returns options which are used now.
+pub async fn reload(reloader: web::Data<Reloader>) -> RustusResult<HttpResponse> {
+    let options = reloader.reload().await?;
+    Ok(HttpResponse::Ok().json(json!({
+        "hooks": options.hooks.iter().map(ToString::to_string).collect::<Vec<_>>(),
+        "max_file_size": options.max_file_size,
+        "cors": options.cors,
+    })))
+}
+
+/// Send a hook in background if it's enabled.
+fn spawn_hook(request: &HttpRequest, state: &web::Data<State>, hook: Hook, file_info: FileInfo) {
+    let options = state.reloadable.current();
+    if !options.hook_is_active(hook) {
+        return;
+    }
+    let message = state.config.notification_opts.hooks_format.format(
+        request,
+        &file_info,
+        state.config.notification_opts.behind_proxy,
+    );
+    let headers = request.headers().clone();
+    let upload_id = file_info.id.clone();
+    state.pending.spawn_hook(hook, &upload_id, async move {
+        options
+            .notification_manager
+            .send_message(message, hook, &file_info, &headers)
+            .await
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        http::StatusCode,
+        test::{call_service, read_body_json, TestRequest},
+    };
+
+    use std::path::PathBuf;
+
+    use crate::{
+        admin::test::{get_admin_service, get_tenants_admin_service, TEST_TOKEN},
+        data_storage::base::DataStorage,
+        file_info::FileInfo,
+        info_storage::base::InfoStorage,
+        reload::Reloader,
+        utils::hashes::{DigestAlgorithm, UploadDigest, CHECKSUM_METADATA_KEY},
+        State,
+    };
+
+    fn admin_request(request: TestRequest, uri: &str) -> actix_http::Request {
+        request
+            .uri(uri)
+            .insert_header(("Authorization", format!("Bearer {TEST_TOKEN}")))
+            .to_request()
+    }
+
+    #[actix_rt::test]
+    async fn list_uploads() {
+        let state = State::test_new().await;
+        let service = get_admin_service(state.clone()).await;
+        let unfinished = state.create_test_file().await;
+        let mut finished = state.create_test_file().await;
+        finished.offset = 10;
+        state.info_storage.set_info(&finished, false).await.unwrap();
+        let request = TestRequest::get()
+            .uri("/admin/uploads?state=unfinished&limit=10")
+            .insert_header(("Authorization", format!("Bearer {TEST_TOKEN}")))
+            .to_request();
+        let response = call_service(&service, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = read_body_json(response).await;
+        let uploads = body["uploads"].as_array().unwrap();
+        assert_eq!(uploads.len(), 1);
+        assert_eq!(uploads[0]["id"], unfinished.id.as_str());
+        assert!(body["next_cursor"].is_null());
+    }
+
+    #[actix_rt::test]
+    async fn wrong_limit() {
+        let state = State::test_new().await;
+        let service = get_admin_service(state.clone()).await;
+        let request = TestRequest::get()
+            .uri("/admin/uploads?limit=0")
+            .insert_header(("Authorization", format!("Bearer {TEST_TOKEN}")))
+            .to_request();
+        let response = call_service(&service, request).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_rt::test]
+    async fn unauthorized() {
+        let state = State::test_new().await;
+        let service = get_admin_service(state.clone()).await;
+        let request = TestRequest::get().uri("/admin/uploads").to_request();
+        let response = call_service(&service, request).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let request = TestRequest::get()
+            .uri("/admin/uploads")
+            .insert_header(("Authorization", "Bearer wrong"))
+            .to_request();
+        let response = call_service(&service, request).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_rt::test]
+    async fn get_upload() {
+        let state = State::test_new().await;
+        let service = get_admin_service(state.clone()).await;
+        let file_info = state.create_test_file().await;
+        let uri = format!("/admin/uploads/{}", file_info.id);
+        let response = call_service(&service, admin_request(TestRequest::get(), &uri)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = read_body_json(response).await;
+        assert_eq!(body["id"], file_info.id.as_str());
+        assert_eq!(body["length"], 10);
+        let response = call_service(
+            &service,
+            admin_request(TestRequest::get(), "/admin/uploads/unknown"),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn tenant_upload() {
+        let state = State::test_new().await;
+        let tenant = State::test_new().await;
+        let service =
+            get_tenants_admin_service(state, vec![(String::from("avatars"), tenant.clone())]).await;
+        let file_info = tenant.create_test_file().await;
+        let response = call_service(
+            &service,
+            admin_request(
+                TestRequest::get(),
+                &format!("/admin/uploads/{}", file_info.id),
+            ),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let uri = format!("/admin/tenants/avatars/uploads/{}", file_info.id);
+        let response = call_service(&service, admin_request(TestRequest::get(), &uri)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = read_body_json(response).await;
+        assert_eq!(body["id"], file_info.id.as_str());
+        let response = call_service(&service, admin_request(TestRequest::delete(), &uri)).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(tenant.info_storage.get_info(&file_info.id).await.is_err());
+        let response = call_service(
+            &service,
+            admin_request(TestRequest::get(), "/admin/tenants/unknown/uploads"),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn terminate_upload() {
+        let state = State::test_new().await;
+        let service = get_admin_service(state.clone()).await;
+        let file_info = state.create_test_file().await;
+        let uri = format!("/admin/uploads/{}", file_info.id);
+        // Upload is locked by a request.
+        let lock = state.locker.acquire(&file_info.id).await.unwrap();
+        let response = call_service(&service, admin_request(TestRequest::delete(), &uri)).await;
+        assert_eq!(response.status(), StatusCode::LOCKED);
+        lock.release().await.unwrap();
+        let response = call_service(&service, admin_request(TestRequest::delete(), &uri)).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(state.info_storage.get_info(&file_info.id).await.is_err());
+        assert!(!PathBuf::from(file_info.path.unwrap()).exists());
+    }
+
+    #[actix_rt::test]
+    async fn terminate_upload_without_data() {
+        let state = State::test_new().await;
+        let service = get_admin_service(state.clone()).await;
+        let file_info = state.create_test_file().await;
+        state
+            .data_storages
+            .default_storage()
+            .remove_file(&file_info)
+            .await
+            .unwrap();
+        let uri = format!("/admin/uploads/{}", file_info.id);
+        let response = call_service(&service, admin_request(TestRequest::delete(), &uri)).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(state.info_storage.get_info(&file_info.id).await.is_err());
+    }
+
+    #[actix_rt::test]
+    async fn finish_upload() {
+        let state = State::test_new().await;
+        let service = get_admin_service(state.clone()).await;
+        let mut file_info = state.create_test_file().await;
+        std::fs::write(file_info.path.clone().unwrap(), "memes").unwrap();
+        file_info.offset = 5;
+        state
+            .info_storage
+            .update_info(&mut file_info)
+            .await
+            .unwrap();
+        let uri = format!("/admin/uploads/{}/finish", file_info.id);
+        let response = call_service(&service, admin_request(TestRequest::post(), &uri)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let saved = state.info_storage.get_info(&file_info.id).await.unwrap();
+        assert_eq!(saved.length, Some(5));
+        assert_eq!(saved.offset, 5);
+        let response = call_service(&service, admin_request(TestRequest::post(), &uri)).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_rt::test]
+    async fn finish_upload_digest() {
+        let mut state = State::test_new().await;
+        state.config.upload_digest = Some(DigestAlgorithm::Md5);
+        let service = get_admin_service(state.clone()).await;
+        let mut file_info = state.create_test_file().await;
+        file_info.digest = Some(UploadDigest::new(DigestAlgorithm::Md5));
+        file_info.metadata.insert(
+            String::from(CHECKSUM_METADATA_KEY),
+            String::from("md5 XUFAKrxLKna5cZ2REBfFkg=="),
+        );
+        state
+            .info_storage
+            .update_info(&mut file_info)
+            .await
+            .unwrap();
+        let uri = format!("/admin/uploads/{}/finish", file_info.id);
+        let response = call_service(&service, admin_request(TestRequest::post(), &uri)).await;
+        assert_eq!(response.status(), StatusCode::from_u16(460).unwrap());
+        let saved = state.info_storage.get_info(&file_info.id).await.unwrap();
+        assert_eq!(saved.length, Some(10));
+
+        // Digest of empty data.
+        file_info.metadata.insert(
+            String::from(CHECKSUM_METADATA_KEY),
+            String::from("md5 1B2M2Y8AsgTpgAmY7PhCfg=="),
+        );
+        state
+            .info_storage
+            .update_info(&mut file_info)
+            .await
+            .unwrap();
+        let response = call_service(&service, admin_request(TestRequest::post(), &uri)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let saved = state.info_storage.get_info(&file_info.id).await.unwrap();
+        assert_eq!(saved.length, Some(0));
+        assert_eq!(
+            saved.digest.unwrap().header_value().unwrap(),
+            "md5 1B2M2Y8AsgTpgAmY7PhCfg=="
+        );
+    }
+
+    #[actix_rt::test]
+    async fn finish_locked_upload() {
+        let state = State::test_new().await;
+        let service = get_admin_service(state.clone()).await;
+        let file_info = state.create_test_file().await;
+        let _lock = state.locker.acquire(&file_info.id).await.unwrap();
+        let uri = format!("/admin/uploads/{}/finish", file_info.id);
+        let response = call_service(&service, admin_request(TestRequest::post(), &uri)).await;
+        assert_eq!(response.status(), StatusCode::LOCKED);
+    }
+
+    #[actix_rt::test]
+    async fn concat_upload() {
+        let state = State::test_new().await;
+        let service = get_admin_service(state.clone()).await;
+        let mut parts = Vec::new();
+        for data in ["first part", "secondpart"] {
+            let mut part = state.create_test_file().await;
+            std::fs::write(part.path.clone().unwrap(), data).unwrap();
+            part.offset = 10;
+            part.is_partial = true;
+            state.info_storage.set_info(&part, false).await.unwrap();
+            parts.push(part.id);
+        }
+        let mut final_info = FileInfo::new(
+            uuid::Uuid::new_v4().to_string().as_str(),
+            None,
+            None,
+            state.data_storages.default_storage().get_name().to_string(),
+            None,
+        );
+        final_info.is_final = true;
+        final_info.parts = Some(parts);
+        let path = state
+            .data_storages
+            .default_storage()
+            .create_file(&mut final_info)
+            .await
+            .unwrap();
+        final_info.path = Some(path.clone());
+        // Data of the failed attempt.
+        std::fs::write(&path, "garbage").unwrap();
+        state
+            .info_storage
+            .set_info(&final_info, true)
+            .await
+            .unwrap();
+        let uri = format!("/admin/uploads/{}/concat", final_info.id);
+        let response = call_service(&service, admin_request(TestRequest::post(), &uri)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let saved = state.info_storage.get_info(&final_info.id).await.unwrap();
+        assert_eq!(saved.offset, 20);
+        assert_eq!(saved.length, Some(20));
+        assert_eq!(
+            std::fs::read_to_string(path).unwrap(),
+            "first partsecondpart"
+        );
+    }
+
+    #[actix_rt::test]
+    async fn concat_failure() {
+        let state = State::test_new().await;
+        let service = get_admin_service(state.clone()).await;
+        let mut part = state.create_test_file().await;
+        part.offset = 10;
+        part.is_partial = true;
+        state.info_storage.set_info(&part, false).await.unwrap();
+        // Data of the part is lost.
+        std::fs::remove_file(part.path.clone().unwrap()).unwrap();
+        let mut final_info = state.create_test_file().await;
+        final_info.is_final = true;
+        final_info.parts = Some(vec![part.id]);
+        state
+            .info_storage
+            .set_info(&final_info, false)
+            .await
+            .unwrap();
+        let uri = format!("/admin/uploads/{}/concat", final_info.id);
+        let response = call_service(&service, admin_request(TestRequest::post(), &uri)).await;
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let saved = state.info_storage.get_info(&final_info.id).await.unwrap();
+        assert_eq!(saved.version, final_info.version + 1);
+        assert_eq!(saved.offset, 0);
+    }
+
+    #[actix_rt::test]
+    async fn concat_not_final() {
+        let state = State::test_new().await;
+        let service = get_admin_service(state.clone()).await;
+        let file_info = state.create_test_file().await;
+        let uri = format!("/admin/uploads/{}/concat", file_info.id);
+        let response = call_service(&service, admin_request(TestRequest::post(), &uri)).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_rt::test]
+    async fn send_hook() {
+        let state = State::test_new().await;
+        let service = get_admin_service(state.clone()).await;
+        let file_info = state.create_test_file().await;
+        let uri = format!("/admin/uploads/{}/hooks/post-create", file_info.id);
+        let response = call_service(&service, admin_request(TestRequest::post(), &uri)).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let uri = format!("/admin/uploads/{}/hooks/unknown", file_info.id);
+        let response = call_service(&service, admin_request(TestRequest::post(), &uri)).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_rt::test]
+    async fn send_disabled_hook() {
+        let state = State::test_new().await;
+        let mut config = state.config.clone();
+        config.notification_opts.hooks = vec![];
+        Reloader::new(&state, &[]).apply(&config).await.unwrap();
+        let service = get_admin_service(state.clone()).await;
+        let file_info = state.create_test_file().await;
+        let uri = format!("/admin/uploads/{}/hooks/post-create", file_info.id);
+        let response = call_service(&service, admin_request(TestRequest::post(), &uri)).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+}
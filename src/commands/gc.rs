@@ -0,0 +1,224 @@
+use std::{
+    fs::read_dir,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::{
+    data_storage::{base::DataStorage, registry::DataStorageRegistry, DataStorageImpl},
+    errors::{RustusError, RustusResult},
+    info_storage::{base::InfoStorage, query::UploadFilter, InfoStorageImpl},
+    locks::LockerImpl,
+    protocol::expiration::remove_expired,
+};
+
+use super::PAGE_SIZE;
+
+/// Numbers of removed uploads.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct GcReport {
+    pub expired: usize,
+    pub without_data: usize,
+    pub without_info: usize,
+}
+
+/// Remove uploads that can't be used anymore.
+///
+/// This function removes expired uploads,
+/// information about uploads which data is missing,
+/// and data files that have no information.
+///
+/// # Params
+/// `locker` - locks of uploads, expired uploads
+/// which are in use aren't removed.
+/// `min_age` - data files younger than this are kept,
+/// because their info might be not saved yet.
+pub async fn collect_garbage(
+    data_storages: &DataStorageRegistry,
+    info_storage: &InfoStorageImpl,
+    locker: &LockerImpl,
+    min_age: Duration,
+) -> RustusResult<GcReport> {
+    Ok(GcReport {
+        expired: remove_expired(data_storages, info_storage, locker).await?,
+        without_data: remove_infos_without_data(data_storages, info_storage).await?,
+        without_info: remove_data_without_info(data_storages, info_storage, min_age).await?,
+    })
+}
+
+/// Remove information about uploads which data is missing.
+async fn remove_infos_without_data(
+    data_storages: &DataStorageRegistry,
+    info_storage: &InfoStorageImpl,
+) -> RustusResult<usize> {
+    let mut removed = 0;
+    let mut cursor = None;
+    loop {
+        let page = info_storage
+            .list(&UploadFilter::default(), cursor.as_deref(), PAGE_SIZE)
+            .await?;
+        for file_info in page.uploads {
+            let Some(data_storage) = data_storages.get(&file_info.storage) else {
+                continue;
+            };
+            if data_storage.get_length(&file_info).await?.is_some() {
+                continue;
+            }
+            log::debug!("Removing upload {} without data", file_info.id);
+            info_storage.remove_info(&file_info.id).await?;
+            removed += 1;
+        }
+        cursor = page.next_cursor;
+        if cursor.is_none() {
+            return Ok(removed);
+        }
+    }
+}
+
+/// Remove data files that have no information.
+///
+/// Only local files can be found, so storages
+/// that keep data elsewhere are skipped.
+async fn remove_data_without_info(
+    data_storages: &DataStorageRegistry,
+    info_storage: &InfoStorageImpl,
+    min_age: Duration,
+) -> RustusResult<usize> {
+    let mut data_dirs = Vec::new();
+    for data_dir in data_storages.iter().filter_map(DataStorageImpl::local_dir) {
+        // Storages might share the same directory.
+        if !data_dirs.contains(&data_dir) {
+            data_dirs.push(data_dir);
+        }
+    }
+    let mut removed = 0;
+    for data_dir in data_dirs {
+        let data_dir = data_dir.to_path_buf();
+        let files =
+            tokio::task::spawn_blocking(move || find_data_files(&data_dir, min_age)).await??;
+        removed += remove_files_without_info(info_storage, files).await?;
+    }
+    Ok(removed)
+}
+
+/// Remove files of uploads that have no information.
+async fn remove_files_without_info(
+    info_storage: &InfoStorageImpl,
+    files: Vec<(String, PathBuf)>,
+) -> RustusResult<usize> {
+    let mut removed = 0;
+    for (file_id, path) in files {
+        match info_storage.get_info(&file_id).await {
+            Err(RustusError::FileNotFound) => {}
+            Ok(_) => continue,
+            Err(err) => return Err(err),
+        }
+        log::debug!("Removing data file {} without info", path.display());
+        tokio::fs::remove_file(path).await?;
+        removed += 1;
+    }
+    Ok(removed)
+}
+
+/// Find data files older than `min_age`.
+///
+/// Returns ids of uploads and paths to their files.
+fn find_data_files(data_dir: &Path, min_age: Duration) -> RustusResult<Vec<(String, PathBuf)>> {
+    let mut files = Vec::new();
+    let mut dirs = vec![data_dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in read_dir(dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                dirs.push(entry.path());
+                continue;
+            }
+            // Info files can be stored in the same directory,
+            // so only files named after upload ids are checked.
+            let Some(file_id) = entry
+                .file_name()
+                .to_str()
+                .filter(|name| uuid::Uuid::parse_str(name).is_ok())
+                .map(String::from)
+            else {
+                continue;
+            };
+            let age = entry.metadata()?.modified()?.elapsed().unwrap_or_default();
+            if age >= min_age {
+                files.push((file_id, entry.path()));
+            }
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, time::Duration};
+
+    use super::{collect_garbage, GcReport};
+    use crate::{data_storage::base::DataStorage, info_storage::base::InfoStorage, State};
+
+    #[actix_rt::test]
+    async fn success() {
+        let state = State::test_new().await;
+        let alive = state.create_test_file().await;
+        let mut expired = state.create_test_file().await;
+        expired.expires_at = Some(chrono::Utc::now() - chrono::Duration::seconds(10));
+        state.info_storage.set_info(&expired, false).await.unwrap();
+        let without_data = state.create_test_file().await;
+        state
+            .data_storages
+            .default_storage()
+            .remove_file(&without_data)
+            .await
+            .unwrap();
+        let without_info = state.create_test_file().await;
+        state
+            .info_storage
+            .remove_info(&without_info.id)
+            .await
+            .unwrap();
+
+        let report = collect_garbage(
+            &state.data_storages,
+            &state.info_storage,
+            &state.locker,
+            Duration::ZERO,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            report,
+            GcReport {
+                expired: 1,
+                without_data: 1,
+                without_info: 1,
+            }
+        );
+        assert!(state.info_storage.get_info(&alive.id).await.is_ok());
+        assert!(PathBuf::from(alive.path.unwrap()).exists());
+        assert!(state.info_storage.get_info(&without_data.id).await.is_err());
+        assert!(!PathBuf::from(without_info.path.unwrap()).exists());
+    }
+
+    #[actix_rt::test]
+    async fn young_files() {
+        let state = State::test_new().await;
+        let file_info = state.create_test_file().await;
+        state.info_storage.remove_info(&file_info.id).await.unwrap();
+
+        let report = collect_garbage(
+            &state.data_storages,
+            &state.info_storage,
+            &state.locker,
+            Duration::MAX,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(report, GcReport::default());
+        assert!(PathBuf::from(file_info.path.unwrap()).exists());
+    }
+}
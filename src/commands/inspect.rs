@@ -0,0 +1,56 @@
+use crate::{
+    data_storage::{base::DataStorage, registry::DataStorageRegistry},
+    errors::RustusResult,
+    info_storage::{base::InfoStorage, InfoStorageImpl},
+};
+
+/// Describe an upload.
+///
+/// The description contains information about the upload
+/// as it's saved and the length of its stored data.
+pub async fn describe_upload(
+    data_storages: &DataStorageRegistry,
+    info_storage: &InfoStorageImpl,
+    file_id: &str,
+) -> RustusResult<String> {
+    let file_info = info_storage.get_info(file_id).await?;
+    let data = if let Some(data_storage) = data_storages.get(&file_info.storage) {
+        data_storage.get_length(&file_info).await?.map_or_else(
+            || String::from("Data is missing"),
+            |length| format!("Stored bytes: {length}"),
+        )
+    } else {
+        format!("Data is in unknown storage {}", file_info.storage)
+    };
+    Ok(format!(
+        "{}\n{data}",
+        serde_json::to_string_pretty(&file_info)?
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::describe_upload;
+    use crate::{data_storage::base::DataStorage, State};
+
+    #[actix_rt::test]
+    async fn success() {
+        let state = State::test_new().await;
+        let file_info = state.create_test_file().await;
+        let description = describe_upload(&state.data_storages, &state.info_storage, &file_info.id)
+            .await
+            .unwrap();
+        assert!(description.contains(&file_info.id));
+        assert!(description.ends_with("Stored bytes: 0"));
+        state
+            .data_storages
+            .default_storage()
+            .remove_file(&file_info)
+            .await
+            .unwrap();
+        let description = describe_upload(&state.data_storages, &state.info_storage, &file_info.id)
+            .await
+            .unwrap();
+        assert!(description.ends_with("Data is missing"));
+    }
+}
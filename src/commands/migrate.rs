@@ -0,0 +1,442 @@
+use crate::{
+    data_storage::{base::DataStorage, registry::DataStorageRegistry, DataStorageImpl},
+    errors::{RustusError, RustusResult},
+    file_info::FileInfo,
+    info_storage::{base::InfoStorage, query::UploadFilter, InfoStorageImpl},
+};
+
+use super::PAGE_SIZE;
+
+/// Numbers of processed uploads.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MigrationReport {
+    pub migrated: usize,
+    pub skipped: usize,
+    pub failed: usize,
+}
+
+/// Copy information about all uploads to another info storage.
+///
+/// Uploads that were copied before and haven't changed
+/// since are skipped, so an interrupted migration
+/// can be continued by running it again.
+/// Uploads that have changed are updated in the target.
+///
+/// # Params
+/// `dry_run` - only log uploads that would be copied.
+pub async fn migrate_info(
+    source: &InfoStorageImpl,
+    target: &InfoStorageImpl,
+    dry_run: bool,
+) -> RustusResult<MigrationReport> {
+    let mut report = MigrationReport::default();
+    let mut cursor = None;
+    loop {
+        let page = source
+            .list(&UploadFilter::default(), cursor.as_deref(), PAGE_SIZE)
+            .await?;
+        for file_info in page.uploads {
+            let exists = match target.get_info(&file_info.id).await {
+                Ok(copied) if copied.version >= file_info.version => {
+                    report.skipped += 1;
+                    continue;
+                }
+                Ok(_) => true,
+                Err(RustusError::FileNotFound) => false,
+                Err(err) => {
+                    log::error!("Cannot check upload {}: {err}", file_info.id);
+                    report.failed += 1;
+                    continue;
+                }
+            };
+            if dry_run {
+                log::info!("Upload {} would be copied", file_info.id);
+            } else if let Err(err) = target.set_info(&file_info, !exists).await {
+                log::error!("Cannot copy upload {}: {err}", file_info.id);
+                report.failed += 1;
+                continue;
+            }
+            report.migrated += 1;
+        }
+        cursor = page.next_cursor;
+        if cursor.is_none() {
+            return Ok(report);
+        }
+    }
+}
+
+/// Move data of all uploads to another data storage.
+///
+/// Storages are found by names saved in information about uploads.
+/// Uploads that are already in the target storage are skipped,
+/// so an interrupted migration can be continued by running it again.
+/// Uploads from unknown storages are skipped as well.
+///
+/// # Params
+/// `from` - name of the only storage to move data from.
+/// `to` - name of the storage to move data to.
+/// `dry_run` - only log uploads that would be moved.
+pub async fn migrate_data(
+    info_storage: &InfoStorageImpl,
+    data_storages: &DataStorageRegistry,
+    from: Option<&str>,
+    to: &str,
+    dry_run: bool,
+) -> RustusResult<MigrationReport> {
+    let target = data_storages.get(to).ok_or_else(|| {
+        RustusError::UnableToPrepareStorage(format!("Storage {to} isn't configured."))
+    })?;
+    if from == Some(to) {
+        return Err(RustusError::UnableToPrepareStorage(String::from(
+            "Target storage must differ from the source.",
+        )));
+    }
+    let mut report = MigrationReport::default();
+    let mut cursor = None;
+    loop {
+        let page = info_storage
+            .list(&UploadFilter::default(), cursor.as_deref(), PAGE_SIZE)
+            .await?;
+        for file_info in page.uploads {
+            if file_info.storage == to || from.is_some_and(|from| from != file_info.storage) {
+                report.skipped += 1;
+                continue;
+            }
+            let Some(source) = data_storages.get(&file_info.storage) else {
+                log::warn!(
+                    "Upload {} is skipped, since storage {} isn't configured",
+                    file_info.id,
+                    file_info.storage
+                );
+                report.skipped += 1;
+                continue;
+            };
+            if dry_run {
+                log::info!("Upload {} would be moved", file_info.id);
+            } else if let Err(err) =
+                move_upload(info_storage, source, target, to, file_info.clone()).await
+            {
+                log::error!("Cannot move upload {}: {err}", file_info.id);
+                report.failed += 1;
+                continue;
+            }
+            report.migrated += 1;
+        }
+        cursor = page.next_cursor;
+        if cursor.is_none() {
+            return Ok(report);
+        }
+    }
+}
+
+/// Move data of a single upload.
+///
+/// Information is updated only if nobody has changed
+/// the upload while its data was copied. Otherwise
+/// the copy is removed and the upload stays in the source.
+async fn move_upload(
+    info_storage: &InfoStorageImpl,
+    source: &DataStorageImpl,
+    target: &DataStorageImpl,
+    target_name: &str,
+    old_info: FileInfo,
+) -> RustusResult<()> {
+    // Target would overwrite files of the source.
+    if source.local_dir().is_some() && source.local_dir() == target.local_dir() {
+        return Err(RustusError::UnableToPrepareStorage(format!(
+            "Storages {} and {target_name} use the same data directory.",
+            old_info.storage
+        )));
+    }
+    let mut new_info = old_info.clone();
+    new_info.storage = target_name.to_string();
+    new_info.offset = 0;
+    // Data might be left by an interrupted migration.
+    match target.remove_file(&new_info).await {
+        Ok(()) | Err(RustusError::FileNotFound) => {}
+        Err(err) => return Err(err),
+    }
+    new_info.path = Some(target.create_file(&mut new_info).await?);
+    let result = async {
+        target
+            .add_bytes(&mut new_info, source.read_data(&old_info).await?)
+            .await?;
+        if new_info.offset != old_info.offset {
+            return Err(RustusError::UnableToWrite(format!(
+                "{} bytes were copied instead of {}",
+                new_info.offset, old_info.offset
+            )));
+        }
+        info_storage.update_info(&mut new_info).await
+    }
+    .await;
+    if let Err(err) = result {
+        if let Err(remove_err) = target.remove_file(&new_info).await {
+            log::warn!("Cannot remove copy of upload {}: {remove_err}", new_info.id);
+        }
+        return Err(err);
+    }
+    if let Err(err) = source.remove_file(&old_info).await {
+        log::warn!("Cannot remove old data of upload {}: {err}", old_info.id);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{migrate_data, migrate_info, MigrationReport};
+    use crate::{
+        data_storage::{
+            base::DataStorage,
+            impls::{file_storage::FileDataStorage, s3_hybrid::S3HybridDataStorage},
+            registry::DataStorageRegistry,
+            DataStorageImpl,
+        },
+        file_info::FileInfo,
+        info_storage::{
+            base::InfoStorage, impls::db_storage::DBInfoStorage,
+            impls::file_storage::FileInfoStorage, InfoStorageImpl,
+        },
+        State,
+    };
+
+    #[actix_rt::test]
+    async fn success() {
+        let state = State::test_new().await;
+        let first = state.create_test_file().await;
+        let second = state.create_test_file().await;
+        let target_dir = tempdir::TempDir::new("target_info").unwrap();
+        let target =
+            InfoStorageImpl::File(FileInfoStorage::new(target_dir.path().to_path_buf(), false));
+
+        let report = migrate_info(&state.info_storage, &target, false)
+            .await
+            .unwrap();
+
+        assert_eq!(report.migrated, 2);
+        for file_info in [first, second] {
+            let copied_info = target.get_info(&file_info.id).await.unwrap();
+            assert_eq!(copied_info.length, file_info.length);
+            assert_eq!(copied_info.path, file_info.path);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn resume_info() {
+        let state = State::test_new().await;
+        let mut file_info = state.create_test_file().await;
+        let unchanged = state.create_test_file().await;
+        let target_dir = tempdir::TempDir::new("target_info").unwrap();
+        let target =
+            InfoStorageImpl::File(FileInfoStorage::new(target_dir.path().to_path_buf(), false));
+        migrate_info(&state.info_storage, &target, false)
+            .await
+            .unwrap();
+        file_info.offset = 5;
+        state
+            .info_storage
+            .update_info(&mut file_info)
+            .await
+            .unwrap();
+
+        let report = migrate_info(&state.info_storage, &target, false)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            report,
+            MigrationReport {
+                migrated: 1,
+                skipped: 1,
+                failed: 0,
+            }
+        );
+        assert_eq!(target.get_info(&file_info.id).await.unwrap().offset, 5);
+        assert!(target.get_info(&unchanged.id).await.is_ok());
+    }
+
+    #[actix_rt::test]
+    async fn resume_info_in_db() {
+        let state = State::test_new().await;
+        let mut file_info = state.create_test_file().await;
+        let target_dir = tempdir::TempDir::new("target_info").unwrap();
+        let db_path = target_dir.path().join("info.sqlite3");
+        let mut target = InfoStorageImpl::DB(
+            DBInfoStorage::new(
+                format!("sqlite://{}?mode=rwc", db_path.display()).as_str(),
+                1,
+            )
+            .unwrap(),
+        );
+        target.prepare().await.unwrap();
+        migrate_info(&state.info_storage, &target, false)
+            .await
+            .unwrap();
+        file_info.offset = 5;
+        state
+            .info_storage
+            .update_info(&mut file_info)
+            .await
+            .unwrap();
+
+        let report = migrate_info(&state.info_storage, &target, false)
+            .await
+            .unwrap();
+
+        assert_eq!(report.migrated, 1);
+        assert_eq!(target.get_info(&file_info.id).await.unwrap().offset, 5);
+    }
+
+    #[actix_rt::test]
+    async fn dry_run_info() {
+        let state = State::test_new().await;
+        let file_info = state.create_test_file().await;
+        let target_dir = tempdir::TempDir::new("target_info").unwrap();
+        let target =
+            InfoStorageImpl::File(FileInfoStorage::new(target_dir.path().to_path_buf(), false));
+
+        let report = migrate_info(&state.info_storage, &target, true)
+            .await
+            .unwrap();
+
+        assert_eq!(report.migrated, 1);
+        assert!(target.get_info(&file_info.id).await.is_err());
+    }
+
+    /// Data storage with a different name, to move data to.
+    fn hybrid_target(data_dir: std::path::PathBuf) -> DataStorageImpl {
+        DataStorageImpl::S3Hybrid(S3HybridDataStorage::new_test(data_dir))
+    }
+
+    /// Registry of the state with an additional storage.
+    fn with_target(state: &State, target: DataStorageImpl) -> DataStorageRegistry {
+        let mut data_storages = state.data_storages.clone();
+        data_storages.add_named("target", target).unwrap();
+        data_storages
+    }
+
+    #[actix_rt::test]
+    async fn move_data() {
+        let state = State::test_new().await;
+        let mut file_info = state.create_test_file().await;
+        std::fs::write(file_info.path.clone().unwrap(), "memes and more").unwrap();
+        file_info.offset = 5;
+        state
+            .info_storage
+            .update_info(&mut file_info)
+            .await
+            .unwrap();
+        let target_dir = tempdir::TempDir::new("target_data").unwrap();
+        let data_storages = with_target(&state, hybrid_target(target_dir.path().to_path_buf()));
+
+        let report = migrate_data(&state.info_storage, &data_storages, None, "target", false)
+            .await
+            .unwrap();
+
+        assert_eq!(report.migrated, 1);
+        let moved = state.info_storage.get_info(&file_info.id).await.unwrap();
+        assert_eq!(moved.storage, "target");
+        assert_eq!(moved.offset, 5);
+        assert_ne!(moved.path, file_info.path);
+        let new_path = target_dir.path().join(&file_info.id);
+        assert_eq!(std::fs::read_to_string(new_path).unwrap(), "memes");
+        assert!(!std::path::Path::new(&file_info.path.unwrap()).exists());
+
+        // Moved uploads are skipped.
+        let report = migrate_data(&state.info_storage, &data_storages, None, "target", false)
+            .await
+            .unwrap();
+        assert_eq!(report.skipped, 1);
+    }
+
+    #[actix_rt::test]
+    async fn move_from_named_storage() {
+        let state = State::test_new().await;
+        let source_dir = tempdir::TempDir::new("source_data").unwrap();
+        let target_dir = tempdir::TempDir::new("target_data").unwrap();
+        let mut data_storages = with_target(&state, hybrid_target(target_dir.path().to_path_buf()));
+        let mut source = DataStorageImpl::File(FileDataStorage::new(
+            source_dir.path().to_path_buf(),
+            String::new(),
+            false,
+        ));
+        source.prepare().await.unwrap();
+        data_storages.add_named("source", source.clone()).unwrap();
+        let mut file_info = FileInfo::new_test();
+        file_info.storage = String::from("source");
+        file_info.path = Some(source.create_file(&mut file_info).await.unwrap());
+        state.info_storage.set_info(&file_info, true).await.unwrap();
+        let mut unknown = FileInfo::new_test();
+        unknown.storage = String::from("unknown");
+        state.info_storage.set_info(&unknown, true).await.unwrap();
+        let other = state.create_test_file().await;
+
+        let report = migrate_data(
+            &state.info_storage,
+            &data_storages,
+            Some("source"),
+            "target",
+            false,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            report,
+            MigrationReport {
+                migrated: 1,
+                skipped: 2,
+                failed: 0,
+            }
+        );
+        let moved = state.info_storage.get_info(&file_info.id).await.unwrap();
+        assert_eq!(moved.storage, "target");
+        let kept = state.info_storage.get_info(&other.id).await.unwrap();
+        assert_eq!(kept.storage, other.storage);
+    }
+
+    #[actix_rt::test]
+    async fn dry_run_data() {
+        let state = State::test_new().await;
+        let file_info = state.create_test_file().await;
+        let target_dir = tempdir::TempDir::new("target_data").unwrap();
+        let data_storages = with_target(&state, hybrid_target(target_dir.path().to_path_buf()));
+
+        let report = migrate_data(&state.info_storage, &data_storages, None, "target", true)
+            .await
+            .unwrap();
+
+        assert_eq!(report.migrated, 1);
+        let saved = state.info_storage.get_info(&file_info.id).await.unwrap();
+        assert_eq!(saved.storage, file_info.storage);
+    }
+
+    #[actix_rt::test]
+    async fn same_data_dir() {
+        let state = State::test_new().await;
+        let file_info = state.create_test_file().await;
+        let data_dir = state.config.storage_opts.data_dir.clone();
+        let data_storages = with_target(&state, hybrid_target(data_dir));
+
+        let report = migrate_data(&state.info_storage, &data_storages, None, "target", false)
+            .await
+            .unwrap();
+
+        assert_eq!(report.failed, 1);
+        assert!(std::path::Path::new(&file_info.path.unwrap()).exists());
+    }
+
+    #[actix_rt::test]
+    async fn unknown_target() {
+        let state = State::test_new().await;
+        assert!(migrate_data(
+            &state.info_storage,
+            &state.data_storages,
+            None,
+            "unknown",
+            false
+        )
+        .await
+        .is_err());
+    }
+}
@@ -0,0 +1,129 @@
+use std::time::Duration;
+
+use crate::{
+    config::Command,
+    data_storage::registry::DataStorageRegistry,
+    errors::RustusResult,
+    info_storage::{base::InfoStorage, InfoStorageImpl},
+    locks::LockerImpl,
+    RustusConf,
+};
+
+pub mod gc;
+pub mod inspect;
+pub mod migrate;
+pub mod verify;
+
+/// Number of uploads that are loaded from info storage at once.
+const PAGE_SIZE: usize = 100;
+
+/// Create storages from the configuration.
+async fn prepare_storages(
+    config: &RustusConf,
+) -> RustusResult<(DataStorageRegistry, InfoStorageImpl)> {
+    let mut data_storages = DataStorageRegistry::from_config(config)?;
+    data_storages.prepare().await?;
+    let mut info_storage = config.info_storage_opts.info_storage.get(config)?;
+    info_storage.prepare().await?;
+    Ok((data_storages, info_storage))
+}
+
+fn log_migration(report: &migrate::MigrationReport) {
+    log::info!(
+        "Migrated {} uploads, skipped {}, failed {}",
+        report.migrated,
+        report.skipped,
+        report.failed,
+    );
+}
+
+/// Configuration of uploads which are managed by commands.
+///
+/// It's the configuration of `--tenant` if it's set.
+fn command_config(config: &RustusConf) -> RustusResult<RustusConf> {
+    let Some(name) = &config.tenant else {
+        return Ok(config.clone());
+    };
+    config
+        .tenants
+        .clone()
+        .unwrap_or_default()
+        .config(name, config)
+}
+
+/// Run a maintenance command.
+///
+/// Returns `false` if the command has found problems,
+/// so the process must exit with an error code.
+pub async fn run(config: &RustusConf, command: &Command) -> RustusResult<bool> {
+    let config = &command_config(config)?;
+    match command {
+        Command::Gc { min_age } => {
+            let (data_storages, info_storage) = prepare_storages(config).await?;
+            // Locks are shared with running servers only if they're kept in redis.
+            let locker = LockerImpl::new(config)?;
+            let report = gc::collect_garbage(
+                &data_storages,
+                &info_storage,
+                &locker,
+                Duration::from_secs(*min_age),
+            )
+            .await?;
+            log::info!(
+                "Removed {} expired uploads, {} uploads without data and {} files without info",
+                report.expired,
+                report.without_data,
+                report.without_info,
+            );
+            Ok(true)
+        }
+        Command::Inspect { id } => {
+            let (data_storages, info_storage) = prepare_storages(config).await?;
+            println!(
+                "{}",
+                inspect::describe_upload(&data_storages, &info_storage, id).await?
+            );
+            Ok(true)
+        }
+        Command::MigrateInfo {
+            from,
+            to,
+            to_dir,
+            to_dsn,
+            dry_run,
+        } => {
+            let mut source = from.get(config)?;
+            source.prepare().await?;
+            let mut target_config = config.clone();
+            if let Some(dir) = to_dir {
+                target_config.info_storage_opts.info_dir.clone_from(dir);
+            }
+            if let Some(dsn) = to_dsn {
+                target_config.info_storage_opts.info_db_dsn = Some(dsn.clone());
+            }
+            let mut target = to.get(&target_config)?;
+            target.prepare().await?;
+            let report = migrate::migrate_info(&source, &target, *dry_run).await?;
+            log_migration(&report);
+            Ok(report.failed == 0)
+        }
+        Command::MigrateData { from, to, dry_run } => {
+            let (data_storages, info_storage) = prepare_storages(config).await?;
+            let report =
+                migrate::migrate_data(&info_storage, &data_storages, from.as_deref(), to, *dry_run)
+                    .await?;
+            log_migration(&report);
+            Ok(report.failed == 0)
+        }
+        Command::Verify => {
+            let (data_storages, info_storage) = prepare_storages(config).await?;
+            let problems = verify::verify_uploads(&data_storages, &info_storage).await?;
+            if problems > 0 {
+                log::error!("Found {problems} broken uploads");
+            } else {
+                log::info!("All uploads are consistent");
+            }
+            Ok(problems == 0)
+        }
+    }
+}
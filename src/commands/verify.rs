@@ -0,0 +1,121 @@
+use crate::{
+    data_storage::{base::DataStorage, registry::DataStorageRegistry, DataStorageImpl},
+    errors::RustusResult,
+    file_info::FileInfo,
+    info_storage::{base::InfoStorage, query::UploadFilter, InfoStorageImpl},
+};
+
+use super::PAGE_SIZE;
+
+/// Check that data of the upload matches its offset.
+///
+/// Returns description of the problem if any.
+async fn check_upload(
+    data_storage: &DataStorageImpl,
+    file_info: &FileInfo,
+) -> RustusResult<Option<String>> {
+    let Some(length) = data_storage.get_length(file_info).await? else {
+        return Ok(Some(String::from("data is missing")));
+    };
+    if length < file_info.offset {
+        return Ok(Some(format!(
+            "data has {length} bytes, but offset is {}",
+            file_info.offset
+        )));
+    }
+    // Bytes after the offset in unfinished uploads
+    // are removed before the next write, so they are fine.
+    if length > file_info.offset && Some(file_info.offset) == file_info.length {
+        return Ok(Some(format!(
+            "data has {length} bytes, but length is {}",
+            file_info.offset
+        )));
+    }
+    Ok(None)
+}
+
+/// Check data of all uploads.
+///
+/// Uploads from unknown data storages are skipped.
+///
+/// Returns number of broken uploads.
+pub async fn verify_uploads(
+    data_storages: &DataStorageRegistry,
+    info_storage: &InfoStorageImpl,
+) -> RustusResult<usize> {
+    let mut broken = 0;
+    let mut cursor = None;
+    loop {
+        let page = info_storage
+            .list(&UploadFilter::default(), cursor.as_deref(), PAGE_SIZE)
+            .await?;
+        for file_info in page.uploads {
+            let Some(data_storage) = data_storages.get(&file_info.storage) else {
+                log::debug!(
+                    "Skipping upload {} from storage {}",
+                    file_info.id,
+                    file_info.storage
+                );
+                continue;
+            };
+            if let Some(problem) = check_upload(data_storage, &file_info).await? {
+                log::error!("Upload {} is broken: {problem}", file_info.id);
+                broken += 1;
+            }
+        }
+        cursor = page.next_cursor;
+        if cursor.is_none() {
+            return Ok(broken);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::verify_uploads;
+    use crate::{data_storage::base::DataStorage, info_storage::base::InfoStorage, State};
+
+    #[actix_rt::test]
+    async fn success() {
+        let state = State::test_new().await;
+        let mut unsaved = state.create_test_file().await;
+        std::fs::write(unsaved.path.clone().unwrap(), "memes").unwrap();
+        unsaved.offset = 3;
+        state.info_storage.set_info(&unsaved, false).await.unwrap();
+        assert_eq!(
+            verify_uploads(&state.data_storages, &state.info_storage)
+                .await
+                .unwrap(),
+            0
+        );
+    }
+
+    #[actix_rt::test]
+    async fn broken_uploads() {
+        let state = State::test_new().await;
+        let mut lost_bytes = state.create_test_file().await;
+        lost_bytes.offset = 5;
+        state
+            .info_storage
+            .set_info(&lost_bytes, false)
+            .await
+            .unwrap();
+        let missing = state.create_test_file().await;
+        state
+            .data_storages
+            .default_storage()
+            .remove_file(&missing)
+            .await
+            .unwrap();
+        let mut finished = state.create_test_file().await;
+        std::fs::write(finished.path.clone().unwrap(), "more than ten").unwrap();
+        finished.offset = 10;
+        state.info_storage.set_info(&finished, false).await.unwrap();
+        assert_eq!(
+            verify_uploads(&state.data_storages, &state.info_storage)
+                .await
+                .unwrap(),
+            3
+        );
+    }
+}
@@ -1,12 +1,22 @@
 use std::{ffi::OsString, path::PathBuf};
 
-use clap::Parser;
+use clap::{error::ErrorKind, CommandFactory, Parser, Subcommand};
 
 use crate::{
-    data_storage::AvailableDataStorages,
+    data_storage::{
+        routing::{NamedStorages, StorageRules},
+        AvailableDataStorages,
+    },
+    errors::{RustusError, RustusResult},
     info_storage::AvailableInfoStorages,
-    notifiers::{impls::kafka_notifier::ExtraKafkaOptions, Format, Hook},
+    notifiers::{
+        configs::{NotifierConfig, NotifierConfigs},
+        impls::kafka_notifier::ExtraKafkaOptions,
+        Format, Hook,
+    },
     protocol::extensions::Extensions,
+    tenants::Tenants,
+    utils::hashes::{ChecksumAlgorithm, DigestAlgorithm},
 };
 
 #[derive(Parser, Debug, Clone)]
@@ -18,6 +28,33 @@ pub struct DataStorageOptions {
     #[arg(long, short, default_value = "file-storage", env = "RUSTUS_STORAGE")]
     pub storage: AvailableDataStorages,
 
+    /// Storages that were used before.
+    ///
+    /// New uploads are always created in the main storage,
+    /// but uploads from these storages can still be
+    /// downloaded, continued and removed.
+    /// They use the same options as the main storage.
+    #[arg(long, env = "RUSTUS_LEGACY_STORAGES", use_value_delimiter = true)]
+    pub legacy_storages: Vec<AvailableDataStorages>,
+
+    /// Additional storages with custom names.
+    ///
+    /// JSON object where keys are names of storages
+    /// and values are their options. Options which are
+    /// not set are taken from the main storage.
+    /// Example: `{"videos": {"storage": "s3", "s3_bucket": "videos"}}`.
+    #[arg(long, env = "RUSTUS_NAMED_STORAGES")]
+    pub named_storages: Option<NamedStorages>,
+
+    /// Rules to choose storage for new uploads.
+    ///
+    /// JSON array of rules, the first matching rule
+    /// chooses a named storage. If nothing matches,
+    /// the main storage is used.
+    /// Example: `[{"storage": "videos", "metadata": {"type": "video"}}]`.
+    #[arg(long, env = "RUSTUS_STORAGE_RULES")]
+    pub storage_rules: Option<StorageRules>,
+
     /// Rustus data directory
     ///
     /// This directory is used to store files
@@ -119,20 +156,38 @@ pub struct DataStorageOptions {
     #[arg(long, env = "RUSTUS_S3_HEADERS")]
     pub s3_headers: Option<String>,
 
-    /// Number of concurrent downloads of partial files
-    /// from S3.
-    /// When performing concatenation, Rustus downloads
-    /// all partial files from S3 and concatenates them
-    /// into a single file.
+    /// Number of parts that are created concurrently
+    /// during concatenation.
+    /// Partial uploads are copied on the S3 side,
+    /// only small pieces are downloaded.
     ///
     /// This parameter controls the number of concurrent
-    /// downloads.
+    /// requests to S3.
     #[arg(
         long,
         env = "RUSTUS_S3_CONCAT_CONCURRENT_DOWNLOADS",
         default_value = "10"
     )]
     pub s3_concat_concurrent_downloads: usize,
+
+    /// Redirect downloads to presigned S3 URLs.
+    ///
+    /// If enabled, GET requests for uploaded files
+    /// are answered with a redirect, so clients
+    /// download files directly from S3.
+    #[arg(long, env = "RUSTUS_S3_PRESIGNED_DOWNLOADS")]
+    pub s3_presigned_downloads: bool,
+
+    /// Lifetime of presigned download URLs in seconds.
+    ///
+    /// S3 doesn't allow URLs that live longer than 7 days.
+    #[arg(
+        long,
+        env = "RUSTUS_S3_PRESIGNED_EXPIRATION",
+        default_value = "300",
+        value_parser = clap::value_parser!(u32).range(1..=604_800)
+    )]
+    pub s3_presigned_expiration: u32,
 }
 
 #[derive(Parser, Debug, Clone)]
@@ -160,6 +215,16 @@ pub struct InfoStoreOptions {
     #[arg(long, default_value = "./data", env = "RUSTUS_INFO_DIR")]
     pub info_dir: PathBuf,
 
+    /// Sync info directory after every write.
+    ///
+    /// Info files are always synced before they replace old ones,
+    /// but the replacement itself can be lost on power failure
+    /// unless the directory is synced too.
+    ///
+    /// This parameter is only used by `file-info-storage`.
+    #[arg(long, env = "RUSTUS_INFO_FSYNC_DIR")]
+    pub info_fsync_dir: bool,
+
     /// Connection string for remote info storages.
     ///
     /// This connection string is used for storages
@@ -174,8 +239,42 @@ pub struct InfoStoreOptions {
     )]
     pub info_db_dsn: Option<String>,
 
+    /// Maximum number of connections to the database.
+    ///
+    /// This parameter is used by `db-info-storage` and `redis-info-storage`.
+    /// Locks of `redis-info-storage` have a separate pool of the same size.
+    #[arg(
+        long,
+        default_value = "100",
+        env = "RUSTUS_INFO_DB_MAX_CONNECTIONS",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub info_db_max_connections: u32,
+
     #[arg(long, env = "RUSTUS_REDIS_INFO_EXPIRATION")]
     pub redis_info_expiration: Option<usize>,
+
+    /// Prefix of all keys that Rustus creates in redis.
+    ///
+    /// It allows to list uploads without scanning
+    /// unrelated keys and to share one redis
+    /// database between several deployments.
+    #[arg(long, default_value = "", env = "RUSTUS_REDIS_KEY_PREFIX")]
+    pub redis_key_prefix: String,
+
+    /// Time in seconds after which lock of an upload expires.
+    ///
+    /// Locks are refreshed while requests are in progress,
+    /// so it only matters if Rustus has crashed.
+    ///
+    /// This parameter is only used by `redis-info-storage`.
+    #[arg(
+        long,
+        env = "RUSTUS_REDIS_LOCK_EXPIRATION",
+        default_value = "30",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub redis_lock_expiration: u64,
 }
 #[derive(Parser, Debug, Clone)]
 #[allow(clippy::struct_excessive_bools)]
@@ -514,6 +613,15 @@ pub struct NotificationsOptions {
     #[arg(long, env = "RUSTUS_HOOKS_FILE")]
     pub hooks_file: Option<String>,
 
+    /// Additional notifiers with their own options.
+    ///
+    /// JSON array of notifiers, where `type` is one of
+    /// file, dir, http, amqp, kafka or nats. It allows
+    /// to use several notifiers of the same type.
+    /// Example: `[{"type": "kafka", "urls": "localhost:9092", "topic": "uploads"}]`.
+    #[arg(long, env = "RUSTUS_NOTIFIERS")]
+    pub notifiers: Option<NotifierConfigs>,
+
     #[command(flatten)]
     pub amqp_hook_opts: AMQPHooksOptions,
 
@@ -538,8 +646,120 @@ pub struct SentryOptions {
     pub sample_rate: f32,
 }
 
+#[derive(Debug, Parser, Clone)]
+pub struct AdminOptions {
+    /// Token for admin API.
+    ///
+    /// Admin API is enabled only if the token is set.
+    /// Clients must send it in `Authorization` header
+    /// as `Bearer <token>`.
+    #[arg(name = "admin-token", long, env = "RUSTUS_ADMIN_TOKEN")]
+    pub token: Option<String>,
+
+    /// Base url of admin API.
+    #[arg(
+        name = "admin-url",
+        long,
+        default_value = "/admin",
+        env = "RUSTUS_ADMIN_URL"
+    )]
+    pub url: String,
+
+    /// Port for admin API.
+    ///
+    /// If it's set, admin API is served
+    /// by a separate server on this port
+    /// instead of the main one.
+    #[arg(name = "admin-port", long, env = "RUSTUS_ADMIN_PORT")]
+    pub port: Option<u16>,
+}
+
+/// Maintenance commands.
+///
+/// They use the same storages as the server,
+/// and exit after the work is done.
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Remove expired uploads and uploads without data.
+    ///
+    /// For file storages it also removes
+    /// data files without information.
+    Gc {
+        /// Minimal age of a data file in seconds to be removed.
+        ///
+        /// Information about new uploads is saved
+        /// after the data file is created,
+        /// so younger files are skipped.
+        #[arg(long, default_value = "3600")]
+        min_age: u64,
+    },
+
+    /// Print information about an upload.
+    Inspect {
+        /// Upload id.
+        id: String,
+    },
+
+    /// Copy information about uploads to another info storage.
+    ///
+    /// Target storage uses the same options as the source,
+    /// unless they are overridden. Uploads that were copied
+    /// by previous runs and haven't changed since are skipped.
+    MigrateInfo {
+        /// Info storage to copy from.
+        #[arg(long)]
+        from: AvailableInfoStorages,
+
+        /// Info storage to copy to.
+        #[arg(long)]
+        to: AvailableInfoStorages,
+
+        /// Info directory of the target storage.
+        #[arg(long)]
+        to_dir: Option<PathBuf>,
+
+        /// Connection string of the target storage.
+        #[arg(long)]
+        to_dsn: Option<String>,
+
+        /// Only show what would be copied.
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Move data of uploads to another data storage.
+    ///
+    /// Storages are identified by names that are saved
+    /// in information about uploads, so both storages must be
+    /// configured the same way as for the server: as the main,
+    /// a legacy or a named storage. Information about moved
+    /// uploads is updated to point to the new storage.
+    /// Uploads that were moved by previous runs are skipped.
+    MigrateData {
+        /// Name of the storage to move data from.
+        ///
+        /// Data of all other storages is moved by default.
+        #[arg(long)]
+        from: Option<String>,
+
+        /// Name of the storage to move data to.
+        #[arg(long)]
+        to: String,
+
+        /// Only show what would be moved.
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Check that data of every upload matches its offset.
+    ///
+    /// Exits with non-zero code if any problem is found.
+    Verify,
+}
+
 #[derive(Debug, Parser, Clone)]
 #[command(name = "Rustus")]
+#[allow(clippy::struct_excessive_bools)]
 /// Tus protocol implementation.
 ///
 /// This program is a web-server that
@@ -548,6 +768,14 @@ pub struct SentryOptions {
 /// You can read more about protocol
 /// [here](https://tus.io/).
 pub struct RustusConf {
+    /// Path to the configuration file.
+    ///
+    /// TOML and YAML files are supported. Keys are names
+    /// of other options, CLI arguments and environment
+    /// variables override values from the file.
+    #[arg(long, env = "RUSTUS_CONFIG")]
+    pub config: Option<PathBuf>,
+
     /// Rustus server host
     #[arg(long, default_value = "0.0.0.0", env = "RUSTUS_SERVER_HOST")]
     pub host: String,
@@ -572,6 +800,8 @@ pub struct RustusConf {
     /// Maximum payload size.
     ///
     /// This limit used to reduce amount of consumed memory.
+    /// Upload chunks are streamed, so it only applies
+    /// to chunks that must be buffered, E.G. chunks with checksum.
     #[arg(
         long,
         short = 'm',
@@ -588,6 +818,14 @@ pub struct RustusConf {
     #[arg(long, short, env = "RUSTUS_WORKERS")]
     pub workers: Option<usize>,
 
+    /// Seconds to wait for uploads in progress
+    /// and pending hooks on shutdown.
+    ///
+    /// Requests and hooks which aren't finished
+    /// in time are logged and abandoned.
+    #[arg(long, default_value = "30", env = "RUSTUS_SHUTDOWN_TIMEOUT")]
+    pub shutdown_timeout: u64,
+
     /// Enabled extensions for TUS protocol.
     #[arg(
         long,
@@ -597,6 +835,18 @@ pub struct RustusConf {
     )]
     pub tus_extensions: Vec<Extensions>,
 
+    /// Algorithms that clients can use with the checksum extension.
+    ///
+    /// Only these algorithms are advertised
+    /// in `Tus-Checksum-Algorithm` header.
+    #[arg(
+        long,
+        default_value = "md5,sha1,sha256,sha512,crc32c,xxh3,blake3",
+        env = "RUSTUS_CHECKSUM_ALGORITHMS",
+        use_value_delimiter = true
+    )]
+    pub checksum_algorithms: Vec<ChecksumAlgorithm>,
+
     /// Enabling this parameter
     /// Will allow creation of empty files
     /// when Upload-Length header equals to 0.
@@ -616,6 +866,66 @@ pub struct RustusConf {
     #[arg(long, env = "RUSTUS_MAX_FILE_SIZE")]
     pub max_file_size: Option<usize>,
 
+    /// Algorithm of the digest, computed for every upload.
+    ///
+    /// The digest is available in hooks and in `Upload-Digest` header
+    /// once the upload is complete. If client declares `checksum`
+    /// in upload metadata, the digest is verified against it.
+    ///
+    /// If not set, digests aren't computed. Digests of uploads
+    /// that were created before are discarded on the next write,
+    /// the same as if the algorithm has changed.
+    #[arg(long, env = "RUSTUS_UPLOAD_DIGEST")]
+    pub upload_digest: Option<DigestAlgorithm>,
+
+    /// Time in seconds after which unfinished upload expires.
+    ///
+    /// Expiration time is refreshed every time
+    /// new bytes are received.
+    ///
+    /// This parameter is only needed if expiration extension is enabled.
+    #[arg(long, env = "RUSTUS_UPLOAD_EXPIRATION", default_value = "86400")]
+    pub upload_expiration: u64,
+
+    /// Interval in seconds between removals of expired uploads.
+    ///
+    /// This parameter is only needed if expiration extension is enabled.
+    #[arg(
+        long,
+        env = "RUSTUS_EXPIRATION_INTERVAL",
+        default_value = "600",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub expiration_interval: u64,
+
+    /// Check consistency of unfinished uploads on startup.
+    ///
+    /// Bytes that were written, but not recorded in the
+    /// info storage, are removed. If some data was lost,
+    /// offset of the upload is decreased.
+    ///
+    /// Don't use it if several instances share
+    /// the same storages, since uploads that are
+    /// in progress on other instances can be damaged.
+    #[arg(long, env = "RUSTUS_CHECK_CONSISTENCY")]
+    pub check_consistency: bool,
+
+    /// Tenants served by this instance.
+    ///
+    /// JSON object where keys are names of tenants
+    /// and values are their options. Every tenant has its own
+    /// URL and may override storages, limits and hooks.
+    /// Options which are not set are taken from the main configuration.
+    /// Example: `{"avatars": {"url": "/avatars", "max_file_size": 1048576}}`.
+    #[arg(long, env = "RUSTUS_TENANTS")]
+    pub tenants: Option<Tenants>,
+
+    /// Tenant whose uploads are managed by a maintenance command.
+    ///
+    /// Uploads of the main configuration are managed by default.
+    #[arg(long, env = "RUSTUS_TENANT")]
+    pub tenant: Option<String>,
+
     #[command(flatten)]
     pub storage_opts: DataStorageOptions,
 
@@ -627,6 +937,12 @@ pub struct RustusConf {
 
     #[command(flatten)]
     pub sentry_opts: SentryOptions,
+
+    #[command(flatten)]
+    pub admin_opts: AdminOptions,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
 }
 
 impl RustusConf {
@@ -635,9 +951,21 @@ impl RustusConf {
     /// This is a workaround for issue mentioned
     /// [here](https://www.reddit.com/r/rust/comments/8ddd19/confusion_with_splitting_mainrs_into_smaller/).
     pub fn from_args() -> Self {
-        let mut conf = Self::parse();
+        Self::try_from_args().unwrap_or_else(|err| err.exit())
+    }
+
+    /// Parse options from CLI, environment variables and the configuration file.
+    ///
+    /// Options are validated, so errors are readable for users.
+    pub fn try_from_args() -> Result<Self, clap::Error> {
+        let mut command = Self::command();
+        let args = crate::config_file::with_file_args(&command, std::env::args_os().collect())
+            .map_err(|err| command.error(ErrorKind::Io, err))?;
+        let mut conf = Self::try_parse_from(args)?;
         conf.normalize_extentions();
-        conf
+        conf.validate()
+            .map_err(|err| command.error(ErrorKind::ArgumentConflict, err))?;
+        Ok(conf)
     }
 
     pub fn from_iter<I>(iter: I) -> Self
@@ -654,6 +982,28 @@ impl RustusConf {
         String::from(stripped_prefix.strip_suffix('/').unwrap_or(stripped_prefix))
     }
 
+    /// Connection string for remote info storages.
+    ///
+    /// Clap checks that it's set for the server,
+    /// but maintenance commands may need it for other storages.
+    pub fn info_db_dsn(&self) -> RustusResult<&str> {
+        self.info_storage_opts
+            .info_db_dsn
+            .as_deref()
+            .ok_or_else(|| {
+                RustusError::UnableToPrepareInfoStorage(String::from(
+                    "Connection string is required.",
+                ))
+            })
+    }
+
+    /// Base url of admin API without slashes.
+    pub fn admin_url(&self) -> String {
+        let url = self.admin_opts.url.as_str();
+        let stripped_prefix = url.strip_prefix('/').unwrap_or(url);
+        String::from(stripped_prefix.strip_suffix('/').unwrap_or(stripped_prefix))
+    }
+
     /// Helper for generating URI for test files.
     #[cfg(test)]
     pub fn file_url(&self, file_id: &str) -> String {
@@ -665,16 +1015,24 @@ impl RustusConf {
         format!("/{}/", self.base_url())
     }
 
-    /// Check if hook is enabled by user.
-    pub fn hook_is_active(&self, hook: Hook) -> bool {
-        self.notification_opts.hooks.contains(&hook)
+    /// Lifetime of unfinished uploads.
+    ///
+    /// Returns `None` if expiration extension is disabled.
+    pub fn upload_lifetime(&self) -> Option<chrono::Duration> {
+        if !self.tus_extensions.contains(&Extensions::Expiration) {
+            return None;
+        }
+        i64::try_from(self.upload_expiration)
+            .ok()
+            .and_then(chrono::Duration::try_seconds)
     }
 
     /// Normalize extension vec.
     ///
-    ///  Nomralization consists of two parts:
+    ///  Nomralization consists of three parts:
     ///  1. Adding dependent extentions (e.g. creation-with-upload depends on creation);
-    ///  2. Sorting the resulting extentions;
+    ///  2. Removing checksum extension if no checksum algorithms are allowed;
+    ///  3. Sorting the resulting extentions;
     ///
     /// Protocol extensions must be sorted,
     /// because Actix doesn't override
@@ -693,6 +1051,162 @@ impl RustusConf {
             ext.push(Extensions::Creation);
         }
 
+        // Checksum extension can't be used
+        // if there are no algorithms to verify chunks.
+        if self.checksum_algorithms.is_empty() {
+            ext.retain(|extension| extension != &Extensions::Checksum);
+        }
+
         ext.sort();
     }
+
+    /// Check options which depend on each other.
+    ///
+    /// Clap checks options one by one, but some
+    /// mistakes can be found only by looking at several of them.
+    pub fn validate(&self) -> RustusResult<()> {
+        let wrong = |message: String| Err(RustusError::WrongConfig(message));
+        let storage_opts = &self.storage_opts;
+        let mut storages = vec![(
+            String::from("main"),
+            &storage_opts.storage,
+            storage_opts.s3_bucket.as_ref(),
+        )];
+        storages.extend(storage_opts.legacy_storages.iter().map(|storage| {
+            (
+                format!("legacy {storage}"),
+                storage,
+                storage_opts.s3_bucket.as_ref(),
+            )
+        }));
+        if let Some(named_storages) = &storage_opts.named_storages {
+            storages.extend(named_storages.0.iter().map(|(name, storage)| {
+                (
+                    name.clone(),
+                    &storage.storage,
+                    storage
+                        .s3_bucket
+                        .as_ref()
+                        .or(storage_opts.s3_bucket.as_ref()),
+                )
+            }));
+        }
+        let mut uses_s3 = false;
+        for (name, storage, bucket) in storages {
+            if *storage == AvailableDataStorages::FileStorage {
+                continue;
+            }
+            uses_s3 = true;
+            for (option, value) in [
+                ("s3-url", storage_opts.s3_url.as_ref()),
+                ("s3-region", storage_opts.s3_region.as_ref()),
+                ("s3-bucket", bucket),
+            ] {
+                if value.is_none() {
+                    return wrong(format!(
+                        "--{option} is required for {name} storage, since it's {storage}."
+                    ));
+                }
+            }
+        }
+        if storage_opts.s3_presigned_downloads && !uses_s3 {
+            return wrong(String::from(
+                "--s3-presigned-downloads requires an S3-based storage.",
+            ));
+        }
+        if storage_opts.s3_access_key.is_some() && storage_opts.s3_access_key_path.is_some() {
+            return wrong(String::from(
+                "--s3-access-key and --s3-access-key-path can't be used together.",
+            ));
+        }
+        if storage_opts.s3_secret_key.is_some() && storage_opts.s3_secret_key_path.is_some() {
+            return wrong(String::from(
+                "--s3-secret-key and --s3-secret-key-path can't be used together.",
+            ));
+        }
+        if self.admin_opts.port == Some(self.port) {
+            return wrong(String::from("--admin-port must differ from --port."));
+        }
+        if self.admin_opts.token.is_some()
+            && self.admin_opts.port.is_none()
+            && self.admin_url() == self.base_url()
+        {
+            return wrong(String::from("--admin-url must differ from --url."));
+        }
+        if self.tus_extensions.contains(&Extensions::Expiration) && self.upload_expiration == 0 {
+            return wrong(String::from(
+                "--upload-expiration must be positive, since expiration extension is enabled.",
+            ));
+        }
+        for notifier in NotifierConfig::from_options(&self.notification_opts) {
+            notifier.validate()?;
+        }
+        if let Some(name) = &self.tenant {
+            if !self
+                .tenants
+                .as_ref()
+                .is_some_and(|tenants| tenants.0.contains_key(name))
+            {
+                return wrong(format!("--tenant {name} isn't configured in --tenants."));
+            }
+        }
+        if let Some(tenants) = &self.tenants {
+            for (name, config) in tenants.configs(self)? {
+                config.validate().map_err(|err| match err {
+                    RustusError::WrongConfig(message) => {
+                        RustusError::WrongConfig(format!("Tenant {name}: {message}"))
+                    }
+                    err => err,
+                })?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RustusConf;
+
+    #[test]
+    fn validate() {
+        assert!(RustusConf::from_iter(["rustus"]).validate().is_ok());
+        assert!(RustusConf::from_iter([
+            "rustus",
+            "--storage",
+            "hybrid-s3",
+            "--s3-url",
+            "http://localhost:9000",
+            "--s3-region",
+            "eu-central-1",
+            "--s3-bucket",
+            "rustus",
+            "--s3-presigned-downloads",
+            "--named-storages",
+            r#"{"local": {"storage": "file-storage"}}"#,
+        ])
+        .validate()
+        .is_ok());
+    }
+
+    #[test]
+    fn wrong_options() {
+        for args in [
+            vec!["--storage", "s3", "--s3-url", "http://localhost:9000"],
+            vec!["--named-storages", r#"{"videos": {"storage": "s3"}}"#],
+            vec!["--s3-presigned-downloads"],
+            vec!["--port", "8080", "--admin-port", "8080"],
+            vec!["--admin-token", "secret", "--url", "/admin"],
+            vec!["--tus-extensions", "expiration", "--upload-expiration", "0"],
+            vec!["--notifiers", r#"[{"type": "amqp"}]"#],
+            vec![
+                "--tenants",
+                r#"{"avatars": {"url": "/avatars", "storage": {"storage": "s3"}}}"#,
+            ],
+            vec!["--tenant", "avatars"],
+        ] {
+            let config = RustusConf::from_iter(std::iter::once("rustus").chain(args));
+            assert!(config.validate().is_err());
+        }
+    }
 }
@@ -0,0 +1,234 @@
+use std::{ffi::OsString, path::Path, path::PathBuf};
+
+use clap::{parser::ValueSource, Arg, Command};
+use serde_json::{Map, Value};
+
+use crate::errors::{RustusError, RustusResult};
+
+/// Compare names of options regardless of dashes and underscores.
+fn normalize(name: &str) -> String {
+    name.replace('_', "-").to_lowercase()
+}
+
+/// Find an option of the command by its long name.
+pub fn find_arg<'a>(command: &'a Command, name: &str) -> Option<&'a Arg> {
+    let name = normalize(name);
+    command
+        .get_arguments()
+        .find(|arg| arg.get_long().map(normalize).as_ref() == Some(&name))
+}
+
+/// Message of clap error without usage.
+pub fn error_message(err: &clap::Error) -> String {
+    let message = err.to_string();
+    let message = message.lines().next().unwrap_or_default();
+    String::from(message.strip_prefix("error: ").unwrap_or(message))
+}
+
+/// Convert a value from the configuration file to CLI arguments.
+///
+/// Lists of plain values are joined with commas,
+/// objects and lists of objects are passed as JSON.
+pub fn to_args(arg: &Arg, value: &Value) -> Vec<OsString> {
+    let flag = format!("--{}", arg.get_long().unwrap_or_default());
+    let value = match value {
+        Value::Null => return vec![],
+        Value::Bool(enabled) if !arg.get_action().takes_values() => {
+            return if *enabled {
+                vec![OsString::from(flag)]
+            } else {
+                vec![]
+            };
+        }
+        Value::String(value) => value.clone(),
+        Value::Array(items) if items.iter().all(|item| !item.is_object()) => items
+            .iter()
+            .map(|item| item.as_str().map_or_else(|| item.to_string(), String::from))
+            .collect::<Vec<_>>()
+            .join(","),
+        other => other.to_string(),
+    };
+    vec![OsString::from(format!("{flag}={value}"))]
+}
+
+/// Read options from TOML or YAML file.
+///
+/// Format is chosen by the extension of the file.
+pub fn read_file(path: &Path) -> RustusResult<Map<String, Value>> {
+    let wrong_file = |err: String| RustusError::WrongConfig(format!("{}: {err}", path.display()));
+    let content = std::fs::read_to_string(path).map_err(|err| wrong_file(err.to_string()))?;
+    let value: Value = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("toml") => toml::from_str(&content).map_err(|err| wrong_file(err.to_string()))?,
+        Some("yaml" | "yml") => {
+            serde_yaml_ng::from_str(&content).map_err(|err| wrong_file(err.to_string()))?
+        }
+        _ => {
+            return Err(wrong_file(String::from(
+                "unknown format, use .toml, .yaml or .yml file.",
+            )))
+        }
+    };
+    match value {
+        Value::Object(options) => Ok(options),
+        _ => Err(wrong_file(String::from("options must be a table."))),
+    }
+}
+
+/// Add options from the configuration file to CLI arguments.
+///
+/// Path to the file is taken from the `config` option.
+/// Options which are passed as CLI arguments or environment
+/// variables are skipped, so they override values from the file.
+///
+/// # Params
+/// `command` - command, which parses the arguments.
+/// `args` - CLI arguments, including the name of the program.
+pub fn with_file_args(command: &Command, args: Vec<OsString>) -> RustusResult<Vec<OsString>> {
+    // Other errors are reported by the real parser.
+    let Ok(matches) = command
+        .clone()
+        .ignore_errors(true)
+        .try_get_matches_from(&args)
+    else {
+        return Ok(args);
+    };
+    let Some(path) = matches.get_one::<PathBuf>("config") else {
+        return Ok(args);
+    };
+    let mut file_args = Vec::new();
+    for (name, value) in read_file(path)? {
+        let arg = find_arg(command, &name)
+            .filter(|arg| arg.get_id() != "config")
+            .ok_or_else(|| {
+                RustusError::WrongConfig(format!("{}: unknown option {name}.", path.display()))
+            })?;
+        if matches!(
+            matches.value_source(arg.get_id().as_str()),
+            Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+        ) {
+            continue;
+        }
+        file_args.extend(to_args(arg, &value));
+    }
+    // Options from the file go before subcommands.
+    let mut args = args.into_iter();
+    Ok(args
+        .next()
+        .into_iter()
+        .chain(file_args)
+        .chain(args)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Write,
+        path::{Path, PathBuf},
+    };
+
+    use clap::CommandFactory;
+
+    use super::with_file_args;
+    use crate::{config::Command, notifiers::Hook, RustusConf};
+
+    fn config_file(name: &str, content: &str) -> (tempdir::TempDir, PathBuf) {
+        let dir = tempdir::TempDir::new("config").unwrap();
+        let path = dir.path().join(name);
+        std::fs::File::create(&path)
+            .unwrap()
+            .write_all(content.as_bytes())
+            .unwrap();
+        (dir, path)
+    }
+
+    fn parse(path: &Path, args: &[&str]) -> RustusConf {
+        let mut cli = vec!["rustus", "--config", path.to_str().unwrap()];
+        cli.extend(args);
+        let args = with_file_args(
+            &RustusConf::command(),
+            cli.into_iter().map(Into::into).collect(),
+        )
+        .unwrap();
+        RustusConf::from_iter(args)
+    }
+
+    #[test]
+    fn toml_file() {
+        let (_dir, path) = config_file(
+            "rustus.toml",
+            r#"
+            url = "/uploads"
+            port = 8080
+            max-file-size = 100
+            force_fsync = true
+            remove-parts = false
+            hooks = ["post-create", "post-finish"]
+
+            [named-storages.videos]
+            storage = "file-storage"
+            data_dir = "./videos"
+            "#,
+        );
+        let config = parse(&path, &[]);
+        assert_eq!(config.url, "/uploads");
+        assert_eq!(config.port, 8080);
+        assert_eq!(config.max_file_size, Some(100));
+        assert!(config.storage_opts.force_fsync);
+        assert!(!config.remove_parts);
+        assert_eq!(
+            config.notification_opts.hooks,
+            vec![Hook::PostCreate, Hook::PostFinish]
+        );
+        assert!(config
+            .storage_opts
+            .named_storages
+            .unwrap()
+            .0
+            .contains_key("videos"));
+    }
+
+    #[test]
+    fn yaml_file() {
+        let (_dir, path) = config_file(
+            "rustus.yaml",
+            "url: /uploads\nhooks-http-urls:\n  - http://localhost/hooks\n",
+        );
+        let config = parse(&path, &[]);
+        assert_eq!(config.url, "/uploads");
+        assert_eq!(
+            config.notification_opts.hooks_http_urls,
+            vec![String::from("http://localhost/hooks")]
+        );
+    }
+
+    #[test]
+    fn cli_overrides_file() {
+        let (_dir, path) = config_file(
+            "rustus.toml",
+            "url = \"/uploads\"\nhooks = [\"post-create\"]\n",
+        );
+        let config = parse(
+            &path,
+            &["--url", "/files", "--hooks", "post-finish", "verify"],
+        );
+        assert_eq!(config.url, "/files");
+        assert_eq!(config.notification_opts.hooks, vec![Hook::PostFinish]);
+        assert!(matches!(config.command, Some(Command::Verify)));
+    }
+
+    #[test]
+    fn wrong_files() {
+        for (name, content) in [
+            ("rustus.toml", "unknown = 1"),
+            ("rustus.toml", "config = \"other.toml\""),
+            ("rustus.toml", "url = "),
+            ("rustus.json", "{}"),
+            ("rustus.yaml", "- url"),
+        ] {
+            let (_dir, path) = config_file(name, content);
+            let args = vec!["rustus".into(), "--config".into(), path.into_os_string()];
+            assert!(with_file_args(&RustusConf::command(), args).is_err());
+        }
+    }
+}
@@ -1,6 +1,13 @@
 use crate::{errors::RustusResult, file_info::FileInfo};
 use actix_web::{HttpRequest, HttpResponse};
 use bytes::Bytes;
+use futures::stream::LocalBoxStream;
+
+/// Stream of chunks received from a client.
+///
+/// An error in the stream means that
+/// the client failed to send the rest of the body.
+pub type ByteStream = LocalBoxStream<'static, RustusResult<Bytes>>;
 
 pub trait DataStorage {
     /// Get name of the storage.
@@ -35,19 +42,25 @@ pub trait DataStorage {
 
     /// Add bytes to the file.
     ///
-    /// This method is used to append bytes to some file.
-    /// It returns new offset.
+    /// This method is used to append bytes from the stream to some file.
+    /// Implementations MUST advance `file_info.offset` by the number
+    /// of bytes that were actually saved, even if the stream
+    /// returns an error in the middle. In that case everything
+    /// received before the error is saved and the error is returned.
+    /// If the storage itself fails to write, bytes which aren't
+    /// counted in the offset MUST NOT be left in the storage.
     ///
     /// # Errors
     ///
     /// Implementations MUST throw errors at following cases:
     /// * If the info about the file can't be found.
     /// * If the storage is offline.
+    /// * If the stream returned an error.
     ///
     /// # Params
     /// `file_info` - info about current file.
-    /// `bytes` - bytes to append to the file.
-    async fn add_bytes(&self, file_info: &mut FileInfo, bytes: Bytes) -> RustusResult<()>;
+    /// `stream` - stream of bytes to append to the file.
+    async fn add_bytes(&self, file_info: &mut FileInfo, stream: ByteStream) -> RustusResult<()>;
 
     /// Create file in storage.
     ///
@@ -64,16 +77,51 @@ pub trait DataStorage {
     /// This method is used to merge multiple files together.
     ///
     /// This function is used by concat extension of the protocol.
+    /// Storages might update `file_info`, E.G. to remove
+    /// metadata that is not needed after concatenation.
     ///
     /// # Params
     /// `file_info` - info about current file.
     /// `parts_info` - info about merged files.
     async fn concat_files(
         &self,
-        file_info: &FileInfo,
+        file_info: &mut FileInfo,
         parts_info: Vec<FileInfo>,
     ) -> RustusResult<()>;
 
+    /// Make data of an upload consistent with its info.
+    ///
+    /// Bytes after `file_info.offset` MUST be removed, so
+    /// the next write starts at the right position.
+    /// It happens when bytes were written, but the info about them
+    /// wasn't saved.
+    ///
+    /// If the storage has less bytes than the offset,
+    /// implementations might decrease the offset and return `true`,
+    /// so the updated info is saved.
+    ///
+    /// # Params
+    /// `file_info` - info about current file, as it's saved in info storage.
+    async fn truncate(&self, file_info: &mut FileInfo) -> RustusResult<bool>;
+
+    /// Read data of the upload.
+    ///
+    /// Only saved bytes are returned, so the stream
+    /// yields exactly `file_info.offset` bytes.
+    ///
+    /// # Params
+    /// `file_info` - info about current file.
+    async fn read_data(&self, file_info: &FileInfo) -> RustusResult<ByteStream>;
+
+    /// Get number of bytes that are stored for the upload.
+    ///
+    /// It's used to check that data matches the info.
+    /// Returns `None` if the data doesn't exist.
+    ///
+    /// # Params
+    /// `file_info` - info about current file.
+    async fn get_length(&self, file_info: &FileInfo) -> RustusResult<Option<usize>>;
+
     /// Remove file from storage
     ///
     /// This method removes file and all associated
@@ -83,3 +131,10 @@ pub trait DataStorage {
     /// `file_info` - info about current file.
     async fn remove_file(&self, file_info: &FileInfo) -> RustusResult<()>;
 }
+
+/// Create a stream that yields a single chunk.
+#[cfg(test)]
+pub fn bytes_stream(bytes: impl Into<Bytes>) -> ByteStream {
+    use futures::StreamExt;
+    futures::stream::iter([Ok(bytes.into())]).boxed_local()
+}
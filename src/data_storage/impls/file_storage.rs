@@ -1,23 +1,32 @@
-use std::{fs::File, io::Write, path::PathBuf};
+use std::{
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+};
 
 use actix_files::NamedFile;
 use actix_web::{HttpRequest, HttpResponse};
-use bytes::Bytes;
 use chrono::{DateTime, Utc};
+use futures::StreamExt;
 use log::error;
 use std::{
     fs::{remove_file, DirBuilder, OpenOptions},
     io::{copy, BufReader, BufWriter},
 };
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
 use crate::{
-    data_storage::base::DataStorage,
+    data_storage::base::{ByteStream, DataStorage},
     errors::{RustusError, RustusResult},
     file_info::FileInfo,
     utils::dir_struct::substr_time,
 };
+use bytes::BytesMut;
 use derive_more::Display;
 
+/// Size of chunks in which data is read.
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
 #[derive(Display, Clone, Debug)]
 #[display("file_storage")]
 pub struct FileDataStorage {
@@ -35,6 +44,11 @@ impl FileDataStorage {
         }
     }
 
+    /// Directory where all files are stored.
+    pub fn data_dir(&self) -> &Path {
+        &self.data_dir
+    }
+
     pub fn data_file_path(
         &self,
         file_id: &str,
@@ -98,39 +112,69 @@ impl DataStorage for FileDataStorage {
         }
     }
 
-    async fn add_bytes(&self, file_info: &mut FileInfo, mut bytes: Bytes) -> RustusResult<()> {
+    async fn add_bytes(
+        &self,
+        file_info: &mut FileInfo,
+        mut stream: ByteStream,
+    ) -> RustusResult<()> {
         // In normal situation this `if` statement is not
         // gonna be called, but what if it is ...
         if file_info.path.is_none() {
             return Err(RustusError::FileNotFound);
         }
         let path = self.data_file_path(&file_info.id, file_info.created_at)?;
-        let force_sync = self.force_fsync;
-        tokio::task::spawn_blocking(move || {
-            // Opening file in w+a mode.
-            // It means that we're going to append some
-            // bytes to the end of a file.
-            let file = OpenOptions::new()
-                .append(true)
-                .create(false)
-                .read(false)
-                .truncate(false)
-                .open(path)
-                .map_err(|err| {
-                    error!("{:?}", err);
-                    RustusError::UnableToWrite(err.to_string())
-                })?;
-            let mut writer = BufWriter::new(file);
-            writer.write_all(bytes.as_ref())?;
-            writer.flush()?;
-            if force_sync {
-                writer.get_ref().sync_data()?;
+        // Opening file in w+a mode.
+        // It means that we're going to append some
+        // bytes to the end of a file.
+        let file = tokio::fs::OpenOptions::new()
+            .append(true)
+            .create(false)
+            .read(false)
+            .truncate(false)
+            .open(path)
+            .await
+            .map_err(|err| {
+                error!("{:?}", err);
+                RustusError::UnableToWrite(err.to_string())
+            })?;
+        let mut writer = tokio::io::BufWriter::new(file);
+        let mut written = 0;
+        let mut stream_result = Ok(());
+        let write_result = async {
+            while let Some(chunk) = stream.next().await {
+                match chunk {
+                    Ok(mut chunk) => {
+                        let chunk_len = chunk.len();
+                        writer.write_all_buf(&mut chunk).await?;
+                        written += chunk_len;
+                    }
+                    // Client has gone, but we still
+                    // save everything we've received.
+                    Err(err) => {
+                        stream_result = Err(err);
+                        break;
+                    }
+                }
             }
-            bytes.clear();
-
-            Ok(())
-        })
-        .await?
+            writer.flush().await?;
+            if self.force_fsync {
+                writer.get_ref().sync_data().await?;
+            }
+            Ok::<_, std::io::Error>(())
+        }
+        .await;
+        if let Err(err) = write_result {
+            error!("Cannot write to upload {}: {}", file_info.id, err);
+            // Some bytes might be written already, but they aren't
+            // counted in the offset, so the file is truncated back.
+            writer
+                .get_ref()
+                .set_len(u64::try_from(file_info.offset)?)
+                .await?;
+            return Err(RustusError::UnableToWrite(err.to_string()));
+        }
+        file_info.offset += written;
+        stream_result
     }
 
     async fn create_file(&self, file_info: &mut FileInfo) -> RustusResult<String> {
@@ -155,7 +199,7 @@ impl DataStorage for FileDataStorage {
 
     async fn concat_files(
         &self,
-        file_info: &FileInfo,
+        file_info: &mut FileInfo,
         parts_info: Vec<FileInfo>,
     ) -> RustusResult<()> {
         let force_fsync = self.force_fsync;
@@ -188,6 +232,68 @@ impl DataStorage for FileDataStorage {
         .await?
     }
 
+    async fn truncate(&self, file_info: &mut FileInfo) -> RustusResult<bool> {
+        let path = self.data_file_path(&file_info.id, file_info.created_at)?;
+        let file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .open(path)
+            .await
+            .map_err(|err| {
+                error!("{:?}", err);
+                RustusError::FileNotFound
+            })?;
+        let len = usize::try_from(file.metadata().await?.len())?;
+        if len < file_info.offset {
+            // Bytes were lost, E.G. the server has crashed before
+            // they were synced to disk. Upload can be continued
+            // from the last byte we actually have.
+            log::warn!(
+                "Upload {} has {} bytes instead of {}",
+                file_info.id,
+                len,
+                file_info.offset
+            );
+            file_info.offset = len;
+            // Running digest has already seen lost bytes.
+            file_info.digest = None;
+            return Ok(true);
+        }
+        if len > file_info.offset {
+            file.set_len(u64::try_from(file_info.offset)?).await?;
+            if self.force_fsync {
+                file.sync_data().await?;
+            }
+        }
+        Ok(false)
+    }
+
+    async fn read_data(&self, file_info: &FileInfo) -> RustusResult<ByteStream> {
+        let path = self.data_file_path(&file_info.id, file_info.created_at)?;
+        let file = tokio::fs::File::open(path).await.map_err(|err| {
+            error!("{:?}", err);
+            RustusError::FileNotFound
+        })?;
+        // Bytes after the offset weren't saved.
+        let reader = file.take(u64::try_from(file_info.offset)?);
+        Ok(
+            futures::stream::try_unfold(reader, |mut reader| async move {
+                let mut buffer = BytesMut::with_capacity(READ_CHUNK_SIZE);
+                let read = reader.read_buf(&mut buffer).await?;
+                Ok::<_, RustusError>((read > 0).then(|| (buffer.freeze(), reader)))
+            })
+            .boxed_local(),
+        )
+    }
+
+    async fn get_length(&self, file_info: &FileInfo) -> RustusResult<Option<usize>> {
+        let path = self.data_file_path(&file_info.id, file_info.created_at)?;
+        match tokio::fs::metadata(path).await {
+            Ok(metadata) => Ok(Some(usize::try_from(metadata.len())?)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
     async fn remove_file(&self, file_info: &FileInfo) -> RustusResult<()> {
         let info = file_info.clone();
         let file_path = self.data_file_path(&info.id, info.created_at)?;
@@ -209,9 +315,14 @@ impl DataStorage for FileDataStorage {
 #[cfg(test)]
 mod tests {
     use super::FileDataStorage;
-    use crate::{data_storage::base::DataStorage, file_info::FileInfo};
+    use crate::{
+        data_storage::base::{bytes_stream, DataStorage},
+        errors::RustusError,
+        file_info::FileInfo,
+    };
     use actix_web::test::TestRequest;
     use bytes::Bytes;
+    use futures::StreamExt;
     use std::{
         fs::File,
         io::{Read, Write},
@@ -221,7 +332,7 @@ mod tests {
     #[actix_rt::test]
     async fn preparation() {
         let dir = tempdir::TempDir::new("file_storage").unwrap();
-        let target_path = dir.into_path().join("not_exist");
+        let target_path = dir.path().join("not_exist");
         let mut storage = FileDataStorage::new(target_path.clone(), String::new(), false);
         assert!(!target_path.exists());
         storage.prepare().await.unwrap();
@@ -231,7 +342,7 @@ mod tests {
     #[actix_rt::test]
     async fn create_file() {
         let dir = tempdir::TempDir::new("file_storage").unwrap();
-        let storage = FileDataStorage::new(dir.into_path(), String::new(), false);
+        let storage = FileDataStorage::new(dir.path().to_path_buf(), String::new(), false);
         let mut file_info = FileInfo::new("test_id", Some(5), None, storage.to_string(), None);
         let new_path = storage.create_file(&mut file_info).await.unwrap();
         assert!(PathBuf::from(new_path).exists());
@@ -240,7 +351,7 @@ mod tests {
     #[actix_rt::test]
     async fn create_file_but_it_exists() {
         let dir = tempdir::TempDir::new("file_storage").unwrap();
-        let base_path = dir.into_path().clone();
+        let base_path = dir.path().to_path_buf();
         let storage = FileDataStorage::new(base_path.clone(), String::new(), false);
         let mut file_info = FileInfo::new("test_id", Some(5), None, storage.to_string(), None);
         File::create(base_path.join("test_id")).unwrap();
@@ -251,25 +362,51 @@ mod tests {
     #[actix_rt::test]
     async fn adding_bytes() {
         let dir = tempdir::TempDir::new("file_storage").unwrap();
-        let storage = FileDataStorage::new(dir.into_path(), String::new(), false);
+        let storage = FileDataStorage::new(dir.path().to_path_buf(), String::new(), false);
         let mut file_info = FileInfo::new("test_id", Some(5), None, storage.to_string(), None);
         let new_path = storage.create_file(&mut file_info).await.unwrap();
         let test_data = "MyTestData";
         file_info.path = Some(new_path.clone());
         storage
-            .add_bytes(&mut file_info, Bytes::from(test_data))
+            .add_bytes(&mut file_info, bytes_stream(test_data))
             .await
             .unwrap();
         let mut file = File::open(new_path).unwrap();
         let mut contents = String::new();
         file.read_to_string(&mut contents).unwrap();
         assert_eq!(contents, String::from(test_data));
+        assert_eq!(file_info.offset, test_data.len());
+    }
+
+    #[actix_rt::test]
+    async fn adding_bytes_interrupted() {
+        let dir = tempdir::TempDir::new("file_storage").unwrap();
+        let storage = FileDataStorage::new(dir.path().to_path_buf(), String::new(), false);
+        let mut file_info = FileInfo::new("test_id", Some(20), None, storage.to_string(), None);
+        let new_path = storage.create_file(&mut file_info).await.unwrap();
+        file_info.path = Some(new_path.clone());
+        let stream = futures::stream::iter([
+            Ok(Bytes::from("MyTest")),
+            Ok(Bytes::from("Data")),
+            Err(RustusError::Unknown),
+            Ok(Bytes::from("Lost")),
+        ])
+        .boxed_local();
+        let result = storage.add_bytes(&mut file_info, stream).await;
+        assert!(result.is_err());
+        assert_eq!(file_info.offset, 10);
+        let mut contents = String::new();
+        File::open(new_path)
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "MyTestData");
     }
 
     #[actix_rt::test]
     async fn adding_bytes_to_unknown_file() {
         let dir = tempdir::TempDir::new("file_storage").unwrap();
-        let storage = FileDataStorage::new(dir.into_path(), String::new(), false);
+        let storage = FileDataStorage::new(dir.path().to_path_buf(), String::new(), false);
         let mut file_info = FileInfo::new(
             "test_id",
             Some(5),
@@ -279,7 +416,7 @@ mod tests {
         );
         let test_data = "MyTestData";
         let result = storage
-            .add_bytes(&mut file_info, Bytes::from(test_data))
+            .add_bytes(&mut file_info, bytes_stream(test_data))
             .await;
         assert!(result.is_err());
     }
@@ -287,7 +424,7 @@ mod tests {
     #[actix_rt::test]
     async fn get_contents_of_unknown_file() {
         let dir = tempdir::TempDir::new("file_storage").unwrap();
-        let storage = FileDataStorage::new(dir.into_path(), String::new(), false);
+        let storage = FileDataStorage::new(dir.path().to_path_buf(), String::new(), false);
         let file_info = FileInfo::new(
             "test_id",
             Some(5),
@@ -303,7 +440,7 @@ mod tests {
     #[actix_rt::test]
     async fn remove_unknown_file() {
         let dir = tempdir::TempDir::new("file_storage").unwrap();
-        let storage = FileDataStorage::new(dir.into_path(), String::new(), false);
+        let storage = FileDataStorage::new(dir.path().to_path_buf(), String::new(), false);
         let file_info = FileInfo::new(
             "test_id",
             Some(5),
@@ -318,7 +455,7 @@ mod tests {
     #[actix_rt::test]
     async fn success_concatenation() {
         let dir = tempdir::TempDir::new("file_storage").unwrap();
-        let storage = FileDataStorage::new(dir.into_path(), String::new(), false);
+        let storage = FileDataStorage::new(dir.path().to_path_buf(), String::new(), false);
 
         let mut parts = Vec::new();
         let mut part1 = FileInfo::new("part_id1", None, None, storage.to_string(), None);
@@ -356,11 +493,87 @@ mod tests {
                 .to_string(),
         );
 
-        storage.concat_files(&final_info, parts).await.unwrap();
+        storage.concat_files(&mut final_info, parts).await.unwrap();
         let mut final_file = File::open(final_info.path.unwrap()).unwrap();
         let mut buffer = String::new();
         final_file.read_to_string(&mut buffer).unwrap();
 
         assert_eq!(buffer.as_str(), "hello world");
     }
+
+    #[actix_rt::test]
+    async fn truncate_unsaved_bytes() {
+        let dir = tempdir::TempDir::new("file_storage").unwrap();
+        let storage = FileDataStorage::new(dir.path().to_path_buf(), String::new(), false);
+        let mut file_info = FileInfo::new("test_id", Some(20), None, storage.to_string(), None);
+        let new_path = storage.create_file(&mut file_info).await.unwrap();
+        file_info.path = Some(new_path.clone());
+        storage
+            .add_bytes(&mut file_info, bytes_stream("MyTest"))
+            .await
+            .unwrap();
+        let mut saved_info = file_info.clone();
+        storage
+            .add_bytes(&mut file_info, bytes_stream("Data"))
+            .await
+            .unwrap();
+        let changed = storage.truncate(&mut saved_info).await.unwrap();
+        assert!(!changed);
+        assert_eq!(saved_info.offset, 6);
+        let mut contents = String::new();
+        File::open(new_path)
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "MyTest");
+    }
+
+    #[actix_rt::test]
+    async fn truncate_lost_bytes() {
+        let dir = tempdir::TempDir::new("file_storage").unwrap();
+        let storage = FileDataStorage::new(dir.path().to_path_buf(), String::new(), false);
+        let mut file_info = FileInfo::new("test_id", Some(20), None, storage.to_string(), None);
+        file_info.path = Some(storage.create_file(&mut file_info).await.unwrap());
+        file_info.offset = 10;
+        let changed = storage.truncate(&mut file_info).await.unwrap();
+        assert!(changed);
+        assert_eq!(file_info.offset, 0);
+    }
+
+    #[actix_rt::test]
+    async fn get_length() {
+        let dir = tempdir::TempDir::new("file_storage").unwrap();
+        let storage = FileDataStorage::new(dir.path().to_path_buf(), String::new(), false);
+        let mut file_info = FileInfo::new("test_id", Some(20), None, storage.to_string(), None);
+        assert_eq!(storage.get_length(&file_info).await.unwrap(), None);
+        file_info.path = Some(storage.create_file(&mut file_info).await.unwrap());
+        storage
+            .add_bytes(&mut file_info, bytes_stream("MyTest"))
+            .await
+            .unwrap();
+        assert_eq!(storage.get_length(&file_info).await.unwrap(), Some(6));
+    }
+
+    #[actix_rt::test]
+    async fn read_data() {
+        let dir = tempdir::TempDir::new("file_storage").unwrap();
+        let storage = FileDataStorage::new(dir.path().to_path_buf(), String::new(), false);
+        let mut file_info = FileInfo::new("test_id", Some(20), None, storage.to_string(), None);
+        file_info.path = Some(storage.create_file(&mut file_info).await.unwrap());
+        storage
+            .add_bytes(&mut file_info, bytes_stream("MyTest"))
+            .await
+            .unwrap();
+        let mut saved_info = file_info.clone();
+        saved_info.offset = 4;
+        let data = storage
+            .read_data(&saved_info)
+            .await
+            .unwrap()
+            .map(Result::unwrap)
+            .collect::<Vec<Bytes>>()
+            .await
+            .concat();
+        assert_eq!(data, b"MyTe");
+    }
 }
@@ -1,3 +1,5 @@
 pub mod file_storage;
+pub mod s3_concat;
+pub mod s3_download;
 pub mod s3_hybrid;
 pub mod s3_storage;
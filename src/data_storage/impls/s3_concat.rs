@@ -0,0 +1,285 @@
+use bytes::BytesMut;
+use futures::{StreamExt, TryStreamExt};
+use s3::{
+    command::{Command, Multipart},
+    error::S3Error,
+    request::{tokio_backend::HyperRequest, Request},
+    serde_types::Part,
+    signing::uri_encode,
+    Bucket,
+};
+
+use crate::errors::{RustusError, RustusResult};
+
+/// Minimal size of a multipart upload part allowed by S3.
+pub const MIN_PART_SIZE: usize = 5 * 1024 * 1024;
+/// Maximal size of a part that can be copied with a single request.
+pub const MAX_COPY_PART_SIZE: usize = 5 * 1024 * 1024 * 1024;
+
+/// Range of bytes of an S3 object.
+///
+/// The end of the range is exclusive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Segment {
+    key: String,
+    start: usize,
+    end: usize,
+}
+
+/// The way a part of the final object is created.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum PartPlan {
+    /// Part is copied by S3 itself from an existing object.
+    Copy(Segment),
+    /// Part is downloaded and uploaded back.
+    /// It's used only for data that is less than the minimal part size.
+    Upload(Vec<Segment>),
+}
+
+/// Split source objects into parts of the final object.
+///
+/// Everything large enough is copied on the S3 side.
+/// Small pieces are glued together until they reach the
+/// minimal part size, so every part except the last one
+/// satisfies S3 limits.
+fn plan_parts(sources: &[(String, usize)]) -> Vec<PartPlan> {
+    let mut plan = Vec::new();
+    let mut pending = Vec::new();
+    let mut pending_len = 0;
+    for (key, len) in sources {
+        let mut pos = 0;
+        while pos < *len {
+            let remaining = len - pos;
+            if pending_len > 0 || remaining < MIN_PART_SIZE {
+                let take = remaining.min(MIN_PART_SIZE - pending_len);
+                pending.push(Segment {
+                    key: key.clone(),
+                    start: pos,
+                    end: pos + take,
+                });
+                pending_len += take;
+                pos += take;
+                if pending_len >= MIN_PART_SIZE {
+                    plan.push(PartPlan::Upload(std::mem::take(&mut pending)));
+                    pending_len = 0;
+                }
+            } else {
+                let take = remaining.min(MAX_COPY_PART_SIZE);
+                plan.push(PartPlan::Copy(Segment {
+                    key: key.clone(),
+                    start: pos,
+                    end: pos + take,
+                }));
+                pos += take;
+            }
+        }
+    }
+    // Multipart upload must have at least one part,
+    // even if all sources are empty.
+    if !pending.is_empty() || plan.is_empty() {
+        plan.push(PartPlan::Upload(pending));
+    }
+    plan
+}
+
+/// Copy range of an object into a part of multipart upload.
+///
+/// This is an `UploadPartCopy` request, which is
+/// a regular part upload with copy source headers.
+async fn copy_part(
+    bucket: &Bucket,
+    key: &str,
+    upload_id: &str,
+    part_number: u32,
+    segment: &Segment,
+) -> RustusResult<Part> {
+    let mut copy_bucket = bucket.clone();
+    copy_bucket.add_header(
+        "x-amz-copy-source",
+        &format!(
+            "{}/{}",
+            bucket.name(),
+            uri_encode(segment.key.trim_start_matches('/'), false)
+        ),
+    );
+    copy_bucket.add_header(
+        "x-amz-copy-source-range",
+        &format!("bytes={}-{}", segment.start, segment.end - 1),
+    );
+    let command = Command::PutObject {
+        content: &[],
+        content_type: "application/octet-stream",
+        multipart: Some(Multipart::new(part_number, upload_id)),
+    };
+    let request = HyperRequest::new(&copy_bucket, key, command).await?;
+    let response = request.response_data(false).await?;
+    let body = String::from_utf8_lossy(response.as_slice());
+    // Etag is returned in the body. S3 might respond with 200
+    // and an error in the body, so missing etag is an error.
+    let etag = body
+        .split_once("<ETag>")
+        .and_then(|(_, rest)| rest.split_once("</ETag>"))
+        .map(|(etag, _)| etag.replace("&quot;", "\""))
+        .ok_or_else(|| S3Error::HttpFailWithBody(response.status_code(), body.to_string()))?;
+    Ok(Part { part_number, etag })
+}
+
+/// Download segments and upload them as a part of multipart upload.
+async fn upload_part(
+    bucket: &Bucket,
+    key: &str,
+    upload_id: &str,
+    part_number: u32,
+    segments: &[Segment],
+    content_type: &str,
+) -> RustusResult<Part> {
+    let mut data = BytesMut::new();
+    for segment in segments {
+        let command = Command::GetObjectRange {
+            start: u64::try_from(segment.start)?,
+            end: Some(u64::try_from(segment.end - 1)?),
+        };
+        let request = HyperRequest::new(bucket, &segment.key, command).await?;
+        let response = request.response_data(false).await?;
+        data.extend_from_slice(response.bytes());
+    }
+    Ok(bucket
+        .put_multipart_chunk(data.to_vec(), key, part_number, upload_id, content_type)
+        .await?)
+}
+
+/// Concatenate S3 objects into a new object.
+///
+/// The object is assembled as a multipart upload with the given id,
+/// where large pieces of sources are copied on the S3 side,
+/// and only small pieces are downloaded.
+/// In case of error the multipart upload is aborted.
+///
+/// # Params
+/// `sources` - keys and lengths of objects to concatenate.
+/// `concurrency` - maximum number of parts created simultaneously.
+///
+/// # Errors
+///
+/// Returns an error if any S3 request has failed.
+pub async fn concat_objects(
+    bucket: &Bucket,
+    key: &str,
+    upload_id: &str,
+    content_type: &str,
+    sources: &[(String, usize)],
+    concurrency: usize,
+) -> RustusResult<()> {
+    let plan = plan_parts(sources);
+    let result = async {
+        let parts = futures::stream::iter(plan.iter().enumerate())
+            .map(|(index, part_plan)| async move {
+                let part_number = u32::try_from(index + 1)?;
+                match part_plan {
+                    PartPlan::Copy(segment) => {
+                        copy_part(bucket, key, upload_id, part_number, segment).await
+                    }
+                    PartPlan::Upload(segments) => {
+                        upload_part(bucket, key, upload_id, part_number, segments, content_type)
+                            .await
+                    }
+                }
+            })
+            // Parts are created concurrently,
+            // but their order is preserved.
+            .buffered(concurrency.max(1))
+            .try_collect::<Vec<_>>()
+            .await?;
+        bucket
+            .complete_multipart_upload(key, upload_id, parts)
+            .await?;
+        Ok::<_, RustusError>(())
+    }
+    .await;
+    if result.is_err() {
+        if let Err(err) = bucket.abort_upload(key, upload_id).await {
+            log::error!("Cannot abort multipart upload {upload_id}: {err}");
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{plan_parts, PartPlan, Segment, MAX_COPY_PART_SIZE, MIN_PART_SIZE};
+
+    fn segment(key: &str, start: usize, end: usize) -> Segment {
+        Segment {
+            key: key.to_string(),
+            start,
+            end,
+        }
+    }
+
+    #[test]
+    fn large_parts_are_copied() {
+        let plan = plan_parts(&[
+            (String::from("a"), MIN_PART_SIZE),
+            (String::from("b"), MIN_PART_SIZE + 1),
+        ]);
+        assert_eq!(
+            plan,
+            vec![
+                PartPlan::Copy(segment("a", 0, MIN_PART_SIZE)),
+                PartPlan::Copy(segment("b", 0, MIN_PART_SIZE + 1)),
+            ]
+        );
+    }
+
+    #[test]
+    fn small_parts_are_glued() {
+        let plan = plan_parts(&[(String::from("a"), 10), (String::from("b"), 20)]);
+        assert_eq!(
+            plan,
+            vec![PartPlan::Upload(vec![
+                segment("a", 0, 10),
+                segment("b", 0, 20)
+            ])]
+        );
+    }
+
+    #[test]
+    fn small_part_before_large() {
+        let plan = plan_parts(&[
+            (String::from("a"), 10),
+            (String::from("b"), MIN_PART_SIZE * 2),
+        ]);
+        assert_eq!(
+            plan,
+            vec![
+                PartPlan::Upload(vec![
+                    segment("a", 0, 10),
+                    segment("b", 0, MIN_PART_SIZE - 10)
+                ]),
+                PartPlan::Copy(segment("b", MIN_PART_SIZE - 10, MIN_PART_SIZE * 2)),
+            ]
+        );
+    }
+
+    #[test]
+    fn huge_part_is_split() {
+        let plan = plan_parts(&[(String::from("a"), MAX_COPY_PART_SIZE + 10)]);
+        assert_eq!(
+            plan,
+            vec![
+                PartPlan::Copy(segment("a", 0, MAX_COPY_PART_SIZE)),
+                PartPlan::Upload(vec![segment(
+                    "a",
+                    MAX_COPY_PART_SIZE,
+                    MAX_COPY_PART_SIZE + 10
+                )]),
+            ]
+        );
+    }
+
+    #[test]
+    fn empty_sources() {
+        let plan = plan_parts(&[(String::from("a"), 0)]);
+        assert_eq!(plan, vec![PartPlan::Upload(vec![])]);
+    }
+}
@@ -0,0 +1,328 @@
+use std::collections::HashMap;
+
+use actix_files::HttpRange;
+use actix_web::{
+    http::{
+        header::{self, CacheControl, CacheDirective, HttpDate},
+        StatusCode,
+    },
+    HttpRequest, HttpResponse, HttpResponseBuilder,
+};
+use s3::{
+    command::Command,
+    request::{tokio_backend::HyperRequest, Request},
+    Bucket,
+};
+
+use crate::{
+    errors::RustusResult,
+    utils::headers::{generate_disposition, parse_header},
+};
+
+/// Information about S3 object,
+/// which is used to answer conditional requests.
+#[derive(Debug, Clone)]
+struct ObjectMeta {
+    length: u64,
+    etag: Option<String>,
+    last_modified: Option<HttpDate>,
+}
+
+/// Which part of an object must be sent.
+#[derive(Debug, Clone, Copy)]
+enum Selection {
+    Full,
+    Partial(HttpRange),
+    NotModified,
+    Unsatisfiable,
+}
+
+/// Check if the etag matches any of comma-separated etags from the header.
+///
+/// Weak comparison is used, as required for `If-None-Match`.
+fn etag_matches(header_value: &str, etag: &str) -> bool {
+    let etag = etag.trim_start_matches("W/");
+    header_value
+        .split(',')
+        .map(str::trim)
+        .any(|candidate| candidate == "*" || candidate.trim_start_matches("W/") == etag)
+}
+
+/// Decide which part of the object the client should receive.
+fn select(request: &HttpRequest, meta: &ObjectMeta) -> Selection {
+    let header_str = |name| {
+        request
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+    };
+    // If-None-Match takes precedence over If-Modified-Since.
+    if let Some(if_none_match) = header_str(header::IF_NONE_MATCH) {
+        if meta
+            .etag
+            .as_deref()
+            .is_some_and(|etag| etag_matches(if_none_match, etag))
+        {
+            return Selection::NotModified;
+        }
+    } else if let (Some(since), Some(modified)) = (
+        parse_header::<HttpDate>(request, header::IF_MODIFIED_SINCE.as_str()),
+        meta.last_modified,
+    ) {
+        if modified <= since {
+            return Selection::NotModified;
+        }
+    }
+    let Some(range) = header_str(header::RANGE) else {
+        return Selection::Full;
+    };
+    // If the object has changed, client must receive it fully.
+    if let Some(if_range) = header_str(header::IF_RANGE) {
+        let unchanged = if if_range.starts_with('"') {
+            // Only strong comparison is allowed here.
+            meta.etag.as_deref() == Some(if_range)
+        } else {
+            if_range
+                .parse::<HttpDate>()
+                .is_ok_and(|date| Some(date) == meta.last_modified)
+        };
+        if !unchanged {
+            return Selection::Full;
+        }
+    }
+    HttpRange::parse(range, meta.length).map_or(Selection::Unsatisfiable, |ranges| {
+        match ranges.as_slice() {
+            [range] => Selection::Partial(*range),
+            // Multiple ranges are not supported,
+            // so the whole object is sent.
+            _ => Selection::Full,
+        }
+    })
+}
+
+/// Create response with contents of an S3 object.
+///
+/// This function supports `Range`, `If-Range`,
+/// `If-None-Match` and `If-Modified-Since` headers.
+///
+/// # Errors
+///
+/// Returns an error if S3 requests fail.
+pub async fn get_object_response(
+    bucket: &Bucket,
+    key: &str,
+    filename: &str,
+    request: &HttpRequest,
+) -> RustusResult<HttpResponse> {
+    let (head, _) = bucket.head_object(key).await?;
+    let meta = ObjectMeta {
+        length: u64::try_from(head.content_length.unwrap_or_default())?,
+        etag: head.e_tag,
+        last_modified: head
+            .last_modified
+            .and_then(|last_modified| last_modified.parse().ok()),
+    };
+    let mut response = HttpResponseBuilder::new(StatusCode::OK);
+    response
+        .insert_header(generate_disposition(filename))
+        .insert_header((header::ACCEPT_RANGES, "bytes"));
+    if let Some(content_type) = head.content_type {
+        response.insert_header((header::CONTENT_TYPE, content_type));
+    }
+    if let Some(etag) = &meta.etag {
+        response.insert_header((header::ETAG, etag.as_str()));
+    }
+    if let Some(last_modified) = meta.last_modified {
+        response.insert_header((header::LAST_MODIFIED, last_modified));
+    }
+    let (command, length) = match select(request, &meta) {
+        Selection::NotModified => {
+            return Ok(response.status(StatusCode::NOT_MODIFIED).finish());
+        }
+        Selection::Unsatisfiable => {
+            return Ok(response
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .insert_header((header::CONTENT_RANGE, format!("bytes */{}", meta.length)))
+                .finish());
+        }
+        Selection::Full => (Command::GetObject, meta.length),
+        Selection::Partial(range) => {
+            let end = range.start + range.length - 1;
+            response.status(StatusCode::PARTIAL_CONTENT).insert_header((
+                header::CONTENT_RANGE,
+                format!("bytes {}-{end}/{}", range.start, meta.length),
+            ));
+            (
+                Command::GetObjectRange {
+                    start: range.start,
+                    end: Some(end),
+                },
+                range.length,
+            )
+        }
+    };
+    let s3_request = HyperRequest::new(bucket, key, command).await?;
+    let s3_response = s3_request.response_data_to_stream().await?;
+    Ok(response.no_chunking(length).streaming(s3_response.bytes))
+}
+
+/// Redirect client to a presigned URL of an S3 object.
+///
+/// Content disposition is passed to S3 with the URL,
+/// so the file is downloaded with the correct name.
+///
+/// # Errors
+///
+/// Returns an error if URL can't be signed.
+pub async fn presigned_redirect(
+    bucket: &Bucket,
+    key: &str,
+    filename: &str,
+    expiration: u32,
+) -> RustusResult<HttpResponse> {
+    let queries = HashMap::from([(
+        String::from("response-content-disposition"),
+        generate_disposition(filename).to_string(),
+    )]);
+    let url = bucket.presign_get(key, expiration, Some(queries)).await?;
+    Ok(HttpResponse::TemporaryRedirect()
+        .insert_header((header::LOCATION, url))
+        .insert_header(CacheControl(vec![CacheDirective::NoStore]))
+        .finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        http::{header::HttpDate, StatusCode},
+        test::TestRequest,
+    };
+
+    use super::{presigned_redirect, select, ObjectMeta, Selection};
+
+    fn get_meta() -> ObjectMeta {
+        ObjectMeta {
+            length: 100,
+            etag: Some(String::from("\"etag\"")),
+            last_modified: "Wed, 21 Oct 2015 07:28:00 GMT".parse::<HttpDate>().ok(),
+        }
+    }
+
+    #[test]
+    fn full() {
+        let request = TestRequest::get().to_http_request();
+        assert!(matches!(select(&request, &get_meta()), Selection::Full));
+    }
+
+    #[test]
+    fn partial() {
+        let request = TestRequest::get()
+            .insert_header(("Range", "bytes=10-19"))
+            .to_http_request();
+        match select(&request, &get_meta()) {
+            Selection::Partial(range) => {
+                assert_eq!(range.start, 10);
+                assert_eq!(range.length, 10);
+            }
+            selection => panic!("Unexpected selection: {selection:?}"),
+        }
+    }
+
+    #[test]
+    fn unsatisfiable() {
+        let request = TestRequest::get()
+            .insert_header(("Range", "bytes=200-"))
+            .to_http_request();
+        assert!(matches!(
+            select(&request, &get_meta()),
+            Selection::Unsatisfiable
+        ));
+    }
+
+    #[test]
+    fn if_range_matches() {
+        let request = TestRequest::get()
+            .insert_header(("Range", "bytes=10-19"))
+            .insert_header(("If-Range", "\"etag\""))
+            .to_http_request();
+        assert!(matches!(
+            select(&request, &get_meta()),
+            Selection::Partial(_)
+        ));
+        let request = TestRequest::get()
+            .insert_header(("Range", "bytes=10-19"))
+            .insert_header(("If-Range", "Wed, 21 Oct 2015 07:28:00 GMT"))
+            .to_http_request();
+        assert!(matches!(
+            select(&request, &get_meta()),
+            Selection::Partial(_)
+        ));
+    }
+
+    #[test]
+    fn if_range_changed() {
+        let request = TestRequest::get()
+            .insert_header(("Range", "bytes=10-19"))
+            .insert_header(("If-Range", "\"other\""))
+            .to_http_request();
+        assert!(matches!(select(&request, &get_meta()), Selection::Full));
+        let request = TestRequest::get()
+            .insert_header(("Range", "bytes=10-19"))
+            .insert_header(("If-Range", "Thu, 22 Oct 2015 07:28:00 GMT"))
+            .to_http_request();
+        assert!(matches!(select(&request, &get_meta()), Selection::Full));
+    }
+
+    #[test]
+    fn not_modified() {
+        let request = TestRequest::get()
+            .insert_header(("If-None-Match", "\"other\", W/\"etag\""))
+            .to_http_request();
+        assert!(matches!(
+            select(&request, &get_meta()),
+            Selection::NotModified
+        ));
+        let request = TestRequest::get()
+            .insert_header(("If-Modified-Since", "Thu, 22 Oct 2015 07:28:00 GMT"))
+            .to_http_request();
+        assert!(matches!(
+            select(&request, &get_meta()),
+            Selection::NotModified
+        ));
+    }
+
+    #[test]
+    fn modified() {
+        let request = TestRequest::get()
+            .insert_header(("If-None-Match", "\"other\""))
+            .to_http_request();
+        assert!(matches!(select(&request, &get_meta()), Selection::Full));
+        let request = TestRequest::get()
+            .insert_header(("If-Modified-Since", "Tue, 20 Oct 2015 07:28:00 GMT"))
+            .to_http_request();
+        assert!(matches!(select(&request, &get_meta()), Selection::Full));
+    }
+
+    #[actix_rt::test]
+    async fn presigned() {
+        let credentials =
+            s3::creds::Credentials::new(Some("access"), Some("secret"), None, None, None).unwrap();
+        let bucket = s3::Bucket::new(
+            "rustus",
+            s3::Region::Custom {
+                region: String::from("eu-west-1"),
+                endpoint: String::from("http://localhost:9000"),
+            },
+            credentials,
+        )
+        .unwrap();
+        let resp = presigned_redirect(&bucket, "/some/key", "video.mp4", 60)
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::TEMPORARY_REDIRECT);
+        let location = resp.headers().get("Location").unwrap().to_str().unwrap();
+        assert!(location.contains("/some/key"));
+        assert!(location.contains("X-Amz-Expires=60"));
+        assert!(location.contains("response-content-disposition"));
+    }
+}
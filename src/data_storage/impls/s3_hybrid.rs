@@ -1,26 +1,23 @@
-use std::{collections::HashMap, io::Write, path::PathBuf};
+use std::{collections::HashMap, path::PathBuf};
 
 use crate::{
-    data_storage::base::DataStorage,
+    data_storage::base::{ByteStream, DataStorage},
     errors::{RustusError, RustusResult},
     file_info::FileInfo,
-    utils::headers::generate_disposition,
 };
 
 use crate::utils::dir_struct::substr_time;
 
-use actix_web::{HttpRequest, HttpResponse, HttpResponseBuilder};
-use bytes::Bytes;
+use actix_web::{HttpRequest, HttpResponse};
 use chrono::{DateTime, Utc};
 use futures::{StreamExt, TryStreamExt};
-use s3::{
-    command::Command,
-    request::{tokio_backend::HyperRequest, Request as S3Request},
-    Bucket,
-};
-use tokio::io::AsyncWriteExt;
+use s3::{error::S3Error, Bucket};
 
-use super::file_storage::FileDataStorage;
+use super::{
+    file_storage::FileDataStorage,
+    s3_concat::concat_objects,
+    s3_download::{get_object_response, presigned_redirect},
+};
 
 /// This storage is useful for small files when you have chunks less than 5MB.
 /// This restriction is based on the S3 API limitations.
@@ -35,6 +32,7 @@ pub struct S3HybridDataStorage {
     local_storage: FileDataStorage,
     dir_struct: String,
     concurrent_concat_downloads: usize,
+    presigned_expiration: Option<u32>,
 }
 
 impl S3HybridDataStorage {
@@ -54,6 +52,7 @@ impl S3HybridDataStorage {
         dir_struct: String,
         force_fsync: bool,
         concurrent_concat_downloads: usize,
+        presigned_expiration: Option<u32>,
     ) -> Self {
         let local_storage = FileDataStorage::new(data_dir, dir_struct.clone(), force_fsync);
         let creds = s3::creds::Credentials::new(
@@ -96,9 +95,35 @@ impl S3HybridDataStorage {
             local_storage,
             dir_struct,
             concurrent_concat_downloads,
+            presigned_expiration,
         }
     }
 
+    /// Storage for tests.
+    ///
+    /// S3 is only used for finished uploads,
+    /// so it doesn't need to be available.
+    #[cfg(test)]
+    pub fn new_test(data_dir: PathBuf) -> Self {
+        Self::new(
+            String::from("http://localhost:9000"),
+            String::from("eu-west-1"),
+            Some(&String::from("access")),
+            Some(&String::from("secret")),
+            None,
+            None,
+            None,
+            None,
+            "rustus",
+            false,
+            data_dir,
+            String::new(),
+            false,
+            1,
+            None,
+        )
+    }
+
     /// Upload file to S3.
     ///
     /// This function is called to upload file to s3 completely.
@@ -124,6 +149,11 @@ impl S3HybridDataStorage {
         Ok(())
     }
 
+    /// Storage for uploads that aren't moved to S3 yet.
+    pub const fn local_storage(&self) -> &FileDataStorage {
+        &self.local_storage
+    }
+
     // Construct an S3 key which is used to upload files.
     fn get_s3_key(&self, id: &str, created_at: DateTime<Utc>) -> String {
         let base_path = substr_time(self.dir_struct.as_str(), created_at);
@@ -150,24 +180,23 @@ impl DataStorage for S3HybridDataStorage {
             return self.local_storage.get_contents(file_info, request).await;
         }
         let key = self.get_s3_key(&file_info.id, file_info.created_at);
-        let command = Command::GetObject;
-        let s3_request = HyperRequest::new(&self.bucket, &key, command).await?;
-        let s3_response = s3_request.response_data_to_stream().await?;
-        let mut response = HttpResponseBuilder::new(actix_web::http::StatusCode::OK);
-        Ok(response
-            .insert_header(generate_disposition(file_info.get_filename()))
-            .streaming(s3_response.bytes))
+        if let Some(expiration) = self.presigned_expiration {
+            return presigned_redirect(&self.bucket, &key, file_info.get_filename(), expiration)
+                .await;
+        }
+        get_object_response(&self.bucket, &key, file_info.get_filename(), request).await
     }
 
-    async fn add_bytes(&self, file_info: &mut FileInfo, bytes: Bytes) -> RustusResult<()> {
-        let part_len = bytes.len();
-        self.local_storage.add_bytes(file_info, bytes).await?;
+    async fn add_bytes(&self, file_info: &mut FileInfo, stream: ByteStream) -> RustusResult<()> {
+        // The stream may fail after the last byte was written,
+        // so the upload is moved to S3 even if writing has failed.
+        let write_result = self.local_storage.add_bytes(file_info, stream).await;
         // If upload is complete. Upload the resulting file onto S3.
-        if Some(file_info.offset + part_len) == file_info.length {
+        if Some(file_info.offset) == file_info.length {
             self.upload_file(file_info).await?;
             self.local_storage.remove_file(file_info).await?;
         }
-        Ok(())
+        write_result
     }
 
     async fn create_file(&self, file_info: &mut FileInfo) -> RustusResult<String> {
@@ -177,93 +206,228 @@ impl DataStorage for S3HybridDataStorage {
 
     async fn concat_files(
         &self,
-        file_info: &FileInfo,
+        file_info: &mut FileInfo,
         parts_info: Vec<FileInfo>,
     ) -> RustusResult<()> {
-        let dir = tempdir::TempDir::new(&file_info.id)?;
-        let mut download_futures = vec![];
-
-        // At first we need to download all parts.
-        for part_info in &parts_info {
-            let part_key = self.get_s3_key(&part_info.id, part_info.created_at);
-            let part_out = dir.path().join(&part_info.id);
-            // Here we create a future which downloads the part
-            // into a temporary file.
-            download_futures.push(async move {
-                let part_file = tokio::fs::File::create(&part_out).await?;
-                let mut writer = tokio::io::BufWriter::new(part_file);
-                let mut reader = self.bucket.get_object_stream(&part_key).await?;
-                while let Some(chunk) = reader.bytes().next().await {
-                    let mut chunk = chunk?;
-                    writer.write_all_buf(&mut chunk).await.map_err(|err| {
-                        log::error!("{:?}", err);
-                        RustusError::UnableToWrite(err.to_string())
-                    })?;
-                }
-                writer.flush().await?;
-                writer.get_ref().sync_data().await?;
-                Ok::<_, RustusError>(())
-            });
-        }
-        // Here we await all download futures.
-        // We use buffer_unordered to limit the number of concurrent downloads.
-        futures::stream::iter(download_futures)
-            // Number of concurrent downloads.
-            .buffer_unordered(self.concurrent_concat_downloads)
-            // We use try_collect to collect all results
-            // and return an error if any of the futures returned an error.
-            .try_collect::<Vec<_>>()
-            .await?;
-
-        let output_path = dir.path().join(&file_info.id);
-        let output_path_cloned = output_path.clone();
-        let parts_files = parts_info
+        let key = self.get_s3_key(&file_info.id, file_info.created_at);
+        let upload_id = self
+            .bucket
+            .initiate_multipart_upload(&key, file_info.get_mime_type().as_ref())
+            .await?
+            .upload_id;
+        // All parts are complete, so they are already on S3.
+        let sources = parts_info
             .iter()
-            .map(|info| dir.path().join(&info.id))
+            .map(|info| {
+                (
+                    self.get_s3_key(&info.id, info.created_at),
+                    info.length.unwrap_or(info.offset),
+                )
+            })
             .collect::<Vec<_>>();
-        tokio::task::spawn_blocking(move || {
-            let file = std::fs::OpenOptions::new()
-                .append(true)
-                .create(true)
-                .open(output_path_cloned)
-                .map_err(|err| {
-                    log::error!("{:?}", err);
-                    RustusError::UnableToWrite(err.to_string())
-                })?;
-            let mut writer = std::io::BufWriter::new(file);
-            for part in &parts_files {
-                let part_file = std::fs::OpenOptions::new().read(true).open(part)?;
-                let mut reader = std::io::BufReader::new(part_file);
-                std::io::copy(&mut reader, &mut writer)?;
+        concat_objects(
+            &self.bucket,
+            &key,
+            &upload_id,
+            file_info.get_mime_type().as_ref(),
+            &sources,
+            self.concurrent_concat_downloads,
+        )
+        .await?;
+        // Local file that was created for the final upload isn't needed.
+        self.local_storage.remove_file(file_info).await?;
+        Ok(())
+    }
+
+    async fn truncate(&self, file_info: &mut FileInfo) -> RustusResult<bool> {
+        // Complete uploads are never truncated.
+        if Some(file_info.offset) == file_info.length {
+            return Ok(false);
+        }
+        let local_path = self
+            .local_storage
+            .data_file_path(&file_info.id, file_info.created_at)?;
+        if !local_path.exists() {
+            // The last write has completed the upload and it was moved to S3,
+            // but the info about it wasn't saved. So we move it back.
+            let key = self.get_s3_key(&file_info.id, file_info.created_at);
+            let mut file = tokio::fs::File::create(&local_path).await?;
+            match self.bucket.get_object_to_writer(&key, &mut file).await {
+                Ok(_) => {
+                    self.bucket.delete_object(&key).await?;
+                }
+                // Nothing was moved, for example concatenation
+                // has failed after the local file was removed.
+                // Empty file is enough if no bytes must be kept.
+                Err(S3Error::HttpFailWithBody(404, _)) if file_info.offset == 0 => {}
+                Err(err) => {
+                    drop(file);
+                    tokio::fs::remove_file(&local_path).await?;
+                    return Err(match err {
+                        S3Error::HttpFailWithBody(404, _) => RustusError::FileNotFound,
+                        err => err.into(),
+                    });
+                }
             }
-            writer.flush()?;
-            writer.get_ref().sync_data()?;
-            Ok::<_, RustusError>(())
-        })
-        .await??;
+        }
+        self.local_storage.truncate(file_info).await
+    }
 
-        // We reopen the file to upload it to S3.
-        // This is needed because we need to open the file in read mode.
-        let output_file = tokio::fs::File::open(&output_path).await?;
-        let mut reader = tokio::io::BufReader::new(output_file);
+    async fn read_data(&self, file_info: &FileInfo) -> RustusResult<ByteStream> {
+        let local_path = self
+            .local_storage
+            .data_file_path(&file_info.id, file_info.created_at)?;
+        if local_path.exists() {
+            return self.local_storage.read_data(file_info).await;
+        }
         let key = self.get_s3_key(&file_info.id, file_info.created_at);
-        self.bucket
-            .put_object_stream_with_content_type(&mut reader, key, file_info.get_mime_type())
-            .await?;
-
-        tokio::fs::remove_file(output_path).await?;
+        let response = self.bucket.get_object_stream(key).await?;
+        Ok(response.bytes.map_err(RustusError::from).boxed_local())
+    }
 
-        Ok(())
+    async fn get_length(&self, file_info: &FileInfo) -> RustusResult<Option<usize>> {
+        // Data is stored locally until it's moved to S3.
+        if let Some(length) = self.local_storage.get_length(file_info).await? {
+            return Ok(Some(length));
+        }
+        if Some(file_info.offset) != file_info.length {
+            return Ok(None);
+        }
+        let key = self.get_s3_key(&file_info.id, file_info.created_at);
+        match self.bucket.head_object(key).await {
+            Ok((head, _)) => Ok(Some(usize::try_from(
+                head.content_length.unwrap_or_default(),
+            )?)),
+            Err(S3Error::HttpFailWithBody(404, _)) => Ok(None),
+            Err(err) => Err(err.into()),
+        }
     }
 
     async fn remove_file(&self, file_info: &FileInfo) -> RustusResult<()> {
+        // Local file exists until the upload is moved to S3.
+        // It also stays there if moving to S3 has failed.
+        match self.local_storage.remove_file(file_info).await {
+            Ok(()) => {}
+            Err(RustusError::FileNotFound) if Some(file_info.offset) == file_info.length => {}
+            Err(err) => return Err(err),
+        }
         if Some(file_info.offset) == file_info.length {
-            self.bucket
+            match self
+                .bucket
                 .delete_object(self.get_s3_key(&file_info.id, file_info.created_at))
-                .await?;
-        } else {
-            self.local_storage.remove_file(file_info).await?;
+                .await
+            {
+                Ok(_) | Err(S3Error::HttpFailWithBody(404, _)) => {}
+                Err(err) => return Err(err.into()),
+            }
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use bytes::Bytes;
+    use futures::StreamExt;
+
+    use crate::{data_storage::base::DataStorage, errors::RustusError, file_info::FileInfo};
+
+    use super::S3HybridDataStorage;
+
+    fn get_storage(data_dir: &tempdir::TempDir) -> S3HybridDataStorage {
+        let endpoint = std::env::var("TEST_S3_ENDPOINT")
+            .unwrap_or_else(|_| String::from("http://localhost:9000"));
+        let region = std::env::var("TEST_S3_REGION").unwrap_or_else(|_| String::from("eu-west-1"));
+        let access_key =
+            std::env::var("TEST_S3_ACCESS_KEY").unwrap_or_else(|_| String::from("rustus-test"));
+        let secret_key =
+            std::env::var("TEST_S3_SECRET_KEY").unwrap_or_else(|_| String::from("rustus-test"));
+        let bucket = std::env::var("TEST_S3_BUCKET").unwrap_or_else(|_| String::from("rustus"));
+        let path_style = std::env::var("TEST_S3_FORCE_PATH_STYLE")
+            .unwrap_or_else(|_| String::from("true"))
+            .parse()
+            .unwrap();
+        S3HybridDataStorage::new(
+            endpoint,
+            region,
+            Some(&access_key),
+            Some(&secret_key),
+            None,
+            None,
+            None,
+            None,
+            &bucket,
+            path_style,
+            data_dir.path().to_path_buf(),
+            String::new(),
+            false,
+            1,
+            None,
+        )
+    }
+
+    #[actix_rt::test]
+    async fn body_too_large() {
+        let dir = tempdir::TempDir::new("s3_hybrid").unwrap();
+        let storage = get_storage(&dir);
+        let mut file_info = FileInfo::new(
+            &uuid::Uuid::new_v4().to_string(),
+            Some(5),
+            None,
+            storage.get_name().to_string(),
+            None,
+        );
+        file_info.path = Some(storage.create_file(&mut file_info).await.unwrap());
+        // The last byte exceeds the length of the upload.
+        let stream = futures::stream::iter([
+            Ok(Bytes::from_static(b"Hello")),
+            Err(RustusError::BodyTooLarge),
+        ])
+        .boxed_local();
+        let result = storage.add_bytes(&mut file_info, stream).await;
+        assert!(matches!(result, Err(RustusError::BodyTooLarge)));
+        assert_eq!(file_info.offset, 5);
+        let local_path = storage
+            .local_storage
+            .data_file_path(&file_info.id, file_info.created_at)
+            .unwrap();
+        assert!(!local_path.exists());
+        let object = storage
+            .bucket
+            .get_object(storage.get_s3_key(&file_info.id, file_info.created_at))
+            .await
+            .unwrap();
+        assert_eq!(object.bytes().as_ref(), b"Hello");
+        storage.remove_file(&file_info).await.unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn truncate_missing_object() {
+        let dir = tempdir::TempDir::new("s3_hybrid").unwrap();
+        let storage = get_storage(&dir);
+        let mut file_info = FileInfo::new(
+            &uuid::Uuid::new_v4().to_string(),
+            Some(5),
+            None,
+            storage.get_name().to_string(),
+            None,
+        );
+        file_info.path = Some(storage.create_file(&mut file_info).await.unwrap());
+        // Local file was removed, but nothing was uploaded to S3.
+        storage.local_storage.remove_file(&file_info).await.unwrap();
+        storage.truncate(&mut file_info).await.unwrap();
+        let local_path = storage
+            .local_storage
+            .data_file_path(&file_info.id, file_info.created_at)
+            .unwrap();
+        assert!(local_path.exists());
+        // Bytes after the offset can't be restored.
+        file_info.offset = 3;
+        storage.local_storage.remove_file(&file_info).await.unwrap();
+        assert!(matches!(
+            storage.truncate(&mut file_info).await,
+            Err(RustusError::FileNotFound)
+        ));
+        assert!(!local_path.exists());
+    }
+}
@@ -1,26 +1,32 @@
-use std::{collections::HashMap, io::Write};
+use std::collections::HashMap;
 
-use actix_web::{HttpRequest, HttpResponse, HttpResponseBuilder};
+use actix_web::{HttpRequest, HttpResponse};
+use bytes::BytesMut;
 use chrono::{DateTime, Utc};
 use futures::{StreamExt, TryStreamExt};
-use s3::{
-    command::Command,
-    request::{tokio_backend::HyperRequest, Request},
-    serde_types::Part,
-    Bucket,
-};
+use s3::{error::S3Error, serde_types::Part, Bucket};
 use serde::{Deserialize, Serialize};
-use tokio::io::AsyncWriteExt;
 
 use crate::{
-    data_storage::base::DataStorage,
+    data_storage::base::{ByteStream, DataStorage},
     errors::{RustusError, RustusResult},
     file_info::FileInfo,
-    utils::{dir_struct::substr_time, headers::generate_disposition},
+    utils::dir_struct::substr_time,
+};
+
+use super::{
+    s3_concat::{concat_objects, MIN_PART_SIZE},
+    s3_download::{get_object_response, presigned_redirect},
 };
 
 const UPLOAD_ID_KEY: &str = "_s3_upload_id";
 const PARTS_KEY: &str = "_s3_parts";
+/// Number of bytes that are staged and not yet uploaded as a part.
+const STAGED_KEY: &str = "_s3_staged";
+/// Number of a part, whose staged object isn't needed anymore.
+const STALE_KEY: &str = "_s3_stale";
+/// Suffix of objects that hold staged bytes.
+const STAGED_SUFFIX: &str = ".staged";
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct S3MPUPart {
@@ -33,6 +39,7 @@ pub struct S3DataStorage {
     bucket: Bucket,
     dir_struct: String,
     concurrent_concat_downloads: usize,
+    presigned_expiration: Option<u32>,
 }
 
 impl From<S3MPUPart> for Part {
@@ -68,6 +75,7 @@ impl S3DataStorage {
         force_path_style: bool,
         dir_struct: String,
         concat_concurrent_downloads: usize,
+        presigned_expiration: Option<u32>,
     ) -> Self {
         let creds = s3::creds::Credentials::new(
             access_key.map(String::as_str),
@@ -108,6 +116,7 @@ impl S3DataStorage {
             bucket: *bucket,
             dir_struct,
             concurrent_concat_downloads: concat_concurrent_downloads,
+            presigned_expiration,
         }
     }
 
@@ -117,6 +126,192 @@ impl S3DataStorage {
         let trimmed_path = base_path.trim_end_matches('/');
         format!("{trimmed_path}/{id}")
     }
+
+    /// Key of an object that holds a staged chunk of the given part.
+    ///
+    /// Every part has its own objects, so if the info about the last
+    /// write wasn't saved, staged bytes of the saved info are still there.
+    /// Every request stages only the bytes it has received, so small
+    /// chunks aren't downloaded and uploaded again on each request.
+    fn get_staged_key(s3_path: &str, part_number: usize, chunk: usize) -> String {
+        format!("{s3_path}{STAGED_SUFFIX}.{part_number}.{chunk}")
+    }
+
+    /// Lengths of staged chunks of the next part.
+    fn staged_chunks(file_info: &FileInfo) -> RustusResult<Vec<usize>> {
+        Ok(serde_json::from_str(
+            file_info
+                .metadata
+                .get(STAGED_KEY)
+                .map_or("[]", String::as_str),
+        )?)
+    }
+
+    /// Parse the number of stale part and the number of its staged chunks.
+    fn parse_stale(stale: &str) -> RustusResult<(usize, usize)> {
+        let (part, chunks) = stale
+            .split_once(':')
+            .ok_or(RustusError::S3StagedDataCorrupted)?;
+        Ok((part.parse()?, chunks.parse()?))
+    }
+
+    /// Download staged chunks of the given part.
+    ///
+    /// Bytes after the saved length of a chunk were written by a request,
+    /// which info wasn't saved. They are ignored.
+    async fn load_staged(
+        &self,
+        s3_path: &str,
+        part_number: usize,
+        chunks: &[usize],
+    ) -> RustusResult<BytesMut> {
+        let mut buffer = BytesMut::new();
+        for (index, length) in chunks.iter().enumerate() {
+            let object = self
+                .bucket
+                .get_object(Self::get_staged_key(s3_path, part_number, index))
+                .await?;
+            let staged_bytes = object
+                .bytes()
+                .get(..*length)
+                .ok_or(RustusError::S3StagedDataCorrupted)?;
+            buffer.extend_from_slice(staged_bytes);
+        }
+        Ok(buffer)
+    }
+
+    /// Upload the next part of the multipart upload.
+    async fn upload_part(
+        &self,
+        file_info: &mut FileInfo,
+        s3_path: &str,
+        upload_id: &str,
+        parts: &mut Vec<S3MPUPart>,
+        data: BytesMut,
+    ) -> RustusResult<()> {
+        log::debug!(
+            "UPLOADING PART: {part_num} for {file_id}",
+            part_num = parts.len() + 1,
+            file_id = file_info.id
+        );
+        let resp = self
+            .bucket
+            .put_multipart_chunk(
+                data.to_vec(),
+                s3_path,
+                u32::try_from(parts.len() + 1)?,
+                upload_id,
+                file_info.get_mime_type().as_ref(),
+            )
+            .await?;
+        parts.push(resp.into());
+        file_info
+            .metadata
+            .insert(PARTS_KEY.to_string(), serde_json::to_string(&parts)?);
+        Ok(())
+    }
+
+    /// Complete the multipart upload and forget about its parts.
+    async fn complete_upload(
+        &self,
+        file_info: &mut FileInfo,
+        s3_path: &str,
+        upload_id: &str,
+        parts: &[S3MPUPart],
+    ) -> RustusResult<()> {
+        if parts.is_empty() {
+            // S3 can't complete multipart upload without parts,
+            // so empty uploads are saved as regular objects.
+            match self.bucket.abort_upload(s3_path, upload_id).await {
+                // Upload was aborted by the previous attempt.
+                Ok(()) | Err(S3Error::HttpFailWithBody(404, _)) => {}
+                Err(err) => return Err(err.into()),
+            }
+            self.bucket
+                .put_object_with_content_type(s3_path, &[], file_info.get_mime_type().as_ref())
+                .await?;
+        } else {
+            self.bucket
+                .complete_multipart_upload(
+                    s3_path,
+                    upload_id,
+                    parts.iter().cloned().map(Part::from).collect(),
+                )
+                .await?;
+        }
+        file_info.metadata.remove(PARTS_KEY);
+        file_info.metadata.remove(UPLOAD_ID_KEY);
+        Ok(())
+    }
+
+    /// Actual lengths of staged chunks of the given part.
+    ///
+    /// Missing chunks have zero length.
+    async fn staged_lengths(
+        &self,
+        s3_path: &str,
+        part_number: usize,
+        chunks: usize,
+    ) -> RustusResult<Vec<usize>> {
+        let mut lengths = Vec::with_capacity(chunks);
+        for index in 0..chunks {
+            let key = Self::get_staged_key(s3_path, part_number, index);
+            let length = match self.bucket.head_object(key).await {
+                Ok((head, _)) => usize::try_from(head.content_length.unwrap_or_default())?,
+                Err(S3Error::HttpFailWithBody(404, _)) => 0,
+                Err(err) => return Err(err.into()),
+            };
+            lengths.push(length);
+        }
+        Ok(lengths)
+    }
+
+    /// Remove staged chunks of the given part.
+    ///
+    /// Missing chunks are not an error.
+    async fn remove_staged(
+        &self,
+        s3_path: &str,
+        part_number: usize,
+        chunks: usize,
+    ) -> RustusResult<()> {
+        for index in 0..chunks {
+            let key = Self::get_staged_key(s3_path, part_number, index);
+            match self.bucket.delete_object(key).await {
+                // Chunk was already removed, or the request
+                // that was staging it has failed.
+                Ok(_) | Err(S3Error::HttpFailWithBody(404, _)) => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove staged chunks that aren't needed anymore.
+    ///
+    /// Leftovers don't break the upload, so errors are only logged.
+    async fn remove_staged_logged(
+        &self,
+        file_info: &FileInfo,
+        s3_path: &str,
+        part_number: usize,
+        chunks: usize,
+    ) {
+        if let Err(err) = self.remove_staged(s3_path, part_number, chunks).await {
+            log::warn!("Cannot remove staged data of {}: {}", file_info.id, err);
+        }
+    }
+
+    /// Number of the part that is going to be uploaded next.
+    fn next_part_number(file_info: &FileInfo) -> RustusResult<usize> {
+        let parts: Vec<S3MPUPart> = serde_json::from_str(
+            file_info
+                .metadata
+                .get(PARTS_KEY)
+                .map_or("[]", String::as_str),
+        )?;
+        Ok(parts.len() + 1)
+    }
 }
 
 impl DataStorage for S3DataStorage {
@@ -131,22 +326,20 @@ impl DataStorage for S3DataStorage {
     async fn get_contents(
         &self,
         file_info: &FileInfo,
-        _request: &HttpRequest,
+        request: &HttpRequest,
     ) -> RustusResult<HttpResponse> {
         let key = self.get_s3_key(&file_info.id, file_info.created_at);
-        let command = Command::GetObject;
-        let s3_request = HyperRequest::new(&self.bucket, &key, command).await?;
-        let s3_response = s3_request.response_data_to_stream().await?;
-        let mut response = HttpResponseBuilder::new(actix_web::http::StatusCode::OK);
-        Ok(response
-            .insert_header(generate_disposition(file_info.get_filename()))
-            .streaming(s3_response.bytes))
+        if let Some(expiration) = self.presigned_expiration {
+            return presigned_redirect(&self.bucket, &key, file_info.get_filename(), expiration)
+                .await;
+        }
+        get_object_response(&self.bucket, &key, file_info.get_filename(), request).await
     }
 
     async fn add_bytes(
         &self,
         file_info: &mut FileInfo,
-        bytes: bytes::Bytes,
+        mut stream: ByteStream,
     ) -> crate::errors::RustusResult<()> {
         let s3_path = self.get_s3_key(&file_info.id, file_info.created_at);
         let mut parts: Vec<S3MPUPart> = serde_json::from_str(
@@ -158,39 +351,91 @@ impl DataStorage for S3DataStorage {
         let upload_id = file_info
             .metadata
             .get(UPLOAD_ID_KEY)
-            .ok_or(RustusError::S3UploadIdMissing)?;
-        log::debug!(
-            "UPLOADING PART: {part_num} for {file_id}",
-            part_num = parts.len() + 1,
-            file_id = file_info.id
-        );
-        let resp = self
-            .bucket
-            .put_multipart_chunk(
-                bytes.to_vec(),
-                &s3_path,
-                u32::try_from(parts.len() + 1)?,
-                upload_id,
-                file_info.get_mime_type().as_ref(),
-            )
-            .await?;
-        parts.push(resp.into());
-        if Some(file_info.offset + bytes.len()) == file_info.length {
+            .ok_or(RustusError::S3UploadIdMissing)?
+            .clone();
+        // Staged bytes were uploaded as a part by the previous request.
+        // Now the info about it is saved, so they can be removed.
+        if let Some(stale) = file_info.metadata.remove(STALE_KEY) {
+            let (stale_part, stale_chunks) = Self::parse_stale(&stale)?;
+            self.remove_staged_logged(file_info, &s3_path, stale_part, stale_chunks)
+                .await;
+        }
+        let staged_part = parts.len() + 1;
+        // Lengths of chunks that were staged by previous requests.
+        // They are already counted in the offset.
+        let mut staged = Self::staged_chunks(file_info)?;
+        let had_staged = staged.len();
+        // Bytes received by this request that aren't uploaded yet.
+        let mut buffer = BytesMut::new();
+        let mut stream_result = Ok(());
+        loop {
+            let chunk = match stream.next().await {
+                Some(Ok(chunk)) => Some(chunk),
+                // Client has gone, but we still
+                // save everything we've received.
+                Some(Err(err)) => {
+                    stream_result = Err(err);
+                    None
+                }
+                None => None,
+            };
+            let finished = chunk.is_none();
+            if let Some(chunk) = chunk {
+                buffer.extend_from_slice(&chunk);
+            }
+            let pending = staged.iter().sum::<usize>() + buffer.len();
+            let is_last = Some(file_info.offset + buffer.len()) == file_info.length;
+            // S3 doesn't allow parts less than 5MB, except the last one.
+            // So parts are uploaded only when they are large enough,
+            // or when all bytes of the upload were received.
+            if pending >= MIN_PART_SIZE || (finished && is_last && pending > 0) {
+                // Staged chunks are downloaded only once, right before
+                // they become a part, so every byte is transferred a constant
+                // number of times regardless of the chunk size.
+                let mut part = self.load_staged(&s3_path, parts.len() + 1, &staged).await?;
+                let received = buffer.len();
+                part.extend_from_slice(&buffer.split());
+                self.upload_part(file_info, &s3_path, &upload_id, &mut parts, part)
+                    .await?;
+                file_info.offset += received;
+                // Staged bytes are now a part of the upload.
+                staged.clear();
+                file_info.metadata.remove(STAGED_KEY);
+            }
+            if finished {
+                break;
+            }
+        }
+        // The rest of bytes is too small to become a part,
+        // so we keep it in a separate object until there's enough data.
+        if !buffer.is_empty() {
             self.bucket
-                .complete_multipart_upload(
-                    &s3_path,
-                    upload_id,
-                    parts.iter().cloned().map(Part::from).collect(),
+                .put_object(
+                    Self::get_staged_key(&s3_path, parts.len() + 1, staged.len()),
+                    &buffer,
                 )
                 .await?;
-            file_info.metadata.remove(PARTS_KEY);
-            file_info.metadata.remove(UPLOAD_ID_KEY);
-            return Ok(());
+            file_info.offset += buffer.len();
+            staged.push(buffer.len());
+            file_info
+                .metadata
+                .insert(STAGED_KEY.to_string(), serde_json::to_string(&staged)?);
         }
-        file_info
-            .metadata
-            .insert(PARTS_KEY.to_string(), serde_json::to_string(&parts)?);
-        Ok(())
+        if Some(file_info.offset) == file_info.length {
+            self.complete_upload(file_info, &s3_path, &upload_id, &parts)
+                .await?;
+            // Complete upload can't be rolled back,
+            // so staged bytes aren't needed anymore.
+            self.remove_staged_logged(file_info, &s3_path, staged_part, had_staged)
+                .await;
+        } else if had_staged > 0 && parts.len() + 1 != staged_part {
+            // Staged bytes were uploaded as a part, but the objects
+            // are kept until the info about this request is saved.
+            file_info
+                .metadata
+                .insert(STALE_KEY.to_string(), format!("{staged_part}:{had_staged}"));
+        }
+        stream_result
     }
 
     async fn create_file(&self, file_info: &mut FileInfo) -> crate::errors::RustusResult<String> {
@@ -208,94 +453,130 @@ impl DataStorage for S3DataStorage {
 
     async fn concat_files(
         &self,
-        file_info: &crate::file_info::FileInfo,
-        parts_info: Vec<crate::file_info::FileInfo>,
-    ) -> crate::errors::RustusResult<()> {
-        let dir = tempdir::TempDir::new(&file_info.id)?;
-        let mut download_futures = vec![];
-
-        // At first we need to download all parts.
-        for part_info in &parts_info {
-            let part_key = self.get_s3_key(&part_info.id, part_info.created_at);
-            let part_out = dir.path().join(&part_info.id);
-            // Here we create a future which downloads the part
-            // into a temporary file.
-            download_futures.push(async move {
-                let part_file = tokio::fs::File::create(&part_out).await?;
-                let mut writer = tokio::io::BufWriter::new(part_file);
-                let mut reader = self.bucket.get_object_stream(&part_key).await?;
-                while let Some(chunk) = reader.bytes().next().await {
-                    let mut chunk = chunk?;
-                    writer.write_all_buf(&mut chunk).await.map_err(|err| {
-                        log::error!("{:?}", err);
-                        RustusError::UnableToWrite(err.to_string())
-                    })?;
-                }
-                writer.flush().await?;
-                writer.get_ref().sync_data().await?;
-                Ok::<_, RustusError>(())
-            });
-        }
-        // Here we await all download futures.
-        // We use buffer_unordered to limit the number of concurrent downloads.
-        futures::stream::iter(download_futures)
-            // Number of concurrent downloads.
-            .buffer_unordered(self.concurrent_concat_downloads)
-            // We use try_collect to collect all results
-            // and return an error if any of the futures returned an error.
-            .try_collect::<Vec<_>>()
-            .await?;
-
-        let output_path = dir.path().join(&file_info.id);
-        let output_path_cloned = output_path.clone();
-        let parts_files = parts_info
+        file_info: &mut FileInfo,
+        parts_info: Vec<FileInfo>,
+    ) -> RustusResult<()> {
+        let key = self.get_s3_key(&file_info.id, file_info.created_at);
+        // Multipart upload is initiated during file creation,
+        // so it's reused for the final object.
+        let upload_id = match file_info.metadata.get(UPLOAD_ID_KEY) {
+            Some(upload_id) => upload_id.clone(),
+            None => {
+                self.bucket
+                    .initiate_multipart_upload(&key, file_info.get_mime_type().as_ref())
+                    .await?
+                    .upload_id
+            }
+        };
+        let sources = parts_info
             .iter()
-            .map(|info| dir.path().join(&info.id))
+            .map(|info| {
+                (
+                    self.get_s3_key(&info.id, info.created_at),
+                    info.length.unwrap_or(info.offset),
+                )
+            })
             .collect::<Vec<_>>();
-        tokio::task::spawn_blocking(move || {
-            let file = std::fs::OpenOptions::new()
-                .append(true)
-                .create(true)
-                .open(output_path_cloned)
-                .map_err(|err| {
-                    log::error!("{:?}", err);
-                    RustusError::UnableToWrite(err.to_string())
-                })?;
-            let mut writer = std::io::BufWriter::new(file);
-            for part in &parts_files {
-                let part_file = std::fs::OpenOptions::new().read(true).open(part)?;
-                let mut reader = std::io::BufReader::new(part_file);
-                std::io::copy(&mut reader, &mut writer)?;
-            }
-            writer.flush()?;
-            writer.get_ref().sync_data()?;
-            Ok::<_, RustusError>(())
-        })
-        .await??;
-
-        // We reopen the file to upload it to S3.
-        // This is needed because we need to open the file in read mode.
-        let output_file = tokio::fs::File::open(&output_path).await?;
-        let mut reader = tokio::io::BufReader::new(output_file);
-        let key = self.get_s3_key(&file_info.id, file_info.created_at);
-        self.bucket
-            .put_object_stream_with_content_type(&mut reader, key, file_info.get_mime_type())
+        let result = concat_objects(
+            &self.bucket,
+            &key,
+            &upload_id,
+            file_info.get_mime_type().as_ref(),
+            &sources,
+            self.concurrent_concat_downloads,
+        )
+        .await;
+        // Multipart upload is either completed or aborted,
+        // so it can't be used anymore.
+        file_info.metadata.remove(UPLOAD_ID_KEY);
+        file_info.metadata.remove(PARTS_KEY);
+        result
+    }
+
+    async fn truncate(&self, file_info: &mut FileInfo) -> RustusResult<bool> {
+        // Parts uploaded after the saved ones are replaced by next writes,
+        // and staged bytes after the saved lengths are ignored.
+        // So we only check that saved staged bytes are still there.
+        let staged = Self::staged_chunks(file_info)?;
+        if staged.is_empty() {
+            return Ok(false);
+        }
+        let s3_path = self.get_s3_key(&file_info.id, file_info.created_at);
+        let actual = self
+            .staged_lengths(&s3_path, Self::next_part_number(file_info)?, staged.len())
             .await?;
+        if actual
+            .iter()
+            .zip(&staged)
+            .any(|(actual, saved)| actual < saved)
+        {
+            return Err(RustusError::S3StagedDataCorrupted);
+        }
+        Ok(false)
+    }
 
-        tokio::fs::remove_file(output_path).await?;
+    async fn read_data(&self, file_info: &FileInfo) -> RustusResult<ByteStream> {
+        // Parts of a multipart upload can't be downloaded.
+        if Some(file_info.offset) != file_info.length {
+            return Err(RustusError::Unimplemented(String::from(
+                "Data of unfinished uploads can't be read from S3.",
+            )));
+        }
+        let s3_path = self.get_s3_key(&file_info.id, file_info.created_at);
+        let response = self.bucket.get_object_stream(s3_path).await?;
+        Ok(response.bytes.map_err(RustusError::from).boxed_local())
+    }
 
-        Ok(())
+    async fn get_length(&self, file_info: &FileInfo) -> RustusResult<Option<usize>> {
+        let s3_path = self.get_s3_key(&file_info.id, file_info.created_at);
+        if Some(file_info.offset) == file_info.length {
+            return match self.bucket.head_object(s3_path).await {
+                Ok((head, _)) => Ok(Some(usize::try_from(
+                    head.content_length.unwrap_or_default(),
+                )?)),
+                Err(S3Error::HttpFailWithBody(404, _)) => Ok(None),
+                Err(err) => Err(err.into()),
+            };
+        }
+        // Sizes of uploaded parts can't be checked without listing them,
+        // so they are taken from the offset and only staged bytes are checked.
+        let staged = Self::staged_chunks(file_info)?;
+        if staged.is_empty() {
+            return Ok(Some(file_info.offset));
+        }
+        let actual = self
+            .staged_lengths(&s3_path, Self::next_part_number(file_info)?, staged.len())
+            .await?;
+        Ok(Some(
+            file_info.offset.saturating_sub(staged.iter().sum()) + actual.iter().sum::<usize>(),
+        ))
     }
 
     async fn remove_file(
         &self,
         file_info: &crate::file_info::FileInfo,
     ) -> crate::errors::RustusResult<()> {
-        if Some(file_info.offset) == file_info.length {
-            self.bucket
-                .delete_object(self.get_s3_key(&file_info.id, file_info.created_at))
+        let s3_path = self.get_s3_key(&file_info.id, file_info.created_at);
+        let staged = Self::staged_chunks(file_info)?;
+        self.remove_staged(&s3_path, Self::next_part_number(file_info)?, staged.len())
+            .await?;
+        if let Some(stale) = file_info.metadata.get(STALE_KEY) {
+            let (stale_part, stale_chunks) = Self::parse_stale(stale)?;
+            self.remove_staged(&s3_path, stale_part, stale_chunks)
                 .await?;
         }
+        // Upload is not complete, so the object doesn't exist yet.
+        // Aborting multipart upload removes all uploaded parts.
+        if let Some(upload_id) = file_info.metadata.get(UPLOAD_ID_KEY) {
+            match self.bucket.abort_upload(&s3_path, upload_id).await {
+                // Upload was already aborted or completed.
+                Ok(()) | Err(S3Error::HttpFailWithBody(404, _)) => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+        if Some(file_info.offset) == file_info.length {
+            self.bucket.delete_object(s3_path).await?;
+        }
         Ok(())
     }
 }
@@ -306,19 +587,21 @@ mod test {
 
     use s3::error::S3Error;
 
-    use crate::data_storage::base::DataStorage;
+    use crate::data_storage::base::{bytes_stream, DataStorage};
 
-    use super::S3DataStorage;
+    use super::{S3DataStorage, STAGED_KEY, UPLOAD_ID_KEY};
 
     fn get_s3_storage() -> S3DataStorage {
-        let endpoint =
-            std::env::var("TEST_S3_ENDPOINT").unwrap_or(String::from("http://localhost:9000"));
-        let region = std::env::var("TEST_S3_REGION").unwrap_or(String::from("eu-west-1"));
-        let access_key = std::env::var("TEST_S3_ACCESS_KEY").unwrap_or(String::from("rustus-test"));
-        let secret_key = std::env::var("TEST_S3_SECRET_KEY").unwrap_or(String::from("rustus-test"));
-        let bucket = std::env::var("TEST_S3_BUCKET").unwrap_or(String::from("rustus"));
+        let endpoint = std::env::var("TEST_S3_ENDPOINT")
+            .unwrap_or_else(|_| String::from("http://localhost:9000"));
+        let region = std::env::var("TEST_S3_REGION").unwrap_or_else(|_| String::from("eu-west-1"));
+        let access_key =
+            std::env::var("TEST_S3_ACCESS_KEY").unwrap_or_else(|_| String::from("rustus-test"));
+        let secret_key =
+            std::env::var("TEST_S3_SECRET_KEY").unwrap_or_else(|_| String::from("rustus-test"));
+        let bucket = std::env::var("TEST_S3_BUCKET").unwrap_or_else(|_| String::from("rustus"));
         let path_style = env::var("TEST_S3_FORCE_PATH_STYLE")
-            .unwrap_or(String::from("true"))
+            .unwrap_or_else(|_| String::from("true"))
             .parse()
             .unwrap();
         S3DataStorage::new(
@@ -332,15 +615,16 @@ mod test {
             None,
             &bucket,
             path_style,
-            "".to_string(),
+            String::new(),
             4,
+            None,
         )
     }
 
     #[actix_rt::test]
     async fn test_successfull_create_upload() {
         let storage = get_s3_storage();
-        let data = "Hello World".as_bytes();
+        let data: &[u8] = b"Hello World";
         let mut file_info = crate::file_info::FileInfo::new(
             &uuid::Uuid::new_v4().to_string(),
             Some(data.len()),
@@ -368,7 +652,47 @@ mod test {
     #[actix_rt::test]
     async fn test_successfull_upload() {
         let storage = get_s3_storage();
-        let data = "Hello World".as_bytes();
+        let data: &[u8] = b"Hello World";
+        let mut file_info = crate::file_info::FileInfo::new(
+            &uuid::Uuid::new_v4().to_string(),
+            Some(data.len()),
+            None,
+            storage.get_name().to_string(),
+            None,
+        );
+        let s3_path = storage.create_file(&mut file_info).await.unwrap();
+        storage
+            .add_bytes(&mut file_info, bytes_stream(data))
+            .await
+            .unwrap();
+        let object = storage.bucket.get_object(s3_path).await.unwrap();
+        assert_eq!(object.bytes(), data);
+    }
+
+    #[actix_rt::test]
+    async fn test_empty_upload() {
+        let storage = get_s3_storage();
+        let mut file_info = crate::file_info::FileInfo::new(
+            &uuid::Uuid::new_v4().to_string(),
+            Some(0),
+            None,
+            storage.get_name().to_string(),
+            None,
+        );
+        let s3_path = storage.create_file(&mut file_info).await.unwrap();
+        storage
+            .add_bytes(&mut file_info, bytes_stream(""))
+            .await
+            .unwrap();
+        assert!(!file_info.metadata.contains_key(UPLOAD_ID_KEY));
+        let object = storage.bucket.get_object(s3_path).await.unwrap();
+        assert!(object.bytes().is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn test_successfull_small_chunks() {
+        let storage = get_s3_storage();
+        let data: &[u8] = b"Hello World";
         let mut file_info = crate::file_info::FileInfo::new(
             &uuid::Uuid::new_v4().to_string(),
             Some(data.len()),
@@ -378,17 +702,92 @@ mod test {
         );
         let s3_path = storage.create_file(&mut file_info).await.unwrap();
         storage
-            .add_bytes(&mut file_info, data.into())
+            .add_bytes(&mut file_info, bytes_stream(&data[..5]))
+            .await
+            .unwrap();
+        assert_eq!(file_info.offset, 5);
+        assert_eq!(
+            file_info.metadata.get(STAGED_KEY).map(String::as_str),
+            Some("[5]")
+        );
+        storage
+            .add_bytes(&mut file_info, bytes_stream(&data[5..]))
             .await
             .unwrap();
+        assert_eq!(file_info.offset, data.len());
+        assert!(!file_info.metadata.contains_key(STAGED_KEY));
+        let object = storage.bucket.get_object(s3_path).await.unwrap();
+        assert_eq!(object.bytes(), data);
+    }
+
+    #[actix_rt::test]
+    async fn test_staged_chunks() {
+        let storage = get_s3_storage();
+        let data: &[u8] = b"Hello World";
+        let mut file_info = crate::file_info::FileInfo::new(
+            &uuid::Uuid::new_v4().to_string(),
+            Some(data.len()),
+            None,
+            storage.get_name().to_string(),
+            None,
+        );
+        let s3_path = storage.create_file(&mut file_info).await.unwrap();
+        for chunk in data.chunks(4) {
+            storage
+                .add_bytes(&mut file_info, bytes_stream(chunk))
+                .await
+                .unwrap();
+        }
+        // Staged chunks are removed after the upload is complete.
+        for index in 0..2 {
+            let resp = storage
+                .bucket
+                .get_object(S3DataStorage::get_staged_key(&s3_path, 1, index))
+                .await
+                .unwrap_err();
+            match resp {
+                S3Error::HttpFailWithBody(404, _) => {}
+                _ => panic!("Unexpected error: {resp}"),
+            }
+        }
         let object = storage.bucket.get_object(s3_path).await.unwrap();
         assert_eq!(object.bytes(), data);
     }
 
+    #[actix_rt::test]
+    async fn test_range_download() {
+        let storage = get_s3_storage();
+        let data: &[u8] = b"Hello World";
+        let mut file_info = crate::file_info::FileInfo::new(
+            &uuid::Uuid::new_v4().to_string(),
+            Some(data.len()),
+            None,
+            storage.get_name().to_string(),
+            None,
+        );
+        storage.create_file(&mut file_info).await.unwrap();
+        storage
+            .add_bytes(&mut file_info, bytes_stream(data))
+            .await
+            .unwrap();
+        let request = actix_web::test::TestRequest::get()
+            .insert_header(("Range", "bytes=6-"))
+            .to_http_request();
+        let resp = storage.get_contents(&file_info, &request).await.unwrap();
+        assert_eq!(resp.status(), actix_web::http::StatusCode::PARTIAL_CONTENT);
+        assert_eq!(
+            resp.headers().get("Content-Range").unwrap(),
+            "bytes 6-10/11"
+        );
+        assert!(resp.headers().contains_key("ETag"));
+        let body = actix_web::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(body.as_ref(), b"World");
+    }
+
     #[actix_rt::test]
     async fn test_successfull_delete() {
         let storage = get_s3_storage();
-        let data = "Hello World".as_bytes();
+        let data: &[u8] = b"Hello World";
         let mut file_info = crate::file_info::FileInfo::new(
             &uuid::Uuid::new_v4().to_string(),
             Some(data.len()),
@@ -398,10 +797,9 @@ mod test {
         );
         let s3_path = storage.create_file(&mut file_info).await.unwrap();
         storage
-            .add_bytes(&mut file_info, data.into())
+            .add_bytes(&mut file_info, bytes_stream(data))
             .await
             .unwrap();
-        file_info.offset += data.len();
         let object = storage.bucket.get_object(s3_path.clone()).await.unwrap();
         assert_eq!(object.bytes(), data);
         storage.remove_file(&file_info).await.unwrap();
@@ -412,10 +810,46 @@ mod test {
         }
     }
 
+    #[actix_rt::test]
+    async fn test_successfull_abort() {
+        let storage = get_s3_storage();
+        let data: &[u8] = b"Hello World";
+        let mut file_info = crate::file_info::FileInfo::new(
+            &uuid::Uuid::new_v4().to_string(),
+            Some(data.len() * 2),
+            None,
+            storage.get_name().to_string(),
+            None,
+        );
+        let s3_path = storage.create_file(&mut file_info).await.unwrap();
+        storage
+            .add_bytes(&mut file_info, bytes_stream(data))
+            .await
+            .unwrap();
+        storage.remove_file(&file_info).await.unwrap();
+        let ups = storage
+            .bucket
+            .list_multiparts_uploads(Some(&s3_path), None)
+            .await
+            .unwrap();
+        assert!(ups.iter().all(|page| page.uploads.is_empty()));
+        let resp = storage
+            .bucket
+            .get_object(S3DataStorage::get_staged_key(&s3_path, 1, 0))
+            .await
+            .unwrap_err();
+        match resp {
+            S3Error::HttpFailWithBody(404, _) => {}
+            _ => panic!("Unexpected error: {resp}"),
+        }
+        // Staged data and multipart upload are already gone.
+        storage.remove_file(&file_info).await.unwrap();
+    }
+
     #[actix_rt::test]
     async fn test_successfull_mime() {
         let storage = get_s3_storage();
-        let data = "Helloworld of videos!".as_bytes();
+        let data: &[u8] = b"Helloworld of videos!";
         let mut file_info = crate::file_info::FileInfo::new(
             &uuid::Uuid::new_v4().to_string(),
             Some(data.len()),
@@ -428,7 +862,7 @@ mod test {
             .insert(String::from("filename"), String::from("meme.mp4"));
         let s3_path = storage.create_file(&mut file_info).await.unwrap();
         storage
-            .add_bytes(&mut file_info, data.into())
+            .add_bytes(&mut file_info, bytes_stream(data))
             .await
             .unwrap();
         let object = storage.bucket.get_object(s3_path).await.unwrap();
@@ -436,14 +870,14 @@ mod test {
         assert_eq!(
             object.headers().get("content-type"),
             Some(&String::from("video/mp4"))
-        )
+        );
     }
 
     #[actix_rt::test]
     async fn test_successfull_concat() {
         let storage = get_s3_storage();
 
-        let fst_data = "Hello".as_bytes();
+        let fst_data: &[u8] = b"Hello";
         let mut fst_file_info = crate::file_info::FileInfo::new(
             &uuid::Uuid::new_v4().to_string(),
             Some(fst_data.len()),
@@ -452,7 +886,7 @@ mod test {
             None,
         );
         fst_file_info.is_partial = true;
-        let snd_data = "World".as_bytes();
+        let snd_data: &[u8] = b"World";
         let mut snd_file_info = crate::file_info::FileInfo::new(
             &uuid::Uuid::new_v4().to_string(),
             Some(snd_data.len()),
@@ -464,11 +898,11 @@ mod test {
         let fst_s3_path = storage.create_file(&mut fst_file_info).await.unwrap();
         let snd_s3_path = storage.create_file(&mut snd_file_info).await.unwrap();
         storage
-            .add_bytes(&mut fst_file_info, fst_data.into())
+            .add_bytes(&mut fst_file_info, bytes_stream(fst_data))
             .await
             .unwrap();
         storage
-            .add_bytes(&mut snd_file_info, snd_data.into())
+            .add_bytes(&mut snd_file_info, bytes_stream(snd_data))
             .await
             .unwrap();
 
@@ -489,7 +923,7 @@ mod test {
             .metadata
             .insert("filename".into(), "test.mp4".into());
         storage
-            .concat_files(&final_file_info, vec![fst_file_info, snd_file_info])
+            .concat_files(&mut final_file_info, vec![fst_file_info, snd_file_info])
             .await
             .unwrap();
         let final_s3_path = storage.get_s3_key(&final_file_info.id, final_file_info.created_at);
@@ -499,4 +933,73 @@ mod test {
         assert_eq!(content_type, "video/mp4");
         assert_eq!(object.bytes(), b"HelloWorld".as_slice());
     }
+
+    #[actix_rt::test]
+    async fn test_failed_concat() {
+        let storage = get_s3_storage();
+        let mut part_info = crate::file_info::FileInfo::new(
+            &uuid::Uuid::new_v4().to_string(),
+            Some(5),
+            None,
+            storage.get_name().to_string(),
+            None,
+        );
+        part_info.is_partial = true;
+        // Data of the part was never uploaded.
+        part_info.offset = 5;
+        let mut final_file_info = crate::file_info::FileInfo::new(
+            &uuid::Uuid::new_v4().to_string(),
+            Some(5),
+            None,
+            storage.get_name().to_string(),
+            None,
+        );
+        final_file_info.is_final = true;
+        let s3_path = storage.create_file(&mut final_file_info).await.unwrap();
+        assert!(final_file_info.metadata.contains_key(UPLOAD_ID_KEY));
+        storage
+            .concat_files(&mut final_file_info, vec![part_info])
+            .await
+            .unwrap_err();
+        assert!(!final_file_info.metadata.contains_key(UPLOAD_ID_KEY));
+        let ups = storage
+            .bucket
+            .list_multiparts_uploads(Some(&s3_path), None)
+            .await
+            .unwrap();
+        assert!(ups.iter().all(|page| page.uploads.is_empty()));
+        // Aborted upload doesn't need any cleanup.
+        storage.remove_file(&final_file_info).await.unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn test_truncate_staged() {
+        let storage = get_s3_storage();
+        let data: &[u8] = b"Hello World";
+        let mut file_info = crate::file_info::FileInfo::new(
+            &uuid::Uuid::new_v4().to_string(),
+            Some(data.len()),
+            None,
+            storage.get_name().to_string(),
+            None,
+        );
+        let s3_path = storage.create_file(&mut file_info).await.unwrap();
+        storage
+            .add_bytes(&mut file_info, bytes_stream(&data[..5]))
+            .await
+            .unwrap();
+        let mut saved_info = file_info.clone();
+        // Info about these bytes is lost.
+        storage
+            .add_bytes(&mut file_info, bytes_stream("Lost"))
+            .await
+            .unwrap();
+        assert!(!storage.truncate(&mut saved_info).await.unwrap());
+        storage
+            .add_bytes(&mut saved_info, bytes_stream(&data[5..]))
+            .await
+            .unwrap();
+        let object = storage.bucket.get_object(s3_path).await.unwrap();
+        assert_eq!(object.bytes(), data);
+    }
 }
@@ -1,13 +1,24 @@
-use std::{fs::File, io::Read, path::PathBuf};
+use std::{
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+};
 
 use base::DataStorage;
 use std::str::FromStr;
 use strum::IntoEnumIterator;
 
-use crate::{config::RustusConf, file_info::FileInfo, from_str};
+use crate::{
+    config::RustusConf,
+    errors::{RustusError, RustusResult},
+    file_info::FileInfo,
+    from_str,
+};
 
 pub mod base;
 pub mod impls;
+pub mod registry;
+pub mod routing;
 
 /// Enum of available Storage implementations.
 #[derive(PartialEq, Eq, strum::Display, strum::EnumIter, Clone, Debug)]
@@ -29,6 +40,21 @@ pub enum DataStorageImpl {
     S3(impls::s3_storage::S3DataStorage),
 }
 
+impl DataStorageImpl {
+    /// Directory where the storage keeps files locally.
+    ///
+    /// Returns `None` if all data is kept elsewhere.
+    pub fn local_dir(&self) -> Option<&Path> {
+        match self {
+            Self::File(file_data_storage) => Some(file_data_storage.data_dir()),
+            Self::S3Hybrid(s3_hybrid_data_storage) => {
+                Some(s3_hybrid_data_storage.local_storage().data_dir())
+            }
+            Self::S3(_) => None,
+        }
+    }
+}
+
 impl AvailableDataStorages {
     /// Convert `AvailableStores` to the Storage.
     ///
@@ -36,9 +62,10 @@ impl AvailableDataStorages {
     /// `config` - Rustus configuration.
     /// `info_storage` - Storage for information about files.
     ///
-    pub fn get(&self, config: &RustusConf) -> DataStorageImpl {
+    /// Returns an error if required S3 options are missing.
+    pub fn get(&self, config: &RustusConf) -> RustusResult<DataStorageImpl> {
         #[allow(clippy::single_match)]
-        match self {
+        Ok(match self {
             Self::FileStorage => DataStorageImpl::File(impls::file_storage::FileDataStorage::new(
                 config.storage_opts.data_dir.clone(),
                 config.storage_opts.dir_structure.clone(),
@@ -55,20 +82,25 @@ impl AvailableDataStorages {
                     config.storage_opts.s3_secret_key_path.as_ref(),
                 );
                 DataStorageImpl::S3Hybrid(impls::s3_hybrid::S3HybridDataStorage::new(
-                    config.storage_opts.s3_url.clone().unwrap(),
-                    config.storage_opts.s3_region.clone().unwrap(),
+                    required_s3_option(config.storage_opts.s3_url.as_ref(), "s3-url")?,
+                    required_s3_option(config.storage_opts.s3_region.as_ref(), "s3-region")?,
                     Some(&access_key),
                     Some(&secret_key),
                     config.storage_opts.s3_security_token.as_ref(),
                     config.storage_opts.s3_session_token.as_ref(),
                     config.storage_opts.s3_profile.as_ref(),
                     config.storage_opts.s3_headers.as_ref(),
-                    config.storage_opts.s3_bucket.clone().unwrap().as_str(),
+                    required_s3_option(config.storage_opts.s3_bucket.as_ref(), "s3-bucket")?
+                        .as_str(),
                     config.storage_opts.s3_force_path_style,
                     config.storage_opts.data_dir.clone(),
                     config.storage_opts.dir_structure.clone(),
                     config.storage_opts.force_fsync,
                     config.storage_opts.s3_concat_concurrent_downloads,
+                    config
+                        .storage_opts
+                        .s3_presigned_downloads
+                        .then_some(config.storage_opts.s3_presigned_expiration),
                 ))
             }
             Self::S3 => {
@@ -81,29 +113,41 @@ impl AvailableDataStorages {
                     config.storage_opts.s3_secret_key_path.as_ref(),
                 );
                 DataStorageImpl::S3(impls::s3_storage::S3DataStorage::new(
-                    config.storage_opts.s3_url.clone().unwrap(),
-                    config.storage_opts.s3_region.clone().unwrap(),
+                    required_s3_option(config.storage_opts.s3_url.as_ref(), "s3-url")?,
+                    required_s3_option(config.storage_opts.s3_region.as_ref(), "s3-region")?,
                     Some(&access_key),
                     Some(&secret_key),
                     config.storage_opts.s3_security_token.as_ref(),
                     config.storage_opts.s3_session_token.as_ref(),
                     config.storage_opts.s3_profile.as_ref(),
                     config.storage_opts.s3_headers.as_ref(),
-                    config.storage_opts.s3_bucket.clone().unwrap().as_str(),
+                    required_s3_option(config.storage_opts.s3_bucket.as_ref(), "s3-bucket")?
+                        .as_str(),
                     config.storage_opts.s3_force_path_style,
                     config.storage_opts.dir_structure.clone(),
                     config.storage_opts.s3_concat_concurrent_downloads,
+                    config
+                        .storage_opts
+                        .s3_presigned_downloads
+                        .then_some(config.storage_opts.s3_presigned_expiration),
                 ))
             }
-        }
+        })
     }
 }
 
+/// Get S3 option which is required by S3 storages.
+fn required_s3_option(value: Option<&String>, name: &str) -> RustusResult<String> {
+    value.cloned().ok_or_else(|| {
+        RustusError::UnableToPrepareStorage(format!("--{name} is required for S3 storages."))
+    })
+}
+
 // TODO this should probably be a COW
 fn from_string_or_path(variable: Option<&String>, path: Option<&PathBuf>) -> String {
     #[allow(clippy::option_if_let_else)]
     if let Some(variable) = variable {
-        variable.to_string()
+        variable.clone()
     } else if let Some(path) = path {
         let file =
             File::open(path).unwrap_or_else(|_| panic!("failed to open path {}", path.display()));
@@ -155,14 +199,14 @@ impl DataStorage for DataStorageImpl {
     async fn add_bytes(
         &self,
         file_info: &mut FileInfo,
-        bytes: bytes::Bytes,
+        stream: base::ByteStream,
     ) -> crate::errors::RustusResult<()> {
         match self {
-            Self::File(file_data_storage) => file_data_storage.add_bytes(file_info, bytes).await,
+            Self::File(file_data_storage) => file_data_storage.add_bytes(file_info, stream).await,
             Self::S3Hybrid(s3_hybrid_data_storage) => {
-                s3_hybrid_data_storage.add_bytes(file_info, bytes).await
+                s3_hybrid_data_storage.add_bytes(file_info, stream).await
             }
-            Self::S3(s3_data_storage) => s3_data_storage.add_bytes(file_info, bytes).await,
+            Self::S3(s3_data_storage) => s3_data_storage.add_bytes(file_info, stream).await,
         }
     }
 
@@ -178,7 +222,7 @@ impl DataStorage for DataStorageImpl {
 
     async fn concat_files(
         &self,
-        file_info: &FileInfo,
+        file_info: &mut FileInfo,
         parts_info: Vec<FileInfo>,
     ) -> crate::errors::RustusResult<()> {
         match self {
@@ -194,6 +238,39 @@ impl DataStorage for DataStorageImpl {
         }
     }
 
+    async fn truncate(&self, file_info: &mut FileInfo) -> crate::errors::RustusResult<bool> {
+        match self {
+            Self::File(file_data_storage) => file_data_storage.truncate(file_info).await,
+            Self::S3Hybrid(s3_hybrid_data_storage) => {
+                s3_hybrid_data_storage.truncate(file_info).await
+            }
+            Self::S3(s3_data_storage) => s3_data_storage.truncate(file_info).await,
+        }
+    }
+
+    async fn get_length(&self, file_info: &FileInfo) -> crate::errors::RustusResult<Option<usize>> {
+        match self {
+            Self::File(file_data_storage) => file_data_storage.get_length(file_info).await,
+            Self::S3Hybrid(s3_hybrid_data_storage) => {
+                s3_hybrid_data_storage.get_length(file_info).await
+            }
+            Self::S3(s3_data_storage) => s3_data_storage.get_length(file_info).await,
+        }
+    }
+
+    async fn read_data(
+        &self,
+        file_info: &FileInfo,
+    ) -> crate::errors::RustusResult<base::ByteStream> {
+        match self {
+            Self::File(file_data_storage) => file_data_storage.read_data(file_info).await,
+            Self::S3Hybrid(s3_hybrid_data_storage) => {
+                s3_hybrid_data_storage.read_data(file_info).await
+            }
+            Self::S3(s3_data_storage) => s3_data_storage.read_data(file_info).await,
+        }
+    }
+
     async fn remove_file(&self, file_info: &FileInfo) -> crate::errors::RustusResult<()> {
         match self {
             Self::File(file_data_storage) => file_data_storage.remove_file(file_info).await,
@@ -0,0 +1,181 @@
+use std::collections::HashMap;
+
+use actix_web::HttpRequest;
+
+use crate::{
+    config::RustusConf,
+    errors::{RustusError, RustusResult},
+    file_info::FileInfo,
+};
+
+use super::{base::DataStorage, routing::StorageRule, DataStorageImpl};
+
+/// All data storages Rustus works with.
+///
+/// New uploads are created in the default storage,
+/// unless one of the rules chooses a named storage.
+/// Other storages are used to serve uploads
+/// which were created before the default storage was changed.
+#[derive(Clone, Debug)]
+pub struct DataStorageRegistry {
+    default_name: String,
+    storages: HashMap<String, DataStorageImpl>,
+    rules: Vec<StorageRule>,
+}
+
+impl DataStorageRegistry {
+    pub fn new(default: DataStorageImpl) -> Self {
+        let default_name = default.get_name().to_string();
+        Self {
+            storages: HashMap::from([(default_name.clone(), default)]),
+            default_name,
+            rules: Vec::new(),
+        }
+    }
+
+    /// Create storages from the configuration.
+    ///
+    /// Storages must be prepared before use.
+    pub fn from_config(config: &RustusConf) -> RustusResult<Self> {
+        let mut registry = Self::new(config.storage_opts.storage.get(config)?);
+        for storage in &config.storage_opts.legacy_storages {
+            registry.add(storage.get(config)?)?;
+        }
+        if let Some(named_storages) = &config.storage_opts.named_storages {
+            for (name, storage) in &named_storages.0 {
+                registry.add_named(name, storage.get(config)?)?;
+            }
+        }
+        if let Some(rules) = &config.storage_opts.storage_rules {
+            for rule in &rules.0 {
+                registry.add_rule(rule.clone())?;
+            }
+        }
+        Ok(registry)
+    }
+
+    /// Add a storage for existing uploads.
+    ///
+    /// Storages are identified by their names,
+    /// so two storages with the same name can't be added.
+    pub fn add(&mut self, storage: DataStorageImpl) -> RustusResult<()> {
+        self.add_named(storage.get_name(), storage)
+    }
+
+    /// Add a storage with custom name.
+    ///
+    /// New uploads are created in such storages
+    /// only if some rule chooses them.
+    pub fn add_named(&mut self, name: &str, storage: DataStorageImpl) -> RustusResult<()> {
+        if self.storages.contains_key(name) {
+            return Err(RustusError::UnableToPrepareStorage(format!(
+                "Storage {name} is configured twice."
+            )));
+        }
+        self.storages.insert(name.to_string(), storage);
+        Ok(())
+    }
+
+    /// Add a rule to choose storage for new uploads.
+    ///
+    /// Rules are checked in the same order as they were added.
+    pub fn add_rule(&mut self, rule: StorageRule) -> RustusResult<()> {
+        if !self.storages.contains_key(&rule.storage) {
+            return Err(RustusError::UnableToPrepareStorage(format!(
+                "Storage rule uses unknown storage {}.",
+                rule.storage
+            )));
+        }
+        self.rules.push(rule);
+        Ok(())
+    }
+
+    pub async fn prepare(&mut self) -> RustusResult<()> {
+        for storage in self.storages.values_mut() {
+            storage.prepare().await?;
+        }
+        Ok(())
+    }
+
+    /// Storage for new uploads.
+    pub fn default_storage(&self) -> &DataStorageImpl {
+        &self.storages[&self.default_name]
+    }
+
+    /// Choose storage for a new upload.
+    ///
+    /// Returns the storage of the first matching rule,
+    /// or the default one. Its name must be saved
+    /// in the information about the upload.
+    pub fn for_new_upload(
+        &self,
+        request: &HttpRequest,
+        file_info: &FileInfo,
+    ) -> (&str, &DataStorageImpl) {
+        let name = self
+            .rules
+            .iter()
+            .find(|rule| rule.matches(request, file_info))
+            .map_or(self.default_name.as_str(), |rule| rule.storage.as_str());
+        (name, &self.storages[name])
+    }
+
+    pub fn get(&self, name: &str) -> Option<&DataStorageImpl> {
+        self.storages.get(name)
+    }
+
+    /// Find the storage where data of the upload is kept.
+    ///
+    /// Returns `FileNotFound` if this storage isn't configured.
+    pub fn for_upload(&self, file_info: &FileInfo) -> RustusResult<&DataStorageImpl> {
+        self.get(file_info.storage.as_str())
+            .ok_or(RustusError::FileNotFound)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &DataStorageImpl> {
+        self.storages.values()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::DataStorageRegistry;
+    use crate::{
+        data_storage::{base::DataStorage, impls::file_storage::FileDataStorage, DataStorageImpl},
+        errors::RustusError,
+        State,
+    };
+
+    /// Storage that is never written to.
+    fn file_storage() -> DataStorageImpl {
+        DataStorageImpl::File(FileDataStorage::new(
+            PathBuf::from("data"),
+            String::new(),
+            false,
+        ))
+    }
+
+    #[actix_rt::test]
+    async fn for_upload() {
+        let state = State::test_new().await;
+        let mut file_info = state.create_test_file().await;
+        let registry = DataStorageRegistry::new(file_storage());
+        assert_eq!(
+            registry.for_upload(&file_info).unwrap().get_name(),
+            file_info.storage
+        );
+        file_info.storage = String::from("unknown");
+        assert!(matches!(
+            registry.for_upload(&file_info),
+            Err(RustusError::FileNotFound)
+        ));
+    }
+
+    #[test]
+    fn duplicate_storage() {
+        let mut registry = DataStorageRegistry::new(file_storage());
+        assert!(registry.add(file_storage()).is_err());
+    }
+}
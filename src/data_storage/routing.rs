@@ -0,0 +1,210 @@
+use std::{collections::HashMap, path::PathBuf, str::FromStr};
+
+use actix_web::HttpRequest;
+use serde::{Deserialize, Deserializer};
+
+use crate::{
+    config::RustusConf,
+    errors::{RustusError, RustusResult},
+    file_info::FileInfo,
+};
+
+use super::{AvailableDataStorages, DataStorageImpl};
+
+/// Data storage with its own options.
+///
+/// Options which aren't set are taken
+/// from the main storage configuration.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NamedStorage {
+    #[serde(deserialize_with = "deserialize_storage")]
+    pub storage: AvailableDataStorages,
+    pub data_dir: Option<PathBuf>,
+    pub dir_structure: Option<String>,
+    pub s3_bucket: Option<String>,
+}
+
+fn deserialize_storage<'de, D>(deserializer: D) -> Result<AvailableDataStorages, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let storage = String::deserialize(deserializer)?;
+    AvailableDataStorages::from_str(&storage).map_err(serde::de::Error::custom)
+}
+
+impl NamedStorage {
+    /// Create the storage.
+    ///
+    /// # Params
+    /// `config` - Rustus configuration with options for the main storage.
+    pub fn get(&self, config: &RustusConf) -> RustusResult<DataStorageImpl> {
+        let mut storage_config = config.clone();
+        self.apply(&mut storage_config);
+        storage_config.storage_opts.storage.get(&storage_config)
+    }
+
+    /// Make this storage the main one in the configuration.
+    pub fn apply(&self, config: &mut RustusConf) {
+        let storage_opts = &mut config.storage_opts;
+        storage_opts.storage = self.storage.clone();
+        if let Some(data_dir) = &self.data_dir {
+            storage_opts.data_dir.clone_from(data_dir);
+        }
+        if let Some(dir_structure) = &self.dir_structure {
+            storage_opts.dir_structure.clone_from(dir_structure);
+        }
+        if let Some(bucket) = &self.s3_bucket {
+            storage_opts.s3_bucket = Some(bucket.clone());
+        }
+    }
+}
+
+/// Named storages from the configuration.
+///
+/// They are passed as JSON object where keys are names
+/// and values are options of storages.
+#[derive(Debug, Clone, Default)]
+pub struct NamedStorages(pub HashMap<String, NamedStorage>);
+
+impl FromStr for NamedStorages {
+    type Err = RustusError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_json::from_str(s).map(Self).map_err(|err| {
+            RustusError::UnableToPrepareStorage(format!("Cannot parse named storages: {err}"))
+        })
+    }
+}
+
+/// Rule to choose a storage for new uploads.
+///
+/// All conditions of the rule must match. Rules without
+/// conditions match any upload.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StorageRule {
+    /// Name of the storage.
+    pub storage: String,
+    /// Metadata values of the upload.
+    #[serde(default)]
+    pub metadata: HashMap<String, String>,
+    /// Values of request headers.
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// Prefix of the request path.
+    pub path_prefix: Option<String>,
+    /// Minimal length of the upload.
+    pub min_size: Option<usize>,
+    /// Maximal length of the upload.
+    pub max_size: Option<usize>,
+}
+
+impl StorageRule {
+    /// Check that the rule matches the new upload.
+    ///
+    /// Size conditions never match uploads with deferred length.
+    pub fn matches(&self, request: &HttpRequest, file_info: &FileInfo) -> bool {
+        let metadata_matches = self
+            .metadata
+            .iter()
+            .all(|(key, value)| file_info.metadata.get(key) == Some(value));
+        let headers_match = self.headers.iter().all(|(name, value)| {
+            request
+                .headers()
+                .get(name)
+                .and_then(|header| header.to_str().ok())
+                == Some(value.as_str())
+        });
+        let path_matches = self
+            .path_prefix
+            .as_ref()
+            .is_none_or(|prefix| request.path().starts_with(prefix.as_str()));
+        let size_matches = match (self.min_size, self.max_size) {
+            (None, None) => true,
+            (min_size, max_size) => file_info.length.is_some_and(|length| {
+                min_size.is_none_or(|min_size| length >= min_size)
+                    && max_size.is_none_or(|max_size| length <= max_size)
+            }),
+        };
+        metadata_matches && headers_match && path_matches && size_matches
+    }
+}
+
+/// Rules from the configuration.
+///
+/// They are passed as JSON array and checked in order.
+#[derive(Debug, Clone, Default)]
+pub struct StorageRules(pub Vec<StorageRule>);
+
+impl FromStr for StorageRules {
+    type Err = RustusError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_json::from_str(s).map(Self).map_err(|err| {
+            RustusError::UnableToPrepareStorage(format!("Cannot parse storage rules: {err}"))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, str::FromStr};
+
+    use actix_web::test::TestRequest;
+
+    use super::{NamedStorages, StorageRules};
+    use crate::{data_storage::AvailableDataStorages, file_info::FileInfo};
+
+    fn upload(length: Option<usize>, metadata: &[(&str, &str)]) -> FileInfo {
+        let metadata = metadata
+            .iter()
+            .map(|(key, value)| ((*key).to_string(), (*value).to_string()))
+            .collect::<HashMap<_, _>>();
+        FileInfo::new("id", length, None, String::new(), Some(metadata))
+    }
+
+    #[test]
+    fn parse_storages() {
+        let storages =
+            NamedStorages::from_str(r#"{"videos": {"storage": "s3", "s3_bucket": "videos"}}"#)
+                .unwrap();
+        let videos = &storages.0["videos"];
+        assert_eq!(videos.storage, AvailableDataStorages::S3);
+        assert_eq!(videos.s3_bucket.as_deref(), Some("videos"));
+        assert!(NamedStorages::from_str(r#"{"videos": {"storage": "ftp"}}"#).is_err());
+        assert!(
+            NamedStorages::from_str(r#"{"videos": {"storage": "s3", "bucket": "videos"}}"#)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn matches() {
+        let rules = StorageRules::from_str(
+            r#"[
+                {"storage": "videos", "metadata": {"type": "video"}, "min_size": 10},
+                {"storage": "tenant", "headers": {"X-Tenant": "memes"}, "path_prefix": "/files"}
+            ]"#,
+        )
+        .unwrap();
+        let request = TestRequest::post()
+            .uri("/files/")
+            .insert_header(("X-Tenant", "memes"))
+            .to_http_request();
+        let video = [("type", "video")];
+
+        assert!(rules.0[0].matches(&request, &upload(Some(10), &video)));
+        assert!(!rules.0[0].matches(&request, &upload(Some(9), &video)));
+        assert!(!rules.0[0].matches(&request, &upload(None, &video)));
+        assert!(!rules.0[0].matches(&request, &upload(Some(10), &[])));
+        assert!(rules.0[1].matches(&request, &upload(None, &[])));
+        let other_path = TestRequest::post()
+            .uri("/other/")
+            .insert_header(("X-Tenant", "memes"))
+            .to_http_request();
+        assert!(!rules.0[1].matches(&other_path, &upload(None, &[])));
+        let other_tenant = TestRequest::post().uri("/files/").to_http_request();
+        assert!(!rules.0[1].matches(&other_tenant, &upload(None, &[])));
+    }
+}
@@ -1,4 +1,4 @@
-use std::io::{Error, ErrorKind};
+use std::io::Error;
 
 use actix_web::{http::StatusCode, HttpResponse, HttpResponseBuilder, ResponseError};
 use log::error;
@@ -19,16 +19,36 @@ pub enum RustusError {
     Unknown,
     #[error("File is frozen")]
     FrozenFile,
+    #[error("Upload has expired")]
+    FileExpired,
+    #[error("Unauthorized")]
+    Unauthorized,
+    #[error("Upload is locked by another request")]
+    UploadLocked,
+    #[error("Lock of the upload has expired")]
+    LockExpired,
+    #[error("Upload was changed by another request")]
+    InfoVersionConflict,
+    #[error("Information about the upload is being changed by another request")]
+    InfoLocked,
     #[error("Size already known")]
     SizeAlreadyKnown,
+    #[error("Request body is larger than allowed")]
+    BodyTooLarge,
+    #[error("Unable to read request body: {0}")]
+    PayloadError(#[from] actix_web::error::PayloadError),
     #[error("Unable to serialize object")]
     UnableToSerialize(#[from] serde_json::Error),
     #[error("Redis error: {0}")]
     RedisError(#[from] redis::RedisError),
     #[error("Redis pooling error: {0}")]
     MobcError(#[from] mobc::Error<redis::RedisError>),
+    #[error("Database error: {0}")]
+    DBError(#[from] sqlx::Error),
     #[error("Unable to get file information")]
     UnableToReadInfo,
+    #[error("Information about upload {0} is corrupted")]
+    CorruptedInfo(String),
     #[error("Unable to write file {0}")]
     UnableToWrite(String),
     #[error("Unable to remove file {0}")]
@@ -37,6 +57,10 @@ pub enum RustusError {
     UnableToPrepareInfoStorage(String),
     #[error("Unable to prepare storage. Reason: {0}")]
     UnableToPrepareStorage(String),
+    #[error("Wrong configuration: {0}")]
+    WrongConfig(String),
+    #[error("Unable to reload configuration: {0}")]
+    ReloadError(String),
     #[error("Unknown extension: {0}")]
     UnknownExtension(String),
     #[error("Http request failed: {0}")]
@@ -59,8 +83,26 @@ pub enum RustusError {
     UnknownHashAlgorithm,
     #[error("Wrong checksum")]
     WrongChecksum,
+    #[error("Upload checksum must be computed with {0}")]
+    ChecksumAlgorithmMismatch(String),
     #[error("The header value is incorrect")]
     WrongHeaderValue,
+    #[error("Limit must be between 1 and {0}")]
+    WrongPageLimit(usize),
+    #[error("Upload is already finished")]
+    UploadFinished,
+    #[error("Final uploads are finished by concatenation")]
+    FinishingFinalUpload,
+    #[error("Upload is not final")]
+    NotFinalUpload,
+    #[error("{0} upload is not complete")]
+    PartNotComplete(String),
+    #[error("{0} upload is stored in another storage")]
+    PartInAnotherStorage(String),
+    #[error("Unknown hook: {0}")]
+    UnknownHook(String),
+    #[error("Hook {0} is disabled")]
+    HookDisabled(String),
     #[error("Metrics error: {0}")]
     PrometheusError(#[from] prometheus::Error),
     #[error("Blocking error: {0}")]
@@ -71,6 +113,8 @@ pub enum RustusError {
     S3Error(#[from] s3::error::S3Error),
     #[error("Missing S3 upload id in metadata")]
     S3UploadIdMissing,
+    #[error("Staged S3 data doesn't match upload metadata")]
+    S3StagedDataCorrupted,
     #[error("Can't parse integer: {0}")]
     ParseIntError(#[from] std::num::ParseIntError),
     #[error("Can't convert int: {0}")]
@@ -92,7 +136,7 @@ pub enum RustusError {
 /// This conversion allows us to use `RustusError` in the `main` function.
 impl From<RustusError> for Error {
     fn from(err: RustusError) -> Self {
-        Self::new(ErrorKind::Other, err)
+        Self::other(err)
     }
 }
 
@@ -120,13 +164,28 @@ impl ResponseError for RustusError {
     fn status_code(&self) -> StatusCode {
         match self {
             Self::FileNotFound => StatusCode::NOT_FOUND,
-            Self::WrongOffset => StatusCode::CONFLICT,
+            Self::Unauthorized => StatusCode::UNAUTHORIZED,
+            Self::WrongOffset | Self::InfoVersionConflict => StatusCode::CONFLICT,
+            Self::FileExpired => StatusCode::GONE,
+            Self::UploadLocked | Self::LockExpired | Self::InfoLocked => StatusCode::LOCKED,
             Self::FrozenFile
             | Self::SizeAlreadyKnown
             | Self::HookError(_)
             | Self::UnknownHashAlgorithm
-            | Self::WrongHeaderValue => StatusCode::BAD_REQUEST,
-            Self::WrongChecksum => StatusCode::EXPECTATION_FAILED,
+            | Self::ChecksumAlgorithmMismatch(_)
+            | Self::PayloadError(_)
+            | Self::WrongHeaderValue
+            | Self::WrongPageLimit(_)
+            | Self::UploadFinished
+            | Self::FinishingFinalUpload
+            | Self::NotFinalUpload
+            | Self::PartNotComplete(_)
+            | Self::PartInAnotherStorage(_)
+            | Self::UnknownHook(_)
+            | Self::HookDisabled(_) => StatusCode::BAD_REQUEST,
+            // Checksum Mismatch from the tus checksum extension.
+            Self::WrongChecksum => StatusCode::from_u16(460).unwrap_or(StatusCode::BAD_REQUEST),
+            Self::BodyTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
             Self::HTTPHookError(status, _, _) => {
                 StatusCode::from_u16(*status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
             }
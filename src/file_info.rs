@@ -1,11 +1,28 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, time::SystemTime};
 
-use crate::{errors::RustusError, RustusResult};
+use crate::{errors::RustusError, utils::hashes::UploadDigest, RustusResult};
+use actix_web::http::header::HttpDate;
 use base64::{engine::general_purpose, Engine};
-use chrono::{serde::ts_seconds, DateTime, Utc};
+use chrono::{
+    serde::{ts_seconds, ts_seconds_option},
+    DateTime, Duration, Utc,
+};
 use log::error;
 use serde::{Deserialize, Serialize};
 
+/// Representation of `FileInfo` in info storages.
+///
+/// Besides public fields it keeps internal state of the upload,
+/// which isn't sent to hooks and admin API.
+#[derive(Serialize, Deserialize)]
+struct StoredFileInfo<T> {
+    #[serde(flatten)]
+    info: T,
+    /// Serialized state of the running digest.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    digest_state: Option<String>,
+}
+
 /// Information about file.
 /// It has everything about stored file.
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -22,6 +39,14 @@ pub struct FileInfo {
     pub parts: Option<Vec<String>>,
     pub storage: String,
     pub metadata: HashMap<String, String>,
+    #[serde(default, with = "ts_seconds_option")]
+    pub expires_at: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub digest: Option<UploadDigest>,
+    /// Number of updates of this information.
+    /// It's used to detect concurrent changes.
+    #[serde(default)]
+    pub version: u64,
 }
 
 impl FileInfo {
@@ -58,6 +83,9 @@ impl FileInfo {
             is_partial: false,
             parts: None,
             created_at: chrono::Utc::now(),
+            expires_at: None,
+            digest: None,
+            version: 0,
         }
     }
 
@@ -84,6 +112,34 @@ impl FileInfo {
         }
     }
 
+    /// Refresh expiration time of an upload.
+    ///
+    /// Finished uploads never expire, so
+    /// expiration time is removed as soon as
+    /// all bytes are received.
+    ///
+    /// `lifetime` - time for which upload stays alive,
+    /// `None` means that upload never expires.
+    pub fn refresh_expiration(&mut self, lifetime: Option<Duration>) {
+        self.expires_at = match lifetime {
+            Some(lifetime) if Some(self.offset) != self.length => Some(Utc::now() + lifetime),
+            _ => None,
+        };
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| expires_at <= Utc::now())
+    }
+
+    /// Expiration time formatted as HTTP date.
+    ///
+    /// This value is used in `Upload-Expires` header.
+    pub fn get_expiration_string(&self) -> Option<String> {
+        self.expires_at
+            .map(|expires_at| HttpDate::from(SystemTime::from(expires_at)).to_string())
+    }
+
     pub fn get_filename(&self) -> &str {
         self.metadata.get("filename").unwrap_or(&self.id)
     }
@@ -92,27 +148,43 @@ impl FileInfo {
         mime_guess::from_path(self.get_filename()).first_or_octet_stream()
     }
 
+    /// Serialize information to save it in an info storage.
+    ///
+    /// Unlike regular serialization, it keeps internal state of the upload.
+    pub fn to_stored(&self) -> serde_json::Result<String> {
+        serde_json::to_string(&StoredFileInfo {
+            info: self,
+            digest_state: self.digest.as_ref().and_then(|digest| digest.state.clone()),
+        })
+    }
+
+    /// Parse information that was saved with `to_stored`.
+    pub fn from_stored(data: &str) -> serde_json::Result<Self> {
+        let stored = serde_json::from_str::<StoredFileInfo<Self>>(data)?;
+        let mut info = stored.info;
+        if let Some(digest) = info.digest.as_mut() {
+            digest.state = stored.digest_state;
+        }
+        Ok(info)
+    }
+
     pub async fn json(&self) -> RustusResult<String> {
         let info_clone = self.clone();
-        tokio::task::spawn_blocking(move || {
-            serde_json::to_string(&info_clone).map_err(RustusError::from)
-        })
-        .await
-        .map_err(|err| {
-            error!("{}", err);
-            RustusError::UnableToWrite("Can't serialize info".into())
-        })?
+        tokio::task::spawn_blocking(move || info_clone.to_stored().map_err(RustusError::from))
+            .await
+            .map_err(|err| {
+                error!("{}", err);
+                RustusError::UnableToWrite("Can't serialize info".into())
+            })?
     }
 
     pub async fn from_json(data: String) -> RustusResult<Self> {
-        tokio::task::spawn_blocking(move || {
-            serde_json::from_str::<Self>(data.as_str()).map_err(RustusError::from)
-        })
-        .await
-        .map_err(|err| {
-            error!("{}", err);
-            RustusError::UnableToWrite("Can't serialize info".into())
-        })?
+        tokio::task::spawn_blocking(move || Self::from_stored(&data).map_err(RustusError::from))
+            .await
+            .map_err(|err| {
+                error!("{}", err);
+                RustusError::UnableToWrite("Can't serialize info".into())
+            })?
     }
 
     #[cfg(test)]
@@ -1,4 +1,10 @@
-use crate::{errors::RustusResult, file_info::FileInfo};
+use chrono::{DateTime, Utc};
+
+use crate::{
+    errors::RustusResult,
+    file_info::FileInfo,
+    info_storage::query::{UploadFilter, UploadsPage},
+};
 
 /// Trait for every info storage.
 ///
@@ -25,6 +31,18 @@ pub trait InfoStorage {
     /// for any update operation.
     async fn set_info(&self, file_info: &FileInfo, create: bool) -> RustusResult<()>;
 
+    /// Update information about an upload,
+    /// if nobody else has changed it.
+    ///
+    /// Information is saved only if the stored version is equal
+    /// to the version of the given info. Then the version is incremented.
+    /// Comparison and update **must** happen atomically.
+    ///
+    /// Returns `InfoVersionConflict` if versions are different
+    /// and `FileNotFound` if information was removed,
+    /// so removed uploads never come back.
+    async fn update_info(&self, file_info: &mut FileInfo) -> RustusResult<()>;
+
     /// Retrieve information from storage.
     ///
     /// This function must return information about file
@@ -36,4 +54,45 @@ pub trait InfoStorage {
     /// This function must actually delete any stored information
     /// associated with the given `file_id`.
     async fn remove_info(&self, file_id: &str) -> RustusResult<()>;
+
+    /// Remove information about an upload,
+    /// if nobody else has changed it.
+    ///
+    /// Information is removed only if the stored version is equal
+    /// to the version of the given info. Comparison and removal
+    /// **must** happen atomically, the same as in `update_info`.
+    ///
+    /// Returns `InfoVersionConflict` if versions are different
+    /// and `FileNotFound` if information was already removed.
+    async fn remove_unchanged_info(&self, file_info: &FileInfo) -> RustusResult<()>;
+
+    /// Retrieve information about expired uploads.
+    ///
+    /// This function must return all uploads
+    /// which have `expires_at` earlier than `now`.
+    /// It's used by expiration extension to remove
+    /// abandoned uploads.
+    async fn get_expired(&self, now: DateTime<Utc>) -> RustusResult<Vec<FileInfo>>;
+
+    /// List uploads that match the filter.
+    ///
+    /// Uploads are returned page by page. The `cursor`
+    /// is a value from the previous page, or `None` for
+    /// the first page. A page holds about `limit` uploads,
+    /// but storages that can't stop in the middle of a scan
+    /// might return more.
+    async fn list(
+        &self,
+        filter: &UploadFilter,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> RustusResult<UploadsPage>;
+
+    /// Retrieve information about unfinished uploads.
+    ///
+    /// This function must return all uploads
+    /// which offset is not equal to their length.
+    /// It's used to check that data of uploads
+    /// is consistent with information about them.
+    async fn get_unfinished(&self) -> RustusResult<Vec<FileInfo>>;
 }
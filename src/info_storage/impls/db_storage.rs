@@ -0,0 +1,487 @@
+use chrono::{DateTime, Utc};
+use sqlx::{
+    any::{install_default_drivers, AnyPoolOptions},
+    AnyPool,
+};
+
+use crate::{
+    errors::{RustusError, RustusResult},
+    file_info::FileInfo,
+    info_storage::{
+        base::InfoStorage,
+        query::{UploadFilter, UploadsPage},
+    },
+};
+
+/// Info storage that keeps upload information in SQL database.
+///
+/// Currently `PostgreSQL` and `SQLite` are supported.
+/// The database backend is chosen based on the scheme
+/// of the connection string.
+#[derive(Clone, Debug)]
+pub struct DBInfoStorage {
+    pool: AnyPool,
+}
+
+impl DBInfoStorage {
+    /// Create new `DBInfoStorage`.
+    ///
+    /// Connections are established lazily,
+    /// so the actual connection happens during `prepare`.
+    ///
+    /// # Params
+    /// `db_dsn` - connection string of the database.
+    /// `max_connections` - maximum size of the connection pool.
+    ///
+    /// # Errors
+    ///
+    /// Might return an error, if connection string is invalid.
+    pub fn new(db_dsn: &str, max_connections: u32) -> RustusResult<Self> {
+        install_default_drivers();
+        let pool = AnyPoolOptions::new()
+            .max_connections(max_connections)
+            .connect_lazy(db_dsn)?;
+        Ok(Self { pool })
+    }
+}
+
+impl InfoStorage for DBInfoStorage {
+    async fn prepare(&mut self) -> RustusResult<()> {
+        let prepare_error =
+            |err: sqlx::Error| RustusError::UnableToPrepareInfoStorage(err.to_string());
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS fileinfo \
+            (id VARCHAR(40) PRIMARY KEY, info TEXT NOT NULL, expires_at BIGINT)",
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(prepare_error)?;
+        // Tables created before expiration support
+        // don't have the column. Not all databases support
+        // `ADD COLUMN IF NOT EXISTS`, so it's checked by a query.
+        let has_expiration = sqlx::query("SELECT expires_at FROM fileinfo LIMIT 1")
+            .fetch_optional(&self.pool)
+            .await
+            .is_ok();
+        if !has_expiration {
+            sqlx::query("ALTER TABLE fileinfo ADD COLUMN expires_at BIGINT")
+                .execute(&self.pool)
+                .await
+                .map_err(prepare_error)?;
+        }
+        Ok(())
+    }
+
+    async fn set_info(&self, file_info: &FileInfo, create: bool) -> RustusResult<()> {
+        let info = file_info.json().await?;
+        let query = if create {
+            sqlx::query("INSERT INTO fileinfo (info, expires_at, id) VALUES ($1, $2, $3)")
+        } else {
+            sqlx::query("UPDATE fileinfo SET info = $1, expires_at = $2 WHERE id = $3")
+        };
+        let result = query
+            .bind(info)
+            .bind(
+                file_info
+                    .expires_at
+                    .map(|expires_at| expires_at.timestamp()),
+            )
+            .bind(file_info.id.as_str())
+            .execute(&self.pool)
+            .await?;
+        // Updated information must not be lost silently.
+        if result.rows_affected() == 0 {
+            return Err(RustusError::FileNotFound);
+        }
+        Ok(())
+    }
+
+    async fn update_info(&self, file_info: &mut FileInfo) -> RustusResult<()> {
+        let current = sqlx::query_scalar::<_, String>("SELECT info FROM fileinfo WHERE id = $1")
+            .bind(file_info.id.as_str())
+            .fetch_optional(&self.pool)
+            .await?
+            .ok_or(RustusError::FileNotFound)?;
+        if FileInfo::from_json(current.clone()).await?.version != file_info.version {
+            return Err(RustusError::InfoVersionConflict);
+        }
+        let mut new_info = file_info.clone();
+        new_info.version += 1;
+        // Row is updated only if nobody has changed it since we've read it.
+        let result = sqlx::query(
+            "UPDATE fileinfo SET info = $1, expires_at = $2 WHERE id = $3 AND info = $4",
+        )
+        .bind(new_info.json().await?)
+        .bind(new_info.expires_at.map(|expires_at| expires_at.timestamp()))
+        .bind(new_info.id.as_str())
+        .bind(current)
+        .execute(&self.pool)
+        .await?;
+        if result.rows_affected() == 0 {
+            return Err(RustusError::InfoVersionConflict);
+        }
+        file_info.version = new_info.version;
+        Ok(())
+    }
+
+    async fn get_info(&self, file_id: &str) -> RustusResult<FileInfo> {
+        let info = sqlx::query_scalar::<_, String>("SELECT info FROM fileinfo WHERE id = $1")
+            .bind(file_id)
+            .fetch_optional(&self.pool)
+            .await?;
+        match info {
+            Some(info) => FileInfo::from_json(info).await,
+            None => Err(RustusError::FileNotFound),
+        }
+    }
+
+    async fn remove_info(&self, file_id: &str) -> RustusResult<()> {
+        let result = sqlx::query("DELETE FROM fileinfo WHERE id = $1")
+            .bind(file_id)
+            .execute(&self.pool)
+            .await?;
+        if result.rows_affected() == 0 {
+            return Err(RustusError::FileNotFound);
+        }
+        Ok(())
+    }
+
+    async fn remove_unchanged_info(&self, file_info: &FileInfo) -> RustusResult<()> {
+        let current = sqlx::query_scalar::<_, String>("SELECT info FROM fileinfo WHERE id = $1")
+            .bind(file_info.id.as_str())
+            .fetch_optional(&self.pool)
+            .await?
+            .ok_or(RustusError::FileNotFound)?;
+        if FileInfo::from_json(current.clone()).await?.version != file_info.version {
+            return Err(RustusError::InfoVersionConflict);
+        }
+        // Row is removed only if nobody has changed it since we've read it.
+        let result = sqlx::query("DELETE FROM fileinfo WHERE id = $1 AND info = $2")
+            .bind(file_info.id.as_str())
+            .bind(current)
+            .execute(&self.pool)
+            .await?;
+        if result.rows_affected() == 0 {
+            return Err(RustusError::InfoVersionConflict);
+        }
+        Ok(())
+    }
+
+    async fn get_expired(&self, now: DateTime<Utc>) -> RustusResult<Vec<FileInfo>> {
+        let rows = sqlx::query_as::<_, (String, String)>(
+            "SELECT id, info FROM fileinfo WHERE expires_at <= $1",
+        )
+        .bind(now.timestamp())
+        .fetch_all(&self.pool)
+        .await?;
+        let mut expired = Vec::with_capacity(rows.len());
+        for (id, info) in rows {
+            // Broken rows shouldn't stop us
+            // from removing other uploads.
+            match FileInfo::from_json(info).await {
+                Ok(info) => expired.push(info),
+                Err(err) => log::error!("Cannot parse info of {id}: {err}"),
+            }
+        }
+        Ok(expired)
+    }
+
+    async fn list(
+        &self,
+        filter: &UploadFilter,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> RustusResult<UploadsPage> {
+        // Uploads are sorted by id, so the cursor
+        // is the last id that was checked.
+        let mut last_id = cursor.unwrap_or_default().to_string();
+        let mut uploads = Vec::new();
+        let batch_size = limit.max(1);
+        loop {
+            let rows = sqlx::query_as::<_, (String, String)>(
+                "SELECT id, info FROM fileinfo WHERE id > $1 ORDER BY id LIMIT $2",
+            )
+            .bind(last_id.as_str())
+            .bind(i64::try_from(batch_size)?)
+            .fetch_all(&self.pool)
+            .await?;
+            let rows_count = rows.len();
+            for (id, info) in rows {
+                if uploads.len() == limit {
+                    return Ok(UploadsPage {
+                        uploads,
+                        next_cursor: Some(last_id),
+                    });
+                }
+                match FileInfo::from_json(info).await {
+                    Ok(info) if filter.matches(&info) => uploads.push(info),
+                    Ok(_) => {}
+                    // Single broken row must not hide all other uploads.
+                    Err(err) => log::warn!("Skipping upload {} with broken info: {}", id, err),
+                }
+                last_id = id;
+            }
+            if rows_count < batch_size {
+                return Ok(UploadsPage {
+                    uploads,
+                    next_cursor: None,
+                });
+            }
+        }
+    }
+
+    async fn get_unfinished(&self) -> RustusResult<Vec<FileInfo>> {
+        let rows = sqlx::query_as::<_, (String, String)>("SELECT id, info FROM fileinfo")
+            .fetch_all(&self.pool)
+            .await?;
+        let mut unfinished = Vec::new();
+        for (id, info) in rows {
+            match FileInfo::from_json(info).await {
+                Ok(info) if info.length != Some(info.offset) => unfinished.push(info),
+                Ok(_) => {}
+                Err(err) => log::warn!("Skipping upload {} with broken info: {}", id, err),
+            }
+        }
+        Ok(unfinished)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DBInfoStorage;
+    use crate::{
+        errors::RustusError,
+        file_info::FileInfo,
+        info_storage::{base::InfoStorage, query::UploadFilter, test::check_get_expired},
+    };
+
+    /// Storage in a new `SQLite` database.
+    ///
+    /// Database is removed with the returned directory.
+    async fn get_storage() -> (DBInfoStorage, tempdir::TempDir) {
+        let dir = tempdir::TempDir::new("db_info").unwrap();
+        let db_path = dir.path().join("info.sqlite3");
+        let mut storage = DBInfoStorage::new(
+            format!("sqlite://{}?mode=rwc", db_path.display()).as_str(),
+            1,
+        )
+        .unwrap();
+        storage.prepare().await.unwrap();
+        (storage, dir)
+    }
+
+    #[actix_rt::test]
+    async fn prepare_old_table() {
+        let (mut info_storage, _dir) = get_storage().await;
+        sqlx::query("DROP TABLE fileinfo")
+            .execute(&info_storage.pool)
+            .await
+            .unwrap();
+        // Table created before expiration support.
+        sqlx::query("CREATE TABLE fileinfo (id VARCHAR(40) PRIMARY KEY, info TEXT NOT NULL)")
+            .execute(&info_storage.pool)
+            .await
+            .unwrap();
+        info_storage.prepare().await.unwrap();
+        // Running it again doesn't fail.
+        info_storage.prepare().await.unwrap();
+        let mut file_info = FileInfo::new_test();
+        file_info.expires_at = Some(chrono::Utc::now() - chrono::Duration::seconds(10));
+        info_storage.set_info(&file_info, true).await.unwrap();
+        let expired = info_storage.get_expired(chrono::Utc::now()).await.unwrap();
+        assert_eq!(expired.len(), 1);
+    }
+
+    #[actix_rt::test]
+    async fn success() {
+        let (info_storage, _dir) = get_storage().await;
+        let file_info = FileInfo::new_test();
+        info_storage.set_info(&file_info, true).await.unwrap();
+        let info = info_storage.get_info(file_info.id.as_str()).await.unwrap();
+        assert_eq!(file_info.id, info.id);
+        assert_eq!(file_info.path, info.path);
+        assert_eq!(file_info.storage, info.storage);
+    }
+
+    #[actix_rt::test]
+    async fn update() {
+        let (info_storage, _dir) = get_storage().await;
+        let mut file_info = FileInfo::new_test();
+        info_storage.set_info(&file_info, true).await.unwrap();
+        file_info.offset = 5;
+        info_storage.set_info(&file_info, false).await.unwrap();
+        let info = info_storage.get_info(file_info.id.as_str()).await.unwrap();
+        assert_eq!(info.offset, 5);
+    }
+
+    #[actix_rt::test]
+    async fn update_unknown() {
+        let (info_storage, _dir) = get_storage().await;
+        let file_info = FileInfo::new_test();
+        assert!(matches!(
+            info_storage.set_info(&file_info, false).await,
+            Err(RustusError::FileNotFound)
+        ));
+    }
+
+    #[actix_rt::test]
+    async fn create_twice() {
+        let (info_storage, _dir) = get_storage().await;
+        let file_info = FileInfo::new_test();
+        info_storage.set_info(&file_info, true).await.unwrap();
+        let res = info_storage.set_info(&file_info, true).await;
+        assert!(res.is_err());
+    }
+
+    #[actix_rt::test]
+    async fn unknown_id() {
+        let (info_storage, _dir) = get_storage().await;
+        let res = info_storage
+            .get_info(uuid::Uuid::new_v4().to_string().as_str())
+            .await;
+        assert!(res.is_err());
+    }
+
+    #[actix_rt::test]
+    async fn update_info() {
+        let (info_storage, _dir) = get_storage().await;
+        let mut file_info = FileInfo::new_test();
+        info_storage.set_info(&file_info, true).await.unwrap();
+        let mut stale_info = file_info.clone();
+        file_info.offset = 5;
+        info_storage.update_info(&mut file_info).await.unwrap();
+        assert_eq!(file_info.version, 1);
+        assert!(matches!(
+            info_storage.update_info(&mut stale_info).await,
+            Err(RustusError::InfoVersionConflict)
+        ));
+        let saved_info = info_storage.get_info(&file_info.id).await.unwrap();
+        assert_eq!(saved_info.offset, 5);
+        info_storage.remove_info(&file_info.id).await.unwrap();
+        assert!(matches!(
+            info_storage.update_info(&mut file_info).await,
+            Err(RustusError::FileNotFound)
+        ));
+    }
+
+    #[actix_rt::test]
+    async fn deletion_success() {
+        let (info_storage, _dir) = get_storage().await;
+        let file_info = FileInfo::new_test();
+        info_storage.set_info(&file_info, true).await.unwrap();
+        info_storage
+            .remove_info(file_info.id.as_str())
+            .await
+            .unwrap();
+        assert!(info_storage.get_info(file_info.id.as_str()).await.is_err());
+    }
+
+    #[actix_rt::test]
+    async fn deletion_of_changed_info() {
+        let (info_storage, _dir) = get_storage().await;
+        let mut file_info = FileInfo::new_test();
+        info_storage.set_info(&file_info, true).await.unwrap();
+        let outdated_info = file_info.clone();
+        info_storage.update_info(&mut file_info).await.unwrap();
+        assert!(matches!(
+            info_storage.remove_unchanged_info(&outdated_info).await,
+            Err(RustusError::InfoVersionConflict)
+        ));
+        info_storage
+            .remove_unchanged_info(&file_info)
+            .await
+            .unwrap();
+        assert!(matches!(
+            info_storage.remove_unchanged_info(&file_info).await,
+            Err(RustusError::FileNotFound)
+        ));
+    }
+
+    #[actix_rt::test]
+    async fn get_expired() {
+        let (info_storage, _dir) = get_storage().await;
+        // Broken rows are skipped.
+        sqlx::query("INSERT INTO fileinfo (id, info, expires_at) VALUES ('broken', '{', 0)")
+            .execute(&info_storage.pool)
+            .await
+            .unwrap();
+        check_get_expired(&info_storage).await;
+    }
+
+    #[actix_rt::test]
+    async fn list() {
+        let (info_storage, _dir) = get_storage().await;
+        let mut ids = Vec::new();
+        for _ in 0..5 {
+            let mut file_info = FileInfo::new_test();
+            file_info.is_partial = ids.len() % 2 == 0;
+            info_storage.set_info(&file_info, true).await.unwrap();
+            ids.push(file_info.id);
+        }
+        ids.sort();
+        let filter = UploadFilter::default();
+        let first = info_storage.list(&filter, None, 3).await.unwrap();
+        let second = info_storage
+            .list(&filter, first.next_cursor.as_deref(), 3)
+            .await
+            .unwrap();
+        assert!(second.next_cursor.is_none());
+        let listed = first
+            .uploads
+            .iter()
+            .chain(&second.uploads)
+            .map(|info| info.id.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(listed, ids);
+
+        let filter = UploadFilter {
+            is_partial: Some(true),
+            ..UploadFilter::default()
+        };
+        let partial = info_storage.list(&filter, None, 10).await.unwrap();
+        assert_eq!(partial.uploads.len(), 3);
+    }
+
+    #[actix_rt::test]
+    async fn get_unfinished() {
+        let (info_storage, _dir) = get_storage().await;
+        let unfinished_info = FileInfo::new_test();
+        let mut complete_info = FileInfo::new_test();
+        complete_info.offset = 10;
+        info_storage.set_info(&unfinished_info, true).await.unwrap();
+        info_storage.set_info(&complete_info, true).await.unwrap();
+        let unfinished = info_storage.get_unfinished().await.unwrap();
+        assert_eq!(unfinished.len(), 1);
+        assert_eq!(unfinished[0].id, unfinished_info.id);
+    }
+
+    #[actix_rt::test]
+    async fn broken_rows() {
+        let (info_storage, _dir) = get_storage().await;
+        sqlx::query("INSERT INTO fileinfo (id, info) VALUES ($1, $2)")
+            .bind("broken")
+            .bind("{not a json}")
+            .execute(&info_storage.pool)
+            .await
+            .unwrap();
+        let file_info = FileInfo::new_test();
+        info_storage.set_info(&file_info, true).await.unwrap();
+        let page = info_storage
+            .list(&UploadFilter::default(), None, 10)
+            .await
+            .unwrap();
+        assert_eq!(page.uploads.len(), 1);
+        assert_eq!(page.uploads[0].id, file_info.id);
+        let unfinished = info_storage.get_unfinished().await.unwrap();
+        assert_eq!(unfinished.len(), 1);
+    }
+
+    #[actix_rt::test]
+    async fn deletion_unknown() {
+        let (info_storage, _dir) = get_storage().await;
+        let res = info_storage
+            .remove_info(uuid::Uuid::new_v4().to_string().as_str())
+            .await;
+        assert!(res.is_err());
+    }
+}
@@ -1,34 +1,178 @@
 use std::{
-    io::{Read, Write},
-    path::PathBuf,
+    ffi::OsStr,
+    io::{ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+    time::Duration,
 };
 
+use chrono::{DateTime, Utc};
 use log::error;
 use std::{
-    fs::{remove_file, File, OpenOptions},
-    io::{BufReader, BufWriter},
+    fs::{read_dir, remove_file, File, OpenOptions},
+    io::BufReader,
 };
 use tokio::fs::DirBuilder;
 
 use crate::{
     errors::{RustusError, RustusResult},
     file_info::FileInfo,
-    info_storage::base::InfoStorage,
+    info_storage::{
+        base::InfoStorage,
+        query::{UploadFilter, UploadsPage},
+    },
 };
 
 #[derive(Clone, Debug)]
 pub struct FileInfoStorage {
     info_dir: PathBuf,
+    fsync_dir: bool,
 }
 
 impl FileInfoStorage {
-    pub const fn new(info_dir: PathBuf) -> Self {
-        Self { info_dir }
+    pub const fn new(info_dir: PathBuf, fsync_dir: bool) -> Self {
+        Self {
+            info_dir,
+            fsync_dir,
+        }
     }
 
     pub fn info_file_path(&self, file_id: &str) -> PathBuf {
         self.info_dir.join(format!("{file_id}.info"))
     }
+
+    fn lock_file_path(&self, file_id: &str) -> PathBuf {
+        self.info_dir.join(format!("{file_id}.lock"))
+    }
+}
+
+/// Exclusive lock of a single `.info` file.
+///
+/// The lock is a file which is created only if it doesn't exist,
+/// so it works for all processes that share the directory.
+struct InfoFileLock {
+    path: PathBuf,
+}
+
+impl InfoFileLock {
+    /// Lock is considered abandoned after this time.
+    const STALE_AFTER: Duration = Duration::from_secs(10);
+    /// Number of attempts to take the lock.
+    const ATTEMPTS: u32 = 8;
+    /// Delay before the second attempt, it's doubled after every attempt.
+    const FIRST_DELAY: Duration = Duration::from_millis(5);
+
+    /// Lock info file.
+    ///
+    /// Updates of info files are short, so the lock is taken
+    /// again a few times with growing delays between attempts.
+    /// Returns `InfoLocked` if the file is still
+    /// locked, because someone is updating it.
+    fn acquire(path: PathBuf) -> RustusResult<Self> {
+        let mut delay = Self::FIRST_DELAY;
+        for attempt in 1..=Self::ATTEMPTS {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(_) => return Ok(Self { path }),
+                Err(err) if err.kind() == ErrorKind::AlreadyExists => {}
+                Err(err) => return Err(err.into()),
+            }
+            // Process that held the lock has crashed,
+            // so the lock is taken right after it's removed.
+            if is_stale(&path) {
+                match remove_file(&path) {
+                    // Someone else has removed it first.
+                    Ok(()) => {}
+                    Err(err) if err.kind() == ErrorKind::NotFound => {}
+                    Err(err) => return Err(err.into()),
+                }
+                continue;
+            }
+            if attempt < Self::ATTEMPTS {
+                std::thread::sleep(delay);
+                delay *= 2;
+            }
+        }
+        Err(RustusError::InfoLocked)
+    }
+}
+
+impl Drop for InfoFileLock {
+    fn drop(&mut self) {
+        if let Err(err) = remove_file(&self.path) {
+            error!("Cannot remove lock {}: {}", self.path.display(), err);
+        }
+    }
+}
+
+/// Check that a lock or a temporary file was left by a crashed process.
+///
+/// Files that are in use are never older than `InfoFileLock::STALE_AFTER`.
+fn is_stale(path: &Path) -> bool {
+    std::fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+        .and_then(|modified| modified.elapsed().ok())
+        .is_some_and(|age| age > InfoFileLock::STALE_AFTER)
+}
+
+/// Remove locks and temporary files left by crashed processes.
+fn remove_leftovers(info_dir: &Path) -> RustusResult<()> {
+    for entry in read_dir(info_dir)? {
+        let path = entry?.path();
+        let extension = path.extension().and_then(OsStr::to_str);
+        if !matches!(extension, Some("lock" | "tmp")) || !is_stale(&path) {
+            continue;
+        }
+        log::info!("Removing leftover {}", path.display());
+        if let Err(err) = remove_file(&path) {
+            error!("Cannot remove {}: {}", path.display(), err);
+        }
+    }
+    Ok(())
+}
+
+/// Read and parse single `.info` file.
+fn read_info(info_path: &Path) -> RustusResult<FileInfo> {
+    let info = File::open(info_path)?;
+    let mut contents = String::new();
+    let mut reader = BufReader::new(info);
+    reader.read_to_string(&mut contents)?;
+    FileInfo::from_stored(contents.as_str()).map_err(|err| {
+        error!("Cannot parse {}: {}", info_path.display(), err);
+        let file_id = info_path.file_stem().and_then(OsStr::to_str);
+        RustusError::CorruptedInfo(file_id.unwrap_or_default().to_string())
+    })
+}
+
+/// Write `.info` file atomically.
+///
+/// Information is written to a temporary file,
+/// which replaces the old one after all data is on disk.
+/// So a crash never leaves a partially written file.
+///
+/// If `fsync_dir` is true, the directory is synced as well,
+/// so the rename itself survives a power loss.
+fn write_info(info_path: &Path, info: &FileInfo, fsync_dir: bool) -> RustusResult<()> {
+    let data = info.to_stored()?;
+    let tmp_path = info_path.with_extension(format!("{}.tmp", uuid::Uuid::new_v4()));
+    let result = File::create(&tmp_path)
+        .and_then(|mut file| {
+            file.write_all(data.as_bytes())?;
+            file.sync_all()
+        })
+        .and_then(|()| std::fs::rename(&tmp_path, info_path));
+    if let Err(err) = result {
+        error!("Cannot write {}: {}", info_path.display(), err);
+        if let Err(err) = remove_file(&tmp_path) {
+            error!("Cannot remove {}: {}", tmp_path.display(), err);
+        }
+        return Err(RustusError::UnableToWrite(err.to_string()));
+    }
+    if fsync_dir {
+        if let Some(dir) = info_path.parent() {
+            File::open(dir)?.sync_all()?;
+        }
+    }
+    Ok(())
 }
 
 impl InfoStorage for FileInfoStorage {
@@ -39,44 +183,55 @@ impl InfoStorage for FileInfoStorage {
                 .await
                 .map_err(|err| RustusError::UnableToPrepareInfoStorage(err.to_string()))?;
         }
-        Ok(())
+        let info_dir = self.info_dir.clone();
+        tokio::task::spawn_blocking(move || remove_leftovers(&info_dir)).await?
     }
 
     async fn set_info(&self, file_info: &FileInfo, create: bool) -> RustusResult<()> {
         let info = file_info.clone();
         let path = self.info_file_path(info.id.as_str());
+        let lock_path = self.lock_file_path(info.id.as_str());
+        let fsync_dir = self.fsync_dir;
         tokio::task::spawn_blocking(move || {
-            let file = OpenOptions::new()
-                .write(true)
-                .create(create)
-                .truncate(true)
-                .open(path)
-                .map_err(|err| {
-                    error!("{:?}", err);
-                    RustusError::UnableToWrite(err.to_string())
-                })?;
-            let data = serde_json::to_string(&info).map_err(RustusError::from)?;
-            {
-                let mut writer = BufWriter::new(file);
-                writer.write_all(data.as_bytes())?;
-                writer.flush()?;
+            let _lock = InfoFileLock::acquire(lock_path)?;
+            // Only existing information can be updated.
+            if !create && !path.exists() {
+                return Err(RustusError::FileNotFound);
             }
-            Ok(())
+            write_info(path.as_path(), &info, fsync_dir)
         })
         .await?
     }
 
+    async fn update_info(&self, file_info: &mut FileInfo) -> RustusResult<()> {
+        let mut new_info = file_info.clone();
+        new_info.version += 1;
+        let path = self.info_file_path(new_info.id.as_str());
+        let lock_path = self.lock_file_path(new_info.id.as_str());
+        let fsync_dir = self.fsync_dir;
+        let expected_version = file_info.version;
+        tokio::task::spawn_blocking(move || {
+            let _lock = InfoFileLock::acquire(lock_path)?;
+            if !path.exists() {
+                return Err(RustusError::FileNotFound);
+            }
+            if read_info(path.as_path())?.version != expected_version {
+                return Err(RustusError::InfoVersionConflict);
+            }
+            write_info(path.as_path(), &new_info, fsync_dir)
+        })
+        .await??;
+        file_info.version += 1;
+        Ok(())
+    }
+
     async fn get_info(&self, file_id: &str) -> RustusResult<FileInfo> {
         let info_path = self.info_file_path(file_id);
         tokio::task::spawn_blocking(move || {
             if !info_path.exists() {
                 return Err(RustusError::FileNotFound);
             }
-            let info = File::open(info_path)?;
-            let mut contents = String::new();
-            let mut reader = BufReader::new(info);
-            reader.read_to_string(&mut contents)?;
-            serde_json::from_str::<FileInfo>(contents.as_str()).map_err(RustusError::from)
+            read_info(info_path.as_path())
         })
         .await?
     }
@@ -84,7 +239,11 @@ impl InfoStorage for FileInfoStorage {
     async fn remove_info(&self, file_id: &str) -> RustusResult<()> {
         let id = String::from(file_id);
         let info_path = self.info_file_path(id.as_str());
+        let lock_path = self.lock_file_path(id.as_str());
         tokio::task::spawn_blocking(move || {
+            // Without the lock an update that is in progress
+            // could write the information back after removal.
+            let _lock = InfoFileLock::acquire(lock_path)?;
             if !info_path.exists() {
                 return Err(RustusError::FileNotFound);
             }
@@ -95,15 +254,136 @@ impl InfoStorage for FileInfoStorage {
         })
         .await?
     }
+
+    async fn remove_unchanged_info(&self, file_info: &FileInfo) -> RustusResult<()> {
+        let id = file_info.id.clone();
+        let info_path = self.info_file_path(id.as_str());
+        let lock_path = self.lock_file_path(id.as_str());
+        let expected_version = file_info.version;
+        tokio::task::spawn_blocking(move || {
+            let _lock = InfoFileLock::acquire(lock_path)?;
+            if !info_path.exists() {
+                return Err(RustusError::FileNotFound);
+            }
+            if read_info(info_path.as_path())?.version != expected_version {
+                return Err(RustusError::InfoVersionConflict);
+            }
+            remove_file(info_path).map_err(|err| {
+                error!("{:?}", err);
+                RustusError::UnableToRemove(id)
+            })
+        })
+        .await?
+    }
+
+    async fn get_expired(&self, now: DateTime<Utc>) -> RustusResult<Vec<FileInfo>> {
+        let info_dir = self.info_dir.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut expired = Vec::new();
+            for entry in read_dir(info_dir)? {
+                let path = entry?.path();
+                if path.extension().and_then(OsStr::to_str) != Some("info") {
+                    continue;
+                }
+                // Broken info files shouldn't stop us
+                // from removing other uploads.
+                match read_info(path.as_path()) {
+                    Ok(info) if info.expires_at.is_some_and(|expires_at| expires_at <= now) => {
+                        expired.push(info);
+                    }
+                    Ok(_) => {}
+                    Err(err) => error!("Cannot read {}: {}", path.display(), err),
+                }
+            }
+            Ok(expired)
+        })
+        .await?
+    }
+
+    async fn list(
+        &self,
+        filter: &UploadFilter,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> RustusResult<UploadsPage> {
+        let info_dir = self.info_dir.clone();
+        let filter = filter.clone();
+        let cursor = cursor.map(String::from);
+        tokio::task::spawn_blocking(move || {
+            let mut ids = Vec::new();
+            for entry in read_dir(&info_dir)? {
+                let path = entry?.path();
+                if path.extension().and_then(OsStr::to_str) != Some("info") {
+                    continue;
+                }
+                if let Some(id) = path.file_stem().and_then(OsStr::to_str) {
+                    ids.push(id.to_string());
+                }
+            }
+            // Uploads are sorted by id, so the cursor
+            // is the last id that was checked.
+            ids.sort_unstable();
+            let start = cursor.map_or(0, |cursor| ids.partition_point(|id| *id <= cursor));
+            let mut uploads = Vec::new();
+            let mut last_id = None;
+            for id in &ids[start..] {
+                if uploads.len() == limit {
+                    return Ok(UploadsPage {
+                        uploads,
+                        next_cursor: last_id,
+                    });
+                }
+                match read_info(info_dir.join(format!("{id}.info")).as_path()) {
+                    Ok(info) if filter.matches(&info) => uploads.push(info),
+                    Ok(_) => {}
+                    Err(err) => error!("Cannot read info of {}: {}", id, err),
+                }
+                last_id = Some(id.clone());
+            }
+            Ok(UploadsPage {
+                uploads,
+                next_cursor: None,
+            })
+        })
+        .await?
+    }
+
+    async fn get_unfinished(&self) -> RustusResult<Vec<FileInfo>> {
+        let info_dir = self.info_dir.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut unfinished = Vec::new();
+            for entry in read_dir(info_dir)? {
+                let path = entry?.path();
+                if path.extension().and_then(OsStr::to_str) != Some("info") {
+                    continue;
+                }
+                match read_info(path.as_path()) {
+                    Ok(info) if info.length != Some(info.offset) => unfinished.push(info),
+                    Ok(_) => {}
+                    Err(err) => error!("Cannot read {}: {}", path.display(), err),
+                }
+            }
+            Ok(unfinished)
+        })
+        .await?
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{file_info::FileInfo, info_storage::base::InfoStorage};
+    use crate::{
+        errors::RustusError,
+        file_info::FileInfo,
+        info_storage::{
+            base::InfoStorage,
+            query::{UploadFilter, UploadState},
+            test::check_get_expired,
+        },
+    };
 
     use super::FileInfoStorage;
     use std::{
-        collections::HashMap,
+        collections::{HashMap, HashSet},
         fs::File,
         io::{Read, Write},
     };
@@ -111,8 +391,8 @@ mod tests {
     #[actix_rt::test]
     async fn preparation() {
         let dir = tempdir::TempDir::new("file_info").unwrap();
-        let target_path = dir.into_path().join("not_exist");
-        let mut storage = FileInfoStorage::new(target_path.clone());
+        let target_path = dir.path().to_path_buf().join("not_exist");
+        let mut storage = FileInfoStorage::new(target_path.clone(), false);
         assert!(!target_path.exists());
         storage.prepare().await.unwrap();
         assert!(target_path.exists());
@@ -121,7 +401,7 @@ mod tests {
     #[actix_rt::test]
     async fn setting_info() {
         let dir = tempdir::TempDir::new("file_info").unwrap();
-        let storage = FileInfoStorage::new(dir.into_path());
+        let storage = FileInfoStorage::new(dir.path().to_path_buf(), false);
         let file_info = FileInfo::new(
             uuid::Uuid::new_v4().to_string().as_str(),
             Some(10),
@@ -142,7 +422,7 @@ mod tests {
     #[actix_rt::test]
     async fn set_get_info() {
         let dir = tempdir::TempDir::new("file_info").unwrap();
-        let storage = FileInfoStorage::new(dir.into_path());
+        let storage = FileInfoStorage::new(dir.path().to_path_buf(), false);
         let file_info = FileInfo::new(
             uuid::Uuid::new_v4().to_string().as_str(),
             Some(10),
@@ -162,14 +442,232 @@ mod tests {
         assert_eq!(read_info.metadata, read_info.metadata);
     }
 
+    #[actix_rt::test]
+    async fn get_expired() {
+        let dir = tempdir::TempDir::new("file_info").unwrap();
+        let storage = FileInfoStorage::new(dir.path().to_path_buf(), false);
+        check_get_expired(&storage).await;
+    }
+
+    #[actix_rt::test]
+    async fn update_info() {
+        let dir = tempdir::TempDir::new("file_info").unwrap();
+        let storage = FileInfoStorage::new(dir.path().to_path_buf(), false);
+        let mut file_info = FileInfo::new_test();
+        storage.set_info(&file_info, true).await.unwrap();
+        let mut stale_info = file_info.clone();
+        file_info.offset = 5;
+        storage.update_info(&mut file_info).await.unwrap();
+        assert_eq!(file_info.version, 1);
+        stale_info.offset = 3;
+        assert!(matches!(
+            storage.update_info(&mut stale_info).await,
+            Err(RustusError::InfoVersionConflict)
+        ));
+        let saved_info = storage.get_info(&file_info.id).await.unwrap();
+        assert_eq!(saved_info.offset, 5);
+        assert_eq!(saved_info.version, 1);
+    }
+
+    #[actix_rt::test]
+    async fn update_removed_info() {
+        let dir = tempdir::TempDir::new("file_info").unwrap();
+        let storage = FileInfoStorage::new(dir.path().to_path_buf(), false);
+        let mut file_info = FileInfo::new_test();
+        storage.set_info(&file_info, true).await.unwrap();
+        storage.remove_info(&file_info.id).await.unwrap();
+        assert!(matches!(
+            storage.update_info(&mut file_info).await,
+            Err(RustusError::FileNotFound)
+        ));
+        assert!(storage.get_info(&file_info.id).await.is_err());
+    }
+
+    #[actix_rt::test]
+    async fn update_locked_info() {
+        let dir = tempdir::TempDir::new("file_info").unwrap();
+        let storage = FileInfoStorage::new(dir.path().to_path_buf(), false);
+        let mut file_info = FileInfo::new_test();
+        storage.set_info(&file_info, true).await.unwrap();
+        File::create(storage.lock_file_path(&file_info.id)).unwrap();
+        assert!(matches!(
+            storage.update_info(&mut file_info).await,
+            Err(RustusError::InfoLocked)
+        ));
+        assert!(matches!(
+            storage.remove_info(&file_info.id).await,
+            Err(RustusError::InfoLocked)
+        ));
+        std::fs::remove_file(storage.lock_file_path(&file_info.id)).unwrap();
+        storage.update_info(&mut file_info).await.unwrap();
+        assert!(!storage.lock_file_path(&file_info.id).exists());
+    }
+
+    #[actix_rt::test]
+    async fn update_stale_locked_info() {
+        let dir = tempdir::TempDir::new("file_info").unwrap();
+        let storage = FileInfoStorage::new(dir.path().to_path_buf(), false);
+        let mut file_info = FileInfo::new_test();
+        storage.set_info(&file_info, true).await.unwrap();
+        let lock = File::create(storage.lock_file_path(&file_info.id)).unwrap();
+        lock.set_modified(std::time::SystemTime::now() - std::time::Duration::from_secs(100))
+            .unwrap();
+        storage.update_info(&mut file_info).await.unwrap();
+        assert!(!storage.lock_file_path(&file_info.id).exists());
+    }
+
+    #[actix_rt::test]
+    async fn update_released_info() {
+        let dir = tempdir::TempDir::new("file_info").unwrap();
+        let storage = FileInfoStorage::new(dir.path().to_path_buf(), false);
+        let mut file_info = FileInfo::new_test();
+        storage.set_info(&file_info, true).await.unwrap();
+        let lock_path = storage.lock_file_path(&file_info.id);
+        File::create(&lock_path).unwrap();
+        // Lock is released while the update is waiting for it.
+        let release = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            std::fs::remove_file(lock_path).unwrap();
+        });
+        storage.update_info(&mut file_info).await.unwrap();
+        release.join().unwrap();
+        assert_eq!(file_info.version, 1);
+    }
+
+    #[actix_rt::test]
+    async fn remove_changed_info() {
+        let dir = tempdir::TempDir::new("file_info").unwrap();
+        let storage = FileInfoStorage::new(dir.path().to_path_buf(), false);
+        let mut file_info = FileInfo::new_test();
+        storage.set_info(&file_info, true).await.unwrap();
+        let outdated_info = file_info.clone();
+        storage.update_info(&mut file_info).await.unwrap();
+        assert!(matches!(
+            storage.remove_unchanged_info(&outdated_info).await,
+            Err(RustusError::InfoVersionConflict)
+        ));
+        assert!(storage.get_info(&file_info.id).await.is_ok());
+        storage.remove_unchanged_info(&file_info).await.unwrap();
+        assert!(matches!(
+            storage.remove_unchanged_info(&file_info).await,
+            Err(RustusError::FileNotFound)
+        ));
+    }
+
+    #[actix_rt::test]
+    async fn list() {
+        let dir = tempdir::TempDir::new("file_info").unwrap();
+        let storage = FileInfoStorage::new(dir.path().to_path_buf(), false);
+        let mut ids = Vec::new();
+        for offset in 0..5 {
+            let mut file_info = FileInfo::new_test();
+            file_info.offset = offset * 5;
+            storage.set_info(&file_info, true).await.unwrap();
+            ids.push(file_info.id);
+        }
+        ids.sort();
+        let filter = UploadFilter::default();
+        let first = storage.list(&filter, None, 3).await.unwrap();
+        assert_eq!(first.uploads.len(), 3);
+        let second = storage
+            .list(&filter, first.next_cursor.as_deref(), 3)
+            .await
+            .unwrap();
+        assert_eq!(second.uploads.len(), 2);
+        assert!(second.next_cursor.is_none());
+        let listed = first
+            .uploads
+            .iter()
+            .chain(&second.uploads)
+            .map(|info| info.id.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(listed, ids);
+
+        let filter = UploadFilter {
+            state: Some(UploadState::Finished),
+            ..UploadFilter::default()
+        };
+        let finished = storage.list(&filter, None, 10).await.unwrap();
+        assert_eq!(finished.uploads.len(), 1);
+        assert_eq!(finished.uploads[0].offset, 10);
+    }
+
+    #[actix_rt::test]
+    async fn get_unfinished() {
+        let dir = tempdir::TempDir::new("file_info").unwrap();
+        let storage = FileInfoStorage::new(dir.path().to_path_buf(), false);
+        let unfinished_info = FileInfo::new_test();
+        let mut complete_info = FileInfo::new_test();
+        complete_info.offset = 10;
+        storage.set_info(&unfinished_info, true).await.unwrap();
+        storage.set_info(&complete_info, true).await.unwrap();
+        let unfinished = storage.get_unfinished().await.unwrap();
+        assert_eq!(unfinished.len(), 1);
+        assert_eq!(unfinished[0].id, unfinished_info.id);
+    }
+
     #[actix_rt::test]
     async fn get_broken_info() {
         let dir = tempdir::TempDir::new("file_info").unwrap();
-        let storage = FileInfoStorage::new(dir.into_path());
+        let storage = FileInfoStorage::new(dir.path().to_path_buf(), false);
         let file_id = "random_file";
         let mut file = File::create(storage.info_file_path(file_id)).unwrap();
         file.write_all(b"{not a json}").unwrap();
         let read_info = storage.get_info(file_id).await;
-        assert!(read_info.is_err());
+        assert!(matches!(read_info, Err(RustusError::CorruptedInfo(id)) if id == file_id));
+    }
+
+    #[actix_rt::test]
+    async fn update_without_leftovers() {
+        let dir = tempdir::TempDir::new("file_info").unwrap();
+        let info_dir = dir.path().to_path_buf();
+        let storage = FileInfoStorage::new(info_dir.clone(), true);
+        let mut file_info = FileInfo::new_test();
+        storage.set_info(&file_info, true).await.unwrap();
+        file_info.offset = 5;
+        storage.set_info(&file_info, false).await.unwrap();
+        storage.update_info(&mut file_info).await.unwrap();
+        // Only the info file itself is left in the directory.
+        let files = std::fs::read_dir(info_dir).unwrap().count();
+        assert_eq!(files, 1);
+        let saved_info = storage.get_info(&file_info.id).await.unwrap();
+        assert_eq!(saved_info.offset, 5);
+    }
+
+    #[actix_rt::test]
+    async fn prepare_removes_leftovers() {
+        let dir = tempdir::TempDir::new("file_info").unwrap();
+        let mut storage = FileInfoStorage::new(dir.path().to_path_buf(), false);
+        let file_info = FileInfo::new_test();
+        storage.set_info(&file_info, true).await.unwrap();
+        let old_time = std::time::SystemTime::now() - std::time::Duration::from_secs(100);
+        let stale_lock = File::create(storage.lock_file_path("stale")).unwrap();
+        stale_lock.set_modified(old_time).unwrap();
+        let stale_tmp = File::create(dir.path().join("stale.info.tmp")).unwrap();
+        stale_tmp.set_modified(old_time).unwrap();
+        // Lock of a running update must be kept.
+        File::create(storage.lock_file_path("active")).unwrap();
+        storage.prepare().await.unwrap();
+        let files = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<HashSet<_>>();
+        let expected = HashSet::from([
+            String::from("active.lock"),
+            format!("{}.info", file_info.id),
+        ]);
+        assert_eq!(files, expected);
+    }
+
+    #[actix_rt::test]
+    async fn update_unknown_info() {
+        let dir = tempdir::TempDir::new("file_info").unwrap();
+        let storage = FileInfoStorage::new(dir.path().to_path_buf(), false);
+        let file_info = FileInfo::new_test();
+        assert!(matches!(
+            storage.set_info(&file_info, false).await,
+            Err(RustusError::FileNotFound)
+        ));
+        assert!(!storage.info_file_path(&file_info.id).exists());
     }
 }
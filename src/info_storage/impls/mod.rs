@@ -1,2 +1,3 @@
+pub mod db_storage;
 pub mod file_storage;
 pub mod redis_storage;
@@ -1,12 +1,81 @@
+use chrono::{DateTime, Utc};
 use mobc::{Manager, Pool};
 
 use crate::{
     errors::{RustusError, RustusResult},
     file_info::FileInfo,
-    info_storage::base::InfoStorage,
+    info_storage::{
+        base::InfoStorage,
+        query::{UploadFilter, UploadsPage},
+    },
 };
 
-struct RedisConnectionManager {
+/// Sorted set with expiration times of uploads.
+///
+/// Scores are unix timestamps and members are upload ids.
+const EXPIRATIONS_KEY: &str = "rustus_expirations";
+/// Set with ids of unfinished uploads.
+const UNFINISHED_KEY: &str = "rustus_unfinished";
+/// Sorted set with ids of all uploads.
+///
+/// All scores are zero, so members are sorted by id
+/// and uploads are listed page by page with `ZRANGEBYLEX`.
+const UPLOADS_KEY: &str = "rustus_uploads";
+
+/// Save information only if its stored version matches.
+///
+/// Indexes are updated by the same script,
+/// so they always match the saved information.
+/// Returns -1 if information doesn't exist,
+/// 0 if versions are different and 1 if information was saved.
+const UPDATE_SCRIPT: &str = r#"
+local current = redis.call("GET", KEYS[1])
+if not current then
+    return -1
+end
+if (cjson.decode(current).version or 0) ~= tonumber(ARGV[1]) then
+    return 0
+end
+if ARGV[3] ~= "" then
+    redis.call("SET", KEYS[1], ARGV[2], "EX", ARGV[3])
+else
+    redis.call("SET", KEYS[1], ARGV[2])
+end
+if ARGV[5] ~= "" then
+    redis.call("ZADD", KEYS[2], ARGV[5], ARGV[4])
+else
+    redis.call("ZREM", KEYS[2], ARGV[4])
+end
+if ARGV[6] == "1" then
+    redis.call("SREM", KEYS[3], ARGV[4])
+else
+    redis.call("SADD", KEYS[3], ARGV[4])
+end
+redis.call("ZADD", KEYS[4], 0, ARGV[4])
+return 1
+"#;
+
+/// Remove information only if its stored version matches.
+///
+/// Upload is removed from indexes as well.
+/// Returns -1 if information doesn't exist,
+/// 0 if versions are different and 1 if information was removed.
+const REMOVE_SCRIPT: &str = r#"
+local current = redis.call("GET", KEYS[1])
+if not current then
+    return -1
+end
+if (cjson.decode(current).version or 0) ~= tonumber(ARGV[1]) then
+    return 0
+end
+redis.call("DEL", KEYS[1])
+redis.call("ZREM", KEYS[2], ARGV[2])
+redis.call("SREM", KEYS[3], ARGV[2])
+redis.call("ZREM", KEYS[4], ARGV[2])
+return 1
+"#;
+
+pub struct RedisConnectionManager {
     client: redis::Client,
 }
 
@@ -38,6 +107,7 @@ impl Manager for RedisConnectionManager {
 pub struct RedisInfoStorage {
     pool: Pool<RedisConnectionManager>,
     expiration: Option<usize>,
+    key_prefix: String,
 }
 
 impl RedisInfoStorage {
@@ -46,11 +116,105 @@ impl RedisInfoStorage {
     /// # Errors
     ///
     /// Might return an error, if redis client cannot be created.
-    pub fn new(db_dsn: &str, expiration: Option<usize>) -> RustusResult<Self> {
+    pub fn new(
+        db_dsn: &str,
+        max_connections: u32,
+        expiration: Option<usize>,
+        key_prefix: String,
+    ) -> RustusResult<Self> {
         let client = redis::Client::open(db_dsn)?;
         let manager = RedisConnectionManager::new(client);
-        let pool = mobc::Pool::builder().max_open(100).build(manager);
-        Ok(Self { pool, expiration })
+        let pool = mobc::Pool::builder()
+            .max_open(u64::from(max_connections))
+            .build(manager);
+        Ok(Self {
+            pool,
+            expiration,
+            key_prefix,
+        })
+    }
+
+    /// Key that holds information about an upload.
+    fn info_key(&self, file_id: &str) -> String {
+        format!("{}{file_id}", self.key_prefix)
+    }
+
+    /// Key of an index of uploads.
+    fn index_key(&self, index: &str) -> String {
+        format!("{}{index}", self.key_prefix)
+    }
+
+    /// Add commands that keep indexes of uploads up to date.
+    fn update_indexes(&self, pipe: &mut redis::Pipeline, file_info: &FileInfo) {
+        pipe.cmd("ZADD")
+            .arg(self.index_key(UPLOADS_KEY))
+            .arg(0)
+            .arg(file_info.id.as_str())
+            .ignore();
+        // Keeping track of expiration times, so we can find
+        // expired uploads without scanning the whole database.
+        if let Some(expires_at) = file_info.expires_at {
+            pipe.cmd("ZADD")
+                .arg(self.index_key(EXPIRATIONS_KEY))
+                .arg(expires_at.timestamp())
+                .arg(file_info.id.as_str())
+                .ignore();
+        } else {
+            pipe.cmd("ZREM")
+                .arg(self.index_key(EXPIRATIONS_KEY))
+                .arg(file_info.id.as_str())
+                .ignore();
+        }
+        let unfinished_cmd = if Some(file_info.offset) == file_info.length {
+            "SREM"
+        } else {
+            "SADD"
+        };
+        pipe.cmd(unfinished_cmd)
+            .arg(self.index_key(UNFINISHED_KEY))
+            .arg(file_info.id.as_str())
+            .ignore();
+    }
+
+    /// Load information about uploads from an index.
+    ///
+    /// Ids of uploads, which information was removed
+    /// by redis expiration, are removed from the index
+    /// with the `remove_cmd` command.
+    async fn load_indexed(
+        &self,
+        conn: &mut redis::aio::MultiplexedConnection,
+        remove_cmd: &str,
+        index: &str,
+        ids: Vec<String>,
+    ) -> RustusResult<Vec<FileInfo>> {
+        if ids.is_empty() {
+            return Ok(vec![]);
+        }
+        let infos = redis::cmd("MGET")
+            .arg(ids.iter().map(|id| self.info_key(id)).collect::<Vec<_>>())
+            .query_async::<Vec<Option<String>>>(&mut *conn)
+            .await?;
+        let mut found = Vec::new();
+        let mut stale = Vec::new();
+        for (id, info) in ids.into_iter().zip(infos) {
+            match info.map(|info| FileInfo::from_stored(info.as_str())) {
+                Some(Ok(info)) => found.push(info),
+                // Broken information shouldn't stop us
+                // from processing other uploads.
+                Some(Err(err)) => log::error!("Cannot parse info of {id}: {err}"),
+                // Information was removed by redis expiration.
+                None => stale.push(id),
+            }
+        }
+        if !stale.is_empty() {
+            redis::cmd(remove_cmd)
+                .arg(self.index_key(index))
+                .arg(stale)
+                .query_async::<()>(conn)
+                .await?;
+        }
+        Ok(found)
     }
 }
 
@@ -60,37 +224,90 @@ impl InfoStorage for RedisInfoStorage {
     }
 
     async fn set_info(&self, file_info: &FileInfo, _create: bool) -> RustusResult<()> {
-        let mut cmd = redis::cmd("SET");
-        let mut cmd = cmd
-            .arg(file_info.id.as_str())
-            .arg(serde_json::to_string(file_info)?);
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .cmd("SET")
+            .arg(self.info_key(&file_info.id))
+            .arg(file_info.to_stored()?);
         if let Some(expiration) = self.expiration.as_ref() {
-            cmd = cmd.arg("EX").arg(expiration);
+            pipe.arg("EX").arg(expiration);
         }
+        pipe.ignore();
+        self.update_indexes(&mut pipe, file_info);
         let mut conn = self.pool.get().await?;
-        cmd.query_async::<String>(&mut *conn).await?;
+        pipe.query_async::<()>(&mut *conn).await?;
         drop(conn);
         Ok(())
     }
 
+    async fn update_info(&self, file_info: &mut FileInfo) -> RustusResult<()> {
+        let mut new_info = file_info.clone();
+        new_info.version += 1;
+        let mut conn = self.pool.get().await?;
+        let updated = redis::Script::new(UPDATE_SCRIPT)
+            .key(self.info_key(&new_info.id))
+            .key(self.index_key(EXPIRATIONS_KEY))
+            .key(self.index_key(UNFINISHED_KEY))
+            .key(self.index_key(UPLOADS_KEY))
+            .arg(file_info.version)
+            .arg(new_info.to_stored()?)
+            .arg(
+                self.expiration
+                    .map(|expiration| expiration.to_string())
+                    .unwrap_or_default(),
+            )
+            .arg(new_info.id.as_str())
+            .arg(
+                new_info
+                    .expires_at
+                    .map(|expires_at| expires_at.timestamp().to_string())
+                    .unwrap_or_default(),
+            )
+            .arg(u8::from(Some(new_info.offset) == new_info.length))
+            .invoke_async::<i64>(&mut *conn)
+            .await?;
+        drop(conn);
+        match updated {
+            -1 => return Err(RustusError::FileNotFound),
+            0 => return Err(RustusError::InfoVersionConflict),
+            _ => {}
+        }
+        file_info.version = new_info.version;
+        Ok(())
+    }
+
     async fn get_info(&self, file_id: &str) -> RustusResult<FileInfo> {
         let mut conn = self.pool.get().await?;
         let res = redis::cmd("GET")
-            .arg(file_id)
+            .arg(self.info_key(file_id))
             .query_async::<Option<String>>(&mut *conn)
             .await?;
         drop(conn);
 
         res.map_or(Err(RustusError::FileNotFound), |res| {
-            serde_json::from_str::<FileInfo>(res.as_str()).map_err(RustusError::from)
+            FileInfo::from_stored(res.as_str()).map_err(RustusError::from)
         })
     }
 
     async fn remove_info(&self, file_id: &str) -> RustusResult<()> {
         let mut conn = self.pool.get().await?;
-        let resp = redis::cmd("DEL")
+        let (resp,) = redis::pipe()
+            .atomic()
+            .cmd("DEL")
+            .arg(self.info_key(file_id))
+            .cmd("ZREM")
+            .arg(self.index_key(EXPIRATIONS_KEY))
+            .arg(file_id)
+            .ignore()
+            .cmd("SREM")
+            .arg(self.index_key(UNFINISHED_KEY))
             .arg(file_id)
-            .query_async::<Option<usize>>(&mut *conn)
+            .ignore()
+            .cmd("ZREM")
+            .arg(self.index_key(UPLOADS_KEY))
+            .arg(file_id)
+            .ignore()
+            .query_async::<(Option<usize>,)>(&mut *conn)
             .await?;
         drop(conn);
         match resp {
@@ -98,21 +315,125 @@ impl InfoStorage for RedisInfoStorage {
             _ => Ok(()),
         }
     }
+
+    async fn remove_unchanged_info(&self, file_info: &FileInfo) -> RustusResult<()> {
+        let mut conn = self.pool.get().await?;
+        let removed = redis::Script::new(REMOVE_SCRIPT)
+            .key(self.info_key(&file_info.id))
+            .key(self.index_key(EXPIRATIONS_KEY))
+            .key(self.index_key(UNFINISHED_KEY))
+            .key(self.index_key(UPLOADS_KEY))
+            .arg(file_info.version)
+            .arg(file_info.id.as_str())
+            .invoke_async::<i64>(&mut *conn)
+            .await?;
+        drop(conn);
+        match removed {
+            -1 => Err(RustusError::FileNotFound),
+            0 => Err(RustusError::InfoVersionConflict),
+            _ => Ok(()),
+        }
+    }
+
+    async fn get_expired(&self, now: DateTime<Utc>) -> RustusResult<Vec<FileInfo>> {
+        let mut conn = self.pool.get().await?;
+        let ids = redis::cmd("ZRANGEBYSCORE")
+            .arg(self.index_key(EXPIRATIONS_KEY))
+            .arg("-inf")
+            .arg(now.timestamp())
+            .query_async::<Vec<String>>(&mut *conn)
+            .await?;
+        let expired = self
+            .load_indexed(&mut conn, "ZREM", EXPIRATIONS_KEY, ids)
+            .await?;
+        drop(conn);
+        Ok(expired)
+    }
+
+    async fn get_unfinished(&self) -> RustusResult<Vec<FileInfo>> {
+        let mut conn = self.pool.get().await?;
+        let ids = redis::cmd("SMEMBERS")
+            .arg(self.index_key(UNFINISHED_KEY))
+            .query_async::<Vec<String>>(&mut *conn)
+            .await?;
+        let unfinished = self
+            .load_indexed(&mut conn, "SREM", UNFINISHED_KEY, ids)
+            .await?;
+        drop(conn);
+        Ok(unfinished)
+    }
+
+    async fn list(
+        &self,
+        filter: &UploadFilter,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> RustusResult<UploadsPage> {
+        // Uploads are sorted by id, so the cursor
+        // is the last id that was checked.
+        let mut last_id = cursor.map(String::from);
+        let mut uploads = Vec::new();
+        let batch_size = limit.max(1);
+        let mut conn = self.pool.get().await?;
+        loop {
+            let start = last_id
+                .as_ref()
+                .map_or_else(|| String::from("-"), |id| format!("({id}"));
+            let ids = redis::cmd("ZRANGEBYLEX")
+                .arg(self.index_key(UPLOADS_KEY))
+                .arg(start)
+                .arg("+")
+                .arg("LIMIT")
+                .arg(0)
+                .arg(batch_size)
+                .query_async::<Vec<String>>(&mut *conn)
+                .await?;
+            let ids_count = ids.len();
+            let batch_last = ids.last().cloned();
+            for info in self
+                .load_indexed(&mut conn, "ZREM", UPLOADS_KEY, ids)
+                .await?
+            {
+                if uploads.len() == limit {
+                    return Ok(UploadsPage {
+                        uploads,
+                        next_cursor: last_id,
+                    });
+                }
+                last_id = Some(info.id.clone());
+                if filter.matches(&info) {
+                    uploads.push(info);
+                }
+            }
+            if ids_count < batch_size {
+                drop(conn);
+                return Ok(UploadsPage {
+                    uploads,
+                    next_cursor: None,
+                });
+            }
+            last_id = batch_last;
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{file_info::FileInfo, info_storage::base::InfoStorage};
+    use crate::{
+        errors::RustusError,
+        file_info::FileInfo,
+        info_storage::{base::InfoStorage, query::UploadFilter, test::check_get_expired},
+    };
 
     use super::RedisInfoStorage;
     use redis::AsyncCommands;
 
     fn get_url() -> String {
-        std::env::var("TEST_REDIS_URL").unwrap_or("redis://localhost/0".to_string())
+        std::env::var("TEST_REDIS_URL").unwrap_or_else(|_| "redis://localhost/0".to_string())
     }
 
-    async fn get_storage() -> RedisInfoStorage {
-        RedisInfoStorage::new(get_url().as_str(), None).unwrap()
+    fn get_storage() -> RedisInfoStorage {
+        RedisInfoStorage::new(get_url().as_str(), 10, None, String::new()).unwrap()
     }
 
     async fn get_redis() -> redis::aio::MultiplexedConnection {
@@ -122,7 +443,7 @@ mod tests {
 
     #[actix_rt::test]
     async fn success() {
-        let info_storage = get_storage().await;
+        let info_storage = get_storage();
         let file_info = FileInfo::new_test();
         info_storage.set_info(&file_info, true).await.unwrap();
         let mut redis = get_redis().await;
@@ -138,7 +459,8 @@ mod tests {
 
     #[actix_rt::test]
     async fn no_connection() {
-        let info_storage = RedisInfoStorage::new("redis://unknonwn_url/0", None).unwrap();
+        let info_storage =
+            RedisInfoStorage::new("redis://unknonwn_url/0", 10, None, String::new()).unwrap();
         let file_info = FileInfo::new_test();
         let res = info_storage.set_info(&file_info, true).await;
         assert!(res.is_err());
@@ -146,7 +468,7 @@ mod tests {
 
     #[actix_rt::test]
     async fn unknown_id() {
-        let info_storage = get_storage().await;
+        let info_storage = get_storage();
         let res = info_storage
             .get_info(uuid::Uuid::new_v4().to_string().as_str())
             .await;
@@ -155,16 +477,143 @@ mod tests {
 
     #[actix_rt::test]
     async fn expiration() {
-        let info_storage = get_storage().await;
+        let info_storage = get_storage();
         let res = info_storage
             .get_info(uuid::Uuid::new_v4().to_string().as_str())
             .await;
         assert!(res.is_err());
     }
 
+    #[actix_rt::test]
+    async fn get_expired() {
+        let info_storage = get_storage();
+        let expired_info = check_get_expired(&info_storage).await;
+        info_storage
+            .remove_info(expired_info.id.as_str())
+            .await
+            .unwrap();
+        let expired = info_storage.get_expired(chrono::Utc::now()).await.unwrap();
+        assert!(!expired.iter().any(|info| info.id == expired_info.id));
+    }
+
+    #[actix_rt::test]
+    async fn get_unfinished() {
+        let info_storage = get_storage();
+        let mut file_info = FileInfo::new_test();
+        info_storage.set_info(&file_info, true).await.unwrap();
+        let unfinished = info_storage.get_unfinished().await.unwrap();
+        assert!(unfinished.iter().any(|info| info.id == file_info.id));
+        file_info.offset = 10;
+        info_storage.set_info(&file_info, false).await.unwrap();
+        let unfinished = info_storage.get_unfinished().await.unwrap();
+        assert!(!unfinished.iter().any(|info| info.id == file_info.id));
+    }
+
+    #[actix_rt::test]
+    async fn update_indexes() {
+        let info_storage = get_storage();
+        let mut file_info = FileInfo::new_test();
+        info_storage.set_info(&file_info, true).await.unwrap();
+        let mut redis = get_redis().await;
+        file_info.expires_at = Some(chrono::Utc::now());
+        info_storage.update_info(&mut file_info).await.unwrap();
+        let score: Option<i64> = redis
+            .zscore("rustus_expirations", file_info.id.as_str())
+            .await
+            .unwrap();
+        assert_eq!(score, file_info.expires_at.map(|time| time.timestamp()));
+        file_info.offset = 10;
+        file_info.expires_at = None;
+        info_storage.update_info(&mut file_info).await.unwrap();
+        let score: Option<i64> = redis
+            .zscore("rustus_expirations", file_info.id.as_str())
+            .await
+            .unwrap();
+        assert!(score.is_none());
+        let unfinished: bool = redis
+            .sismember("rustus_unfinished", file_info.id.as_str())
+            .await
+            .unwrap();
+        assert!(!unfinished);
+        info_storage.remove_info(&file_info.id).await.unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn update_info() {
+        let info_storage = get_storage();
+        let mut file_info = FileInfo::new_test();
+        info_storage.set_info(&file_info, true).await.unwrap();
+        let mut stale_info = file_info.clone();
+        file_info.offset = 5;
+        info_storage.update_info(&mut file_info).await.unwrap();
+        assert_eq!(file_info.version, 1);
+        assert!(matches!(
+            info_storage.update_info(&mut stale_info).await,
+            Err(RustusError::InfoVersionConflict)
+        ));
+        let saved_info = info_storage.get_info(&file_info.id).await.unwrap();
+        assert_eq!(saved_info.offset, 5);
+        info_storage.remove_info(&file_info.id).await.unwrap();
+        // Removed uploads never come back.
+        assert!(matches!(
+            info_storage.update_info(&mut file_info).await,
+            Err(RustusError::FileNotFound)
+        ));
+        assert!(info_storage.get_info(&file_info.id).await.is_err());
+    }
+
+    #[actix_rt::test]
+    async fn list() {
+        // Unique prefix hides uploads of other tests.
+        let prefix = format!("{}:", uuid::Uuid::new_v4());
+        let info_storage =
+            RedisInfoStorage::new(get_url().as_str(), 10, None, prefix.clone()).unwrap();
+        let mut ids = Vec::new();
+        for _ in 0..5 {
+            let file_info = FileInfo::new_test();
+            info_storage.set_info(&file_info, true).await.unwrap();
+            ids.push(file_info.id);
+        }
+        // Other keys with the same prefix aren't uploads.
+        let mut redis = get_redis().await;
+        let lock_key = format!("{prefix}rustus_lock:{}", ids[0]);
+        redis.set::<_, _, ()>(&lock_key, "1").await.unwrap();
+        // Info removed by expiration is dropped from the index.
+        redis
+            .zadd::<_, _, _, ()>(format!("{prefix}rustus_uploads"), "expired", 0)
+            .await
+            .unwrap();
+        let filter = UploadFilter::default();
+        let mut listed = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = info_storage
+                .list(&filter, cursor.as_deref(), 2)
+                .await
+                .unwrap();
+            assert!(page.uploads.len() <= 2);
+            listed.extend(page.uploads.into_iter().map(|info| info.id));
+            cursor = page.next_cursor;
+            if cursor.is_none() {
+                break;
+            }
+        }
+        ids.sort();
+        assert_eq!(listed, ids);
+        let indexed: Vec<String> = redis
+            .zrange(format!("{prefix}rustus_uploads"), 0, -1)
+            .await
+            .unwrap();
+        assert_eq!(indexed, ids);
+        for id in ids {
+            info_storage.remove_info(&id).await.unwrap();
+        }
+        redis.del::<_, ()>(&lock_key).await.unwrap();
+    }
+
     #[actix_rt::test]
     async fn deletion_success() {
-        let mut info_storage = get_storage().await;
+        let mut info_storage = get_storage();
         info_storage.expiration = Some(1);
         let mut redis = get_redis().await;
         let file_info = FileInfo::new_test();
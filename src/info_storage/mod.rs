@@ -1,5 +1,6 @@
 pub mod base;
 pub mod impls;
+pub mod query;
 
 use derive_more::{Display, From};
 use std::str::FromStr;
@@ -15,6 +16,8 @@ pub enum AvailableInfoStorages {
     Files,
     #[display("redis-info-storage")]
     Redis,
+    #[display("db-info-storage")]
+    DB,
 }
 
 from_str!(AvailableInfoStorages, "info storage");
@@ -23,6 +26,7 @@ from_str!(AvailableInfoStorages, "info storage");
 pub enum InfoStorageImpl {
     File(impls::file_storage::FileInfoStorage),
     Redis(impls::redis_storage::RedisInfoStorage),
+    DB(impls::db_storage::DBInfoStorage),
 }
 
 impl AvailableInfoStorages {
@@ -36,19 +40,21 @@ impl AvailableInfoStorages {
             Self::Files => Ok(InfoStorageImpl::File(
                 impls::file_storage::FileInfoStorage::new(
                     config.info_storage_opts.info_dir.clone(),
+                    config.info_storage_opts.info_fsync_dir,
                 ),
             )),
             Self::Redis => Ok(InfoStorageImpl::Redis(
                 impls::redis_storage::RedisInfoStorage::new(
-                    config
-                        .info_storage_opts
-                        .info_db_dsn
-                        .clone()
-                        .unwrap()
-                        .as_str(),
+                    config.info_db_dsn()?,
+                    config.info_storage_opts.info_db_max_connections,
                     config.info_storage_opts.redis_info_expiration,
+                    config.info_storage_opts.redis_key_prefix.clone(),
                 )?,
             )),
+            Self::DB => Ok(InfoStorageImpl::DB(impls::db_storage::DBInfoStorage::new(
+                config.info_db_dsn()?,
+                config.info_storage_opts.info_db_max_connections,
+            )?)),
         }
     }
 }
@@ -58,6 +64,7 @@ impl base::InfoStorage for InfoStorageImpl {
         match self {
             Self::File(storage) => storage.prepare().await,
             Self::Redis(storage) => storage.prepare().await,
+            Self::DB(storage) => storage.prepare().await,
         }
     }
 
@@ -69,6 +76,15 @@ impl base::InfoStorage for InfoStorageImpl {
         match self {
             Self::File(storage) => storage.set_info(file_info, create).await,
             Self::Redis(storage) => storage.set_info(file_info, create).await,
+            Self::DB(storage) => storage.set_info(file_info, create).await,
+        }
+    }
+
+    async fn update_info(&self, file_info: &mut crate::file_info::FileInfo) -> RustusResult<()> {
+        match self {
+            Self::File(storage) => storage.update_info(file_info).await,
+            Self::Redis(storage) => storage.update_info(file_info).await,
+            Self::DB(storage) => storage.update_info(file_info).await,
         }
     }
 
@@ -76,6 +92,7 @@ impl base::InfoStorage for InfoStorageImpl {
         match self {
             Self::File(storage) => storage.get_info(file_id).await,
             Self::Redis(storage) => storage.get_info(file_id).await,
+            Self::DB(storage) => storage.get_info(file_id).await,
         }
     }
 
@@ -83,6 +100,78 @@ impl base::InfoStorage for InfoStorageImpl {
         match self {
             Self::File(storage) => storage.remove_info(file_id).await,
             Self::Redis(storage) => storage.remove_info(file_id).await,
+            Self::DB(storage) => storage.remove_info(file_id).await,
+        }
+    }
+
+    async fn remove_unchanged_info(
+        &self,
+        file_info: &crate::file_info::FileInfo,
+    ) -> RustusResult<()> {
+        match self {
+            Self::File(storage) => storage.remove_unchanged_info(file_info).await,
+            Self::Redis(storage) => storage.remove_unchanged_info(file_info).await,
+            Self::DB(storage) => storage.remove_unchanged_info(file_info).await,
+        }
+    }
+
+    async fn get_expired(
+        &self,
+        now: chrono::DateTime<chrono::Utc>,
+    ) -> RustusResult<Vec<crate::file_info::FileInfo>> {
+        match self {
+            Self::File(storage) => storage.get_expired(now).await,
+            Self::Redis(storage) => storage.get_expired(now).await,
+            Self::DB(storage) => storage.get_expired(now).await,
+        }
+    }
+
+    async fn list(
+        &self,
+        filter: &query::UploadFilter,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> RustusResult<query::UploadsPage> {
+        match self {
+            Self::File(storage) => storage.list(filter, cursor, limit).await,
+            Self::Redis(storage) => storage.list(filter, cursor, limit).await,
+            Self::DB(storage) => storage.list(filter, cursor, limit).await,
+        }
+    }
+
+    async fn get_unfinished(&self) -> RustusResult<Vec<crate::file_info::FileInfo>> {
+        match self {
+            Self::File(storage) => storage.get_unfinished().await,
+            Self::Redis(storage) => storage.get_unfinished().await,
+            Self::DB(storage) => storage.get_unfinished().await,
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::base::InfoStorage;
+    use crate::file_info::FileInfo;
+
+    /// Check that only expired uploads are found.
+    ///
+    /// Storage might contain uploads of other tests,
+    /// so only uploads saved here are checked.
+    /// Returns the expired upload.
+    pub async fn check_get_expired(storage: &impl InfoStorage) -> FileInfo {
+        let mut expired_info = FileInfo::new_test();
+        expired_info.expires_at = Some(chrono::Utc::now() - chrono::Duration::seconds(10));
+        let mut alive_info = FileInfo::new_test();
+        alive_info.expires_at = Some(chrono::Utc::now() + chrono::Duration::seconds(10));
+        let eternal_info = FileInfo::new_test();
+        for file_info in [&expired_info, &alive_info, &eternal_info] {
+            storage.set_info(file_info, true).await.unwrap();
         }
+        let expired = storage.get_expired(chrono::Utc::now()).await.unwrap();
+        let is_found = |file_info: &FileInfo| expired.iter().any(|info| info.id == file_info.id);
+        assert!(is_found(&expired_info));
+        assert!(!is_found(&alive_info));
+        assert!(!is_found(&eternal_info));
+        expired_info
     }
 }
@@ -0,0 +1,156 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::file_info::FileInfo;
+
+/// State of an upload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UploadState {
+    Finished,
+    Unfinished,
+}
+
+/// Filter for listing uploads.
+///
+/// Every field is optional and
+/// an upload must match all given fields.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UploadFilter {
+    pub state: Option<UploadState>,
+    pub created_after: Option<DateTime<Utc>>,
+    pub created_before: Option<DateTime<Utc>>,
+    /// Upload must have this key in its metadata.
+    pub metadata_key: Option<String>,
+    /// Value of the metadata key.
+    /// It's ignored if the key isn't set.
+    pub metadata_value: Option<String>,
+    pub is_partial: Option<bool>,
+    pub is_final: Option<bool>,
+}
+
+impl UploadFilter {
+    /// Check if an upload matches the filter.
+    pub fn matches(&self, file_info: &FileInfo) -> bool {
+        let finished = file_info.length == Some(file_info.offset);
+        let state_matches = match self.state {
+            Some(UploadState::Finished) => finished,
+            Some(UploadState::Unfinished) => !finished,
+            None => true,
+        };
+        let metadata_matches = self.metadata_key.as_ref().is_none_or(|key| {
+            metadata_has(&file_info.metadata, key, self.metadata_value.as_deref())
+        });
+        state_matches
+            && metadata_matches
+            && self
+                .created_after
+                .is_none_or(|after| file_info.created_at >= after)
+            && self
+                .created_before
+                .is_none_or(|before| file_info.created_at < before)
+            && self
+                .is_partial
+                .is_none_or(|is_partial| file_info.is_partial == is_partial)
+            && self
+                .is_final
+                .is_none_or(|is_final| file_info.is_final == is_final)
+    }
+}
+
+fn metadata_has(metadata: &HashMap<String, String>, key: &str, value: Option<&str>) -> bool {
+    metadata
+        .get(key)
+        .is_some_and(|actual| value.is_none_or(|value| actual == value))
+}
+
+/// Single page of listed uploads.
+#[derive(Debug, Clone, Serialize)]
+pub struct UploadsPage {
+    pub uploads: Vec<FileInfo>,
+    /// Cursor to get the next page.
+    /// If it's empty, there are no more uploads.
+    pub next_cursor: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{UploadFilter, UploadState};
+    use crate::file_info::FileInfo;
+
+    #[test]
+    fn empty_filter() {
+        assert!(UploadFilter::default().matches(&FileInfo::new_test()));
+    }
+
+    #[test]
+    fn state() {
+        let mut file_info = FileInfo::new_test();
+        let finished = UploadFilter {
+            state: Some(UploadState::Finished),
+            ..UploadFilter::default()
+        };
+        let unfinished = UploadFilter {
+            state: Some(UploadState::Unfinished),
+            ..UploadFilter::default()
+        };
+        assert!(!finished.matches(&file_info));
+        assert!(unfinished.matches(&file_info));
+        file_info.offset = 10;
+        assert!(finished.matches(&file_info));
+        assert!(!unfinished.matches(&file_info));
+    }
+
+    #[test]
+    fn created_at() {
+        let file_info = FileInfo::new_test();
+        let hour = chrono::Duration::hours(1);
+        let filter = UploadFilter {
+            created_after: Some(file_info.created_at - hour),
+            created_before: Some(file_info.created_at + hour),
+            ..UploadFilter::default()
+        };
+        assert!(filter.matches(&file_info));
+        let filter = UploadFilter {
+            created_after: Some(file_info.created_at + hour),
+            ..UploadFilter::default()
+        };
+        assert!(!filter.matches(&file_info));
+    }
+
+    #[test]
+    fn metadata() {
+        let mut file_info = FileInfo::new_test();
+        file_info
+            .metadata
+            .insert(String::from("filename"), String::from("video.mp4"));
+        let mut filter = UploadFilter {
+            metadata_key: Some(String::from("filename")),
+            ..UploadFilter::default()
+        };
+        assert!(filter.matches(&file_info));
+        filter.metadata_value = Some(String::from("video.mp4"));
+        assert!(filter.matches(&file_info));
+        filter.metadata_value = Some(String::from("image.png"));
+        assert!(!filter.matches(&file_info));
+        filter.metadata_key = Some(String::from("unknown"));
+        filter.metadata_value = None;
+        assert!(!filter.matches(&file_info));
+    }
+
+    #[test]
+    fn concatenation() {
+        let mut file_info = FileInfo::new_test();
+        file_info.is_partial = true;
+        let filter = UploadFilter {
+            is_partial: Some(true),
+            is_final: Some(false),
+            ..UploadFilter::default()
+        };
+        assert!(filter.matches(&file_info));
+        file_info.is_partial = false;
+        assert!(!filter.matches(&file_info));
+    }
+}
@@ -0,0 +1,32 @@
+use std::time::Duration;
+
+use crate::errors::RustusResult;
+
+/// Trait for every upload locker.
+///
+/// Lockers guarantee that only one request
+/// at a time can modify an upload.
+pub trait Locker {
+    /// Try to lock an upload.
+    ///
+    /// Returns `false` if the upload is already locked.
+    /// The `token` is a unique value of the lock,
+    /// so only the owner of the lock can release it.
+    async fn lock(&self, file_id: &str, token: &str) -> RustusResult<bool>;
+
+    /// Prolong the lock.
+    ///
+    /// Returns `false` if the lock has expired
+    /// and now it doesn't belong to the owner of the token.
+    async fn refresh(&self, file_id: &str, token: &str) -> RustusResult<bool>;
+
+    /// Release the lock.
+    ///
+    /// Locks of other owners must never be released.
+    async fn unlock(&self, file_id: &str, token: &str) -> RustusResult<()>;
+
+    /// How often the lock must be refreshed.
+    ///
+    /// Locks that never expire don't need to be refreshed.
+    fn refresh_interval(&self) -> Option<Duration>;
+}
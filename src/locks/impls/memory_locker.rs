@@ -0,0 +1,78 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::Duration,
+};
+
+use crate::{errors::RustusResult, locks::base::Locker};
+
+/// Locker that keeps locks in memory.
+///
+/// It's only suitable if a single instance of Rustus
+/// handles uploads, because locks aren't shared between processes.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryLocker {
+    /// Tokens of locked uploads.
+    locks: Arc<Mutex<HashMap<String, String>>>,
+}
+
+impl MemoryLocker {
+    fn locks(&self) -> MutexGuard<'_, HashMap<String, String>> {
+        // Map is always consistent, even if some thread has panicked.
+        self.locks.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Locker for MemoryLocker {
+    async fn lock(&self, file_id: &str, token: &str) -> RustusResult<bool> {
+        match self.locks().entry(file_id.to_string()) {
+            Entry::Occupied(_) => Ok(false),
+            Entry::Vacant(entry) => {
+                entry.insert(token.to_string());
+                Ok(true)
+            }
+        }
+    }
+
+    async fn refresh(&self, file_id: &str, token: &str) -> RustusResult<bool> {
+        Ok(self.locks().get(file_id).map(String::as_str) == Some(token))
+    }
+
+    async fn unlock(&self, file_id: &str, token: &str) -> RustusResult<()> {
+        if let Entry::Occupied(entry) = self.locks().entry(file_id.to_string()) {
+            if entry.get() == token {
+                entry.remove();
+            }
+        }
+        Ok(())
+    }
+
+    fn refresh_interval(&self) -> Option<Duration> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MemoryLocker;
+    use crate::locks::base::Locker;
+
+    #[actix_rt::test]
+    async fn lock_twice() {
+        let locker = MemoryLocker::default();
+        assert!(locker.lock("file", "first").await.unwrap());
+        assert!(!locker.lock("file", "second").await.unwrap());
+        assert!(locker.lock("other_file", "second").await.unwrap());
+    }
+
+    #[actix_rt::test]
+    async fn unlock() {
+        let locker = MemoryLocker::default();
+        assert!(locker.lock("file", "first").await.unwrap());
+        // Only the owner can release the lock.
+        locker.unlock("file", "second").await.unwrap();
+        assert!(!locker.lock("file", "second").await.unwrap());
+        locker.unlock("file", "first").await.unwrap();
+        assert!(locker.lock("file", "second").await.unwrap());
+    }
+}
@@ -0,0 +1,2 @@
+pub mod memory_locker;
+pub mod redis_locker;
@@ -0,0 +1,156 @@
+use std::time::Duration;
+
+use mobc::Pool;
+
+use crate::{
+    errors::RustusResult, info_storage::impls::redis_storage::RedisConnectionManager,
+    locks::base::Locker,
+};
+
+/// Prefix of keys that hold locks.
+const LOCK_PREFIX: &str = "rustus_lock:";
+
+/// Prolong the lock only if it belongs to the owner of the token.
+const REFRESH_SCRIPT: &str = r#"
+if redis.call("GET", KEYS[1]) == ARGV[1] then
+    return redis.call("PEXPIRE", KEYS[1], ARGV[2])
+end
+return 0
+"#;
+
+/// Remove the lock only if it belongs to the owner of the token.
+const UNLOCK_SCRIPT: &str = r#"
+if redis.call("GET", KEYS[1]) == ARGV[1] then
+    return redis.call("DEL", KEYS[1])
+end
+return 0
+"#;
+
+/// Locker that keeps locks in redis.
+///
+/// Locks are shared between all instances that use
+/// the same redis. Every lock expires, so uploads
+/// don't stay locked forever if Rustus has crashed.
+/// While the lock is held, it's refreshed periodically.
+#[derive(Clone, Debug)]
+pub struct RedisLocker {
+    pool: Pool<RedisConnectionManager>,
+    expiration: Duration,
+    key_prefix: String,
+}
+
+impl RedisLocker {
+    /// Create new `RedisLocker`.
+    ///
+    /// # Errors
+    ///
+    /// Might return an error, if redis client cannot be created.
+    pub fn new(
+        db_dsn: &str,
+        max_connections: u32,
+        expiration: Duration,
+        key_prefix: String,
+    ) -> RustusResult<Self> {
+        let client = redis::Client::open(db_dsn)?;
+        let manager = RedisConnectionManager::new(client);
+        let pool = mobc::Pool::builder()
+            .max_open(u64::from(max_connections))
+            .build(manager);
+        Ok(Self {
+            pool,
+            expiration,
+            key_prefix,
+        })
+    }
+
+    fn lock_key(&self, file_id: &str) -> String {
+        format!("{}{LOCK_PREFIX}{file_id}", self.key_prefix)
+    }
+
+    fn expiration_millis(&self) -> u64 {
+        u64::try_from(self.expiration.as_millis()).unwrap_or(u64::MAX)
+    }
+}
+
+impl Locker for RedisLocker {
+    async fn lock(&self, file_id: &str, token: &str) -> RustusResult<bool> {
+        let mut conn = self.pool.get().await?;
+        let reply = redis::cmd("SET")
+            .arg(self.lock_key(file_id))
+            .arg(token)
+            .arg("NX")
+            .arg("PX")
+            .arg(self.expiration_millis())
+            .query_async::<Option<String>>(&mut *conn)
+            .await?;
+        drop(conn);
+        Ok(reply.is_some())
+    }
+
+    async fn refresh(&self, file_id: &str, token: &str) -> RustusResult<bool> {
+        let mut conn = self.pool.get().await?;
+        let refreshed = redis::Script::new(REFRESH_SCRIPT)
+            .key(self.lock_key(file_id))
+            .arg(token)
+            .arg(self.expiration_millis())
+            .invoke_async::<i64>(&mut *conn)
+            .await?;
+        drop(conn);
+        Ok(refreshed == 1)
+    }
+
+    async fn unlock(&self, file_id: &str, token: &str) -> RustusResult<()> {
+        let mut conn = self.pool.get().await?;
+        redis::Script::new(UNLOCK_SCRIPT)
+            .key(self.lock_key(file_id))
+            .arg(token)
+            .invoke_async::<i64>(&mut *conn)
+            .await?;
+        drop(conn);
+        Ok(())
+    }
+
+    fn refresh_interval(&self) -> Option<Duration> {
+        Some(self.expiration / 3)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::RedisLocker;
+    use crate::locks::base::Locker;
+
+    fn get_locker(expiration: Duration) -> RedisLocker {
+        let redis_url =
+            std::env::var("TEST_REDIS_URL").unwrap_or_else(|_| "redis://localhost/0".into());
+        RedisLocker::new(redis_url.as_str(), 10, expiration, String::new()).unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn lock_and_unlock() {
+        let locker = get_locker(Duration::from_secs(10));
+        let file_id = uuid::Uuid::new_v4().to_string();
+        assert!(locker.lock(&file_id, "first").await.unwrap());
+        assert!(!locker.lock(&file_id, "second").await.unwrap());
+        // Only the owner can release the lock.
+        locker.unlock(&file_id, "second").await.unwrap();
+        assert!(!locker.lock(&file_id, "second").await.unwrap());
+        locker.unlock(&file_id, "first").await.unwrap();
+        assert!(locker.lock(&file_id, "second").await.unwrap());
+        locker.unlock(&file_id, "second").await.unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn expiration() {
+        let locker = get_locker(Duration::from_millis(500));
+        let file_id = uuid::Uuid::new_v4().to_string();
+        assert!(locker.lock(&file_id, "first").await.unwrap());
+        assert!(locker.refresh(&file_id, "first").await.unwrap());
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(!locker.refresh(&file_id, "first").await.unwrap());
+        assert!(locker.lock(&file_id, "second").await.unwrap());
+        locker.unlock(&file_id, "second").await.unwrap();
+    }
+}
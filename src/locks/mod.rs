@@ -0,0 +1,267 @@
+pub mod base;
+pub mod impls;
+
+use std::{future::Future, sync::Arc, time::Duration};
+
+use tokio::{sync::watch, task::JoinHandle};
+
+use crate::{
+    errors::{RustusError, RustusResult},
+    info_storage::AvailableInfoStorages,
+    RustusConf,
+};
+
+use base::Locker;
+
+#[derive(Clone, Debug)]
+pub enum LockerImpl {
+    Memory(impls::memory_locker::MemoryLocker),
+    Redis(impls::redis_locker::RedisLocker),
+}
+
+impl LockerImpl {
+    /// Create locker that matches the info storage.
+    ///
+    /// If information is stored in redis, locks are
+    /// stored there too, so they're shared between instances.
+    /// Otherwise locks are kept in memory.
+    ///
+    /// # Errors
+    ///
+    /// Might return an error, if redis client cannot be created.
+    pub fn new(config: &RustusConf) -> RustusResult<Self> {
+        match (
+            &config.info_storage_opts.info_storage,
+            &config.info_storage_opts.info_db_dsn,
+        ) {
+            (AvailableInfoStorages::Redis, Some(dsn)) => {
+                Ok(Self::Redis(impls::redis_locker::RedisLocker::new(
+                    dsn.as_str(),
+                    config.info_storage_opts.info_db_max_connections,
+                    Duration::from_secs(config.info_storage_opts.redis_lock_expiration),
+                    config.info_storage_opts.redis_key_prefix.clone(),
+                )?))
+            }
+            _ => Ok(Self::Memory(impls::memory_locker::MemoryLocker::default())),
+        }
+    }
+
+    /// Lock an upload.
+    ///
+    /// The lock is held until the returned guard is released or dropped.
+    ///
+    /// # Errors
+    ///
+    /// Returns `UploadLocked` error if the upload
+    /// is already locked by another request.
+    pub async fn acquire(&self, file_id: &str) -> RustusResult<UploadLock> {
+        let token = uuid::Uuid::new_v4().to_string();
+        if !self.lock(file_id, &token).await? {
+            return Err(RustusError::UploadLocked);
+        }
+        let expired = Arc::new(watch::Sender::new(false));
+        let refresher = self.refresh_interval().map(|interval| {
+            let locker = self.clone();
+            let file_id = file_id.to_string();
+            let token = token.clone();
+            let expired = expired.clone();
+            tokio::spawn(async move {
+                let mut ticker = tokio::time::interval(interval);
+                // The first tick completes immediately.
+                ticker.tick().await;
+                loop {
+                    ticker.tick().await;
+                    match locker.refresh(&file_id, &token).await {
+                        Ok(true) => {}
+                        Ok(false) => {
+                            log::warn!("Lock of upload {file_id} has expired");
+                            expired.send_replace(true);
+                            break;
+                        }
+                        Err(err) => log::error!("Cannot refresh lock of upload {file_id}: {err}"),
+                    }
+                }
+            })
+        });
+        Ok(UploadLock {
+            locker: self.clone(),
+            file_id: file_id.to_string(),
+            token,
+            refresher,
+            expired,
+            released: false,
+        })
+    }
+
+    /// Run a future while the upload is locked.
+    ///
+    /// If the lock expires before the future is complete,
+    /// the future is cancelled, because another request
+    /// might have locked the upload already.
+    ///
+    /// # Errors
+    ///
+    /// Returns `UploadLocked` error if the upload is already locked,
+    /// `LockExpired` error if the lock has expired,
+    /// or the error returned by the future.
+    pub async fn with_lock<T>(
+        &self,
+        file_id: &str,
+        future: impl Future<Output = RustusResult<T>>,
+    ) -> RustusResult<T> {
+        let lock = self.acquire(file_id).await?;
+        let result = lock.run(future).await;
+        if let Err(err) = lock.release().await {
+            log::error!("Cannot release lock of upload {file_id}: {err}");
+        }
+        result
+    }
+}
+
+impl Locker for LockerImpl {
+    async fn lock(&self, file_id: &str, token: &str) -> RustusResult<bool> {
+        match self {
+            Self::Memory(locker) => locker.lock(file_id, token).await,
+            Self::Redis(locker) => locker.lock(file_id, token).await,
+        }
+    }
+
+    async fn refresh(&self, file_id: &str, token: &str) -> RustusResult<bool> {
+        match self {
+            Self::Memory(locker) => locker.refresh(file_id, token).await,
+            Self::Redis(locker) => locker.refresh(file_id, token).await,
+        }
+    }
+
+    async fn unlock(&self, file_id: &str, token: &str) -> RustusResult<()> {
+        match self {
+            Self::Memory(locker) => locker.unlock(file_id, token).await,
+            Self::Redis(locker) => locker.unlock(file_id, token).await,
+        }
+    }
+
+    fn refresh_interval(&self) -> Option<Duration> {
+        match self {
+            Self::Memory(locker) => locker.refresh_interval(),
+            Self::Redis(locker) => locker.refresh_interval(),
+        }
+    }
+}
+
+/// Lock of a single upload.
+///
+/// If the guard is dropped without releasing,
+/// e.g. when the client has disconnected and the
+/// request handler was cancelled, the lock is released
+/// in background.
+pub struct UploadLock {
+    locker: LockerImpl,
+    file_id: String,
+    token: String,
+    refresher: Option<JoinHandle<()>>,
+    /// Becomes `true` when the lock can't be refreshed anymore.
+    expired: Arc<watch::Sender<bool>>,
+    released: bool,
+}
+
+impl UploadLock {
+    /// Run a future until it completes or the lock expires.
+    async fn run<T>(&self, future: impl Future<Output = RustusResult<T>>) -> RustusResult<T> {
+        let mut expired = self.expired.subscribe();
+        tokio::select! {
+            result = future => result,
+            // The sender is owned by the lock, so it's never closed here.
+            _ = expired.wait_for(|expired| *expired) => Err(RustusError::LockExpired),
+        }
+    }
+
+    /// Release the lock.
+    ///
+    /// # Errors
+    ///
+    /// Might return an error if the locker is unavailable.
+    /// In that case the lock is released after it expires.
+    pub async fn release(mut self) -> RustusResult<()> {
+        self.released = true;
+        if let Some(refresher) = self.refresher.take() {
+            refresher.abort();
+        }
+        self.locker.unlock(&self.file_id, &self.token).await
+    }
+}
+
+impl Drop for UploadLock {
+    fn drop(&mut self) {
+        if let Some(refresher) = self.refresher.take() {
+            refresher.abort();
+        }
+        if self.released {
+            return;
+        }
+        let locker = self.locker.clone();
+        let file_id = std::mem::take(&mut self.file_id);
+        let token = std::mem::take(&mut self.token);
+        tokio::spawn(async move {
+            if let Err(err) = locker.unlock(&file_id, &token).await {
+                log::error!("Cannot release lock of upload {file_id}: {err}");
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{impls::memory_locker::MemoryLocker, LockerImpl};
+    use crate::errors::{RustusError, RustusResult};
+
+    #[actix_rt::test]
+    async fn acquire_locked() {
+        let locker = LockerImpl::Memory(MemoryLocker::default());
+        let lock = locker.acquire("file").await.unwrap();
+        assert!(matches!(
+            locker.acquire("file").await,
+            Err(RustusError::UploadLocked)
+        ));
+        lock.release().await.unwrap();
+        assert!(locker.acquire("file").await.is_ok());
+    }
+
+    #[actix_rt::test]
+    async fn run_until_expired() {
+        let locker = LockerImpl::Memory(MemoryLocker::default());
+        let lock = locker.acquire("file").await.unwrap();
+        assert_eq!(lock.run(async { Ok(1) }).await.unwrap(), 1);
+        lock.expired.send_replace(true);
+        let result = lock
+            .run(futures::future::pending::<RustusResult<()>>())
+            .await;
+        assert!(matches!(result, Err(RustusError::LockExpired)));
+        lock.release().await.unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn with_lock() {
+        let locker = LockerImpl::Memory(MemoryLocker::default());
+        let result = locker
+            .with_lock("file", async {
+                // Upload is locked while the future is running.
+                assert!(matches!(
+                    locker.acquire("file").await,
+                    Err(RustusError::UploadLocked)
+                ));
+                Ok(())
+            })
+            .await;
+        assert!(result.is_ok());
+        assert!(locker.acquire("file").await.is_ok());
+    }
+
+    #[actix_rt::test]
+    async fn release_on_drop() {
+        let locker = LockerImpl::Memory(MemoryLocker::default());
+        drop(locker.acquire("file").await.unwrap());
+        // Lock is released by a background task.
+        tokio::task::yield_now().await;
+        assert!(locker.acquire("file").await.is_ok());
+    }
+}
@@ -19,7 +19,7 @@
     )
 ]
 
-use std::str::FromStr;
+use std::{str::FromStr, time::Duration};
 
 use actix_cors::Cors;
 use actix_web::{
@@ -40,28 +40,37 @@ use wildmatch::WildMatch;
 
 use crate::{
     errors::{RustusError, RustusResult},
+    protocol::extensions::Extensions,
+    reload::{Reloadable, Reloader},
     server::rustus_service,
     state::State,
 };
 
+mod admin;
+mod commands;
 mod config;
+mod config_file;
 mod data_storage;
 mod errors;
 mod file_info;
 mod info_storage;
+mod locks;
 mod metrics;
 mod notifiers;
 mod protocol;
+mod reload;
 mod routes;
 mod server;
+mod shutdown;
 mod state;
+mod tenants;
 mod utils;
 
 #[cfg(not(target_env = "msvc"))]
 #[global_allocator]
 static GLOBAL: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
 
-fn greeting(app_conf: &RustusConf) {
+fn greeting(app_conf: &RustusConf, tenants: &[(String, RustusConf)]) {
     let extensions = app_conf
         .tus_extensions
         .clone()
@@ -82,6 +91,9 @@ fn greeting(app_conf: &RustusConf) {
     eprintln!("Base URL: /{}", app_conf.base_url());
     eprintln!("Available extensions: {extensions}");
     eprintln!("Enabled hooks: {hooks}");
+    for (name, tenant_conf) in tenants {
+        eprintln!("Tenant {name}: /{}", tenant_conf.base_url());
+    }
     eprintln!();
     eprintln!();
 }
@@ -93,8 +105,11 @@ fn greeting(app_conf: &RustusConf) {
 /// If the origins vector is empty all origins are
 /// welcome, otherwise it will create a wildcard match for
 /// every host.
-fn create_cors(origins: Vec<String>, additional_headers: Vec<String>) -> Cors {
-    let mut cors = Cors::default()
+///
+/// Origins are taken from the current options
+/// on every request, so they can be changed without restart.
+fn create_cors(reloadable: Reloadable, additional_headers: Vec<String>) -> Cors {
+    Cors::default()
         .allowed_methods(vec!["OPTIONS", "GET", "HEAD", "POST", "PATCH", "DELETE"])
         .allowed_headers(vec![
             "Content-Type",
@@ -128,22 +143,18 @@ fn create_cors(origins: Vec<String>, additional_headers: Vec<String>) -> Cors {
             "Upload-Defer-Length",
             "Upload-Concat",
             "Upload-Offset",
+            "Upload-Expires",
+            "Upload-Digest",
         ])
-        .max_age(86400);
-
-    // We allow any origin by default if no origin is specified.
-    if origins.is_empty() {
-        return cors.allow_any_origin();
-    }
-
-    // Adding origins.
-    for origin in origins {
-        cors = cors.allowed_origin_fn(move |request_origin, _| {
-            WildMatch::new(&origin) == request_origin.to_str().unwrap_or_default()
-        });
-    }
-
-    cors
+        .max_age(86400)
+        .allowed_origin_fn(move |request_origin, _| {
+            let origins = &reloadable.current().cors;
+            // We allow any origin by default if no origin is specified.
+            origins.is_empty()
+                || origins.iter().any(|origin| {
+                    WildMatch::new(origin) == request_origin.to_str().unwrap_or_default()
+                })
+        })
 }
 
 /// Creates Actix server.
@@ -154,23 +165,39 @@ fn create_cors(origins: Vec<String>, additional_headers: Vec<String>) -> Cors {
 /// This storage can later be used in
 /// handlers.
 ///
+/// Every tenant is served under its own URL
+/// with its own state.
+///
 /// # Errors
 ///
 /// This function may throw an error
 /// if the server can't be bound to the
 /// given address.
 #[allow(clippy::too_many_lines)]
-pub fn create_server(state: State) -> RustusResult<Server> {
+pub fn create_server(
+    state: State,
+    tenants: Vec<(String, State)>,
+    reloader: Reloader,
+) -> RustusResult<Server> {
     let host = state.config.host.clone();
     let port = state.config.port;
     let disable_health_log = state.config.disable_health_access_log;
-    let cors_hosts = state.config.cors.clone();
+    let reloadable = state.reloadable.clone();
     let workers = state.config.workers;
+    let separate_admin = state.config.admin_opts.port.is_some();
     let proxy_headers = state
         .config
         .notification_opts
         .hooks_http_proxy_headers
         .clone();
+    // Longer URLs are registered first,
+    // so nested URLs aren't captured by shorter ones.
+    let mut services = tenants
+        .iter()
+        .map(|(_, tenant)| tenant.clone())
+        .collect::<Vec<_>>();
+    services.push(state.clone());
+    services.sort_by_key(|service| std::cmp::Reverse(service.config.base_url().len()));
     let metrics = RustusMetrics::new()?;
     let metrics_middleware = actix_web_prom::PrometheusMetricsBuilder::new("")
         .endpoint("/metrics")
@@ -189,10 +216,20 @@ pub fn create_server(state: State) -> RustusResult<Server> {
         App::new()
             .app_data(web::Data::new(metrics.clone()))
             .route("/health", web::get().to(routes::health_check))
-            .configure(rustus_service(state.clone()))
+            .configure(|web_app| {
+                // Otherwise admin API has its own server.
+                if !separate_admin {
+                    admin::setup(state.clone(), tenants.clone(), reloader.clone())(web_app);
+                }
+            })
+            .configure(|web_app| {
+                for service in &services {
+                    rustus_service(service.clone())(web_app);
+                }
+            })
             .wrap(metrics_middleware.clone())
             .wrap(logger)
-            .wrap(create_cors(cors_hosts.clone(), proxy_headers.clone()))
+            .wrap(create_cors(reloadable.clone(), proxy_headers.clone()))
             .wrap(sentry_actix::Sentry::new())
             // Middleware that overrides method of a request if
             // "X-HTTP-Method-Override" header is provided.
@@ -230,6 +267,8 @@ pub fn create_server(state: State) -> RustusResult<Server> {
             .default_service(web::route().to(routes::not_found))
     })
     .keep_alive(KeepAlive::Disabled)
+    // Shutdown is done in main, after pending work is finished.
+    .disable_signals()
     .bind((host, port))?;
 
     // If custom workers count variable is provided.
@@ -273,26 +312,126 @@ async fn main() -> std::io::Result<()> {
     // I may change it to another log system like `fern` later, idk.
     setup_logging(&app_conf)?;
 
-    #[allow(clippy::collection_is_never_read)]
-    let mut _guard = None;
-    if let Some(dsn) = &app_conf.sentry_opts.dsn {
+    let _guard = app_conf.sentry_opts.dsn.as_ref().map(|dsn| {
         log::info!("Setting up sentry .");
-        _guard = Some(sentry::init((
+        sentry::init((
             dsn.as_str(),
             sentry::ClientOptions {
                 debug: true,
                 sample_rate: app_conf.sentry_opts.sample_rate,
                 ..Default::default()
             },
-        )));
+        ))
+    });
+
+    // Maintenance commands don't start the server.
+    if let Some(command) = &app_conf.command {
+        if !commands::run(&app_conf, command).await? {
+            std::process::exit(1);
+        }
+        return Ok(());
     }
 
+    let tenant_confs = match &app_conf.tenants {
+        Some(tenants) => tenants.configs(&app_conf)?,
+        None => Vec::new(),
+    };
+
     // Printing cool message.
-    greeting(&app_conf);
+    greeting(&app_conf, &tenant_confs);
 
     let state = State::new(app_conf.clone()).await?;
+    let mut tenants = Vec::new();
+    for (name, tenant_conf) in tenant_confs {
+        tenants.push((name, State::new(tenant_conf).await?));
+    }
+
+    for service in std::iter::once(&state).chain(tenants.iter().map(|(_, tenant)| tenant)) {
+        if service.config.check_consistency {
+            let fixed = protocol::consistency::check_uploads(service).await?;
+            log::info!(
+                "Consistency check has fixed {fixed} uploads at /{}",
+                service.config.base_url()
+            );
+        }
+
+        // Starting background removal of abandoned uploads.
+        if service
+            .config
+            .tus_extensions
+            .contains(&Extensions::Expiration)
+        {
+            tokio::spawn(protocol::expiration::run_reaper(service.clone()));
+        }
+    }
+
+    // Reloading hooks and limits without restart.
+    let reloader = Reloader::new(&state, &tenants);
+    #[cfg(unix)]
+    tokio::spawn(reload::reload_on_hangup(reloader.clone()));
+
+    let shutdown_timeout = Duration::from_secs(app_conf.shutdown_timeout);
+    let pending = std::iter::once(&state)
+        .chain(tenants.iter().map(|(_, tenant)| tenant))
+        .map(|service| service.pending.clone())
+        .collect::<Vec<_>>();
 
     // Creating actual server and running it.
-    let server = create_server(state)?;
-    server.await
+    let server = create_server(state.clone(), tenants.clone(), reloader.clone())?;
+    let admin_server = admin::create_server(state, tenants, reloader)?;
+    let handles = std::iter::once(server.handle())
+        .chain(admin_server.as_ref().map(Server::handle))
+        .collect::<Vec<_>>();
+    tokio::spawn(shutdown::on_signal(handles, pending, shutdown_timeout));
+    futures::future::try_join_all(std::iter::once(server).chain(admin_server))
+        .await
+        .map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::create_cors;
+    use crate::{reload::ReloadableOptions, State};
+    use actix_web::{
+        http::header,
+        test::{call_service, init_service, TestRequest},
+        web, App, HttpResponse,
+    };
+
+    #[actix_rt::test]
+    async fn reload_cors() {
+        let state = State::test_new().await;
+        let service = init_service(
+            App::new()
+                .route("/", web::get().to(HttpResponse::Ok))
+                .wrap(create_cors(state.reloadable.clone(), Vec::new())),
+        )
+        .await;
+        let allowed_origin = |origin: &'static str| {
+            let request = TestRequest::get()
+                .uri("/")
+                .insert_header((header::ORIGIN, origin))
+                .to_request();
+            async {
+                call_service(&service, request)
+                    .await
+                    .headers()
+                    .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+            }
+        };
+        // All origins are allowed by default.
+        assert!(allowed_origin("https://example.com").await);
+        let mut config = state.config.clone();
+        config.cors = vec![String::from("*.example.com")];
+        state
+            .reloadable
+            .replace(ReloadableOptions::new(&config).await.unwrap());
+        assert!(!allowed_origin("https://example.com").await);
+        assert!(allowed_origin("https://files.example.com").await);
+        config.cors = Vec::new();
+        state
+            .reloadable
+            .replace(ReloadableOptions::new(&config).await.unwrap());
+        assert!(allowed_origin("https://example.com").await);
+    }
 }
@@ -0,0 +1,231 @@
+use std::{ffi::OsString, path::PathBuf, str::FromStr};
+
+use clap::{builder::Resettable, CommandFactory, FromArgMatches};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{Map, Value};
+
+use crate::{
+    config::{AMQPHooksOptions, KafkaHookOptions, NatsHookOptions, NotificationsOptions},
+    config_file::{error_message, find_arg, to_args},
+    errors::{RustusError, RustusResult},
+};
+
+/// Options of HTTP notifier.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HttpNotifierOptions {
+    pub urls: Vec<String>,
+    #[serde(default)]
+    pub proxy_headers: Vec<String>,
+    pub timeout: Option<u64>,
+}
+
+/// Options of notifiers which call executables.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PathNotifierOptions {
+    pub path: String,
+}
+
+/// Notifier with its own options.
+#[derive(Debug, Clone)]
+pub enum NotifierConfig {
+    File(String),
+    Dir(PathBuf),
+    Http(HttpNotifierOptions),
+    Amqp(AMQPHooksOptions),
+    Kafka(KafkaHookOptions),
+    Nats(NatsHookOptions),
+}
+
+/// Parse options of a notifier, which are also available as CLI arguments.
+///
+/// Keys are names of CLI arguments without `hooks-{kind}-` prefix.
+/// Defaults are the same as for CLI arguments,
+/// but environment variables aren't used.
+fn parse_options<T>(kind: &str, options: Map<String, Value>) -> RustusResult<T>
+where
+    T: CommandFactory + FromArgMatches,
+{
+    let command = T::command().mut_args(|arg| arg.env(Resettable::Reset));
+    let mut args = vec![OsString::from("rustus")];
+    for (name, value) in options {
+        let arg = find_arg(&command, &format!("hooks-{kind}-{name}")).ok_or_else(|| {
+            RustusError::WrongConfig(format!("Unknown option {name} of {kind} notifier."))
+        })?;
+        args.extend(to_args(arg, &value));
+    }
+    let wrong_options = |err: clap::Error| {
+        RustusError::WrongConfig(format!(
+            "Wrong options of {kind} notifier: {}",
+            error_message(&err)
+        ))
+    };
+    let matches = command.try_get_matches_from(args).map_err(wrong_options)?;
+    T::from_arg_matches(&matches).map_err(wrong_options)
+}
+
+/// Parse options of a notifier, which has no CLI arguments for them.
+fn deserialize_options<T: DeserializeOwned>(
+    kind: &str,
+    options: Map<String, Value>,
+) -> RustusResult<T> {
+    serde_json::from_value(Value::Object(options))
+        .map_err(|err| RustusError::WrongConfig(format!("Wrong options of {kind} notifier: {err}")))
+}
+
+impl NotifierConfig {
+    fn from_value(value: Value) -> RustusResult<Self> {
+        let Value::Object(mut options) = value else {
+            return Err(RustusError::WrongConfig(String::from(
+                "Notifier must be an object.",
+            )));
+        };
+        let Some(Value::String(kind)) = options.remove("type") else {
+            return Err(RustusError::WrongConfig(String::from(
+                "Notifier must have a type.",
+            )));
+        };
+        match kind.as_str() {
+            "file" => Ok(Self::File(
+                deserialize_options::<PathNotifierOptions>(&kind, options)?.path,
+            )),
+            "dir" => Ok(Self::Dir(PathBuf::from(
+                deserialize_options::<PathNotifierOptions>(&kind, options)?.path,
+            ))),
+            "http" => Ok(Self::Http(deserialize_options(&kind, options)?)),
+            "amqp" => Ok(Self::Amqp(parse_options(&kind, options)?)),
+            "kafka" => Ok(Self::Kafka(parse_options(&kind, options)?)),
+            "nats" => Ok(Self::Nats(parse_options(&kind, options)?)),
+            _ => Err(RustusError::WrongConfig(format!(
+                "Unknown notifier type {kind}, available types are: file, dir, http, amqp, kafka, nats."
+            ))),
+        }
+    }
+
+    /// Check that the notifier has enough options to work.
+    pub fn validate(&self) -> RustusResult<()> {
+        let missing = match self {
+            Self::Http(options) if options.urls.is_empty() => "http notifier requires urls",
+            Self::Amqp(options) if options.url.is_none() => "amqp notifier requires url",
+            Self::Kafka(options) if options.urls.is_none() => "kafka notifier requires urls",
+            Self::Nats(options) if options.urls.is_empty() => "nats notifier requires urls",
+            _ => return Ok(()),
+        };
+        Err(RustusError::WrongConfig(format!("{missing}.")))
+    }
+
+    /// Notifiers from the main options of hooks.
+    pub fn from_options(options: &NotificationsOptions) -> Vec<Self> {
+        let mut configs = Vec::new();
+        if let Some(hooks_file) = &options.hooks_file {
+            configs.push(Self::File(hooks_file.clone()));
+        }
+        if let Some(hooks_dir) = &options.hooks_dir {
+            configs.push(Self::Dir(hooks_dir.clone()));
+        }
+        if !options.hooks_http_urls.is_empty() {
+            configs.push(Self::Http(HttpNotifierOptions {
+                urls: options.hooks_http_urls.clone(),
+                proxy_headers: options.hooks_http_proxy_headers.clone(),
+                timeout: options.http_hook_timeout,
+            }));
+        }
+        if !options.nats_hook_opts.urls.is_empty() {
+            configs.push(Self::Nats(options.nats_hook_opts.clone()));
+        }
+        if options.amqp_hook_opts.url.is_some() {
+            configs.push(Self::Amqp(options.amqp_hook_opts.clone()));
+        }
+        if options.kafka_hook_opts.urls.is_some() {
+            configs.push(Self::Kafka(options.kafka_hook_opts.clone()));
+        }
+        if let Some(notifiers) = &options.notifiers {
+            configs.extend(notifiers.0.iter().cloned());
+        }
+        configs
+    }
+}
+
+/// Additional notifiers from the configuration.
+///
+/// They are passed as JSON array of objects,
+/// where `type` is the type of a notifier and other
+/// keys are its options.
+#[derive(Debug, Clone, Default)]
+pub struct NotifierConfigs(pub Vec<NotifierConfig>);
+
+impl FromStr for NotifierConfigs {
+    type Err = RustusError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let values: Vec<Value> = serde_json::from_str(s)
+            .map_err(|err| RustusError::WrongConfig(format!("Cannot parse notifiers: {err}")))?;
+        values
+            .into_iter()
+            .map(NotifierConfig::from_value)
+            .collect::<RustusResult<_>>()
+            .map(Self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::{NotifierConfig, NotifierConfigs};
+
+    #[test]
+    fn parse_notifiers() {
+        let configs = NotifierConfigs::from_str(
+            r#"[
+                {"type": "kafka", "urls": "first:9092", "topic": "uploads"},
+                {"type": "kafka", "urls": ["second:9092"], "prefix": "rustus", "send_timeout": 5},
+                {"type": "amqp", "url": "amqp://localhost", "declare_exchange": true},
+                {"type": "http", "urls": ["http://localhost/hooks"], "timeout": 3},
+                {"type": "dir", "path": "./hooks"}
+            ]"#,
+        )
+        .unwrap();
+        assert_eq!(configs.0.len(), 5);
+        let NotifierConfig::Kafka(first) = &configs.0[0] else {
+            panic!("Wrong notifier");
+        };
+        assert_eq!(first.urls.as_deref(), Some("first:9092"));
+        assert_eq!(first.topic.as_deref(), Some("uploads"));
+        let NotifierConfig::Kafka(second) = &configs.0[1] else {
+            panic!("Wrong notifier");
+        };
+        assert_eq!(second.urls.as_deref(), Some("second:9092"));
+        assert_eq!(second.prefix.as_deref(), Some("rustus"));
+        assert_eq!(second.send_timeout, Some(5));
+        let NotifierConfig::Amqp(amqp) = &configs.0[2] else {
+            panic!("Wrong notifier");
+        };
+        assert!(amqp.declare_exchange);
+        // Defaults are the same as for CLI.
+        assert_eq!(amqp.exchange, "rustus");
+        let NotifierConfig::Http(http) = &configs.0[3] else {
+            panic!("Wrong notifier");
+        };
+        assert_eq!(http.timeout, Some(3));
+        assert!(http.proxy_headers.is_empty());
+        assert!(configs.0.iter().all(|config| config.validate().is_ok()));
+    }
+
+    #[test]
+    fn wrong_notifiers() {
+        for notifiers in [
+            r#"{"type": "kafka"}"#,
+            r#"[{"urls": "localhost:9092"}]"#,
+            r#"[{"type": "unknown"}]"#,
+            r#"[{"type": "kafka", "unknown": 1}]"#,
+            r#"[{"type": "kafka", "topic": "uploads", "prefix": "rustus"}]"#,
+            r#"[{"type": "http", "url": "http://localhost"}]"#,
+        ] {
+            assert!(NotifierConfigs::from_str(notifiers).is_err());
+        }
+        let configs = NotifierConfigs::from_str(r#"[{"type": "nats", "subject": "hooks"}]"#);
+        assert!(configs.unwrap().0[0].validate().is_err());
+    }
+}
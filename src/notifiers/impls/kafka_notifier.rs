@@ -164,7 +164,8 @@ mod test {
     };
 
     fn get_notifier(topic: Option<&str>, prefix: Option<&str>) -> KafkaNotifier {
-        let urls = std::env::var("TEST_KAFKA_URLS").unwrap_or(String::from("localhost:9094"));
+        let urls =
+            std::env::var("TEST_KAFKA_URLS").unwrap_or_else(|_| String::from("localhost:9094"));
         KafkaNotifier::new(
             urls,
             Some("rustus".to_string()),
@@ -180,7 +181,8 @@ mod test {
     }
 
     async fn get_consumer(topics: &[&str]) -> StreamConsumer {
-        let urls = std::env::var("TEST_KAFKA_URLS").unwrap_or(String::from("localhost:9094"));
+        let urls =
+            std::env::var("TEST_KAFKA_URLS").unwrap_or_else(|_| String::from("localhost:9094"));
         let mut config = ClientConfig::new();
         config
             .set("bootstrap.servers", urls)
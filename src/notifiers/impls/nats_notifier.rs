@@ -108,7 +108,7 @@ mod test {
         wait_replies: bool,
     ) -> NatsNotifier {
         let urls = std::env::var("TEST_NATS_URLS")
-            .unwrap_or(String::from("localhost:4222"))
+            .unwrap_or_else(|_| String::from("localhost:4222"))
             .split(',')
             .map(String::from)
             .collect::<Vec<_>>();
@@ -127,7 +127,7 @@ mod test {
 
     async fn get_client() -> async_nats::Client {
         let urls = std::env::var("TEST_NATS_URLS")
-            .unwrap_or(String::from("localhost:4222"))
+            .unwrap_or_else(|_| String::from("localhost:4222"))
             .split(',')
             .map(String::from)
             .collect::<Vec<_>>();
@@ -226,7 +226,7 @@ mod test {
             notifier
                 .send_message(cloned_data, Hook::PreCreate, &finfo, &HeaderMap::default())
                 .await
-                .unwrap()
+                .unwrap();
         });
         let msg = tokio::time::timeout(Duration::from_secs(1), listen_task)
             .await
@@ -269,7 +269,7 @@ mod test {
             notifier
                 .send_message(cloned_data, hook, &finfo, &HeaderMap::default())
                 .await
-                .unwrap()
+                .unwrap();
         });
         let msg = tokio::time::timeout(Duration::from_secs(1), listen_task)
             .await
@@ -4,6 +4,7 @@ use log::debug;
 
 use super::{
     base::Notifier,
+    configs::NotifierConfig,
     hooks::Hook,
     impls::{
         amqp_notifier::AMQPNotifier, dir_notifier::DirNotifier, file_notifier::FileNotifier,
@@ -32,89 +33,8 @@ impl NotificationManager {
             notifiers: Vec::new(),
         };
         debug!("Initializing notification manager.");
-        if rustus_config.notification_opts.hooks_file.is_some() {
-            debug!("Found hooks file");
-            manager.notifiers.push(NotifierImpl::File(FileNotifier::new(
-                rustus_config.notification_opts.hooks_file.clone().unwrap(),
-            )));
-        }
-        if rustus_config.notification_opts.hooks_dir.is_some() {
-            debug!("Found hooks directory");
-            manager.notifiers.push(NotifierImpl::Dir(DirNotifier::new(
-                rustus_config.notification_opts.hooks_dir.clone().unwrap(),
-            )));
-        }
-        if !rustus_config.notification_opts.hooks_http_urls.is_empty() {
-            debug!("Found http hook urls.");
-            manager.notifiers.push(NotifierImpl::Http(HttpNotifier::new(
-                rustus_config.notification_opts.hooks_http_urls.clone(),
-                rustus_config
-                    .notification_opts
-                    .hooks_http_proxy_headers
-                    .clone(),
-                rustus_config.notification_opts.http_hook_timeout,
-            )));
-        }
-        if !rustus_config
-            .notification_opts
-            .nats_hook_opts
-            .urls
-            .is_empty()
-        {
-            debug!("Found NATS notifier.");
-            manager.notifiers.push(NotifierImpl::Nats(
-                NatsNotifier::new(
-                    rustus_config.notification_opts.nats_hook_opts.urls.clone(),
-                    rustus_config
-                        .notification_opts
-                        .nats_hook_opts
-                        .subject
-                        .clone(),
-                    rustus_config
-                        .notification_opts
-                        .nats_hook_opts
-                        .prefix
-                        .clone(),
-                    rustus_config
-                        .notification_opts
-                        .nats_hook_opts
-                        .wait_for_replies,
-                    rustus_config
-                        .notification_opts
-                        .nats_hook_opts
-                        .username
-                        .clone(),
-                    rustus_config
-                        .notification_opts
-                        .nats_hook_opts
-                        .password
-                        .clone(),
-                    rustus_config.notification_opts.nats_hook_opts.token.clone(),
-                )
-                .await?,
-            ));
-        }
-        if rustus_config.notification_opts.amqp_hook_opts.url.is_some() {
-            debug!("Found AMQP notifier.");
-            manager.notifiers.push(NotifierImpl::Amqp(AMQPNotifier::new(
-                rustus_config.notification_opts.amqp_hook_opts.clone(),
-            )));
-        }
-        if let Some(urls) = &rustus_config.notification_opts.kafka_hook_opts.urls {
-            let opts = rustus_config.notification_opts.kafka_hook_opts.clone();
-            manager
-                .notifiers
-                .push(NotifierImpl::Kafka(KafkaNotifier::new(
-                    urls.to_owned(),
-                    opts.client_id,
-                    opts.topic,
-                    opts.prefix,
-                    opts.required_acks,
-                    opts.compression,
-                    opts.idle_timeout,
-                    opts.send_timeout,
-                    opts.extra_kafka_opts,
-                )?));
+        for config in NotifierConfig::from_options(&rustus_config.notification_opts) {
+            manager.notifiers.push(NotifierImpl::new(config).await?);
         }
         for notifier in &mut manager.notifiers.iter_mut() {
             notifier.prepare().await?;
@@ -140,6 +60,59 @@ impl NotificationManager {
     }
 }
 
+impl NotifierImpl {
+    pub async fn new(config: NotifierConfig) -> RustusResult<Self> {
+        Ok(match config {
+            NotifierConfig::File(hooks_file) => {
+                debug!("Found hooks file");
+                Self::File(FileNotifier::new(hooks_file))
+            }
+            NotifierConfig::Dir(hooks_dir) => {
+                debug!("Found hooks directory");
+                Self::Dir(DirNotifier::new(hooks_dir))
+            }
+            NotifierConfig::Http(opts) => {
+                debug!("Found http hook urls.");
+                Self::Http(HttpNotifier::new(
+                    opts.urls,
+                    opts.proxy_headers,
+                    opts.timeout,
+                ))
+            }
+            NotifierConfig::Nats(opts) => {
+                debug!("Found NATS notifier.");
+                Self::Nats(
+                    NatsNotifier::new(
+                        opts.urls,
+                        opts.subject,
+                        opts.prefix,
+                        opts.wait_for_replies,
+                        opts.username,
+                        opts.password,
+                        opts.token,
+                    )
+                    .await?,
+                )
+            }
+            NotifierConfig::Amqp(opts) => {
+                debug!("Found AMQP notifier.");
+                Self::Amqp(AMQPNotifier::new(opts))
+            }
+            NotifierConfig::Kafka(opts) => Self::Kafka(KafkaNotifier::new(
+                opts.urls.unwrap_or_default(),
+                opts.client_id,
+                opts.topic,
+                opts.prefix,
+                opts.required_acks,
+                opts.compression,
+                opts.idle_timeout,
+                opts.send_timeout,
+                opts.extra_kafka_opts,
+            )?),
+        })
+    }
+}
+
 impl Notifier for NotifierImpl {
     async fn prepare(&mut self) -> RustusResult<()> {
         match self {
@@ -1,4 +1,5 @@
 pub mod base;
+pub mod configs;
 pub mod hooks;
 pub mod impls;
 pub mod manager;
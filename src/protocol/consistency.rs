@@ -0,0 +1,123 @@
+use crate::{
+    data_storage::base::DataStorage, errors::RustusResult, file_info::FileInfo,
+    info_storage::base::InfoStorage, State,
+};
+
+/// Make data of an upload consistent with the information about it.
+///
+/// Data storage removes bytes that were written
+/// after the saved offset. If some bytes were lost instead,
+/// the offset is decreased and the information is saved.
+///
+/// Returns `true` if the information about the upload was updated.
+pub async fn restore_upload(state: &State, mut file_info: FileInfo) -> RustusResult<bool> {
+    let data_storage = state.data_storages.for_upload(&file_info)?;
+    if !data_storage.truncate(&mut file_info).await? {
+        return Ok(false);
+    }
+    state.info_storage.update_info(&mut file_info).await?;
+    Ok(true)
+}
+
+/// Roll back bytes which were written, but not saved in the info storage.
+///
+/// `saved_info` is the information about the upload
+/// as it was before the write. Errors are only logged,
+/// because the caller already has an error to return.
+pub async fn rollback_write(state: &State, saved_info: FileInfo) {
+    let file_id = saved_info.id.clone();
+    if let Err(err) = restore_upload(state, saved_info).await {
+        log::error!("Cannot roll back write to upload {file_id}: {err}");
+    }
+}
+
+/// Check that data of unfinished uploads matches the saved offsets.
+///
+/// This function must be called only when no uploads are
+/// in progress, otherwise bytes that are being written
+/// are removed.
+///
+/// Returns number of uploads that were fixed.
+pub async fn check_uploads(state: &State) -> RustusResult<usize> {
+    let unfinished = state.info_storage.get_unfinished().await?;
+    let mut fixed = 0;
+    for file_info in unfinished {
+        // Uploads from unknown storages can't be checked.
+        if state.data_storages.get(&file_info.storage).is_none() {
+            continue;
+        }
+        let file_id = file_info.id.clone();
+        match restore_upload(state, file_info).await {
+            Ok(true) => {
+                log::warn!("Offset of upload {file_id} was decreased to match its data");
+                fixed += 1;
+            }
+            Ok(false) => {}
+            Err(err) => log::error!("Cannot check consistency of upload {file_id}: {err}"),
+        }
+    }
+    Ok(fixed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, path::PathBuf};
+
+    use super::{check_uploads, rollback_write};
+    use crate::{info_storage::base::InfoStorage, State};
+
+    #[actix_rt::test]
+    async fn rollback() {
+        let state = State::test_new().await;
+        let file_info = state.create_test_file().await;
+        let path = PathBuf::from(file_info.path.clone().unwrap());
+        std::fs::write(&path, "unsaved").unwrap();
+        rollback_write(&state, file_info).await;
+        assert_eq!(std::fs::metadata(path).unwrap().len(), 0);
+    }
+
+    #[actix_rt::test]
+    async fn lost_bytes() {
+        let state = State::test_new().await;
+        let mut file_info = state.create_test_file().await;
+        let path = PathBuf::from(file_info.path.clone().unwrap());
+        std::fs::File::create(&path)
+            .unwrap()
+            .write_all(b"123")
+            .unwrap();
+        file_info.offset = 5;
+        state
+            .info_storage
+            .set_info(&file_info, false)
+            .await
+            .unwrap();
+
+        assert_eq!(check_uploads(&state).await.unwrap(), 1);
+        let info = state.info_storage.get_info(&file_info.id).await.unwrap();
+        assert_eq!(info.offset, 3);
+    }
+
+    #[actix_rt::test]
+    async fn consistent() {
+        let state = State::test_new().await;
+        state.create_test_file().await;
+        assert_eq!(check_uploads(&state).await.unwrap(), 0);
+    }
+
+    #[actix_rt::test]
+    async fn other_storage() {
+        let state = State::test_new().await;
+        let mut file_info = state.create_test_file().await;
+        file_info.storage = String::from("unknown");
+        file_info.offset = 5;
+        state
+            .info_storage
+            .set_info(&file_info, false)
+            .await
+            .unwrap();
+
+        assert_eq!(check_uploads(&state).await.unwrap(), 0);
+        let info = state.info_storage.get_info(&file_info.id).await.unwrap();
+        assert_eq!(info.offset, 5);
+    }
+}
@@ -1,5 +1,6 @@
 use crate::{
-    data_storage::base::DataStorage, errors::RustusError, info_storage::base::InfoStorage,
+    errors::RustusError, info_storage::base::InfoStorage, protocol::extensions::Extensions,
+    utils::hashes::UploadDigest,
 };
 use actix_web::{
     http::header::{CacheControl, CacheDirective},
@@ -21,8 +22,15 @@ pub async fn get_file_info(
 
     // Getting file info from info_storage.
     let file_info = state.info_storage.get_info(file_id).await?;
-    if file_info.storage != state.data_storage.get_name() {
-        return Err(RustusError::FileNotFound);
+    // Upload must be stored in one of configured storages.
+    state.data_storages.for_upload(&file_info)?;
+    if state
+        .config
+        .tus_extensions
+        .contains(&Extensions::Expiration)
+        && file_info.is_expired()
+    {
+        return Err(RustusError::FileExpired);
     }
     let mut builder = HttpResponse::Ok();
     if file_info.is_partial {
@@ -55,6 +63,16 @@ pub async fn get_file_info(
     if let Some(meta) = file_info.get_metadata_string() {
         builder.insert_header(("Upload-Metadata", meta));
     }
+    if let Some(expires) = file_info.get_expiration_string() {
+        builder.insert_header(("Upload-Expires", expires));
+    }
+    if let Some(digest) = file_info
+        .digest
+        .as_ref()
+        .and_then(UploadDigest::header_value)
+    {
+        builder.insert_header(("Upload-Digest", digest));
+    }
     builder.insert_header(("Upload-Created", file_info.created_at.timestamp()));
     builder.insert_header(CacheControl(vec![CacheDirective::NoCache]));
     Ok(builder.streaming(empty::<RustusResult<web::Bytes>>()))
@@ -64,7 +82,13 @@ pub async fn get_file_info(
 mod tests {
     use actix_web::http::{Method, StatusCode};
 
-    use crate::{info_storage::base::InfoStorage, server::test::get_service, State};
+    use crate::{
+        info_storage::base::InfoStorage,
+        protocol::extensions::Extensions,
+        server::test::get_service,
+        utils::hashes::{DigestAlgorithm, UploadDigest},
+        State,
+    };
     use actix_web::test::{call_service, TestRequest};
 
     use base64::{engine::general_purpose, Engine};
@@ -211,6 +235,53 @@ mod tests {
         );
     }
 
+    #[actix_rt::test]
+    async fn success_expiration() {
+        let mut state = State::test_new().await;
+        state.config.tus_extensions.push(Extensions::Expiration);
+        let rustus = get_service(state.clone()).await;
+        let mut file_info = state.create_test_file().await;
+        file_info.expires_at = Some(chrono::Utc::now() + chrono::Duration::seconds(100));
+        state
+            .info_storage
+            .set_info(&file_info, false)
+            .await
+            .unwrap();
+        let request = TestRequest::with_uri(state.config.file_url(file_info.id.as_str()).as_str())
+            .method(Method::HEAD)
+            .to_request();
+        let response = call_service(&rustus, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response
+                .headers()
+                .get("Upload-Expires")
+                .unwrap()
+                .to_str()
+                .unwrap(),
+            file_info.get_expiration_string().unwrap()
+        );
+    }
+
+    #[actix_rt::test]
+    async fn expired() {
+        let mut state = State::test_new().await;
+        state.config.tus_extensions.push(Extensions::Expiration);
+        let rustus = get_service(state.clone()).await;
+        let mut file_info = state.create_test_file().await;
+        file_info.expires_at = Some(chrono::Utc::now() - chrono::Duration::seconds(100));
+        state
+            .info_storage
+            .set_info(&file_info, false)
+            .await
+            .unwrap();
+        let request = TestRequest::with_uri(state.config.file_url(file_info.id.as_str()).as_str())
+            .method(Method::HEAD)
+            .to_request();
+        let response = call_service(&rustus, request).await;
+        assert_eq!(response.status(), StatusCode::GONE);
+    }
+
     #[actix_rt::test]
     async fn no_file() {
         let state = State::test_new().await;
@@ -222,6 +293,24 @@ mod tests {
         assert_eq!(response.status(), StatusCode::NOT_FOUND);
     }
 
+    #[actix_rt::test]
+    async fn corrupted_info() {
+        let state = State::test_new().await;
+        let rustus = get_service(state.clone()).await;
+        let file_info = state.create_test_file().await;
+        let info_path = state
+            .config
+            .info_storage_opts
+            .info_dir
+            .join(format!("{}.info", file_info.id));
+        std::fs::write(info_path, "").unwrap();
+        let request = TestRequest::with_uri(state.config.file_url(&file_info.id).as_str())
+            .method(Method::HEAD)
+            .to_request();
+        let response = call_service(&rustus, request).await;
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
     #[actix_rt::test]
     async fn test_get_file_info_wrong_storage() {
         let state = State::test_new().await;
@@ -239,4 +328,31 @@ mod tests {
         let response = call_service(&rustus, request).await;
         assert_eq!(response.status(), StatusCode::NOT_FOUND);
     }
+
+    #[actix_rt::test]
+    async fn success_digest() {
+        let state = State::test_new().await;
+        let rustus = get_service(state.clone()).await;
+        let mut file_info = state.create_test_file().await;
+        file_info.offset = 100;
+        file_info.length = Some(100);
+        file_info.digest = Some(UploadDigest {
+            algorithm: DigestAlgorithm::Md5,
+            state: None,
+            value: Some(String::from("XUFAKrxLKna5cZ2REBfFkg==")),
+        });
+        state
+            .info_storage
+            .set_info(&file_info, false)
+            .await
+            .unwrap();
+        let request = TestRequest::with_uri(state.config.file_url(file_info.id.as_str()).as_str())
+            .method(Method::HEAD)
+            .to_request();
+        let response = call_service(&rustus, request).await;
+        assert_eq!(
+            response.headers().get("Upload-Digest").unwrap(),
+            "md5 XUFAKrxLKna5cZ2REBfFkg=="
+        );
+    }
 }
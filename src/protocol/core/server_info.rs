@@ -16,14 +16,24 @@ pub async fn server_info(state: web::Data<State>) -> HttpResponse {
     let mut response_builder = HttpResponseBuilder::new(StatusCode::OK);
     response_builder.insert_header(("Tus-Extension", ext_str.as_str()));
     if state.config.tus_extensions.contains(&Extensions::Checksum) {
-        response_builder.insert_header(("Tus-Checksum-Algorithm", "md5,sha1,sha256,sha512"));
+        let algorithms = state
+            .config
+            .checksum_algorithms
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<String>>()
+            .join(",");
+        response_builder.insert_header(("Tus-Checksum-Algorithm", algorithms.as_str()));
     }
     response_builder.finish()
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{protocol::extensions::Extensions, server::test::get_service, State};
+    use crate::{
+        protocol::extensions::Extensions, server::test::get_service,
+        utils::hashes::ChecksumAlgorithm, State,
+    };
     use actix_web::test::{call_service, TestRequest};
 
     use actix_web::http::Method;
@@ -51,4 +61,39 @@ mod tests {
         assert!(extensions.contains(Extensions::Concatenation.to_string().as_str()));
         assert!(extensions.contains(Extensions::Termination.to_string().as_str()));
     }
+
+    #[actix_rt::test]
+    async fn test_checksum_algorithms() {
+        let mut state = State::test_new().await;
+        state.config.checksum_algorithms = vec![ChecksumAlgorithm::Crc32c, ChecksumAlgorithm::Xxh3];
+        let rustus = get_service(state.clone()).await;
+        let request = TestRequest::with_uri(state.config.test_url().as_str())
+            .method(Method::OPTIONS)
+            .to_request();
+        let response = call_service(&rustus, request).await;
+        assert_eq!(
+            response.headers().get("Tus-Checksum-Algorithm").unwrap(),
+            "crc32c,xxh3"
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_no_checksum_algorithms() {
+        let mut state = State::test_new().await;
+        state.config.checksum_algorithms = vec![];
+        state.config.normalize_extentions();
+        let rustus = get_service(state.clone()).await;
+        let request = TestRequest::with_uri(state.config.test_url().as_str())
+            .method(Method::OPTIONS)
+            .to_request();
+        let response = call_service(&rustus, request).await;
+        assert!(response.headers().get("Tus-Checksum-Algorithm").is_none());
+        let extensions = response
+            .headers()
+            .get("Tus-Extension")
+            .unwrap()
+            .to_str()
+            .unwrap();
+        assert!(!extensions.contains(Extensions::Checksum.to_string().as_str()));
+    }
 }
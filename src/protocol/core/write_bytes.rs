@@ -1,9 +1,10 @@
+use std::{cell::Cell, rc::Rc};
+
 use actix_web::{
     http::header::{CacheControl, CacheDirective},
-    web,
-    web::Bytes,
-    HttpRequest, HttpResponse,
+    web, HttpRequest, HttpResponse,
 };
+use futures::{StreamExt, TryStreamExt};
 
 use crate::{
     data_storage::base::DataStorage,
@@ -11,17 +12,41 @@ use crate::{
     info_storage::base::InfoStorage,
     metrics,
     notifiers::Hook,
-    protocol::extensions::Extensions,
+    protocol::{consistency::rollback_write, extensions::Extensions},
     utils::{
-        hashes::verify_chunk_checksum,
+        hashes::{upload_digest_matches, verify_chunk_checksum, RunningDigest},
         headers::{check_header, parse_header},
+        payload::{payload_stream, read_payload},
     },
     RustusResult, State,
 };
 
 pub async fn write_bytes(
     request: HttpRequest,
-    bytes: Bytes,
+    payload: web::Payload,
+    state: web::Data<State>,
+    metrics: web::Data<metrics::RustusMetrics>,
+) -> RustusResult<HttpResponse> {
+    let Some(file_id) = request.match_info().get("file_id") else {
+        return Err(RustusError::FileNotFound);
+    };
+    // Only one request at a time can write to the upload.
+    // The lock is held until the new offset is saved.
+    // If the client disconnects, the handler is cancelled
+    // and the lock is released by its guard.
+    state
+        .locker
+        .with_lock(
+            file_id,
+            write_locked(request.clone(), payload, state.clone(), metrics),
+        )
+        .await
+}
+
+#[allow(clippy::too_many_lines)]
+async fn write_locked(
+    request: HttpRequest,
+    payload: web::Payload,
     state: web::Data<State>,
     metrics: web::Data<metrics::RustusMetrics>,
 ) -> RustusResult<HttpResponse> {
@@ -41,19 +66,6 @@ pub async fn write_bytes(
         return Err(RustusError::FileNotFound);
     }
 
-    if state.config.tus_extensions.contains(&Extensions::Checksum) {
-        if let Some(header) = request.headers().get("Upload-Checksum").cloned() {
-            let cloned_bytes = bytes.clone();
-            if !tokio::task::spawn_blocking(move || {
-                verify_chunk_checksum(&header, cloned_bytes.as_ref())
-            })
-            .await??
-            {
-                return Err(RustusError::WrongChecksum);
-            }
-        }
-    }
-
     // New upload length.
     // Parses header `Upload-Length` only if the creation-defer-length extension is enabled.
     let updated_len = if state
@@ -75,9 +87,16 @@ pub async fn write_bytes(
         return Ok(HttpResponse::Forbidden().finish());
     }
 
-    // Checking if file was stored in the same storage.
-    if file_info.storage != state.data_storage.get_name() {
-        return Err(RustusError::FileNotFound);
+    // Uploads are continued in the storage where they were created.
+    let data_storage = state.data_storages.for_upload(&file_info)?;
+    // Expired uploads can't be continued.
+    if state
+        .config
+        .tus_extensions
+        .contains(&Extensions::Expiration)
+        && file_info.is_expired()
+    {
+        return Err(RustusError::FileExpired);
     }
     // Checking if offset from request is the same as the real offset.
     if offset.unwrap() != file_info.offset {
@@ -109,21 +128,113 @@ pub async fn write_bytes(
     if Some(file_info.offset) == file_info.length {
         return Err(RustusError::FrozenFile);
     }
-    let chunk_len = bytes.len();
+
+    let stream = if let Some(header) = request
+        .headers()
+        .get("Upload-Checksum")
+        .filter(|_| state.config.tus_extensions.contains(&Extensions::Checksum))
+        .cloned()
+    {
+        // Checksum can be verified only after the whole chunk is received,
+        // so such chunks are kept in memory and limited by `max_body_size`.
+        let bytes = read_payload(payload, state.config.max_body_size).await?;
+        let cloned_bytes = bytes.clone();
+        let algorithms = state.config.checksum_algorithms.clone();
+        if !tokio::task::spawn_blocking(move || {
+            verify_chunk_checksum(&header, cloned_bytes.as_ref(), &algorithms)
+        })
+        .await??
+        {
+            return Err(RustusError::WrongChecksum);
+        }
+        futures::stream::iter([Ok(bytes)]).boxed_local()
+    } else {
+        // Client can't send more bytes than the upload can hold.
+        let limit = file_info
+            .length
+            .or(state.reloadable.current().max_file_size)
+            .map(|max_len| max_len.saturating_sub(file_info.offset));
+        payload_stream(payload, limit)
+    };
     // Appending bytes to file.
-    state.data_storage.add_bytes(&mut file_info, bytes).await?;
-    // bytes.clear()
-    // Updating offset.
-    file_info.offset += chunk_len;
+    // Offset is updated by the storage.
+    let start_offset = file_info.offset;
+    let saved_info = file_info.clone();
+    let digest = RunningDigest::restore(&file_info, state.config.upload_digest);
+    // Errors of the stream mean that the client has gone,
+    // all other errors come from the storage itself.
+    let stream_failed = Rc::new(Cell::new(false));
+    let stream = {
+        let stream_failed = stream_failed.clone();
+        stream
+            .inspect_err(move |_| stream_failed.set(true))
+            .boxed_local()
+    };
+    let write_result = match data_storage
+        .add_bytes(&mut file_info, digest.wrap(stream))
+        .await
+    {
+        // Upload doesn't match the checksum declared by the client,
+        // so only bytes of this request are rolled back.
+        Err(RustusError::WrongChecksum) => {
+            rollback_write(&state, saved_info).await;
+            return Err(RustusError::WrongChecksum);
+        }
+        // Storage has failed in the middle of the write,
+        // so bytes after the saved offset can't be trusted.
+        // They are removed and the upload stays as it was.
+        Err(err) if !stream_failed.get() => {
+            rollback_write(&state, saved_info).await;
+            return Err(err);
+        }
+        result => result,
+    };
+    digest.save(&mut file_info, start_offset);
+    // Even if the client has gone in the middle of the request,
+    // we save the information about bytes we have received.
+    file_info.refresh_expiration(state.config.upload_lifetime());
     // Saving info to info storage.
-    state.info_storage.set_info(&file_info, false).await?;
+    // It fails if someone else has changed or removed the upload.
+    match state.info_storage.update_info(&mut file_info).await {
+        Ok(()) => {}
+        // Saved info isn't actual anymore, so it can't be restored.
+        // Only bytes of this request are removed from the data.
+        Err(
+            err @ (RustusError::InfoVersionConflict
+            | RustusError::InfoLocked
+            | RustusError::FileNotFound),
+        ) => {
+            let mut saved_info = saved_info;
+            if let Err(err) = data_storage.truncate(&mut saved_info).await {
+                log::error!("Cannot remove bytes written to {}: {}", saved_info.id, err);
+            }
+            return Err(err);
+        }
+        Err(err) => {
+            // Bytes after the saved offset must be removed,
+            // otherwise the next chunk is written to a wrong position.
+            rollback_write(&state, saved_info).await;
+            return Err(err);
+        }
+    }
+    write_result?;
+
+    // Digest of the complete upload can't be verified,
+    // so it's removed and the client has to upload the file again.
+    if !upload_digest_matches(&file_info) {
+        data_storage.remove_file(&file_info).await?;
+        state.info_storage.remove_info(&file_info.id).await?;
+        metrics.active_uploads.dec();
+        return Err(RustusError::WrongChecksum);
+    }
 
     let hook = if file_info.length == Some(file_info.offset) {
         Hook::PostFinish
     } else {
         Hook::PostReceive
     };
-    if state.config.hook_is_active(hook) {
+    let options = state.reloadable.current();
+    if options.hook_is_active(hook) {
         let message = state.config.notification_opts.hooks_format.format(
             &request,
             &file_info,
@@ -131,8 +242,8 @@ pub async fn write_bytes(
         );
         let headers = request.headers().clone();
         let cloned_info = file_info.clone();
-        tokio::task::spawn_local(async move {
-            state
+        state.pending.spawn_hook(hook, &file_info.id, async move {
+            options
                 .notification_manager
                 .send_message(message, hook, &cloned_info, &headers)
                 .await
@@ -144,15 +255,25 @@ pub async fn write_bytes(
         metrics.finished_uploads.inc();
     }
 
-    Ok(HttpResponse::NoContent()
+    let mut builder = HttpResponse::NoContent();
+    builder
         .insert_header(("Upload-Offset", file_info.offset.to_string()))
-        .insert_header(CacheControl(vec![CacheDirective::NoCache]))
-        .finish())
+        .insert_header(CacheControl(vec![CacheDirective::NoCache]));
+    if let Some(expires) = file_info.get_expiration_string() {
+        builder.insert_header(("Upload-Expires", expires));
+    }
+    Ok(builder.finish())
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{info_storage::base::InfoStorage, server::test::get_service, State};
+    use crate::{
+        info_storage::base::InfoStorage,
+        protocol::extensions::Extensions,
+        server::test::get_service,
+        utils::hashes::{DigestAlgorithm, UploadDigest, CHECKSUM_METADATA_KEY},
+        State,
+    };
     use actix_web::{
         http::StatusCode,
         test::{call_service, TestRequest},
@@ -193,6 +314,109 @@ mod tests {
             .await
             .unwrap();
         assert_eq!(new_info.offset, test_data.len());
+        assert_eq!(new_info.version, file.version + 1);
+    }
+
+    #[actix_rt::test]
+    /// Tests that info isn't updated if the storage
+    /// has failed to write bytes.
+    async fn storage_failure() {
+        let state = State::test_new().await;
+        let rustus = get_service(state.clone()).await;
+        let mut file = state.create_test_file().await;
+        file.length = Some(100);
+        state.info_storage.set_info(&file, false).await.unwrap();
+        std::fs::remove_file(file.path.clone().unwrap()).unwrap();
+        let request = TestRequest::patch()
+            .uri(state.config.file_url(file.id.as_str()).as_str())
+            .insert_header(("Content-Type", "application/offset+octet-stream"))
+            .insert_header(("Upload-Offset", file.offset))
+            .set_payload("memes")
+            .to_request();
+        let resp = call_service(&rustus, request).await;
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let new_info = state.info_storage.get_info(&file.id).await.unwrap();
+        assert_eq!(new_info.offset, file.offset);
+        assert_eq!(new_info.version, file.version);
+    }
+
+    #[actix_rt::test]
+    /// Tests that written bytes are removed if
+    /// information about the upload can't be saved.
+    async fn info_locked() {
+        let state = State::test_new().await;
+        let rustus = get_service(state.clone()).await;
+        let mut file = state.create_test_file().await;
+        file.length = Some(100);
+        state.info_storage.set_info(&file, false).await.unwrap();
+        let lock_path = state
+            .config
+            .info_storage_opts
+            .info_dir
+            .join(format!("{}.lock", file.id));
+        std::fs::File::create(&lock_path).unwrap();
+        let request = TestRequest::patch()
+            .uri(state.config.file_url(file.id.as_str()).as_str())
+            .insert_header(("Content-Type", "application/offset+octet-stream"))
+            .insert_header(("Upload-Offset", file.offset))
+            .set_payload("memes")
+            .to_request();
+        let resp = call_service(&rustus, request).await;
+        assert_eq!(resp.status(), StatusCode::LOCKED);
+        let data = std::fs::read(file.path.clone().unwrap()).unwrap();
+        assert_eq!(data.len(), file.offset);
+        std::fs::remove_file(lock_path).unwrap();
+        let new_info = state.info_storage.get_info(&file.id).await.unwrap();
+        assert_eq!(new_info.version, file.version);
+    }
+
+    #[actix_rt::test]
+    /// Tests that expiration time is sent
+    /// if expiration extension is enabled.
+    async fn success_expiration() {
+        let mut state = State::test_new().await;
+        state.config.tus_extensions.push(Extensions::Expiration);
+        let rustus = get_service(state.clone()).await;
+        let mut file = state.create_test_file().await;
+        file.length = Some(100);
+        file.offset = 0;
+        state.info_storage.set_info(&file, false).await.unwrap();
+        let request = TestRequest::patch()
+            .uri(state.config.file_url(file.id.as_str()).as_str())
+            .insert_header(("Content-Type", "application/offset+octet-stream"))
+            .insert_header(("Upload-Offset", file.offset))
+            .set_payload("memes")
+            .to_request();
+        let resp = call_service(&rustus, request).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert!(resp.headers().contains_key("Upload-Expires"));
+        let new_info = state
+            .info_storage
+            .get_info(file.id.clone().as_str())
+            .await
+            .unwrap();
+        assert!(new_info.expires_at.is_some());
+    }
+
+    #[actix_rt::test]
+    /// Tests that expired uploads can't be continued.
+    async fn expired_upload() {
+        let mut state = State::test_new().await;
+        state.config.tus_extensions.push(Extensions::Expiration);
+        let rustus = get_service(state.clone()).await;
+        let mut file = state.create_test_file().await;
+        file.length = Some(100);
+        file.offset = 0;
+        file.expires_at = Some(chrono::Utc::now() - chrono::Duration::seconds(10));
+        state.info_storage.set_info(&file, false).await.unwrap();
+        let request = TestRequest::patch()
+            .uri(state.config.file_url(file.id.as_str()).as_str())
+            .insert_header(("Content-Type", "application/offset+octet-stream"))
+            .insert_header(("Upload-Offset", file.offset))
+            .set_payload("memes")
+            .to_request();
+        let resp = call_service(&rustus, request).await;
+        assert_eq!(resp.status(), StatusCode::GONE);
     }
 
     #[actix_rt::test]
@@ -260,6 +484,33 @@ mod tests {
         assert_eq!(resp.status(), StatusCode::CONFLICT);
     }
 
+    #[actix_rt::test]
+    /// Checks that concurrent write to
+    /// the same upload is rejected.
+    async fn locked_upload() {
+        let state = State::test_new().await;
+        let rustus = get_service(state.clone()).await;
+        let file = state.create_test_file().await;
+        let lock = state.locker.acquire(&file.id).await.unwrap();
+        let request = TestRequest::patch()
+            .uri(state.config.file_url(file.id.as_str()).as_str())
+            .insert_header(("Content-Type", "application/offset+octet-stream"))
+            .insert_header(("Upload-Offset", file.offset))
+            .set_payload("memes")
+            .to_request();
+        let resp = call_service(&rustus, request).await;
+        assert_eq!(resp.status(), StatusCode::LOCKED);
+        lock.release().await.unwrap();
+        let request = TestRequest::patch()
+            .uri(state.config.file_url(file.id.as_str()).as_str())
+            .insert_header(("Content-Type", "application/offset+octet-stream"))
+            .insert_header(("Upload-Offset", file.offset))
+            .set_payload("memes")
+            .to_request();
+        let resp = call_service(&rustus, request).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+    }
+
     #[actix_rt::test]
     /// Tests if user tries to update
     /// file length with known length,
@@ -358,6 +609,29 @@ mod tests {
         assert_eq!(resp.status(), StatusCode::FORBIDDEN);
     }
 
+    #[actix_rt::test]
+    /// Tests that uploads from legacy storages can be continued.
+    async fn legacy_storage() {
+        let mut state = State::test_new().await;
+        let file = state.create_test_file().await;
+        state.switch_data_storage();
+        let rustus = get_service(state.clone()).await;
+        let request = TestRequest::patch()
+            .uri(state.config.file_url(file.id.as_str()).as_str())
+            .insert_header(("Upload-Offset", file.offset))
+            .insert_header(("Content-Type", "application/offset+octet-stream"))
+            .set_payload("memes")
+            .to_request();
+        let resp = call_service(&rustus, request).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        let saved = state.info_storage.get_info(&file.id).await.unwrap();
+        assert_eq!(saved.offset, 5);
+        assert_eq!(
+            std::fs::read_to_string(file.path.unwrap()).unwrap(),
+            "memes"
+        );
+    }
+
     #[actix_rt::test]
     /// Tests that method would return 404 if file was saved in other storage.
     async fn wrong_storage() {
@@ -399,7 +673,7 @@ mod tests {
 
     #[actix_rt::test]
     /// Tests that method will return 404 if
-    /// unknown file_id is passed.
+    /// unknown `file_id` is passed.
     async fn unknown_file_id() {
         let state = State::test_new().await;
         let rustus = get_service(state.clone()).await;
@@ -430,6 +704,174 @@ mod tests {
             .set_payload("memes")
             .to_request();
         let resp = call_service(&rustus, request).await;
-        assert_eq!(resp.status(), StatusCode::EXPECTATION_FAILED);
+        assert_eq!(resp.status(), StatusCode::from_u16(460).unwrap());
+    }
+
+    #[actix_rt::test]
+    /// Tests that chunks without checksum
+    /// aren't limited by the maximum body size.
+    async fn large_chunk() {
+        let state = State::test_new().await;
+        let rustus = get_service(state.clone()).await;
+        let mut file = state.create_test_file().await;
+        let chunk_len = state.config.max_body_size * 4;
+        file.length = Some(chunk_len);
+        file.offset = 0;
+        state.info_storage.set_info(&file, false).await.unwrap();
+        let request = TestRequest::patch()
+            .uri(state.config.file_url(file.id.as_str()).as_str())
+            .insert_header(("Content-Type", "application/offset+octet-stream"))
+            .insert_header(("Upload-Offset", file.offset))
+            .set_payload(vec![b'a'; chunk_len])
+            .to_request();
+        let resp = call_service(&rustus, request).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        let new_info = state.info_storage.get_info(&file.id).await.unwrap();
+        assert_eq!(new_info.offset, chunk_len);
+        assert_eq!(
+            std::fs::metadata(new_info.path.unwrap()).unwrap().len(),
+            chunk_len as u64
+        );
+    }
+
+    #[actix_rt::test]
+    /// Tests that client can't send
+    /// more bytes than the upload can hold.
+    async fn chunk_exceeds_length() {
+        let state = State::test_new().await;
+        let rustus = get_service(state.clone()).await;
+        let mut file = state.create_test_file().await;
+        file.length = Some(3);
+        file.offset = 0;
+        state.info_storage.set_info(&file, false).await.unwrap();
+        let request = TestRequest::patch()
+            .uri(state.config.file_url(file.id.as_str()).as_str())
+            .insert_header(("Content-Type", "application/offset+octet-stream"))
+            .insert_header(("Upload-Offset", file.offset))
+            .set_payload("memes")
+            .to_request();
+        let resp = call_service(&rustus, request).await;
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let new_info = state.info_storage.get_info(&file.id).await.unwrap();
+        assert_eq!(new_info.offset, 0);
+    }
+
+    #[actix_rt::test]
+    /// Tests that chunks with checksum
+    /// are limited by the maximum body size.
+    async fn checksum_chunk_too_large() {
+        let state = State::test_new().await;
+        let rustus = get_service(state.clone()).await;
+        let mut file = state.create_test_file().await;
+        let chunk_len = state.config.max_body_size + 1;
+        file.length = Some(chunk_len);
+        file.offset = 0;
+        state.info_storage.set_info(&file, false).await.unwrap();
+        let request = TestRequest::patch()
+            .uri(state.config.file_url(file.id.as_str()).as_str())
+            .insert_header(("Content-Type", "application/offset+octet-stream"))
+            .insert_header(("Upload-Checksum", "md5 K9opmNmw7hl9oUKgRH9nJQ=="))
+            .insert_header(("Upload-Offset", file.offset))
+            .set_payload(vec![b'a'; chunk_len])
+            .to_request();
+        let resp = call_service(&rustus, request).await;
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[actix_rt::test]
+    /// Tests that digest of the whole upload
+    /// is computed across several requests.
+    async fn upload_digest() {
+        let mut state = State::test_new().await;
+        state.config.upload_digest = Some(DigestAlgorithm::Md5);
+        let rustus = get_service(state.clone()).await;
+        let mut file = state.create_test_file().await;
+        file.digest = Some(UploadDigest::new(DigestAlgorithm::Md5));
+        state.info_storage.set_info(&file, false).await.unwrap();
+        for (offset, data) in [(0, "hello"), (5, "world")] {
+            let request = TestRequest::patch()
+                .uri(state.config.file_url(file.id.as_str()).as_str())
+                .insert_header(("Content-Type", "application/offset+octet-stream"))
+                .insert_header(("Upload-Offset", offset))
+                .set_payload(data)
+                .to_request();
+            let resp = call_service(&rustus, request).await;
+            assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        }
+        let new_info = state.info_storage.get_info(file.id.as_str()).await.unwrap();
+        assert_eq!(
+            new_info.digest.unwrap().header_value().unwrap(),
+            "md5 /F4DjTilcDIIVEHn/nAQsA=="
+        );
+    }
+
+    #[actix_rt::test]
+    /// Tests that only the chunk which doesn't match
+    /// the checksum from metadata is rolled back.
+    async fn upload_digest_mismatch() {
+        let mut state = State::test_new().await;
+        state.config.upload_digest = Some(DigestAlgorithm::Md5);
+        let rustus = get_service(state.clone()).await;
+        let mut file = state.create_test_file().await;
+        file.digest = Some(UploadDigest::new(DigestAlgorithm::Md5));
+        file.metadata.insert(
+            String::from(CHECKSUM_METADATA_KEY),
+            String::from("md5 /F4DjTilcDIIVEHn/nAQsA=="),
+        );
+        state.info_storage.set_info(&file, false).await.unwrap();
+        for (offset, data, status, saved) in [
+            (0, "hello", StatusCode::NO_CONTENT, "hello"),
+            (5, "earth", StatusCode::from_u16(460).unwrap(), "hello"),
+            (5, "world", StatusCode::NO_CONTENT, "helloworld"),
+        ] {
+            let request = TestRequest::patch()
+                .uri(state.config.file_url(file.id.as_str()).as_str())
+                .insert_header(("Content-Type", "application/offset+octet-stream"))
+                .insert_header(("Upload-Offset", offset))
+                .set_payload(data)
+                .to_request();
+            let resp = call_service(&rustus, request).await;
+            assert_eq!(resp.status(), status);
+            let new_info = state.info_storage.get_info(file.id.as_str()).await.unwrap();
+            assert_eq!(new_info.offset, saved.len());
+            assert_eq!(
+                std::fs::read_to_string(new_info.path.unwrap()).unwrap(),
+                saved
+            );
+        }
+    }
+
+    #[actix_rt::test]
+    /// Tests that checksum from metadata is verified
+    /// even if the digest algorithm was changed.
+    async fn upload_digest_algorithm_changed() {
+        let mut state = State::test_new().await;
+        state.config.upload_digest = Some(DigestAlgorithm::Sha256);
+        let rustus = get_service(state.clone()).await;
+        let mut file = state.create_test_file().await;
+        file.digest = Some(UploadDigest::new(DigestAlgorithm::Md5));
+        file.metadata.insert(
+            String::from(CHECKSUM_METADATA_KEY),
+            String::from("md5 /F4DjTilcDIIVEHn/nAQsA=="),
+        );
+        state.info_storage.set_info(&file, false).await.unwrap();
+        for (data, status) in [
+            ("helloearth", StatusCode::from_u16(460).unwrap()),
+            ("helloworld", StatusCode::NO_CONTENT),
+        ] {
+            let request = TestRequest::patch()
+                .uri(state.config.file_url(file.id.as_str()).as_str())
+                .insert_header(("Content-Type", "application/offset+octet-stream"))
+                .insert_header(("Upload-Offset", 0))
+                .set_payload(data)
+                .to_request();
+            let resp = call_service(&rustus, request).await;
+            assert_eq!(resp.status(), status);
+        }
+        let new_info = state.info_storage.get_info(file.id.as_str()).await.unwrap();
+        assert_eq!(
+            new_info.digest.unwrap().header_value().unwrap(),
+            "md5 /F4DjTilcDIIVEHn/nAQsA=="
+        );
     }
 }
@@ -1,15 +1,20 @@
-use actix_web::{web, web::Bytes, HttpRequest, HttpResponse};
+use actix_web::{web, HttpRequest, HttpResponse};
 use base64::{engine::general_purpose, Engine};
 use std::collections::HashMap;
 
 use crate::{
     data_storage::base::DataStorage,
+    errors::RustusError,
     file_info::FileInfo,
     info_storage::base::InfoStorage,
     metrics,
     notifiers::Hook,
     protocol::extensions::Extensions,
-    utils::headers::{check_header, parse_header},
+    utils::{
+        hashes::{upload_digest_matches, RunningDigest, UploadDigest, CHECKSUM_METADATA_KEY},
+        headers::{check_header, parse_header},
+        payload::payload_stream,
+    },
     State,
 };
 
@@ -56,7 +61,7 @@ fn get_upload_parts(request: &HttpRequest) -> Vec<String> {
     let urls = header_str.strip_prefix("final;").unwrap();
 
     urls.split(' ')
-        .filter_map(|val: &str| val.trim().split('/').last().map(String::from))
+        .filter_map(|val: &str| val.trim().split('/').next_back().map(String::from))
         .filter(|val| val.trim() != "")
         .collect()
 }
@@ -74,7 +79,7 @@ pub async fn create_file(
     metrics: web::Data<metrics::RustusMetrics>,
     state: web::Data<State>,
     request: HttpRequest,
-    bytes: Bytes,
+    payload: web::Payload,
 ) -> actix_web::Result<HttpResponse> {
     // Getting Upload-Length header value as usize.
     let length = parse_header(&request, "Upload-Length");
@@ -108,23 +113,23 @@ pub async fn create_file(
         return Ok(HttpResponse::BadRequest().body("Upload-Length header is required"));
     }
 
-    if state.config.max_file_size.is_some() && state.config.max_file_size < length {
-        return Ok(HttpResponse::BadRequest().body(format!(
-            "Upload-Length should be less than or equal to {}",
-            state.config.max_file_size.unwrap()
-        )));
+    let options = state.reloadable.current();
+    if let Some(max_file_size) = options.max_file_size {
+        if length.is_some_and(|length| max_file_size < length) {
+            return Ok(HttpResponse::BadRequest().body(format!(
+                "Upload-Length should be less than or equal to {max_file_size}"
+            )));
+        }
     }
 
     let meta = get_metadata(&request);
 
     let file_id = uuid::Uuid::new_v4().to_string();
-    let mut file_info = FileInfo::new(
-        file_id.as_str(),
-        length,
-        None,
-        state.data_storage.get_name().to_string(),
-        meta,
-    );
+    let mut file_info = FileInfo::new(file_id.as_str(), length, None, String::new(), meta);
+    // Chosen storage is saved, so the upload
+    // is continued in the same storage.
+    let (storage_name, data_storage) = state.data_storages.for_new_upload(&request, &file_info);
+    file_info.storage = storage_name.to_string();
 
     let is_partial = check_header(&request, "Upload-Concat", |val| val == "partial");
 
@@ -144,7 +149,19 @@ pub async fn create_file(
         }
     }
 
-    if state.config.hook_is_active(Hook::PreCreate) {
+    // Digests of final uploads aren't computed,
+    // since their data is never received by Rustus.
+    if let Some(algorithm) = state.config.upload_digest.filter(|_| !file_info.is_final) {
+        // Declared checksum can be verified only with the same algorithm.
+        if let Some(checksum) = file_info.metadata.get(CHECKSUM_METADATA_KEY) {
+            if checksum.split(' ').next() != Some(algorithm.to_string().as_str()) {
+                return Err(RustusError::ChecksumAlgorithmMismatch(algorithm.to_string()).into());
+            }
+        }
+        file_info.digest = Some(UploadDigest::new(algorithm));
+    }
+
+    if options.hook_is_active(Hook::PreCreate) {
         let message = state.config.notification_opts.hooks_format.format(
             &request,
             &file_info,
@@ -152,14 +169,14 @@ pub async fn create_file(
         );
         let headers = request.headers();
         let cloned_info = file_info.clone();
-        state
+        options
             .notification_manager
             .send_message(message, Hook::PreCreate, &cloned_info, headers)
             .await?;
     }
 
     // Create file and get the it's path.
-    file_info.path = Some(state.data_storage.create_file(&mut file_info).await?);
+    file_info.path = Some(data_storage.create_file(&mut file_info).await?);
 
     // Incrementing number of active uploads
 
@@ -186,18 +203,30 @@ pub async fn create_file(
                     HttpResponse::BadRequest().body(format!("{} upload is not partial.", part.id))
                 );
             }
+            // Storages can't concatenate data from each other.
+            if part.storage != file_info.storage {
+                return Ok(HttpResponse::BadRequest()
+                    .body(format!("{} upload is stored in another storage.", part.id)));
+            }
             final_size += &part.length.unwrap();
             parts_info.push(part.clone());
         }
-        state
-            .data_storage
-            .concat_files(&file_info, parts_info.clone())
-            .await?;
+        if let Err(err) = data_storage
+            .concat_files(&mut file_info, parts_info.clone())
+            .await
+        {
+            // Final upload is saved, so concatenation
+            // can be retried with admin API.
+            file_info.refresh_expiration(state.config.upload_lifetime());
+            state.info_storage.set_info(&file_info, true).await?;
+            metrics.active_uploads.dec();
+            return Err(err.into());
+        }
         file_info.offset = final_size;
         file_info.length = Some(final_size);
         if state.config.remove_parts {
             for part in parts_info {
-                state.data_storage.remove_file(&part).await?;
+                data_storage.remove_file(&part).await?;
                 state.info_storage.remove_info(part.id.as_str()).await?;
             }
         }
@@ -208,19 +237,57 @@ pub async fn create_file(
         .config
         .tus_extensions
         .contains(&Extensions::CreationWithUpload);
-    if with_upload && !bytes.is_empty() && !(concat_ext && is_final) {
+    let mut write_result = Ok(());
+    let digest = RunningDigest::restore(&file_info, state.config.upload_digest);
+    if with_upload && !(concat_ext && is_final) {
         let octet_stream = |val: &str| val == "application/offset+octet-stream";
         if check_header(&request, "Content-Type", octet_stream) {
+            // Client can't send more bytes than the upload can hold.
+            let limit = file_info.length.or(options.max_file_size);
             // Writing first bytes.
-            let chunk_len = bytes.len();
-            // Appending bytes to file.
-            state.data_storage.add_bytes(&mut file_info, bytes).await?;
-            // Updating offset.
-            file_info.offset += chunk_len;
+            // Offset is updated by the storage.
+            let created_info = file_info.clone();
+            write_result = data_storage
+                .add_bytes(&mut file_info, digest.wrap(payload_stream(payload, limit)))
+                .await;
+            // Upload doesn't match the checksum declared by the client,
+            // so it's saved as empty and the client can upload bytes again.
+            if matches!(write_result, Err(RustusError::WrongChecksum)) {
+                file_info = created_info;
+                data_storage.truncate(&mut file_info).await?;
+            }
         }
     }
+    // Empty uploads are complete right after creation,
+    // so the digest is saved even if nothing was written.
+    // Rolled back uploads keep the digest they were created with.
+    if !matches!(write_result, Err(RustusError::WrongChecksum)) {
+        digest.save(&mut file_info, 0);
+    }
 
-    state.info_storage.set_info(&file_info, true).await?;
+    file_info.refresh_expiration(state.config.upload_lifetime());
+    // Upload is saved even if the client has gone
+    // in the middle of the request, so it can be resumed.
+    if let Err(err) = state.info_storage.set_info(&file_info, true).await {
+        // Upload without info can't be resumed or removed later.
+        if let Err(remove_err) = data_storage.remove_file(&file_info).await {
+            log::error!(
+                "Cannot remove data of upload {}: {remove_err}",
+                file_info.id
+            );
+        }
+        metrics.active_uploads.dec();
+        return Err(err.into());
+    }
+    write_result?;
+
+    // Digest of the complete upload can't be verified.
+    if !upload_digest_matches(&file_info) {
+        data_storage.remove_file(&file_info).await?;
+        state.info_storage.remove_info(&file_info.id).await?;
+        metrics.active_uploads.dec();
+        return Err(RustusError::WrongChecksum.into());
+    }
 
     // It's more intuitive to send post-finish
     // hook, when final upload is created.
@@ -231,7 +298,7 @@ pub async fn create_file(
         Hook::PostCreate
     };
 
-    if state.config.hook_is_active(post_hook) {
+    if options.hook_is_active(post_hook) {
         let message = state.config.notification_opts.hooks_format.format(
             &request,
             &file_info,
@@ -241,18 +308,29 @@ pub async fn create_file(
         // Adding send_message task to tokio reactor.
         // Thin function would be executed in background.
         let cloned_info = file_info.clone();
-        tokio::task::spawn_local(async move {
-            state
-                .notification_manager
-                .send_message(message, post_hook, &cloned_info, &headers)
-                .await
-        });
+        state
+            .pending
+            .spawn_hook(post_hook, &file_info.id, async move {
+                options
+                    .notification_manager
+                    .send_message(message, post_hook, &cloned_info, &headers)
+                    .await
+            });
     }
 
     // Create upload URL for this file.
-    let upload_url = request.url_for("core:write_bytes", [file_info.id.clone()])?;
-
-    Ok(HttpResponse::Created()
+    // Route names are shared by all tenants, so the URL
+    // is built from the path of the current request instead.
+    let mut upload_url = request.full_url();
+    upload_url.set_query(None);
+    upload_url.set_path(&format!(
+        "{}/{}",
+        request.path().trim_end_matches('/'),
+        file_info.id
+    ));
+
+    let mut builder = HttpResponse::Created();
+    builder
         .insert_header((
             "Location",
             upload_url
@@ -260,13 +338,29 @@ pub async fn create_file(
                 .strip_suffix('/')
                 .unwrap_or(upload_url.as_str()),
         ))
-        .insert_header(("Upload-Offset", file_info.offset.to_string()))
-        .finish())
+        .insert_header(("Upload-Offset", file_info.offset.to_string()));
+    if let Some(expires) = file_info.get_expiration_string() {
+        builder.insert_header(("Upload-Expires", expires));
+    }
+    Ok(builder.finish())
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{info_storage::base::InfoStorage, server::test::get_service, State};
+    use std::str::FromStr;
+
+    use crate::{
+        data_storage::{
+            base::DataStorage, impls::file_storage::FileDataStorage, routing::StorageRules,
+            DataStorageImpl,
+        },
+        info_storage::base::InfoStorage,
+        protocol::extensions::Extensions,
+        reload::Reloader,
+        server::test::get_service,
+        utils::hashes::DigestAlgorithm,
+        State,
+    };
     use actix_web::{
         http::StatusCode,
         test::{call_service, TestRequest},
@@ -292,13 +386,38 @@ mod tests {
             .to_str()
             .unwrap()
             .split('/')
-            .last()
+            .next_back()
             .unwrap();
         let file_info = state.info_storage.get_info(item_id).await.unwrap();
         assert_eq!(file_info.length, Some(100));
         assert_eq!(file_info.offset, 0);
     }
 
+    #[actix_rt::test]
+    async fn success_expiration() {
+        let mut state = State::test_new().await;
+        state.config.tus_extensions.push(Extensions::Expiration);
+        let rustus = get_service(state.clone()).await;
+        let request = TestRequest::post()
+            .uri(state.config.test_url().as_str())
+            .insert_header(("Upload-Length", 100))
+            .to_request();
+        let resp = call_service(&rustus, request).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        assert!(resp.headers().contains_key("Upload-Expires"));
+        let item_id = resp
+            .headers()
+            .get("Location")
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .split('/')
+            .next_back()
+            .unwrap();
+        let file_info = state.info_storage.get_info(item_id).await.unwrap();
+        assert!(file_info.expires_at.is_some());
+    }
+
     #[actix_rt::test]
     async fn wrong_length() {
         let state = State::test_new().await;
@@ -345,7 +464,7 @@ mod tests {
             .to_str()
             .unwrap()
             .split('/')
-            .last()
+            .next_back()
             .unwrap();
         let file_info = state.info_storage.get_info(item_id).await.unwrap();
         assert_eq!(file_info.length, Some(100));
@@ -373,7 +492,7 @@ mod tests {
             .to_str()
             .unwrap()
             .split('/')
-            .last()
+            .next_back()
             .unwrap();
         let file_info = state.info_storage.get_info(item_id).await.unwrap();
         assert_eq!(file_info.length, Some(100));
@@ -398,7 +517,7 @@ mod tests {
             .to_str()
             .unwrap()
             .split('/')
-            .last()
+            .next_back()
             .unwrap();
         let file_info = state.info_storage.get_info(item_id).await.unwrap();
         assert_eq!(file_info.length, None);
@@ -424,7 +543,7 @@ mod tests {
             .to_str()
             .unwrap()
             .split('/')
-            .last()
+            .next_back()
             .unwrap();
         let file_info = state.info_storage.get_info(item_id).await.unwrap();
         assert_eq!(file_info.length, Some(100));
@@ -467,7 +586,7 @@ mod tests {
             .to_str()
             .unwrap()
             .split('/')
-            .last()
+            .next_back()
             .unwrap();
         let file_info = state.info_storage.get_info(item_id).await.unwrap();
         assert_eq!(file_info.length, Some(200));
@@ -514,7 +633,7 @@ mod tests {
             .to_str()
             .unwrap()
             .split('/')
-            .last()
+            .next_back()
             .unwrap();
         let file_info = state.info_storage.get_info(item_id).await.unwrap();
         assert_eq!(file_info.length, Some(100));
@@ -523,6 +642,76 @@ mod tests {
         assert_eq!(file_info.offset, 0);
     }
 
+    #[actix_rt::test]
+    async fn storage_rules() {
+        let mut state = State::test_new().await;
+        let videos_dir = tempdir::TempDir::new("videos").unwrap();
+        state
+            .data_storages
+            .add_named(
+                "videos",
+                DataStorageImpl::File(FileDataStorage::new(
+                    videos_dir.path().to_path_buf(),
+                    String::new(),
+                    false,
+                )),
+            )
+            .unwrap();
+        state
+            .data_storages
+            .add_rule(
+                StorageRules::from_str(r#"[{"storage": "videos", "metadata": {"type": "video"}}]"#)
+                    .unwrap()
+                    .0
+                    .remove(0),
+            )
+            .unwrap();
+        let rustus = get_service(state.clone()).await;
+        let create = |kind: &str| {
+            TestRequest::post()
+                .uri(state.config.test_url().as_str())
+                .insert_header(("Upload-Length", 100))
+                .insert_header((
+                    "Upload-Metadata",
+                    format!("type {}", general_purpose::STANDARD.encode(kind)),
+                ))
+                .to_request()
+        };
+
+        let resp = call_service(&rustus, create("video")).await;
+        let item_id = resp
+            .headers()
+            .get("Location")
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .split('/')
+            .next_back()
+            .unwrap();
+        let file_info = state.info_storage.get_info(item_id).await.unwrap();
+        assert_eq!(file_info.storage, "videos");
+        assert!(file_info
+            .path
+            .unwrap()
+            .starts_with(videos_dir.path().to_str().unwrap()));
+
+        let resp = call_service(&rustus, create("image")).await;
+        let item_id = resp
+            .headers()
+            .get("Location")
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .split('/')
+            .next_back()
+            .unwrap();
+        let file_info = state.info_storage.get_info(item_id).await.unwrap();
+        assert_eq!(
+            file_info.storage,
+            state.data_storages.default_storage().get_name()
+        );
+    }
+
     #[actix_rt::test]
     async fn success_with_metadata_wrong_encoding() {
         let state = State::test_new().await;
@@ -548,7 +737,7 @@ mod tests {
             .to_str()
             .unwrap()
             .split('/')
-            .last()
+            .next_back()
             .unwrap();
         let file_info = state.info_storage.get_info(item_id).await.unwrap();
         assert_eq!(file_info.length, Some(100));
@@ -570,9 +759,12 @@ mod tests {
 
     #[actix_rt::test]
     async fn max_file_size_exceeded() {
-        let mut state = State::test_new().await;
-        state.config.max_file_size = Some(1000);
+        let state = State::test_new().await;
         let rustus = get_service(state.clone()).await;
+        // Limit is applied without restart.
+        let mut config = state.config.clone();
+        config.max_file_size = Some(1000);
+        Reloader::new(&state, &[]).apply(&config).await.unwrap();
         let request = TestRequest::post()
             .uri(state.config.test_url().as_str())
             .insert_header(("Upload-Length", 1001))
@@ -580,4 +772,81 @@ mod tests {
         let resp = call_service(&rustus, request).await;
         assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
     }
+
+    #[actix_rt::test]
+    async fn success_with_digest() {
+        let mut state = State::test_new().await;
+        state.config.upload_digest = Some(DigestAlgorithm::Sha256);
+        let rustus = get_service(state.clone()).await;
+        let test_data = "hello world";
+        let checksum =
+            general_purpose::STANDARD.encode("sha256 uU0nuZNNPgilLlLX2n2r+sSE7+N6U4DukIj3rOLvzek=");
+        let request = TestRequest::post()
+            .uri(state.config.test_url().as_str())
+            .insert_header(("Upload-Length", test_data.len()))
+            .insert_header(("Upload-Metadata", format!("checksum {checksum}")))
+            .insert_header(("Content-Type", "application/offset+octet-stream"))
+            .set_payload(web::Bytes::from(test_data))
+            .to_request();
+        let resp = call_service(&rustus, request).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let item_id = resp
+            .headers()
+            .get("Location")
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .split('/')
+            .next_back()
+            .unwrap();
+        let file_info = state.info_storage.get_info(item_id).await.unwrap();
+        assert_eq!(
+            file_info.digest.unwrap().value.unwrap(),
+            "uU0nuZNNPgilLlLX2n2r+sSE7+N6U4DukIj3rOLvzek="
+        );
+    }
+
+    #[actix_rt::test]
+    async fn digest_algorithm_mismatch() {
+        let mut state = State::test_new().await;
+        state.config.upload_digest = Some(DigestAlgorithm::Sha256);
+        let rustus = get_service(state.clone()).await;
+        let checksum = general_purpose::STANDARD.encode("md5 XUFAKrxLKna5cZ2REBfFkg==");
+        let request = TestRequest::post()
+            .uri(state.config.test_url().as_str())
+            .insert_header(("Upload-Length", 100))
+            .insert_header(("Upload-Metadata", format!("checksum {checksum}")))
+            .to_request();
+        let resp = call_service(&rustus, request).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_rt::test]
+    async fn digest_mismatch_with_upload() {
+        let mut state = State::test_new().await;
+        state.config.upload_digest = Some(DigestAlgorithm::Sha256);
+        let rustus = get_service(state.clone()).await;
+        let test_data = "hello earth";
+        let checksum =
+            general_purpose::STANDARD.encode("sha256 uU0nuZNNPgilLlLX2n2r+sSE7+N6U4DukIj3rOLvzek=");
+        let request = TestRequest::post()
+            .uri(state.config.test_url().as_str())
+            .insert_header(("Upload-Length", test_data.len()))
+            .insert_header(("Upload-Metadata", format!("checksum {checksum}")))
+            .insert_header(("Content-Type", "application/offset+octet-stream"))
+            .set_payload(web::Bytes::from(test_data))
+            .to_request();
+        let resp = call_service(&rustus, request).await;
+        assert_eq!(resp.status(), StatusCode::from_u16(460).unwrap());
+        // Upload is kept empty, so bytes can be uploaded again.
+        let uploads = state.info_storage.get_unfinished().await.unwrap();
+        assert_eq!(uploads.len(), 1);
+        assert_eq!(uploads[0].offset, 0);
+        assert_eq!(
+            std::fs::metadata(uploads[0].path.clone().unwrap())
+                .unwrap()
+                .len(),
+            0
+        );
+    }
 }
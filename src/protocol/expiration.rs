@@ -0,0 +1,223 @@
+use std::time::Duration;
+
+use crate::{
+    data_storage::{base::DataStorage, registry::DataStorageRegistry, DataStorageImpl},
+    errors::{RustusError, RustusResult},
+    info_storage::{base::InfoStorage, InfoStorageImpl},
+    locks::LockerImpl,
+    State,
+};
+
+/// Remove expired uploads.
+///
+/// This function finds all expired uploads
+/// and removes both data and information about them.
+///
+/// Returns number of removed uploads.
+pub async fn remove_expired(
+    data_storages: &DataStorageRegistry,
+    info_storage: &InfoStorageImpl,
+    locker: &LockerImpl,
+) -> RustusResult<usize> {
+    let expired = info_storage.get_expired(chrono::Utc::now()).await?;
+    let mut removed = 0;
+    for file_info in expired {
+        // Uploads from unknown storages can't be removed.
+        let Some(data_storage) = data_storages.get(&file_info.storage) else {
+            log::debug!(
+                "Skipping expired upload {} from storage {}",
+                file_info.id,
+                file_info.storage
+            );
+            continue;
+        };
+        let result = locker
+            .with_lock(
+                &file_info.id,
+                remove_locked(data_storage, info_storage, &file_info.id),
+            )
+            .await;
+        match result {
+            Ok(true) => {
+                log::debug!("Expired upload {} was removed", file_info.id);
+                removed += 1;
+            }
+            // Upload is being written, so it isn't abandoned.
+            Ok(false) | Err(RustusError::UploadLocked | RustusError::InfoLocked) => {}
+            Err(err) => log::error!("Cannot remove expired upload {}: {}", file_info.id, err),
+        }
+    }
+    Ok(removed)
+}
+
+/// Remove an expired upload, while it's locked.
+///
+/// The upload might have been prolonged after
+/// it was found, so expiration is checked again.
+///
+/// Returns `false` if the upload isn't expired anymore.
+async fn remove_locked(
+    data_storage: &DataStorageImpl,
+    info_storage: &InfoStorageImpl,
+    file_id: &str,
+) -> RustusResult<bool> {
+    let file_info = match info_storage.get_info(file_id).await {
+        Ok(file_info) => file_info,
+        // Someone else has already removed it.
+        Err(RustusError::FileNotFound) => return Ok(false),
+        Err(err) => return Err(err),
+    };
+    if !file_info.is_expired() {
+        return Ok(false);
+    }
+    // Information is removed first, so the upload is kept
+    // if someone has changed it since it was read.
+    match info_storage.remove_unchanged_info(&file_info).await {
+        Ok(()) => {}
+        Err(RustusError::FileNotFound | RustusError::InfoVersionConflict) => return Ok(false),
+        Err(err) => return Err(err),
+    }
+    if let Err(err) = data_storage.remove_file(&file_info).await {
+        log::warn!(
+            "Cannot remove data of expired upload {}: {}",
+            file_info.id,
+            err
+        );
+    }
+    Ok(true)
+}
+
+/// Periodically remove expired uploads.
+///
+/// This function never returns, so it
+/// must be spawned as a separate task.
+pub async fn run_reaper(state: State) {
+    let mut interval = tokio::time::interval(Duration::from_secs(state.config.expiration_interval));
+    loop {
+        interval.tick().await;
+        match remove_expired(&state.data_storages, &state.info_storage, &state.locker).await {
+            Ok(0) => {}
+            Ok(removed) => log::info!("Removed {removed} expired uploads"),
+            Err(err) => log::error!("Cannot remove expired uploads: {err}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::remove_expired;
+    use crate::{info_storage::base::InfoStorage, State};
+
+    #[actix_rt::test]
+    async fn success() {
+        let state = State::test_new().await;
+        let mut expired_info = state.create_test_file().await;
+        expired_info.expires_at = Some(chrono::Utc::now() - chrono::Duration::seconds(10));
+        state
+            .info_storage
+            .set_info(&expired_info, false)
+            .await
+            .unwrap();
+        let mut alive_info = state.create_test_file().await;
+        alive_info.expires_at = Some(chrono::Utc::now() + chrono::Duration::seconds(10));
+        state
+            .info_storage
+            .set_info(&alive_info, false)
+            .await
+            .unwrap();
+
+        let removed = remove_expired(&state.data_storages, &state.info_storage, &state.locker)
+            .await
+            .unwrap();
+
+        assert_eq!(removed, 1);
+        assert!(state
+            .info_storage
+            .get_info(expired_info.id.as_str())
+            .await
+            .is_err());
+        assert!(!PathBuf::from(expired_info.path.unwrap()).exists());
+        assert!(state
+            .info_storage
+            .get_info(alive_info.id.as_str())
+            .await
+            .is_ok());
+        assert!(PathBuf::from(alive_info.path.unwrap()).exists());
+    }
+
+    #[actix_rt::test]
+    async fn locked() {
+        let state = State::test_new().await;
+        let mut file_info = state.create_test_file().await;
+        file_info.expires_at = Some(chrono::Utc::now() - chrono::Duration::seconds(10));
+        state
+            .info_storage
+            .set_info(&file_info, false)
+            .await
+            .unwrap();
+        // Upload is being written by someone else.
+        let lock = state.locker.acquire(&file_info.id).await.unwrap();
+
+        let removed = remove_expired(&state.data_storages, &state.info_storage, &state.locker)
+            .await
+            .unwrap();
+
+        assert_eq!(removed, 0);
+        assert!(state.info_storage.get_info(&file_info.id).await.is_ok());
+        lock.release().await.unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn info_locked() {
+        let state = State::test_new().await;
+        let mut file_info = state.create_test_file().await;
+        file_info.expires_at = Some(chrono::Utc::now() - chrono::Duration::seconds(10));
+        state
+            .info_storage
+            .set_info(&file_info, false)
+            .await
+            .unwrap();
+        // Information is being updated right now.
+        let lock_path = state
+            .config
+            .info_storage_opts
+            .info_dir
+            .join(format!("{}.lock", file_info.id));
+        std::fs::File::create(&lock_path).unwrap();
+
+        let removed = remove_expired(&state.data_storages, &state.info_storage, &state.locker)
+            .await
+            .unwrap();
+
+        assert_eq!(removed, 0);
+        assert!(PathBuf::from(file_info.path.clone().unwrap()).exists());
+        std::fs::remove_file(lock_path).unwrap();
+        assert!(state.info_storage.get_info(&file_info.id).await.is_ok());
+    }
+
+    #[actix_rt::test]
+    async fn other_storage() {
+        let state = State::test_new().await;
+        let mut file_info = state.create_test_file().await;
+        file_info.storage = String::from("unknown");
+        file_info.expires_at = Some(chrono::Utc::now() - chrono::Duration::seconds(10));
+        state
+            .info_storage
+            .set_info(&file_info, false)
+            .await
+            .unwrap();
+
+        let removed = remove_expired(&state.data_storages, &state.info_storage, &state.locker)
+            .await
+            .unwrap();
+
+        assert_eq!(removed, 0);
+        assert!(state
+            .info_storage
+            .get_info(file_info.id.as_str())
+            .await
+            .is_ok());
+    }
+}
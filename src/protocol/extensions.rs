@@ -22,6 +22,8 @@ pub enum Extensions {
     Getting,
     #[display("checksum")]
     Checksum,
+    #[display("expiration")]
+    Expiration,
 }
 
 from_str!(Extensions, "extension");
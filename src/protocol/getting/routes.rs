@@ -12,10 +12,11 @@ pub async fn get_file(request: HttpRequest, state: web::Data<State>) -> RustusRe
     let file_id_opt = request.match_info().get("file_id").map(String::from);
     if let Some(file_id) = file_id_opt {
         let file_info = state.info_storage.get_info(file_id.as_str()).await?;
-        if file_info.storage != state.data_storage.get_name() {
-            return Err(RustusError::FileNotFound);
-        }
-        state.data_storage.get_contents(&file_info, &request).await
+        state
+            .data_storages
+            .for_upload(&file_info)?
+            .get_contents(&file_info, &request)
+            .await
     } else {
         Err(RustusError::FileNotFound)
     }
@@ -24,14 +25,15 @@ pub async fn get_file(request: HttpRequest, state: web::Data<State>) -> RustusRe
 #[cfg(test)]
 mod test {
     use crate::{
-        data_storage::base::DataStorage, info_storage::base::InfoStorage,
-        server::test::get_service, State,
+        data_storage::base::{bytes_stream, DataStorage},
+        info_storage::base::InfoStorage,
+        server::test::get_service,
+        State,
     };
     use actix_web::{
         http::StatusCode,
         test::{call_service, TestRequest},
     };
-    use bytes::Bytes;
 
     #[actix_rt::test]
     async fn success() {
@@ -39,8 +41,9 @@ mod test {
         let rustus = get_service(state.clone()).await;
         let mut file_info = state.create_test_file().await;
         state
-            .data_storage
-            .add_bytes(&mut file_info, Bytes::from("testing"))
+            .data_storages
+            .default_storage()
+            .add_bytes(&mut file_info, bytes_stream("testing"))
             .await
             .unwrap();
         let request = TestRequest::get()
@@ -50,6 +53,25 @@ mod test {
         assert!(resp.status().is_success());
     }
 
+    #[actix_rt::test]
+    async fn legacy_storage() {
+        let mut state = State::test_new().await;
+        let mut file_info = state.create_test_file().await;
+        state
+            .data_storages
+            .default_storage()
+            .add_bytes(&mut file_info, bytes_stream("testing"))
+            .await
+            .unwrap();
+        state.switch_data_storage();
+        let rustus = get_service(state.clone()).await;
+        let request = TestRequest::get()
+            .uri(state.config.file_url(file_info.id.as_str()).as_str())
+            .to_request();
+        let resp = call_service(&rustus, request).await;
+        assert!(resp.status().is_success());
+    }
+
     #[actix_rt::test]
     async fn unknown_file_id() {
         let state = State::test_new().await;
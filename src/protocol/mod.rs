@@ -2,8 +2,10 @@ use actix_web::web;
 
 use crate::RustusConf;
 
+pub mod consistency;
 mod core;
 mod creation;
+pub mod expiration;
 pub mod extensions;
 mod getting;
 mod termination;
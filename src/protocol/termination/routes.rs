@@ -1,12 +1,8 @@
 use actix_web::{web, HttpRequest, HttpResponse};
 
 use crate::{
-    data_storage::base::DataStorage,
-    errors::{RustusError, RustusResult},
-    info_storage::base::InfoStorage,
-    metrics,
-    notifiers::Hook,
-    State,
+    data_storage::base::DataStorage, errors::RustusResult, info_storage::base::InfoStorage,
+    metrics, notifiers::Hook, State,
 };
 
 /// Terminate uploading.
@@ -20,42 +16,60 @@ pub async fn terminate(
 ) -> RustusResult<HttpResponse> {
     let file_id_opt = request.match_info().get("file_id").map(String::from);
     if let Some(file_id) = file_id_opt {
-        let file_info = state.info_storage.get_info(file_id.as_str()).await?;
-        if file_info.storage != state.data_storage.get_name() {
-            return Err(RustusError::FileNotFound);
-        }
-        if state.config.hook_is_active(Hook::PreTerminate) {
-            let message = state.config.notification_opts.hooks_format.format(
-                &request,
-                &file_info,
-                state.config.notification_opts.behind_proxy,
-            );
-            let headers = request.headers();
-            state
-                .notification_manager
-                .send_message(message, Hook::PreTerminate, &file_info, headers)
-                .await?;
-        }
-        state.info_storage.remove_info(file_id.as_str()).await?;
-        state.data_storage.remove_file(&file_info).await?;
-        metrics.terminated_uploads.inc();
-        if state.config.hook_is_active(Hook::PostTerminate) {
-            let message = state.config.notification_opts.hooks_format.format(
-                &request,
-                &file_info,
-                state.config.notification_opts.behind_proxy,
-            );
-            let headers = request.headers().clone();
-            let cloned_info = file_info.clone();
-            tokio::task::spawn_local(async move {
-                state
+        // Upload can't be removed while someone is writing to it.
+        state
+            .locker
+            .with_lock(
+                &file_id,
+                terminate_locked(&request, &state, &metrics, &file_id),
+            )
+            .await?;
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+async fn terminate_locked(
+    request: &HttpRequest,
+    state: &web::Data<State>,
+    metrics: &web::Data<metrics::RustusMetrics>,
+    file_id: &str,
+) -> RustusResult<()> {
+    let file_info = state.info_storage.get_info(file_id).await?;
+    let data_storage = state.data_storages.for_upload(&file_info)?;
+    let options = state.reloadable.current();
+    if options.hook_is_active(Hook::PreTerminate) {
+        let message = state.config.notification_opts.hooks_format.format(
+            request,
+            &file_info,
+            state.config.notification_opts.behind_proxy,
+        );
+        let headers = request.headers();
+        options
+            .notification_manager
+            .send_message(message, Hook::PreTerminate, &file_info, headers)
+            .await?;
+    }
+    state.info_storage.remove_info(file_id).await?;
+    data_storage.remove_file(&file_info).await?;
+    metrics.terminated_uploads.inc();
+    if options.hook_is_active(Hook::PostTerminate) {
+        let message = state.config.notification_opts.hooks_format.format(
+            request,
+            &file_info,
+            state.config.notification_opts.behind_proxy,
+        );
+        let headers = request.headers().clone();
+        let cloned_info = file_info.clone();
+        state
+            .pending
+            .spawn_hook(Hook::PostTerminate, &file_info.id, async move {
+                options
                     .notification_manager
                     .send_message(message, Hook::PostTerminate, &cloned_info, &headers)
                     .await
             });
-        }
     }
-    Ok(HttpResponse::NoContent().finish())
+    Ok(())
 }
 
 #[cfg(test)]
@@ -85,6 +99,21 @@ mod tests {
         assert!(!PathBuf::from(file_info.path.unwrap()).exists());
     }
 
+    #[actix_rt::test]
+    async fn locked_upload() {
+        let state = State::test_new().await;
+        let rustus = get_service(state.clone()).await;
+        let file_info = state.create_test_file().await;
+        let lock = state.locker.acquire(&file_info.id).await.unwrap();
+        let request = TestRequest::delete()
+            .uri(state.config.file_url(file_info.id.as_str()).as_str())
+            .to_request();
+        let response = call_service(&rustus, request).await;
+        assert_eq!(response.status(), StatusCode::LOCKED);
+        assert!(state.info_storage.get_info(&file_info.id).await.is_ok());
+        lock.release().await.unwrap();
+    }
+
     #[actix_rt::test]
     async fn unknown_file_id() {
         let state = State::test_new().await;
@@ -96,6 +125,20 @@ mod tests {
         assert_eq!(result.status(), StatusCode::NOT_FOUND);
     }
 
+    #[actix_rt::test]
+    async fn legacy_storage() {
+        let mut state = State::test_new().await;
+        let file_info = state.create_test_file().await;
+        state.switch_data_storage();
+        let rustus = get_service(state.clone()).await;
+        let request = TestRequest::delete()
+            .uri(state.config.file_url(file_info.id.as_str()).as_str())
+            .to_request();
+        let response = call_service(&rustus, request).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(!PathBuf::from(file_info.path.unwrap()).exists());
+    }
+
     #[actix_rt::test]
     async fn wrong_storage() {
         let state = State::test_new().await;
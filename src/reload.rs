@@ -0,0 +1,221 @@
+use std::sync::{Arc, RwLock};
+
+use crate::{
+    config::RustusConf,
+    config_file::error_message,
+    errors::{RustusError, RustusResult},
+    notifiers::{Hook, NotificationManager},
+    State,
+};
+
+/// Options which can be changed without restart.
+///
+/// Hooks are reloaded with all options of notifiers,
+/// so notifiers are created again.
+pub struct ReloadableOptions {
+    pub hooks: Vec<Hook>,
+    pub max_file_size: Option<usize>,
+    pub cors: Vec<String>,
+    pub notification_manager: NotificationManager,
+}
+
+impl ReloadableOptions {
+    pub async fn new(config: &RustusConf) -> RustusResult<Self> {
+        Ok(Self {
+            hooks: config.notification_opts.hooks.clone(),
+            max_file_size: config.max_file_size,
+            cors: config.cors.clone(),
+            notification_manager: NotificationManager::new(config).await?,
+        })
+    }
+
+    /// Check if hook is enabled by user.
+    pub fn hook_is_active(&self, hook: Hook) -> bool {
+        self.hooks.contains(&hook)
+    }
+}
+
+/// Current reloadable options.
+///
+/// Requests which have already started
+/// keep using options they've got.
+#[derive(Clone)]
+pub struct Reloadable(Arc<RwLock<Arc<ReloadableOptions>>>);
+
+impl Reloadable {
+    pub fn new(options: ReloadableOptions) -> Self {
+        Self(Arc::new(RwLock::new(Arc::new(options))))
+    }
+
+    pub fn current(&self) -> Arc<ReloadableOptions> {
+        self.0
+            .read()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .clone()
+    }
+
+    pub fn replace(&self, options: ReloadableOptions) {
+        *self
+            .0
+            .write()
+            .unwrap_or_else(std::sync::PoisonError::into_inner) = Arc::new(options);
+    }
+}
+
+/// Reloads options of the server and all its tenants.
+#[derive(Clone)]
+pub struct Reloader {
+    main: Reloadable,
+    tenants: Vec<(String, Reloadable)>,
+}
+
+impl Reloader {
+    pub fn new(state: &State, tenants: &[(String, State)]) -> Self {
+        Self {
+            main: state.reloadable.clone(),
+            tenants: tenants
+                .iter()
+                .map(|(name, tenant)| (name.clone(), tenant.reloadable.clone()))
+                .collect(),
+        }
+    }
+
+    /// Read the configuration again and apply it.
+    ///
+    /// Options are read from the same CLI arguments,
+    /// environment variables and configuration file
+    /// as on startup.
+    pub async fn reload(&self) -> RustusResult<Arc<ReloadableOptions>> {
+        let config = RustusConf::try_from_args()
+            .map_err(|err| RustusError::ReloadError(error_message(&err)))?;
+        self.apply(&config).await
+    }
+
+    /// Apply reloadable options from the configuration.
+    ///
+    /// New options are swapped only if all notifiers
+    /// were created successfully, otherwise old ones are kept.
+    /// Tenants can't be added or removed without restart.
+    pub async fn apply(&self, config: &RustusConf) -> RustusResult<Arc<ReloadableOptions>> {
+        let tenant_configs = match &config.tenants {
+            Some(tenants) => tenants.configs(config)?,
+            None => Vec::new(),
+        };
+        let mut updates = vec![(&self.main, ReloadableOptions::new(config).await?)];
+        for (name, reloadable) in &self.tenants {
+            if let Some((_, tenant_config)) =
+                tenant_configs.iter().find(|(tenant, _)| tenant == name)
+            {
+                updates.push((reloadable, ReloadableOptions::new(tenant_config).await?));
+            } else {
+                log::warn!("Tenant {name} was removed, it's served until restart.");
+            }
+        }
+        for (name, _) in &tenant_configs {
+            if !self.tenants.iter().any(|(tenant, _)| tenant == name) {
+                log::warn!("Tenant {name} was added, it's served only after restart.");
+            }
+        }
+        for (reloadable, options) in updates {
+            reloadable.replace(options);
+        }
+        let current = self.main.current();
+        log::info!(
+            "Configuration is reloaded. Enabled hooks: {}",
+            current
+                .hooks
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        Ok(current)
+    }
+}
+
+/// Reload options every time the process receives SIGHUP.
+#[cfg(unix)]
+pub async fn reload_on_hangup(reloader: Reloader) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(err) => {
+            log::error!("Cannot listen to SIGHUP: {err}");
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        log::info!("Reloading configuration.");
+        if let Err(err) = reloader.reload().await {
+            log::error!("{err}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Reloader;
+    use crate::{notifiers::Hook, tenants::Tenants, State};
+    use std::str::FromStr;
+
+    /// Tenant with its own storages.
+    fn tenants(max_file_size: usize) -> String {
+        serde_json::json!({
+            "avatars": {
+                "url": "/avatars",
+                "storage": {"storage": "s3"},
+                "info_dir": "./avatars",
+                "max_file_size": max_file_size,
+            }
+        })
+        .to_string()
+    }
+
+    #[actix_rt::test]
+    async fn apply() {
+        let state = State::test_new().await;
+        let mut config = state.config.clone();
+        config.tenants = Some(Tenants::from_str(&tenants(10)).unwrap());
+        let tenant = State::from_config_test(
+            config
+                .tenants
+                .as_ref()
+                .unwrap()
+                .configs(&config)
+                .unwrap()
+                .remove(0)
+                .1,
+        )
+        .await;
+        let reloader = Reloader::new(&state, &[(String::from("avatars"), tenant.clone())]);
+        // Old options are kept by their users.
+        let old = state.reloadable.current();
+
+        config.notification_opts.hooks = vec![Hook::PostFinish];
+        config.max_file_size = Some(100);
+        config.cors = vec![String::from("*.example.com")];
+        config.tenants = Some(Tenants::from_str(&tenants(20)).unwrap());
+        reloader.apply(&config).await.unwrap();
+
+        let current = state.reloadable.current();
+        assert!(current.hook_is_active(Hook::PostFinish));
+        assert!(!current.hook_is_active(Hook::PostCreate));
+        assert_eq!(current.max_file_size, Some(100));
+        assert_eq!(current.cors, vec![String::from("*.example.com")]);
+        assert_eq!(tenant.reloadable.current().max_file_size, Some(20));
+        assert!(old.hook_is_active(Hook::PostCreate));
+        assert_eq!(old.max_file_size, None);
+    }
+
+    #[actix_rt::test]
+    async fn wrong_config() {
+        let state = State::test_new().await;
+        let reloader = Reloader::new(&state, &[]);
+        let mut config = state.config.clone();
+        config.max_file_size = Some(100);
+        config.tenants = Some(Tenants::from_str(r#"{"files": {"url": "/files"}}"#).unwrap());
+        assert!(reloader.apply(&config).await.is_err());
+        assert_eq!(state.reloadable.current().max_file_size, None);
+    }
+}
@@ -1,8 +1,9 @@
 use crate::{protocol, State};
-use actix_web::{middleware, web, web::PayloadConfig};
+use actix_web::{dev::Service, middleware, web, web::PayloadConfig};
 
 pub fn rustus_service(state: State) -> impl Fn(&mut web::ServiceConfig) {
     move |web_app| {
+        let pending = state.pending.clone();
         web_app.service(
             web::scope(state.config.base_url().as_str())
                 .app_data(web::Data::new(state.clone()))
@@ -16,6 +17,16 @@ pub fn rustus_service(state: State) -> impl Fn(&mut web::ServiceConfig) {
                         .add(("Tus-Resumable", "1.0.0"))
                         .add(("Tus-Version", "1.0.0")),
                 )
+                // Shutdown waits for requests in progress.
+                .wrap_fn(move |req, srv| {
+                    let request = pending.start_request();
+                    let fut = srv.call(req);
+                    async move {
+                        let response = fut.await;
+                        drop(request);
+                        response
+                    }
+                })
                 .configure(protocol::setup(state.config.clone())),
         );
     }
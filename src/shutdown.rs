@@ -0,0 +1,210 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use actix_web::dev::ServerHandle;
+use tokio::sync::Notify;
+
+use crate::{errors::RustusResult, notifiers::Hook};
+
+#[derive(Default)]
+struct Pending {
+    requests: usize,
+    hooks: HashMap<u64, (Hook, String)>,
+    next_hook: u64,
+}
+
+/// Requests and hooks which must be finished before shutdown.
+///
+/// Hooks are sent in background, so they would be lost
+/// if the server stopped right after the last response.
+#[derive(Clone, Default)]
+pub struct PendingWork {
+    pending: Arc<Mutex<Pending>>,
+    changed: Arc<Notify>,
+}
+
+/// Marks a request as finished when dropped.
+pub struct RequestGuard(PendingWork);
+
+impl Drop for RequestGuard {
+    fn drop(&mut self) {
+        self.0.lock().requests -= 1;
+        self.0.changed.notify_waiters();
+    }
+}
+
+/// Removes a hook from pending ones when dropped.
+struct HookGuard(PendingWork, u64);
+
+impl Drop for HookGuard {
+    fn drop(&mut self) {
+        self.0.lock().hooks.remove(&self.1);
+        self.0.changed.notify_waiters();
+    }
+}
+
+impl PendingWork {
+    fn lock(&self) -> MutexGuard<'_, Pending> {
+        self.pending
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    /// Register a request, which is in progress until the guard is dropped.
+    pub fn start_request(&self) -> RequestGuard {
+        self.lock().requests += 1;
+        RequestGuard(self.clone())
+    }
+
+    /// Send a hook in background.
+    ///
+    /// The hook is pending until it's sent,
+    /// errors of notifiers are logged.
+    pub fn spawn_hook<F>(&self, hook: Hook, upload_id: &str, future: F)
+    where
+        F: Future<Output = RustusResult<()>> + 'static,
+    {
+        let guard = {
+            let mut pending = self.lock();
+            let id = pending.next_hook;
+            pending.next_hook += 1;
+            pending.hooks.insert(id, (hook, String::from(upload_id)));
+            drop(pending);
+            HookGuard(self.clone(), id)
+        };
+        let upload_id = String::from(upload_id);
+        tokio::task::spawn_local(async move {
+            if let Err(err) = future.await {
+                log::error!("Cannot send {hook} hook of upload {upload_id}: {err}");
+            }
+            drop(guard);
+        });
+    }
+
+    fn is_empty(&self) -> bool {
+        let pending = self.lock();
+        pending.requests == 0 && pending.hooks.is_empty()
+    }
+
+    /// Wait until all requests and hooks are finished.
+    ///
+    /// If they aren't finished in time, they are logged
+    /// and `false` is returned.
+    pub async fn drain(&self, timeout: Duration) -> bool {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            // Created before the check, so changes aren't missed.
+            let changed = self.changed.notified();
+            if self.is_empty() {
+                return true;
+            }
+            if tokio::time::timeout_at(deadline, changed).await.is_err() {
+                break;
+            }
+        }
+        let pending = self.lock();
+        if pending.requests > 0 {
+            log::warn!("{} requests are interrupted by shutdown.", pending.requests);
+        }
+        for (hook, upload_id) in pending.hooks.values() {
+            log::warn!("Hook {hook} of upload {upload_id} is abandoned by shutdown.");
+        }
+        drop(pending);
+        false
+    }
+}
+
+/// Wait for a signal to stop the server.
+async fn wait_for_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match (signal(SignalKind::terminate()), signal(SignalKind::quit())) {
+            (Ok(mut terminate), Ok(mut quit)) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {},
+                    _ = terminate.recv() => {},
+                    _ = quit.recv() => {},
+                }
+                return;
+            }
+            (Err(err), _) | (_, Err(err)) => {
+                log::error!("Cannot listen to SIGTERM or SIGQUIT: {err}");
+            }
+        }
+    }
+    if let Err(err) = tokio::signal::ctrl_c().await {
+        log::error!("Cannot listen to SIGINT: {err}");
+        futures::future::pending::<()>().await;
+    }
+}
+
+/// Stop servers gracefully on SIGTERM, SIGINT or SIGQUIT.
+///
+/// New connections aren't accepted anymore,
+/// but workers keep running until requests and hooks
+/// are finished or the timeout is reached.
+/// The second signal stops servers immediately.
+pub async fn on_signal(handles: Vec<ServerHandle>, pending: Vec<PendingWork>, timeout: Duration) {
+    wait_for_signal().await;
+    log::info!("Shutting down. Waiting for uploads in progress and pending hooks.");
+    let graceful = async {
+        for handle in &handles {
+            handle.pause().await;
+        }
+        let drained =
+            futures::future::join_all(pending.iter().map(|work| work.drain(timeout))).await;
+        if drained.into_iter().all(|finished| finished) {
+            log::info!("All uploads and hooks are finished.");
+        }
+    };
+    tokio::select! {
+        () = graceful => {},
+        () = wait_for_signal() => {
+            log::warn!("Received the second signal, stopping immediately.");
+        }
+    }
+    for handle in &handles {
+        handle.stop(false).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::PendingWork;
+    use crate::notifiers::Hook;
+
+    #[actix_rt::test]
+    async fn drain() {
+        let pending = PendingWork::default();
+        assert!(pending.drain(Duration::ZERO).await);
+
+        let request = pending.start_request();
+        pending.spawn_hook(Hook::PostFinish, "upload", async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            Ok(())
+        });
+        let cloned = pending.clone();
+        let draining =
+            tokio::task::spawn_local(async move { cloned.drain(Duration::from_secs(5)).await });
+        drop(request);
+        assert!(draining.await.unwrap());
+        assert!(pending.is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn drain_timeout() {
+        let pending = PendingWork::default();
+        let _request = pending.start_request();
+        pending.spawn_hook(Hook::PostFinish, "upload", futures::future::pending());
+        assert!(!pending.drain(Duration::from_millis(10)).await);
+        assert!(!pending.is_empty());
+    }
+}
@@ -1,34 +1,52 @@
 #[cfg(test)]
 use crate::file_info::FileInfo;
 use crate::{
-    data_storage::{base::DataStorage, DataStorageImpl},
+    data_storage::registry::DataStorageRegistry,
     errors::RustusResult,
     info_storage::{base::InfoStorage, InfoStorageImpl},
-    notifiers::NotificationManager,
+    locks::LockerImpl,
+    reload::{Reloadable, ReloadableOptions},
+    shutdown::PendingWork,
     RustusConf,
 };
 
 #[derive(Clone)]
 pub struct State {
+    /// Configuration from startup.
+    ///
+    /// Options which can be reloaded
+    /// must be taken from `reloadable`.
     pub config: RustusConf,
-    pub data_storage: DataStorageImpl,
+    pub data_storages: DataStorageRegistry,
     pub info_storage: InfoStorageImpl,
-    pub notification_manager: NotificationManager,
+    pub locker: LockerImpl,
+    pub reloadable: Reloadable,
+    pub pending: PendingWork,
+    /// Temporary directories of the test state.
+    ///
+    /// They are removed when the last clone of the state is dropped.
+    #[cfg(test)]
+    temp_dirs: std::sync::Arc<std::sync::Mutex<Vec<tempdir::TempDir>>>,
 }
 
 impl State {
     pub async fn new(config: RustusConf) -> RustusResult<Self> {
-        let mut data_storage = config.storage_opts.storage.get(&config);
-        data_storage.prepare().await?;
+        let mut data_storages = DataStorageRegistry::from_config(&config)?;
+        data_storages.prepare().await?;
         let mut info_storage = config.info_storage_opts.info_storage.get(&config)?;
         info_storage.prepare().await?;
-        let notification_manager = NotificationManager::new(&config).await?;
+        let locker = LockerImpl::new(&config)?;
+        let reloadable = Reloadable::new(ReloadableOptions::new(&config).await?);
 
         Ok(Self {
             config,
-            data_storage,
+            data_storages,
             info_storage,
-            notification_manager,
+            locker,
+            reloadable,
+            pending: PendingWork::default(),
+            #[cfg(test)]
+            temp_dirs: std::sync::Arc::default(),
         })
     }
 
@@ -38,19 +56,23 @@ impl State {
 
         Self {
             config: config.clone(),
-            data_storage: DataStorageImpl::File(
+            data_storages: DataStorageRegistry::new(crate::data_storage::DataStorageImpl::File(
                 crate::data_storage::impls::file_storage::FileDataStorage::new(
                     config.storage_opts.data_dir.clone(),
                     config.storage_opts.dir_structure.clone(),
                     config.storage_opts.force_fsync,
                 ),
-            ),
+            )),
             info_storage: InfoStorageImpl::File(
                 crate::info_storage::impls::file_storage::FileInfoStorage::new(
                     config.info_storage_opts.info_dir.clone(),
+                    config.info_storage_opts.info_fsync_dir,
                 ),
             ),
-            notification_manager: NotificationManager::new(&config).await.unwrap(),
+            locker: LockerImpl::Memory(crate::locks::impls::memory_locker::MemoryLocker::default()),
+            reloadable: Reloadable::new(ReloadableOptions::new(&config).await.unwrap()),
+            pending: PendingWork::default(),
+            temp_dirs: std::sync::Arc::default(),
         }
     }
 
@@ -58,31 +80,57 @@ impl State {
     pub async fn test_new() -> Self {
         let data_dir = tempdir::TempDir::new("data_dir").unwrap();
         let info_dir = tempdir::TempDir::new("info_dir").unwrap();
-        let config = RustusConf::from_iter(
-            vec![
-                "rustus",
-                "--data-dir",
-                data_dir.into_path().to_str().unwrap(),
-                "--info-dir",
-                info_dir.into_path().to_str().unwrap(),
-            ]
-            .into_iter(),
-        );
-        Self::from_config_test(config).await
+        let config = RustusConf::from_iter(vec![
+            "rustus",
+            "--data-dir",
+            data_dir.path().to_str().unwrap(),
+            "--info-dir",
+            info_dir.path().to_str().unwrap(),
+        ]);
+        let state = Self::from_config_test(config).await;
+        state.keep_temp_dir(data_dir);
+        state.keep_temp_dir(info_dir);
+        state
+    }
+
+    /// Keep a temporary directory while the state is used.
+    #[cfg(test)]
+    pub fn keep_temp_dir(&self, dir: tempdir::TempDir) {
+        self.temp_dirs.lock().unwrap().push(dir);
+    }
+
+    /// Create new uploads in another storage.
+    ///
+    /// The current storage is kept to serve existing uploads.
+    /// New storage is a hybrid one, which keeps unfinished
+    /// uploads locally, so S3 isn't required.
+    #[cfg(test)]
+    pub fn switch_data_storage(&mut self) {
+        let data_dir = tempdir::TempDir::new("new_data_dir").unwrap();
+        let legacy = self.data_storages.default_storage().clone();
+        self.data_storages =
+            DataStorageRegistry::new(crate::data_storage::DataStorageImpl::S3Hybrid(
+                crate::data_storage::impls::s3_hybrid::S3HybridDataStorage::new_test(
+                    data_dir.path().to_path_buf(),
+                ),
+            ));
+        self.data_storages.add(legacy).unwrap();
+        self.keep_temp_dir(data_dir);
     }
 
     #[cfg(test)]
     pub async fn create_test_file(&self) -> FileInfo {
         use crate::{data_storage::base::DataStorage, info_storage::base::InfoStorage};
 
+        let data_storage = self.data_storages.default_storage();
         let mut new_file = FileInfo::new(
             uuid::Uuid::new_v4().to_string().as_str(),
             Some(10),
             None,
-            self.data_storage.get_name().to_string(),
+            data_storage.get_name().to_string(),
             None,
         );
-        new_file.path = Some(self.data_storage.create_file(&mut new_file).await.unwrap());
+        new_file.path = Some(data_storage.create_file(&mut new_file).await.unwrap());
         self.info_storage.set_info(&new_file, true).await.unwrap();
         new_file
     }
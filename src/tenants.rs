@@ -0,0 +1,385 @@
+use std::{collections::HashMap, path::PathBuf, str::FromStr};
+
+use derive_more::Display;
+use serde::Deserialize;
+
+use crate::{
+    config::RustusConf,
+    data_storage::{routing::NamedStorage, AvailableDataStorages},
+    errors::{RustusError, RustusResult},
+    info_storage::AvailableInfoStorages,
+    notifiers::Hook,
+    protocol::extensions::Extensions,
+};
+
+/// Options of a tenant.
+///
+/// Tenant has its own URL, storages, limits and hooks.
+/// Options which aren't set are taken from the main configuration.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TenantOptions {
+    pub url: String,
+    pub storage: Option<NamedStorage>,
+    pub info_storage: Option<String>,
+    pub info_dir: Option<PathBuf>,
+    pub info_db_dsn: Option<String>,
+    pub max_file_size: Option<usize>,
+    pub tus_extensions: Option<Vec<String>>,
+    pub hooks: Option<Vec<String>>,
+    pub hooks_http_urls: Option<Vec<String>>,
+    pub hooks_file: Option<String>,
+    pub hooks_dir: Option<PathBuf>,
+}
+
+/// Parse list of values from the tenant options.
+fn parse_list<T>(name: &str, values: &[String]) -> RustusResult<Vec<T>>
+where
+    T: FromStr<Err = String>,
+{
+    values
+        .iter()
+        .map(|value| T::from_str(value))
+        .collect::<Result<_, _>>()
+        .map_err(|err| RustusError::WrongConfig(format!("Tenant {name}: {err}")))
+}
+
+impl TenantOptions {
+    /// Create configuration of the tenant.
+    ///
+    /// # Params
+    /// `name` - name of the tenant, used in errors.
+    /// `config` - main configuration.
+    pub fn apply(&self, name: &str, config: &RustusConf) -> RustusResult<RustusConf> {
+        let mut tenant_config = config.clone();
+        tenant_config.tenants = None;
+        tenant_config.tenant = None;
+        tenant_config.url.clone_from(&self.url);
+        if let Some(storage) = &self.storage {
+            storage.apply(&mut tenant_config);
+            // Storages of the main configuration
+            // must not be shared with the tenant.
+            tenant_config.storage_opts.legacy_storages.clear();
+            tenant_config.storage_opts.named_storages = None;
+            tenant_config.storage_opts.storage_rules = None;
+        }
+        if let Some(info_storage) = &self.info_storage {
+            tenant_config.info_storage_opts.info_storage =
+                AvailableInfoStorages::from_str(info_storage)
+                    .map_err(|err| RustusError::WrongConfig(format!("Tenant {name}: {err}")))?;
+        }
+        if let Some(info_dir) = &self.info_dir {
+            tenant_config
+                .info_storage_opts
+                .info_dir
+                .clone_from(info_dir);
+        }
+        if let Some(dsn) = &self.info_db_dsn {
+            tenant_config.info_storage_opts.info_db_dsn = Some(dsn.clone());
+        }
+        if let Some(max_file_size) = self.max_file_size {
+            tenant_config.max_file_size = Some(max_file_size);
+        }
+        if let Some(extensions) = &self.tus_extensions {
+            tenant_config.tus_extensions = parse_list::<Extensions>(name, extensions)?;
+            tenant_config.normalize_extentions();
+        }
+        let notification_opts = &mut tenant_config.notification_opts;
+        if let Some(hooks) = &self.hooks {
+            notification_opts.hooks = parse_list::<Hook>(name, hooks)?;
+        }
+        if let Some(urls) = &self.hooks_http_urls {
+            notification_opts.hooks_http_urls.clone_from(urls);
+        }
+        if let Some(hooks_file) = &self.hooks_file {
+            notification_opts.hooks_file = Some(hooks_file.clone());
+        }
+        if let Some(hooks_dir) = &self.hooks_dir {
+            notification_opts.hooks_dir = Some(hooks_dir.clone());
+        }
+        Ok(tenant_config)
+    }
+}
+
+/// Place where uploads of a configuration are kept.
+///
+/// Tenants can't share such places, because every tenant
+/// has its own locks and removes expired uploads on its own.
+#[derive(Debug, Display, PartialEq, Eq)]
+enum UploadsLocation {
+    #[display("data directory {}", _0.display())]
+    DataDir(PathBuf),
+    #[display("info directory {}", _0.display())]
+    InfoDir(PathBuf),
+    #[display("info database {_0}")]
+    InfoDB(String),
+}
+
+/// Find all places where uploads of the configuration are kept.
+///
+/// Data of S3 storage is identified by upload ids,
+/// so only local directories are checked.
+fn uploads_locations(config: &RustusConf) -> Vec<UploadsLocation> {
+    let storage_opts = &config.storage_opts;
+    let mut storages = vec![(&storage_opts.storage, &storage_opts.data_dir)];
+    storages.extend(
+        storage_opts
+            .legacy_storages
+            .iter()
+            .map(|storage| (storage, &storage_opts.data_dir)),
+    );
+    if let Some(named_storages) = &storage_opts.named_storages {
+        storages.extend(named_storages.0.values().map(|named| {
+            (
+                &named.storage,
+                named.data_dir.as_ref().unwrap_or(&storage_opts.data_dir),
+            )
+        }));
+    }
+    let mut locations = storages
+        .into_iter()
+        .filter(|(storage, _)| **storage != AvailableDataStorages::S3)
+        .map(|(_, data_dir)| UploadsLocation::DataDir(data_dir.clone()))
+        .collect::<Vec<_>>();
+    let info_opts = &config.info_storage_opts;
+    locations.push(match info_opts.info_storage {
+        AvailableInfoStorages::Files => UploadsLocation::InfoDir(info_opts.info_dir.clone()),
+        AvailableInfoStorages::Redis => UploadsLocation::InfoDB(format!(
+            "{} with prefix \"{}\"",
+            info_opts.info_db_dsn.as_deref().unwrap_or_default(),
+            info_opts.redis_key_prefix
+        )),
+        AvailableInfoStorages::DB => {
+            UploadsLocation::InfoDB(info_opts.info_db_dsn.clone().unwrap_or_default())
+        }
+    });
+    locations
+}
+
+/// Tenants from the configuration.
+///
+/// They are passed as JSON object where keys
+/// are names of tenants and values are their options.
+#[derive(Debug, Clone, Default)]
+pub struct Tenants(pub HashMap<String, TenantOptions>);
+
+impl FromStr for Tenants {
+    type Err = RustusError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_json::from_str(s)
+            .map(Self)
+            .map_err(|err| RustusError::WrongConfig(format!("Cannot parse tenants: {err}")))
+    }
+}
+
+impl Tenants {
+    /// Create configurations of all tenants.
+    ///
+    /// Every tenant must have a unique URL, data directory
+    /// and info storage, which differ from the main ones.
+    ///
+    /// Returns names of tenants with their configurations.
+    pub fn configs(&self, config: &RustusConf) -> RustusResult<Vec<(String, RustusConf)>> {
+        let mut names = self.0.keys().collect::<Vec<_>>();
+        names.sort();
+        let mut urls = vec![config.base_url()];
+        let mut locations = uploads_locations(config);
+        let mut configs = Vec::new();
+        for name in names {
+            let tenant_config = self.0[name].apply(name, config)?;
+            let url = tenant_config.base_url();
+            if urls.contains(&url) {
+                return Err(RustusError::WrongConfig(format!(
+                    "Tenant {name} uses URL /{url}, which is already used."
+                )));
+            }
+            urls.push(url);
+            let tenant_locations = uploads_locations(&tenant_config);
+            if let Some(location) = tenant_locations
+                .iter()
+                .find(|location| locations.contains(location))
+            {
+                return Err(RustusError::WrongConfig(format!(
+                    "Tenant {name} uses {location}, which is already used."
+                )));
+            }
+            locations.extend(tenant_locations);
+            configs.push((name.clone(), tenant_config));
+        }
+        Ok(configs)
+    }
+
+    /// Create configuration of one tenant.
+    ///
+    /// All tenants are checked the same way as for the server.
+    pub fn config(&self, name: &str, config: &RustusConf) -> RustusResult<RustusConf> {
+        self.configs(config)?
+            .into_iter()
+            .find(|(tenant, _)| tenant == name)
+            .map(|(_, tenant_config)| tenant_config)
+            .ok_or_else(|| RustusError::WrongConfig(format!("Unknown tenant {name}.")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use actix_web::{
+        http::StatusCode,
+        test::{call_service, init_service, TestRequest},
+        web, App,
+    };
+
+    use super::Tenants;
+    use crate::{
+        data_storage::AvailableDataStorages, info_storage::base::InfoStorage,
+        metrics::RustusMetrics, notifiers::Hook, protocol::extensions::Extensions,
+        server::rustus_service, RustusConf, State,
+    };
+
+    fn main_config() -> RustusConf {
+        RustusConf::from_iter(["rustus", "--url", "/files", "--max-file-size", "100"])
+    }
+
+    #[test]
+    fn apply() {
+        let tenants = Tenants::from_str(
+            r#"{
+                "avatars": {
+                    "url": "/avatars",
+                    "storage": {"storage": "file-storage", "data_dir": "/avatars"},
+                    "info_dir": "/avatars_info",
+                    "max_file_size": 10,
+                    "tus_extensions": ["creation-with-upload"],
+                    "hooks": ["post-finish"]
+                },
+                "documents": {
+                    "url": "/documents",
+                    "storage": {"storage": "s3"},
+                    "info_storage": "redis-info-storage",
+                    "info_db_dsn": "redis://localhost/1"
+                }
+            }"#,
+        )
+        .unwrap();
+        let config = main_config();
+        let configs = tenants.configs(&config).unwrap();
+
+        let (name, avatars) = &configs[0];
+        assert_eq!(name, "avatars");
+        assert_eq!(avatars.base_url(), "avatars");
+        assert_eq!(
+            avatars.storage_opts.storage,
+            AvailableDataStorages::FileStorage
+        );
+        assert_eq!(avatars.storage_opts.data_dir.to_str(), Some("/avatars"));
+        assert_eq!(
+            avatars.info_storage_opts.info_dir.to_str(),
+            Some("/avatars_info")
+        );
+        assert_eq!(avatars.max_file_size, Some(10));
+        assert_eq!(
+            avatars.tus_extensions,
+            vec![Extensions::CreationWithUpload, Extensions::Creation]
+        );
+        assert_eq!(avatars.notification_opts.hooks, vec![Hook::PostFinish]);
+
+        let (name, documents) = &configs[1];
+        assert_eq!(name, "documents");
+        assert_eq!(documents.max_file_size, config.max_file_size);
+        assert_eq!(documents.tus_extensions, config.tus_extensions);
+    }
+
+    #[test]
+    fn wrong_tenants() {
+        let config = main_config();
+        for tenants in [
+            r#"{"files": {"url": "/files/"}}"#,
+            r#"{"first": {"url": "/same"}, "second": {"url": "same"}}"#,
+            r#"{"avatars": {"url": "/avatars", "hooks": ["unknown"]}}"#,
+            r#"{"avatars": {"url": "/avatars", "info_storage": "unknown"}}"#,
+            // Storages of the main configuration.
+            r#"{"avatars": {"url": "/avatars"}}"#,
+            r#"{"avatars": {"url": "/avatars", "info_dir": "/avatars"}}"#,
+            r#"{
+                "avatars": {"url": "/avatars", "info_dir": "/avatars", "storage": {"storage": "file-storage", "data_dir": "./data/"}}
+            }"#,
+            // Storages of another tenant.
+            r#"{
+                "first": {"url": "/first", "info_dir": "/first", "storage": {"storage": "s3"}},
+                "second": {"url": "/second", "info_dir": "/first", "storage": {"storage": "s3"}}
+            }"#,
+        ] {
+            assert!(Tenants::from_str(tenants)
+                .unwrap()
+                .configs(&config)
+                .is_err());
+        }
+        assert!(Tenants::from_str(r#"{"avatars": {"path": "/avatars"}}"#).is_err());
+        // CORS is shared by all tenants.
+        assert!(Tenants::from_str(
+            r#"{"avatars": {"url": "/avatars", "cors": ["*.example.com"]}}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn tenant_config() {
+        let tenants = Tenants::from_str(
+            r#"{"avatars": {"url": "/avatars", "info_dir": "/avatars", "storage": {"storage": "s3"}}}"#,
+        )
+        .unwrap();
+        let config = main_config();
+        let avatars = tenants.config("avatars", &config).unwrap();
+        assert_eq!(avatars.base_url(), "avatars");
+        assert!(avatars.tenants.is_none());
+        assert!(tenants.config("documents", &config).is_err());
+    }
+
+    #[actix_rt::test]
+    async fn separate_state() {
+        let state = State::test_new().await;
+        let data_dir = tempdir::TempDir::new("avatars_data").unwrap();
+        let info_dir = tempdir::TempDir::new("avatars_info").unwrap();
+        let tenants = serde_json::json!({
+            "avatars": {
+                "url": "/avatars",
+                "info_dir": info_dir.path(),
+                "storage": {"storage": "file-storage", "data_dir": data_dir.path()},
+            }
+        });
+        let tenant_config = Tenants::from_str(&tenants.to_string())
+            .unwrap()
+            .configs(&state.config)
+            .unwrap()
+            .remove(0)
+            .1;
+        let tenant = State::from_config_test(tenant_config).await;
+        let service = init_service(
+            App::new()
+                .app_data(web::Data::new(RustusMetrics::new().unwrap()))
+                .configure(rustus_service(tenant.clone()))
+                .configure(rustus_service(state.clone())),
+        )
+        .await;
+
+        let request = TestRequest::post()
+            .uri("/avatars/")
+            .insert_header(("Upload-Length", 10))
+            .to_request();
+        let response = call_service(&service, request).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let location = response
+            .headers()
+            .get("Location")
+            .unwrap()
+            .to_str()
+            .unwrap();
+        assert!(location.contains("/avatars/"));
+        let file_id = location.trim_end_matches('/').rsplit('/').next().unwrap();
+        assert!(tenant.info_storage.get_info(file_id).await.is_ok());
+        assert!(state.info_storage.get_info(file_id).await.is_err());
+    }
+}
@@ -27,8 +27,6 @@ macro_rules! from_str {
 
 #[cfg(test)]
 mod tests {
-    use crate::from_str;
-
     use derive_more::{Display, From};
     use std::str::FromStr;
     use strum::EnumIter;
@@ -1,32 +1,345 @@
-use crate::{errors::RustusError, RustusResult};
+use std::{
+    cell::{Cell, RefCell},
+    rc::Rc,
+    str::FromStr,
+};
+
+use crate::{
+    data_storage::base::ByteStream, errors::RustusError, file_info::FileInfo, from_str,
+    RustusResult,
+};
 use actix_web::http::header::HeaderValue;
-use base64::Engine;
+use base64::{engine::general_purpose, Engine};
+use derive_more::{Display, From};
 use digest::Digest;
+use digest_state::{
+    common::hazmat::{SerializableState, SerializedState},
+    Digest as _,
+};
+use futures::{StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use strum::{EnumIter, IntoEnumIterator};
 
-/// Checks if hash-sum of a slice matches the given checksum.
-fn checksum_verify(algo: &str, bytes: &[u8], checksum: &[u8]) -> RustusResult<bool> {
-    #[allow(clippy::branches_sharing_code)]
-    match algo {
-        "sha1" => {
-            let sum = sha1::Sha1::digest(bytes);
-            Ok(sum.as_slice() == checksum)
+/// Metadata key, where clients can declare
+/// checksum of the whole upload.
+///
+/// Its value has the same format as `Upload-Checksum` header.
+pub const CHECKSUM_METADATA_KEY: &str = "checksum";
+
+/// Algorithms that can be used to compute
+/// digest of the whole upload.
+#[derive(Clone, Copy, Debug, Eq, Display, From, PartialEq, EnumIter, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DigestAlgorithm {
+    #[display("md5")]
+    Md5,
+    #[display("sha256")]
+    Sha256,
+}
+
+from_str!(DigestAlgorithm, "digest algorithm");
+
+/// Digest of the whole upload.
+///
+/// While upload is in progress, only serialized state
+/// of the hasher is stored. When upload is complete,
+/// the state is replaced with the final digest.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UploadDigest {
+    pub algorithm: DigestAlgorithm,
+    /// Base64 encoded state of the hasher.
+    ///
+    /// It's internal, so it's kept only in info storages
+    /// and never sent to hooks or admin API.
+    #[serde(skip)]
+    pub state: Option<String>,
+    /// Base64 encoded digest of the complete upload.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+}
+
+impl UploadDigest {
+    #[must_use]
+    pub fn new(algorithm: DigestAlgorithm) -> Self {
+        Self {
+            algorithm,
+            state: Some(general_purpose::STANDARD.encode(UploadHasher::new(algorithm).state())),
+            value: None,
+        }
+    }
+
+    /// Value for the `Upload-Digest` header.
+    ///
+    /// It has the same format as `Upload-Checksum` header
+    /// and it's available only for complete uploads.
+    #[must_use]
+    pub fn header_value(&self) -> Option<String> {
+        self.value
+            .as_ref()
+            .map(|value| format!("{} {value}", self.algorithm))
+    }
+}
+
+/// Hasher for one of supported digest algorithms.
+#[derive(Clone)]
+enum UploadHasher {
+    Md5(md5_state::Md5),
+    Sha256(sha2_state::Sha256),
+}
+
+impl UploadHasher {
+    fn new(algorithm: DigestAlgorithm) -> Self {
+        match algorithm {
+            DigestAlgorithm::Md5 => Self::Md5(md5_state::Md5::new()),
+            DigestAlgorithm::Sha256 => Self::Sha256(sha2_state::Sha256::new()),
+        }
+    }
+
+    /// Restore hasher from its serialized state.
+    fn restore(algorithm: DigestAlgorithm, state: &[u8]) -> Option<Self> {
+        match algorithm {
+            DigestAlgorithm::Md5 => SerializedState::<md5_state::Md5>::try_from(state)
+                .ok()
+                .and_then(|state| md5_state::Md5::deserialize(&state).ok())
+                .map(Self::Md5),
+            DigestAlgorithm::Sha256 => SerializedState::<sha2_state::Sha256>::try_from(state)
+                .ok()
+                .and_then(|state| sha2_state::Sha256::deserialize(&state).ok())
+                .map(Self::Sha256),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Md5(hasher) => hasher.update(data),
+            Self::Sha256(hasher) => hasher.update(data),
         }
-        "sha256" => {
-            let sum = sha2::Sha256::digest(bytes);
-            Ok(sum.as_slice() == checksum)
+    }
+
+    fn state(&self) -> Vec<u8> {
+        match self {
+            Self::Md5(hasher) => hasher.serialize().to_vec(),
+            Self::Sha256(hasher) => hasher.serialize().to_vec(),
         }
-        "sha512" => {
-            let sum = sha2::Sha512::digest(bytes);
-            Ok(sum.as_slice() == checksum)
+    }
+
+    fn finalize(self) -> Vec<u8> {
+        match self {
+            Self::Md5(hasher) => hasher.finalize().to_vec(),
+            Self::Sha256(hasher) => hasher.finalize().to_vec(),
         }
-        "md5" => {
-            let sum = md5::Md5::digest(bytes);
-            Ok(sum.as_slice() == checksum)
+    }
+}
+
+/// Digest, which is computed while bytes are being written.
+pub struct RunningDigest {
+    hasher: Option<Rc<RefCell<UploadHasher>>>,
+    hashed_len: Rc<Cell<usize>>,
+    /// Checksum declared by the client and number of bytes
+    /// left before the upload is complete.
+    expected: Option<(String, usize)>,
+    algorithm: Option<DigestAlgorithm>,
+}
+
+impl RunningDigest {
+    /// Restore running digest of an upload.
+    ///
+    /// If the upload doesn't have a digest,
+    /// streams are passed through as is.
+    /// If digests are disabled, or computed with another algorithm,
+    /// the digest of the upload is discarded on save.
+    /// Uploads with a declared checksum keep their algorithm,
+    /// since the checksum has to be verified anyway.
+    ///
+    /// # Params
+    /// `enabled` - algorithm of digests from the configuration.
+    #[must_use]
+    pub fn restore(file_info: &FileInfo, enabled: Option<DigestAlgorithm>) -> Self {
+        let declared = file_info.metadata.get(CHECKSUM_METADATA_KEY);
+        let algorithm = file_info
+            .digest
+            .as_ref()
+            .filter(|digest| Some(digest.algorithm) == enabled || declared.is_some())
+            .map(|digest| digest.algorithm);
+        let hasher = file_info
+            .digest
+            .as_ref()
+            .filter(|_| algorithm.is_some())
+            .and_then(|digest| Some((digest.algorithm, digest.state.as_ref()?)))
+            .and_then(|(algorithm, state)| {
+                let hasher = general_purpose::STANDARD
+                    .decode(state)
+                    .ok()
+                    .and_then(|state| UploadHasher::restore(algorithm, &state));
+                if hasher.is_none() {
+                    log::warn!("Cannot restore digest state of upload {}", file_info.id);
+                }
+                hasher
+            });
+        let expected = declared.zip(file_info.length).map(|(checksum, length)| {
+            (
+                checksum.trim().to_string(),
+                length.saturating_sub(file_info.offset),
+            )
+        });
+        Self {
+            hasher: hasher.map(|hasher| Rc::new(RefCell::new(hasher))),
+            hashed_len: Rc::new(Cell::new(0)),
+            expected,
+            algorithm,
+        }
+    }
+
+    /// Feed every chunk of the stream into the hasher.
+    ///
+    /// If the client has declared a checksum of the upload,
+    /// it's verified before the last chunk is passed further.
+    /// The stream returns `WrongChecksum` error instead of
+    /// the chunk if they don't match, so the upload is never completed.
+    #[must_use]
+    pub fn wrap(&self, stream: ByteStream) -> ByteStream {
+        let (Some(hasher), Some(algorithm)) = (self.hasher.clone(), self.algorithm) else {
+            return stream;
+        };
+        let hashed_len = self.hashed_len.clone();
+        let expected = self.expected.clone();
+        stream
+            .and_then(move |bytes| {
+                hasher.borrow_mut().update(&bytes);
+                hashed_len.set(hashed_len.get() + bytes.len());
+                let result = match &expected {
+                    Some((checksum, remaining)) if hashed_len.get() >= *remaining => {
+                        let digest = hasher.borrow().clone().finalize();
+                        let actual =
+                            format!("{algorithm} {}", general_purpose::STANDARD.encode(digest));
+                        if actual == *checksum {
+                            Ok(bytes)
+                        } else {
+                            Err(RustusError::WrongChecksum)
+                        }
+                    }
+                    _ => Ok(bytes),
+                };
+                futures::future::ready(result)
+            })
+            .boxed_local()
+    }
+
+    /// Save the digest into the file info.
+    ///
+    /// Digest is finalized if the upload is complete.
+    /// If the storage hasn't written all hashed bytes,
+    /// the digest can't be trusted anymore, so it's removed.
+    /// Digests of uploads with a declared checksum are kept without a value,
+    /// so the upload fails verification when it's complete.
+    ///
+    /// # Params
+    /// `start_offset` - offset of the upload before writing.
+    pub fn save(self, file_info: &mut FileInfo, start_offset: usize) {
+        let complete = Some(file_info.offset) == file_info.length;
+        let all_written = file_info.offset - start_offset == self.hashed_len.get();
+        let declared = file_info.metadata.contains_key(CHECKSUM_METADATA_KEY);
+        let Some(digest) = file_info
+            .digest
+            .as_mut()
+            .filter(|digest| digest.value.is_none())
+        else {
+            return;
+        };
+        let Some(hasher) = self.hasher.filter(|_| all_written) else {
+            if declared {
+                log::warn!(
+                    "Digest of upload {} can't be computed, its checksum can't be verified.",
+                    file_info.id
+                );
+                digest.state = None;
+            } else {
+                log::warn!(
+                    "Digest of upload {} can't be computed and is discarded.",
+                    file_info.id
+                );
+                file_info.digest = None;
+            }
+            return;
+        };
+        let hasher = hasher.borrow().clone();
+        if complete {
+            digest.value = Some(general_purpose::STANDARD.encode(hasher.finalize()));
+            digest.state = None;
+        } else {
+            digest.state = Some(general_purpose::STANDARD.encode(hasher.state()));
+        }
+    }
+}
+
+/// Check that digest of a complete upload matches
+/// the checksum declared by the client in metadata.
+///
+/// Returns `true` if there's nothing to compare.
+/// Returns `false` if the checksum is declared,
+/// but the digest of the complete upload can't be computed.
+#[must_use]
+pub fn upload_digest_matches(file_info: &FileInfo) -> bool {
+    if Some(file_info.offset) != file_info.length {
+        return true;
+    }
+    let Some(expected) = file_info.metadata.get(CHECKSUM_METADATA_KEY) else {
+        return true;
+    };
+    let Some(digest) = file_info.digest.as_ref() else {
+        return true;
+    };
+    digest
+        .header_value()
+        .is_some_and(|actual| expected.trim() == actual)
+}
+
+/// Algorithms supported by the checksum extension.
+#[derive(Clone, Copy, Debug, Eq, Display, From, PartialEq, EnumIter)]
+pub enum ChecksumAlgorithm {
+    #[display("md5")]
+    Md5,
+    #[display("sha1")]
+    Sha1,
+    #[display("sha256")]
+    Sha256,
+    #[display("sha512")]
+    Sha512,
+    #[display("crc32c")]
+    Crc32c,
+    #[display("xxh3")]
+    Xxh3,
+    #[display("blake3")]
+    Blake3,
+}
+
+from_str!(ChecksumAlgorithm, "checksum algorithm");
+
+impl ChecksumAlgorithm {
+    /// Compute checksum of a slice.
+    ///
+    /// Non-cryptographic checksums are represented
+    /// as big-endian integers, the same way S3 and GCS do it.
+    fn checksum(self, bytes: &[u8]) -> Vec<u8> {
+        match self {
+            Self::Md5 => md5::Md5::digest(bytes).to_vec(),
+            Self::Sha1 => sha1::Sha1::digest(bytes).to_vec(),
+            Self::Sha256 => sha2::Sha256::digest(bytes).to_vec(),
+            Self::Sha512 => sha2::Sha512::digest(bytes).to_vec(),
+            Self::Crc32c => crc32c::crc32c(bytes).to_be_bytes().to_vec(),
+            Self::Xxh3 => xxhash_rust::xxh3::xxh3_64(bytes).to_be_bytes().to_vec(),
+            Self::Blake3 => blake3::hash(bytes).as_bytes().to_vec(),
         }
-        _ => Err(RustusError::UnknownHashAlgorithm),
     }
 }
 
+/// Checks if hash-sum of a slice matches the given checksum.
+fn checksum_verify(algo: &str, bytes: &[u8], checksum: &[u8]) -> RustusResult<bool> {
+    let algorithm =
+        ChecksumAlgorithm::from_str(algo).map_err(|_| RustusError::UnknownHashAlgorithm)?;
+    Ok(algorithm.checksum(bytes) == checksum)
+}
+
 /// Verify checksum of a given chunk based on header's value.
 ///
 /// This function decodes given header value.
@@ -40,12 +353,23 @@ fn checksum_verify(algo: &str, bytes: &[u8], checksum: &[u8]) -> RustusResult<bo
 /// # Errors
 ///
 /// It may return error if header value can't be represented as string,
-/// if checksum can't be decoded with base64 or if unknown algorithm is used.
-pub fn verify_chunk_checksum(header: &HeaderValue, data: &[u8]) -> RustusResult<bool> {
+/// if checksum can't be decoded with base64 or if the algorithm is
+/// not in the list of `algorithms`.
+pub fn verify_chunk_checksum(
+    header: &HeaderValue,
+    data: &[u8],
+    algorithms: &[ChecksumAlgorithm],
+) -> RustusResult<bool> {
     #[allow(clippy::branches_sharing_code)]
     if let Ok(val) = header.to_str() {
         let mut split = val.split(' ');
         if let Some(algo) = split.next() {
+            if !algorithms
+                .iter()
+                .any(|algorithm| algorithm.to_string() == algo)
+            {
+                return Err(RustusError::UnknownHashAlgorithm);
+            }
             if let Some(checksum_base) = split.next() {
                 let checksum = base64::engine::general_purpose::STANDARD
                     .decode(checksum_base)
@@ -65,8 +389,32 @@ pub fn verify_chunk_checksum(header: &HeaderValue, data: &[u8]) -> RustusResult<
 
 #[cfg(test)]
 mod tests {
-    use super::{checksum_verify, verify_chunk_checksum};
+    use super::{
+        checksum_verify, upload_digest_matches, verify_chunk_checksum, ChecksumAlgorithm,
+        DigestAlgorithm, RunningDigest, UploadDigest, CHECKSUM_METADATA_KEY,
+    };
+    use crate::{data_storage::base::bytes_stream, errors::RustusError, file_info::FileInfo};
     use actix_web::http::header::HeaderValue;
+    use futures::TryStreamExt;
+    use strum::IntoEnumIterator;
+
+    fn all_algorithms() -> Vec<ChecksumAlgorithm> {
+        ChecksumAlgorithm::iter().collect()
+    }
+
+    /// Write bytes to the upload as storages do.
+    async fn write(file_info: &mut FileInfo, data: &'static [u8]) {
+        let start_offset = file_info.offset;
+        let algorithm = file_info.digest.as_ref().map(|digest| digest.algorithm);
+        let digest = RunningDigest::restore(file_info, algorithm);
+        let chunks = digest
+            .wrap(bytes_stream(data))
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        file_info.offset += chunks.iter().map(bytes::Bytes::len).sum::<usize>();
+        digest.save(file_info, start_offset);
+    }
 
     #[test]
     fn test_success_checksum_verify() {
@@ -94,6 +442,31 @@ mod tests {
         assert!(res);
     }
 
+    #[test]
+    fn test_success_fast_checksum_verify() {
+        let res = checksum_verify("crc32c", b"hello", b"\x9a\x71\xbb\x4c").unwrap();
+        assert!(res);
+        let res = checksum_verify("xxh3", b"hello", b"\x95\x55\xe8\x55\x5c\x62\xdc\xfd").unwrap();
+        assert!(res);
+        let res = verify_chunk_checksum(
+            &HeaderValue::from_str("blake3 6o8WPbOGgpJeRJHF5Y1Ls1Bu+MFOt4qG6QjFYkpnIA8=").unwrap(),
+            b"hello",
+            &all_algorithms(),
+        )
+        .unwrap();
+        assert!(res);
+    }
+
+    #[test]
+    fn test_disabled_algorithm() {
+        let res = verify_chunk_checksum(
+            &HeaderValue::from_str("md5 XUFAKrxLKna5cZ2REBfFkg==").unwrap(),
+            b"hello",
+            &[ChecksumAlgorithm::Crc32c],
+        );
+        assert!(res.is_err());
+    }
+
     #[test]
     fn test_sum_unknown_algo_checksum_verify() {
         let res = checksum_verify("base64", b"test", b"dGVzdAo=");
@@ -105,6 +478,7 @@ mod tests {
         let res = verify_chunk_checksum(
             &HeaderValue::from_str("md5 XUFAKrxLKna5cZ2REBfFkg==").unwrap(),
             b"hello",
+            &all_algorithms(),
         )
         .unwrap();
         assert!(res);
@@ -112,7 +486,11 @@ mod tests {
 
     #[test]
     fn test_wrong_checksum() {
-        let res = verify_chunk_checksum(&HeaderValue::from_str("md5 memes==").unwrap(), b"hello");
+        let res = verify_chunk_checksum(
+            &HeaderValue::from_str("md5 memes==").unwrap(),
+            b"hello",
+            &all_algorithms(),
+        );
         assert!(res.is_err());
     }
 
@@ -121,13 +499,122 @@ mod tests {
         let res = verify_chunk_checksum(
             &HeaderValue::from_bytes(b"ewq ]A@*\xbcK*v").unwrap(),
             b"hello",
+            &all_algorithms(),
         );
         assert!(res.is_err());
     }
 
     #[test]
     fn test_badly_formatted_header() {
-        let res = verify_chunk_checksum(&HeaderValue::from_str("md5").unwrap(), b"hello");
+        let res = verify_chunk_checksum(
+            &HeaderValue::from_str("md5").unwrap(),
+            b"hello",
+            &all_algorithms(),
+        );
         assert!(res.is_err());
     }
+
+    #[actix_rt::test]
+    async fn test_running_digest() {
+        let mut file_info = FileInfo::new("id", Some(10), None, String::from("storage"), None);
+        file_info.digest = Some(UploadDigest::new(DigestAlgorithm::Md5));
+        write(&mut file_info, b"hello").await;
+        let digest = file_info.digest.clone().unwrap();
+        assert!(digest.state.is_some());
+        assert!(digest.header_value().is_none());
+        // State of the hasher is saved only in info storages.
+        let public = serde_json::to_value(&file_info).unwrap();
+        assert!(public["digest"].get("state").is_none());
+        let mut file_info = FileInfo::from_stored(&file_info.to_stored().unwrap()).unwrap();
+        assert_eq!(file_info.digest.as_ref().unwrap().state, digest.state);
+        write(&mut file_info, b"world").await;
+        let digest = file_info.digest.clone().unwrap();
+        assert!(digest.state.is_none());
+        assert_eq!(
+            digest.header_value().unwrap(),
+            "md5 /F4DjTilcDIIVEHn/nAQsA=="
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_digest_discarded() {
+        let mut file_info = FileInfo::new("id", Some(10), None, String::from("storage"), None);
+        file_info.digest = Some(UploadDigest::new(DigestAlgorithm::Sha256));
+        let digest = RunningDigest::restore(&file_info, Some(DigestAlgorithm::Sha256));
+        digest
+            .wrap(bytes_stream("hello"))
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        // Storage has written less bytes than it received.
+        file_info.offset = 2;
+        digest.save(&mut file_info, 0);
+        assert!(file_info.digest.is_none());
+    }
+
+    #[actix_rt::test]
+    async fn test_digest_disabled() {
+        let mut file_info = FileInfo::new("id", Some(10), None, String::from("storage"), None);
+        file_info.digest = Some(UploadDigest::new(DigestAlgorithm::Sha256));
+        let digest = RunningDigest::restore(&file_info, None);
+        let chunks = digest
+            .wrap(bytes_stream("hello"))
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        file_info.offset = chunks.iter().map(bytes::Bytes::len).sum();
+        digest.save(&mut file_info, 0);
+        assert!(file_info.digest.is_none());
+    }
+
+    #[test]
+    fn test_upload_digest_matches() {
+        let mut file_info = FileInfo::new("id", Some(0), None, String::from("storage"), None);
+        file_info.digest = Some(UploadDigest {
+            algorithm: DigestAlgorithm::Md5,
+            state: None,
+            value: Some(String::from("1B2M2Y8AsgTpgAmY7PhCfg==")),
+        });
+        assert!(upload_digest_matches(&file_info));
+        file_info.metadata.insert(
+            String::from(CHECKSUM_METADATA_KEY),
+            String::from("md5 1B2M2Y8AsgTpgAmY7PhCfg=="),
+        );
+        assert!(upload_digest_matches(&file_info));
+        file_info.metadata.insert(
+            String::from(CHECKSUM_METADATA_KEY),
+            String::from("md5 XUFAKrxLKna5cZ2REBfFkg=="),
+        );
+        assert!(!upload_digest_matches(&file_info));
+        // Digest of the upload couldn't be computed.
+        file_info.digest.as_mut().unwrap().value = None;
+        assert!(!upload_digest_matches(&file_info));
+    }
+
+    #[actix_rt::test]
+    async fn test_declared_checksum_mismatch() {
+        let mut file_info = FileInfo::new("id", Some(10), None, String::from("storage"), None);
+        file_info.digest = Some(UploadDigest::new(DigestAlgorithm::Md5));
+        file_info.metadata.insert(
+            String::from(CHECKSUM_METADATA_KEY),
+            String::from("md5 XUFAKrxLKna5cZ2REBfFkg=="),
+        );
+        // Algorithm of the upload is kept, since the checksum is declared.
+        let digest = RunningDigest::restore(&file_info, None);
+        let chunks = digest
+            .wrap(bytes_stream("hello"))
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        file_info.offset = chunks.iter().map(bytes::Bytes::len).sum();
+        digest.save(&mut file_info, 0);
+        assert!(file_info.digest.as_ref().unwrap().state.is_some());
+        // Last chunk doesn't match the checksum.
+        let digest = RunningDigest::restore(&file_info, None);
+        let result = digest
+            .wrap(bytes_stream("world"))
+            .try_collect::<Vec<_>>()
+            .await;
+        assert!(matches!(result, Err(RustusError::WrongChecksum)));
+    }
 }
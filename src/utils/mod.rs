@@ -3,3 +3,4 @@ pub mod enums;
 pub mod hashes;
 pub mod headers;
 pub mod lapin_pool;
+pub mod payload;
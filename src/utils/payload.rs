@@ -0,0 +1,48 @@
+use actix_web::web;
+use bytes::BytesMut;
+use futures::{StreamExt, TryStreamExt};
+
+use crate::{
+    data_storage::base::ByteStream,
+    errors::{RustusError, RustusResult},
+};
+
+/// Convert request payload into a stream of chunks.
+///
+/// If `limit` is set, the stream returns an error
+/// as soon as more than `limit` bytes were received.
+/// All chunks before the one that exceeded the limit
+/// are passed through.
+pub fn payload_stream(payload: web::Payload, limit: Option<usize>) -> ByteStream {
+    let mut received = 0usize;
+    payload
+        .map_err(RustusError::from)
+        .and_then(move |chunk| {
+            received += chunk.len();
+            let result = if limit.is_some_and(|limit| received > limit) {
+                Err(RustusError::BodyTooLarge)
+            } else {
+                Ok(chunk)
+            };
+            futures::future::ready(result)
+        })
+        .boxed_local()
+}
+
+/// Read the whole request payload into memory.
+///
+/// # Errors
+///
+/// Returns an error if the payload can't be read
+/// or if it's larger than `limit`.
+pub async fn read_payload(mut payload: web::Payload, limit: usize) -> RustusResult<web::Bytes> {
+    let mut body = BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk?;
+        if body.len() + chunk.len() > limit {
+            return Err(RustusError::BodyTooLarge);
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body.freeze())
+}